@@ -1,7 +1,20 @@
 use std::error::Error;
 
 fn main() -> Result<(), Box<dyn Error>> {
-    prost_build::compile_protos(&["proto/messages.proto"], &["proto/"])?;
+    let mut config = prost_build::Config::new();
+
+    // The wire messages live in the `wire` crate; the `Gateway` service only turns into code with the gRPC server
+    #[cfg(feature = "grpc")]
+    {
+        config.extern_path(".messages", "::wire::message");
+        tonic_build::configure().compile_protos_with_config(
+            config,
+            &["wire/proto/messages.proto", "proto/storage.proto"],
+            &["wire/proto/", "proto/"],
+        )?;
+    }
+    #[cfg(not(feature = "grpc"))]
+    config.compile_protos(&["proto/storage.proto"], &["proto/"])?;
 
     Ok(())
 }
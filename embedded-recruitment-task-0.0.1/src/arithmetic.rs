@@ -0,0 +1,142 @@
+// Evaluation of `ArithmeticRequest`s.
+//
+// Two integer operands give an integer result computed with checked
+// arithmetic. If either operand is a double, both are promoted and the result
+// is a double. Division and modulo by zero are errors for both kinds, and a
+// finite double computation that ends up infinite counts as an overflow.
+use crate::message::{operand, ArithmeticOperation, Operand};
+use std::fmt;
+
+/// A single operand or result value
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Number {
+    Int(i64),
+    Double(f64),
+}
+
+impl Number {
+    fn as_f64(self) -> f64 {
+        match self {
+            Number::Int(value) => value as f64,
+            Number::Double(value) => value,
+        }
+    }
+}
+
+impl From<Number> for Operand {
+    fn from(number: Number) -> Self {
+        let value = match number {
+            Number::Int(value) => operand::Value::IntValue(value),
+            Number::Double(value) => operand::Value::DoubleValue(value),
+        };
+        Operand { value: Some(value) }
+    }
+}
+
+impl TryFrom<Option<Operand>> for Number {
+    type Error = ArithmeticError;
+
+    fn try_from(operand: Option<Operand>) -> Result<Self, Self::Error> {
+        match operand.and_then(|operand| operand.value) {
+            Some(operand::Value::IntValue(value)) => Ok(Number::Int(value)),
+            Some(operand::Value::DoubleValue(value)) => Ok(Number::Double(value)),
+            None => Err(ArithmeticError::MissingOperand),
+        }
+    }
+}
+
+/// Reasons an arithmetic request cannot produce a result
+#[derive(Debug, Clone, PartialEq)]
+pub enum ArithmeticError {
+    /// The divisor of a division or modulo is zero
+    DivisionByZero,
+    /// The result does not fit the result type
+    Overflow,
+    /// An operand is not set
+    MissingOperand,
+    /// The operation field holds a value this server does not know
+    UnknownOperation(i32),
+    /// Integer power with a negative exponent, use double operands instead
+    NegativeExponent(i64),
+    /// The double result is NaN, e.g. a fractional power of a negative number
+    NotANumber,
+}
+
+impl fmt::Display for ArithmeticError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ArithmeticError::DivisionByZero => write!(f, "division by zero"),
+            ArithmeticError::Overflow => write!(f, "arithmetic overflow"),
+            ArithmeticError::MissingOperand => write!(f, "both operands must be set"),
+            ArithmeticError::UnknownOperation(operation) => write!(f, "unknown operation {}", operation),
+            ArithmeticError::NegativeExponent(exponent) => {
+                write!(f, "integer power with negative exponent {}", exponent)
+            }
+            ArithmeticError::NotANumber => write!(f, "result is not a number"),
+        }
+    }
+}
+
+impl std::error::Error for ArithmeticError {}
+
+/// Applies `operation` to the two operands
+pub fn evaluate(operation: ArithmeticOperation, lhs: Number, rhs: Number) -> Result<Number, ArithmeticError> {
+    match (lhs, rhs) {
+        (Number::Int(lhs), Number::Int(rhs)) => evaluate_int(operation, lhs, rhs).map(Number::Int),
+        _ => evaluate_double(operation, lhs.as_f64(), rhs.as_f64()).map(Number::Double),
+    }
+}
+
+/// Integer-only evaluation with checked arithmetic
+pub fn evaluate_int(operation: ArithmeticOperation, lhs: i64, rhs: i64) -> Result<i64, ArithmeticError> {
+    let result = match operation {
+        ArithmeticOperation::Add => lhs.checked_add(rhs),
+        ArithmeticOperation::Subtract => lhs.checked_sub(rhs),
+        ArithmeticOperation::Multiply => lhs.checked_mul(rhs),
+        ArithmeticOperation::Divide | ArithmeticOperation::Modulo if rhs == 0 => {
+            return Err(ArithmeticError::DivisionByZero);
+        }
+        ArithmeticOperation::Divide => lhs.checked_div(rhs), // Only i64::MIN / -1 overflows
+        ArithmeticOperation::Modulo => Some(lhs.wrapping_rem(rhs)), // i64::MIN % -1 is 0, only the quotient overflows
+        ArithmeticOperation::Power if rhs < 0 => return Err(ArithmeticError::NegativeExponent(rhs)),
+        ArithmeticOperation::Power => match u32::try_from(rhs) {
+            Ok(exponent) => lhs.checked_pow(exponent),
+            // Exponents beyond u32 only stay in range for bases -1, 0 and 1
+            Err(_) => match lhs {
+                0 | 1 => Some(lhs),
+                -1 => Some(if rhs % 2 == 0 { 1 } else { -1 }),
+                _ => None,
+            },
+        },
+    };
+    result.ok_or(ArithmeticError::Overflow)
+}
+
+fn evaluate_double(operation: ArithmeticOperation, lhs: f64, rhs: f64) -> Result<f64, ArithmeticError> {
+    let result = match operation {
+        ArithmeticOperation::Add => lhs + rhs,
+        ArithmeticOperation::Subtract => lhs - rhs,
+        ArithmeticOperation::Multiply => lhs * rhs,
+        ArithmeticOperation::Divide | ArithmeticOperation::Modulo if rhs == 0.0 => {
+            return Err(ArithmeticError::DivisionByZero);
+        }
+        ArithmeticOperation::Divide => lhs / rhs,
+        ArithmeticOperation::Modulo => lhs % rhs,
+        ArithmeticOperation::Power => lhs.powf(rhs),
+    };
+
+    if result.is_nan() && !lhs.is_nan() && !rhs.is_nan() {
+        Err(ArithmeticError::NotANumber)
+    } else if result.is_infinite() && lhs.is_finite() && rhs.is_finite() {
+        Err(ArithmeticError::Overflow)
+    } else {
+        Ok(result)
+    }
+}
+
+/// Evaluates an operation given as its raw protobuf enum value
+pub fn evaluate_raw(operation: i32, lhs: Option<Operand>, rhs: Option<Operand>) -> Result<Number, ArithmeticError> {
+    let operation = ArithmeticOperation::try_from(operation)
+        .map_err(|_| ArithmeticError::UnknownOperation(operation))?;
+    evaluate(operation, Number::try_from(lhs)?, Number::try_from(rhs)?)
+}
@@ -0,0 +1,327 @@
+// Audit trail of handled requests.
+//
+// Every request a connection sends produces one `AuditRecord`, handed to the
+// server's `AuditSink`. `AuditLog` is the file-backed sink: one JSON object
+// per line, rotated by size as
+//
+//     audit.log  audit.log.1  audit.log.2 ...   newest to oldest
+//
+// With the hash chain enabled, each line also carries the hash of the line
+// before it (`prev`) and its own (`hash`), the SHA-256 of the line up to and
+// including `prev`. The chain continues across rotation and restarts, so
+// editing, removing or reordering any record breaks every later hash;
+// `verify_chain` walks the files and reports the first broken line.
+use crate::message::{server_message, ErrorCode, ServerMessage};
+use log::error;
+use sha2::{Digest, Sha256};
+use std::{
+    fmt::{self, Write as _},
+    fs::{self, File, OpenOptions},
+    io::{self, BufRead, BufReader, ErrorKind, Write},
+    net::SocketAddr,
+    path::PathBuf,
+    sync::Mutex,
+    time::Duration,
+};
+
+/// `prev` of the very first record in a chain
+pub const GENESIS_HASH: &str = "0000000000000000000000000000000000000000000000000000000000000000";
+// Length of the `,"hash":"<hex>"}` suffix of a chained line
+const HASH_SUFFIX_LEN: usize = 9 + 64 + 2;
+
+/// How a request ended
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Outcome {
+    /// The request was answered, or its stream started
+    Ok,
+    /// The request was answered with an `Error` of this code
+    Error(ErrorCode),
+}
+
+impl Outcome {
+    /// Outcome of a request answered with `response`, `None` meaning a stream started
+    pub fn of(response: Option<&ServerMessage>) -> Self {
+        match response.and_then(|response| response.message.as_ref()) {
+            Some(server_message::Message::Error(error)) => {
+                Outcome::Error(ErrorCode::try_from(error.code).unwrap_or(ErrorCode::Unknown))
+            }
+            _ => Outcome::Ok,
+        }
+    }
+}
+
+/// One handled request
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AuditRecord {
+    /// Milliseconds since the Unix epoch when the request arrived
+    pub time_ms: u64,
+    /// Connection the request arrived on
+    pub connection: u64,
+    /// Remote address of that connection, if known
+    pub peer: Option<SocketAddr>,
+    /// Identity the connection had authenticated as after the request, `None` if none
+    pub identity: Option<String>,
+    /// Request id chosen by the client
+    pub request_id: u64,
+    /// Message type, named after its `ClientMessage` field
+    pub operation: &'static str,
+    /// How the request ended
+    pub outcome: Outcome,
+    /// Time spent handling the request
+    pub duration: Duration,
+}
+
+// Appends `value` as a JSON string literal
+fn push_json_string(out: &mut String, value: &str) {
+    out.push('"');
+    for c in value.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => {
+                let _ = write!(out, "\\u{:04x}", c as u32);
+            }
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+}
+
+impl AuditRecord {
+    /// The record as a single-line JSON object
+    pub fn to_json(&self) -> String {
+        let mut out = String::with_capacity(256);
+        let _ = write!(out, "{{\"time_ms\":{},\"connection\":{},\"peer\":", self.time_ms, self.connection);
+        match self.peer {
+            Some(peer) => push_json_string(&mut out, &peer.to_string()),
+            None => out.push_str("null"),
+        }
+        out.push_str(",\"identity\":");
+        match &self.identity {
+            Some(identity) => push_json_string(&mut out, identity),
+            None => out.push_str("null"),
+        }
+        let _ = write!(out, ",\"request_id\":{},\"operation\":", self.request_id);
+        push_json_string(&mut out, self.operation);
+        match self.outcome {
+            Outcome::Ok => out.push_str(",\"outcome\":\"ok\""),
+            Outcome::Error(code) => {
+                out.push_str(",\"outcome\":\"error\",\"error\":");
+                push_json_string(&mut out, code.as_str_name());
+            }
+        }
+        let _ = write!(out, ",\"duration_us\":{}}}", self.duration.as_micros());
+        out
+    }
+}
+
+/// Receives a record for every handled request.
+///
+/// Implement this to ship records somewhere other than a local file. Called on
+/// the connection's thread, so it should not block for long.
+pub trait AuditSink: Send + Sync + fmt::Debug {
+    fn record(&self, record: &AuditRecord);
+}
+
+/// Where the audit log is written and when it rotates
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AuditConfig {
+    /// Current log file, rotated files get `.1`, `.2`, ... appended
+    pub path: PathBuf,
+    /// Rotate once the current file reaches this many bytes
+    pub max_bytes: u64,
+    /// Rotated files to keep, older ones are deleted
+    pub max_files: usize,
+    /// Chain records together with SHA-256 hashes
+    pub hash_chain: bool,
+}
+
+impl AuditConfig {
+    /// Logs to `path` with the hash chain, rotating at 10 MiB and keeping 5 old files
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        AuditConfig {
+            path: path.into(),
+            max_bytes: 10 * 1024 * 1024,
+            max_files: 5,
+            hash_chain: true,
+        }
+    }
+
+    /// Path of the rotated file `index`, 0 being the current file
+    pub fn rotated_path(&self, index: usize) -> PathBuf {
+        if index == 0 {
+            return self.path.clone();
+        }
+        let mut path = self.path.clone().into_os_string();
+        path.push(format!(".{}", index));
+        path.into()
+    }
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+// Adds `prev` and `hash` to a record, returns the line and its hash
+fn chain(json: &str, prev: &str) -> (String, String) {
+    let body = format!("{},\"prev\":\"{}\"}}", &json[..json.len() - 1], prev);
+    let hash = hex(&Sha256::digest(body.as_bytes()));
+    let line = format!("{},\"hash\":\"{}\"}}", &body[..body.len() - 1], hash);
+    (line, hash)
+}
+
+// Splits a chained line into its `prev` and `hash`, checking the hash
+fn unchain(line: &str) -> Option<(&str, &str)> {
+    let split = line.len().checked_sub(HASH_SUFFIX_LEN)?;
+    let (rest, suffix) = (line.get(..split)?, line.get(split..)?);
+    let hash = suffix.strip_prefix(",\"hash\":\"")?.strip_suffix("\"}")?;
+    let body = format!("{}}}", rest);
+    if hex(&Sha256::digest(body.as_bytes())) != hash {
+        return None;
+    }
+    let prev = rest.get(rest.len().checked_sub(66)?..)?.strip_prefix('"')?.strip_suffix('"')?;
+    Some((prev, hash))
+}
+
+/// Checks the hash chain through every file of the log, oldest first.
+///
+/// Returns the number of records checked. A record whose hash is wrong or whose
+/// `prev` does not match the record before it is reported with its file and line.
+/// The oldest remaining record may follow one that was rotated away, so its
+/// `prev` is taken on trust.
+pub fn verify_chain(config: &AuditConfig) -> io::Result<u64> {
+    let mut previous: Option<String> = None;
+    let mut records = 0;
+    for index in (0..=config.max_files).rev() {
+        let path = config.rotated_path(index);
+        let file = match File::open(&path) {
+            Ok(file) => file,
+            Err(e) if e.kind() == ErrorKind::NotFound => continue,
+            Err(e) => return Err(e),
+        };
+        for (number, line) in BufReader::new(file).lines().enumerate() {
+            let line = line?;
+            let broken = || io::Error::new(ErrorKind::InvalidData, format!("{}:{}: hash chain broken", path.display(), number + 1));
+            let (prev, hash) = unchain(&line).ok_or_else(broken)?;
+            if previous.as_deref().is_some_and(|previous| previous != prev) {
+                return Err(broken());
+            }
+            previous = Some(hash.to_string());
+            records += 1;
+        }
+    }
+    Ok(records)
+}
+
+struct LogFile {
+    file: File,
+    size: u64,
+    last_hash: String, // `prev` of the next record
+}
+
+/// Audit sink writing JSON lines to a rotating file
+pub struct AuditLog {
+    config: AuditConfig,
+    state: Mutex<LogFile>,
+}
+
+impl fmt::Debug for AuditLog {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("AuditLog").field("config", &self.config).finish()
+    }
+}
+
+impl AuditLog {
+    /// Opens the log for appending, continuing the hash chain of an existing log
+    pub fn open(config: AuditConfig) -> io::Result<Self> {
+        if let Some(parent) = config.path.parent().filter(|parent| !parent.as_os_str().is_empty()) {
+            fs::create_dir_all(parent)?;
+        }
+        let last_hash = last_hash(&config)?;
+        let file = OpenOptions::new().create(true).append(true).open(&config.path)?;
+        let size = file.metadata()?.len();
+        Ok(AuditLog {
+            config,
+            state: Mutex::new(LogFile { file, size, last_hash }),
+        })
+    }
+
+    /// Configuration the log was opened with
+    pub fn config(&self) -> &AuditConfig {
+        &self.config
+    }
+
+    /// Appends one record, rotating first if the current file is full
+    pub fn append(&self, record: &AuditRecord) -> io::Result<()> {
+        let mut state = self.state.lock().unwrap();
+        if state.size > 0 && state.size >= self.config.max_bytes {
+            self.rotate(&mut state)?;
+        }
+        let (mut line, hash) = if self.config.hash_chain {
+            let (line, hash) = chain(&record.to_json(), &state.last_hash);
+            (line, Some(hash))
+        } else {
+            (record.to_json(), None)
+        };
+        line.push('\n');
+        if let Err(e) = state.file.write_all(line.as_bytes()) {
+            // Cut off whatever part of the line got written, so the next record starts a line of its own
+            if let Err(truncate) = state.file.set_len(state.size) {
+                error!("Failed to remove a partial audit record from {}: {}", self.config.path.display(), truncate);
+            }
+            return Err(e);
+        }
+        // Only a record that is in the file moves the chain on
+        if let Some(hash) = hash {
+            state.last_hash = hash;
+        }
+        state.size += line.len() as u64;
+        Ok(())
+    }
+
+    // Shifts every file one index up, dropping the oldest, and starts a new current file
+    fn rotate(&self, state: &mut LogFile) -> io::Result<()> {
+        state.file.sync_all()?;
+        if self.config.max_files == 0 {
+            state.file = File::create(&self.config.path)?;
+        } else {
+            let _ = fs::remove_file(self.config.rotated_path(self.config.max_files));
+            for index in (0..self.config.max_files).rev() {
+                match fs::rename(self.config.rotated_path(index), self.config.rotated_path(index + 1)) {
+                    Err(e) if e.kind() != ErrorKind::NotFound => return Err(e),
+                    _ => {}
+                }
+            }
+            state.file = OpenOptions::new().create(true).append(true).open(&self.config.path)?;
+        }
+        state.size = 0;
+        Ok(())
+    }
+}
+
+impl AuditSink for AuditLog {
+    fn record(&self, record: &AuditRecord) {
+        if let Err(e) = self.append(record) {
+            error!("Failed to write audit record: {}", e); // The request itself already went through
+        }
+    }
+}
+
+// Hash of the newest record in the log, `GENESIS_HASH` if there is none
+fn last_hash(config: &AuditConfig) -> io::Result<String> {
+    for index in 0..=config.max_files {
+        let contents = match fs::read_to_string(config.rotated_path(index)) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == ErrorKind::NotFound => continue,
+            Err(e) => return Err(e),
+        };
+        if let Some(line) = contents.lines().next_back() {
+            // A log written without the chain restarts it
+            return Ok(unchain(line).map_or(GENESIS_HASH.to_string(), |(_, hash)| hash.to_string()));
+        }
+    }
+    Ok(GENESIS_HASH.to_string())
+}
@@ -0,0 +1,287 @@
+// Client authentication.
+//
+// A server configured with an `Authenticator` answers nothing but `Hello`,
+// `AuthChallenge`, `Authenticate` and `Ping` until an `Authenticate`
+// succeeds; `Ping` stays open so health checks need no credentials. The
+// built-in `Credentials` understand two kinds of credential:
+//
+//     token  a static bearer token, sent as is and compared in constant time
+//     hmac   a pre-shared key, proven by returning HMAC-SHA256(key, nonce)
+//            for a nonce the server chose, so the key never crosses the wire
+//
+// A nonce is good for one attempt on the connection that asked for it, and a
+// connection gets `MAX_ATTEMPTS` tries before every further attempt is refused.
+use crate::message::{authenticate::Credential as Presented, Authenticate};
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use std::{
+    collections::HashMap,
+    fmt, fs,
+    io::{self, ErrorKind},
+    path::Path,
+    sync::Mutex,
+};
+
+/// Length of a challenge nonce in bytes
+pub const NONCE_LEN: usize = 32;
+/// Failed attempts a connection may make before it is refused for good
+pub const MAX_ATTEMPTS: u32 = 3;
+
+/// Reasons an `Authenticate` request is rejected
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AuthError {
+    /// No credential is known for this identity
+    UnknownIdentity(String),
+    /// The identity uses the other kind of credential, or none was sent
+    WrongMethod,
+    /// The token or HMAC does not match
+    InvalidCredential,
+    /// An HMAC was sent without asking for a challenge first
+    NoChallenge,
+    /// The connection has already authenticated as this identity
+    AlreadyAuthenticated(String),
+    /// The connection used up its attempts
+    TooManyAttempts,
+}
+
+impl fmt::Display for AuthError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AuthError::UnknownIdentity(identity) => write!(f, "unknown identity '{}'", identity),
+            AuthError::WrongMethod => write!(f, "credential of the wrong kind"),
+            AuthError::InvalidCredential => write!(f, "invalid credential"),
+            AuthError::NoChallenge => write!(f, "request an AuthChallenge before answering one"),
+            AuthError::AlreadyAuthenticated(identity) => {
+                write!(f, "connection is already authenticated as '{}'", identity)
+            }
+            AuthError::TooManyAttempts => write!(f, "too many failed attempts"),
+        }
+    }
+}
+
+impl std::error::Error for AuthError {}
+
+/// Decides whether an `Authenticate` request proves an identity.
+///
+/// Implement this to check credentials against something other than a
+/// `Credentials` file, such as a directory service.
+pub trait Authenticator: Send + Sync + fmt::Debug {
+    /// Returns the identity `request` proves.
+    ///
+    /// `challenge` is the nonce last sent to this connection, `None` if it did not ask for one.
+    fn authenticate(&self, request: &Authenticate, challenge: Option<&[u8]>) -> Result<String, AuthError>;
+}
+
+/// Secret a known client authenticates with
+#[derive(Clone, PartialEq, Eq)]
+pub enum Credential {
+    /// Static bearer token
+    Token(String),
+    /// Pre-shared HMAC-SHA256 key
+    Key(Vec<u8>),
+}
+
+impl fmt::Debug for Credential {
+    // Secrets stay out of logs
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Credential::Token(_) => write!(f, "Token(..)"),
+            Credential::Key(_) => write!(f, "Key(..)"),
+        }
+    }
+}
+
+/// HMAC-SHA256 of `nonce` keyed with `key`, the answer to a challenge
+pub fn hmac_sha256(key: &[u8], nonce: &[u8]) -> Vec<u8> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC accepts keys of any length");
+    mac.update(nonce);
+    mac.finalize().into_bytes().to_vec()
+}
+
+/// Fresh random nonce for a challenge
+pub fn nonce() -> io::Result<Vec<u8>> {
+    let mut nonce = vec![0; NONCE_LEN];
+    getrandom::getrandom(&mut nonce).map_err(|e| io::Error::other(e.to_string()))?;
+    Ok(nonce)
+}
+
+/// Token credentials from a `Bearer <identity>:<token>` authorization value,
+/// as sent to the HTTP and gRPC gateways, which have no challenge round trip
+pub fn parse_bearer(value: &str) -> Option<Authenticate> {
+    let (identity, token) = value.strip_prefix("Bearer ")?.split_once(':')?;
+    Some(Authenticate {
+        identity: identity.to_string(),
+        credential: Some(Presented::Token(token.to_string())),
+    })
+}
+
+// Compares secrets without revealing through timing how much of them matched
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+fn parse_hex(text: &str) -> Option<Vec<u8>> {
+    if text.is_empty() || !text.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..text.len())
+        .step_by(2)
+        .map(|i| text.get(i..i + 2).and_then(|byte| u8::from_str_radix(byte, 16).ok()))
+        .collect()
+}
+
+/// Credentials of the clients a server knows, usually loaded from a file
+#[derive(Clone, Default, PartialEq, Eq)]
+pub struct Credentials {
+    entries: HashMap<String, Credential>,
+}
+
+impl fmt::Debug for Credentials {
+    // Only the identities, never the secrets
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_set().entries(self.entries.keys()).finish()
+    }
+}
+
+impl Credentials {
+    /// Creates an empty set, which rejects everyone
+    pub fn new() -> Self {
+        Credentials::default()
+    }
+
+    /// Adds or replaces the credential of `identity`
+    pub fn insert(&mut self, identity: impl Into<String>, credential: Credential) {
+        self.entries.insert(identity.into(), credential);
+    }
+
+    /// Number of known identities
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// Returns true if no identity is known
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Reads a credentials file, see `parse` for the format
+    pub fn load(path: impl AsRef<Path>) -> io::Result<Self> {
+        Self::parse(&fs::read_to_string(path)?)
+    }
+
+    /// Parses one credential per line, blank lines and lines starting with `#` are skipped:
+    ///
+    /// ```text
+    /// # identity  kind   secret
+    /// sensor-01   token  s3cret-token
+    /// gateway     hmac   00112233445566778899aabbccddeeff
+    /// ```
+    ///
+    /// `hmac` keys are hex encoded. Each identity may appear once.
+    pub fn parse(text: &str) -> io::Result<Self> {
+        let mut credentials = Credentials::new();
+        for (index, line) in text.lines().enumerate() {
+            let invalid = |reason: &str| io::Error::new(ErrorKind::InvalidData, format!("line {}: {}", index + 1, reason));
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            let [identity, kind, secret] = fields[..] else {
+                return Err(invalid("expected identity, kind and secret"));
+            };
+            let credential = match kind {
+                "token" => Credential::Token(secret.to_string()),
+                "hmac" => Credential::Key(parse_hex(secret).ok_or_else(|| invalid("hmac key is not hex"))?),
+                other => return Err(invalid(&format!("unknown kind '{}'", other))),
+            };
+            if credentials.entries.contains_key(identity) {
+                return Err(invalid(&format!("identity '{}' appears twice", identity)));
+            }
+            credentials.insert(identity, credential);
+        }
+        Ok(credentials)
+    }
+}
+
+impl Authenticator for Credentials {
+    fn authenticate(&self, request: &Authenticate, challenge: Option<&[u8]>) -> Result<String, AuthError> {
+        let credential = self
+            .entries
+            .get(&request.identity)
+            .ok_or_else(|| AuthError::UnknownIdentity(request.identity.clone()))?;
+        let valid = match (credential, &request.credential) {
+            (Credential::Token(token), Some(Presented::Token(presented))) => {
+                constant_time_eq(token.as_bytes(), presented.as_bytes())
+            }
+            (Credential::Key(key), Some(Presented::HmacSha256(presented))) => {
+                let nonce = challenge.ok_or(AuthError::NoChallenge)?;
+                let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC accepts keys of any length");
+                mac.update(nonce);
+                mac.verify_slice(presented).is_ok() // Constant time
+            }
+            _ => return Err(AuthError::WrongMethod),
+        };
+        if valid {
+            Ok(request.identity.clone())
+        } else {
+            Err(AuthError::InvalidCredential)
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct AuthState {
+    identity: Option<String>,
+    challenge: Option<Vec<u8>>, // Nonce for the next attempt
+    failures: u32,
+}
+
+/// Authentication progress of one connection
+#[derive(Debug, Default)]
+pub struct SessionAuth {
+    state: Mutex<AuthState>,
+}
+
+impl SessionAuth {
+    /// Creates the state of a connection that has not authenticated
+    pub fn new() -> Self {
+        SessionAuth::default()
+    }
+
+    /// Identity the connection authenticated as, `None` before that
+    pub fn identity(&self) -> Option<String> {
+        self.state.lock().unwrap().identity.clone()
+    }
+
+    /// Issues a nonce for the next attempt, replacing any earlier one
+    pub fn challenge(&self) -> io::Result<Vec<u8>> {
+        let nonce = nonce()?;
+        self.state.lock().unwrap().challenge = Some(nonce.clone());
+        Ok(nonce)
+    }
+
+    /// Checks `request` with `authenticator` and remembers the identity on success.
+    ///
+    /// Every attempt uses up the current challenge, whatever its outcome.
+    pub fn authenticate(&self, authenticator: &dyn Authenticator, request: &Authenticate) -> Result<String, AuthError> {
+        let mut state = self.state.lock().unwrap();
+        if let Some(identity) = &state.identity {
+            return Err(AuthError::AlreadyAuthenticated(identity.clone()));
+        }
+        if state.failures >= MAX_ATTEMPTS {
+            return Err(AuthError::TooManyAttempts);
+        }
+        let challenge = state.challenge.take();
+        match authenticator.authenticate(request, challenge.as_deref()) {
+            Ok(identity) => {
+                state.identity = Some(identity.clone());
+                Ok(identity)
+            }
+            Err(e) => {
+                state.failures += 1;
+                Err(e)
+            }
+        }
+    }
+}
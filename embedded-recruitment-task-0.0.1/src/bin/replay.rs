@@ -0,0 +1,56 @@
+// Replays a connection capture against a running server and reports replies that differ.
+//
+//     replay <capture file> <server address> [--timing]
+//
+// Exits with 1 if any reply differs and 2 on usage or I/O errors.
+use embedded_recruitment_task::capture::{replay, Capture, ReplayOptions};
+use std::{env, process::ExitCode};
+
+fn main() -> ExitCode {
+    let args: Vec<String> = env::args().skip(1).collect();
+    let timing = args.iter().any(|arg| arg == "--timing");
+    let positional: Vec<&String> = args.iter().filter(|arg| !arg.starts_with("--")).collect();
+    let [path, addr] = positional[..] else {
+        eprintln!("usage: replay <capture file> <server address> [--timing]");
+        return ExitCode::from(2);
+    };
+
+    let capture = match Capture::load(path) {
+        Ok(capture) => capture,
+        Err(e) => {
+            eprintln!("Failed to read {}: {}", path, e);
+            return ExitCode::from(2);
+        }
+    };
+    let options = ReplayOptions {
+        timing,
+        ..ReplayOptions::default()
+    };
+    let report = match replay(&capture, addr.as_str(), &options) {
+        Ok(report) => report,
+        Err(e) => {
+            eprintln!("Replay against {} failed: {}", addr, e);
+            return ExitCode::from(2);
+        }
+    };
+
+    println!(
+        "Connection {} from {}: sent {} frames, {} replies recorded, {} received",
+        capture.connection,
+        capture.peer,
+        report.sent,
+        report.expected.len(),
+        report.received.len()
+    );
+    for mismatch in &report.mismatches {
+        println!("request {}:", mismatch.request_id);
+        println!("  recorded: {:?}", mismatch.expected);
+        println!("  replayed: {:?}", mismatch.received);
+    }
+    if report.matches() {
+        println!("All replies match");
+        ExitCode::SUCCESS
+    } else {
+        ExitCode::from(1)
+    }
+}
@@ -0,0 +1,501 @@
+// Chunked file storage for uploads and downloads, such as firmware images.
+//
+// Directory layout:
+//
+//     files/<name>              committed files
+//     partial/<upload id>.part  bytes received so far for an unfinished upload
+//     partial/<upload id>.meta  name, size and digest of that upload
+//
+// Chunks must arrive in order, so an upload's progress is simply the length
+// of its `.part` file. That makes resuming after a reconnect or a server
+// restart a matter of asking for the current offset: the upload id is derived
+// from the name, size and digest, so beginning the same upload again finds it.
+// The SHA-256 digest is only checked on commit, by reading the part file back
+// outside the store lock, so other transfers go on while a large file is
+// hashed. An upload that receives nothing for `upload_expiry` is dropped and
+// its files deleted, so abandoned uploads do not hold on to the quota. Idle
+// time counts from the part file's modification time, across restarts too.
+use crate::storage::PartialUpload;
+use log::{info, warn};
+use prost::Message;
+use sha2::{Digest, Sha256};
+use std::{
+    collections::HashMap,
+    fmt,
+    fs::{self, File, OpenOptions},
+    io::{self, ErrorKind, Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+    sync::{Mutex, MutexGuard},
+    time::{Duration, SystemTime},
+};
+
+/// Largest chunk a download returns, well below the frame size limit
+pub const MAX_CHUNK_LEN: usize = 256 * 1024;
+/// Length of a SHA-256 digest in bytes
+pub const DIGEST_LEN: usize = 32;
+// Longest accepted file name, in bytes
+const MAX_NAME_LEN: usize = 255;
+
+const FILES_DIR: &str = "files";
+const PARTIAL_DIR: &str = "partial";
+
+/// Where transferred files are kept and how much space they may use
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BlobConfig {
+    /// Directory holding committed and partial files, created if missing
+    pub directory: PathBuf,
+    /// Total bytes of committed files plus the declared size of unfinished uploads
+    pub quota: u64,
+    /// Delete unfinished uploads that received nothing for this long, `None` keeps them for ever
+    pub upload_expiry: Option<Duration>,
+}
+
+impl BlobConfig {
+    /// Stores files in `directory` with a 64 MiB quota, expiring uploads idle for a day
+    pub fn new(directory: impl Into<PathBuf>) -> Self {
+        BlobConfig {
+            directory: directory.into(),
+            quota: 64 * 1024 * 1024,
+            upload_expiry: Some(Duration::from_secs(24 * 60 * 60)),
+        }
+    }
+}
+
+/// Reasons a transfer request is rejected
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BlobError {
+    /// The name is empty, too long, or not a plain file name
+    InvalidName(String),
+    /// The digest is not a SHA-256 digest
+    InvalidDigest,
+    /// Accepting the upload would exceed the storage quota
+    QuotaExceeded { requested: u64, available: u64 },
+    /// No unfinished upload has this id
+    UnknownUpload(String),
+    /// A chunk did not start where the previous one ended
+    WrongOffset { expected: u64, received: u64 },
+    /// The offset or the chunk end lies past the end of the file
+    OutOfRange { offset: u64, size: u64 },
+    /// Commit was requested before all bytes arrived
+    Incomplete { received: u64, size: u64 },
+    /// Another commit of this upload is verifying it
+    Committing(String),
+    /// The received bytes do not match the digest, the upload was discarded
+    ChecksumMismatch,
+    /// No committed file has this name
+    NotFound(String),
+    /// Reading or writing the storage directory failed
+    Io(String),
+}
+
+impl fmt::Display for BlobError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BlobError::InvalidName(name) => write!(f, "invalid file name '{}'", name),
+            BlobError::InvalidDigest => write!(f, "sha256 must be {} bytes", DIGEST_LEN),
+            BlobError::QuotaExceeded { requested, available } => {
+                write!(f, "upload of {} bytes exceeds the quota, {} bytes available", requested, available)
+            }
+            BlobError::UnknownUpload(id) => write!(f, "no upload with id '{}'", id),
+            BlobError::WrongOffset { expected, received } => {
+                write!(f, "chunk starts at offset {}, expected {}", received, expected)
+            }
+            BlobError::OutOfRange { offset, size } => write!(f, "offset {} is past the end of {} bytes", offset, size),
+            BlobError::Incomplete { received, size } => write!(f, "only {} of {} bytes received", received, size),
+            BlobError::Committing(id) => write!(f, "upload '{}' is already being committed", id),
+            BlobError::ChecksumMismatch => write!(f, "sha256 of the received bytes does not match, upload discarded"),
+            BlobError::NotFound(name) => write!(f, "no file named '{}'", name),
+            BlobError::Io(e) => write!(f, "storage error: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for BlobError {}
+
+impl From<io::Error> for BlobError {
+    fn from(error: io::Error) -> Self {
+        BlobError::Io(error.to_string())
+    }
+}
+
+/// Checks that `name` can be used as a file name without escaping the storage directory
+pub fn validate_name(name: &str) -> Result<(), BlobError> {
+    let valid = !name.is_empty()
+        && name.len() <= MAX_NAME_LEN
+        && name != "."
+        && name != ".."
+        && !name.contains(['/', '\\', '\0']);
+    if valid {
+        Ok(())
+    } else {
+        Err(BlobError::InvalidName(name.to_string()))
+    }
+}
+
+/// Id of the upload of `size` bytes named `name` with digest `sha256`, equal for every attempt
+pub fn upload_id(name: &str, size: u64, sha256: &[u8]) -> String {
+    let mut hasher = Sha256::new();
+    hasher.update(name.as_bytes());
+    hasher.update([0]); // Names cannot contain NUL, so the fields cannot run into each other
+    hasher.update(size.to_be_bytes());
+    hasher.update(sha256);
+    hasher.finalize()[..16].iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+// Digest of everything `reader` yields
+fn digest_of(mut reader: impl Read) -> io::Result<Vec<u8>> {
+    let mut hasher = Sha256::new();
+    let mut buffer = vec![0; 64 * 1024];
+    loop {
+        match reader.read(&mut buffer)? {
+            0 => return Ok(hasher.finalize().to_vec()),
+            n => hasher.update(&buffer[..n]),
+        }
+    }
+}
+
+// Makes a rename or file creation in `directory` durable
+fn sync_directory(directory: &Path) -> io::Result<()> {
+    #[cfg(unix)]
+    File::open(directory)?.sync_all()?;
+    #[cfg(not(unix))]
+    let _ = directory; // Directory handles cannot be synced on this platform
+    Ok(())
+}
+
+// An upload that has not been committed yet
+struct Upload {
+    name: String,
+    size: u64,
+    sha256: Vec<u8>,
+    received: u64, // Length of the part file
+    updated: SystemTime, // Last begin or chunk, the upload expires once idle for long
+    committing: bool, // Being verified outside the lock, takes no chunks and does not expire
+}
+
+// A committed file
+struct Stored {
+    size: u64,
+    sha256: Option<Vec<u8>>, // Computed on first download for files found on disk
+}
+
+struct Inner {
+    uploads: HashMap<String, Upload>,
+    files: HashMap<String, Stored>,
+}
+
+impl Inner {
+    // Bytes counted against the quota, ignoring a file about to be replaced
+    fn used(&self, replacing: &str) -> u64 {
+        let stored: u64 = self.files.iter().filter(|(name, _)| *name != replacing).map(|(_, file)| file.size).sum();
+        let reserved: u64 = self.uploads.values().map(|upload| upload.size).sum();
+        stored + reserved
+    }
+}
+
+/// Committed files and unfinished uploads in one storage directory
+pub struct BlobStore {
+    config: BlobConfig,
+    // One lock for all transfers keeps the quota exact, chunks are small enough to write under it
+    inner: Mutex<Inner>,
+}
+
+impl BlobStore {
+    /// Opens (or creates) the storage directory, picking up unfinished uploads
+    pub fn open(config: BlobConfig) -> io::Result<Self> {
+        let files_dir = config.directory.join(FILES_DIR);
+        let partial_dir = config.directory.join(PARTIAL_DIR);
+        fs::create_dir_all(&files_dir)?;
+        fs::create_dir_all(&partial_dir)?;
+
+        let mut files = HashMap::new();
+        for entry in fs::read_dir(&files_dir)? {
+            let entry = entry?;
+            let metadata = entry.metadata()?;
+            if let (true, Ok(name)) = (metadata.is_file(), entry.file_name().into_string()) {
+                files.insert(name, Stored { size: metadata.len(), sha256: None });
+            }
+        }
+
+        let mut uploads = HashMap::new();
+        for entry in fs::read_dir(&partial_dir)? {
+            let path = entry?.path();
+            if path.extension().is_none_or(|extension| extension != "meta") {
+                continue;
+            }
+            let Some(id) = path.file_stem().and_then(|stem| stem.to_str()).map(str::to_string) else {
+                continue;
+            };
+            let meta = PartialUpload::decode(fs::read(&path)?.as_slice())
+                .map_err(|e| io::Error::new(ErrorKind::InvalidData, e))?;
+            match fs::metadata(partial_dir.join(format!("{}.part", id))) {
+                Ok(part) => {
+                    let upload = Upload {
+                        name: meta.name,
+                        size: meta.size,
+                        sha256: meta.sha256,
+                        received: part.len(),
+                        updated: part.modified().unwrap_or_else(|_| SystemTime::now()),
+                        committing: false,
+                    };
+                    uploads.insert(id, upload);
+                }
+                // Committed just before a crash, or the part file was lost: nothing to resume
+                Err(e) if e.kind() == ErrorKind::NotFound => fs::remove_file(&path)?,
+                Err(e) => return Err(e),
+            }
+        }
+
+        info!(
+            "Opened file storage {} with {} files and {} unfinished uploads",
+            config.directory.display(),
+            files.len(),
+            uploads.len()
+        );
+        Ok(BlobStore {
+            config,
+            inner: Mutex::new(Inner { uploads, files }),
+        })
+    }
+
+    fn part_path(&self, id: &str) -> PathBuf {
+        self.config.directory.join(PARTIAL_DIR).join(format!("{}.part", id))
+    }
+
+    fn meta_path(&self, id: &str) -> PathBuf {
+        self.config.directory.join(PARTIAL_DIR).join(format!("{}.meta", id))
+    }
+
+    fn file_path(&self, name: &str) -> PathBuf {
+        self.config.directory.join(FILES_DIR).join(name)
+    }
+
+    // Takes the lock, dropping the uploads that expired meanwhile
+    fn lock(&self) -> MutexGuard<'_, Inner> {
+        let mut inner = self.inner.lock().unwrap();
+        let Some(expiry) = self.config.upload_expiry else {
+            return inner;
+        };
+        let now = SystemTime::now();
+        let expired: Vec<String> = inner
+            .uploads
+            .iter()
+            .filter(|(_, upload)| !upload.committing && now.duration_since(upload.updated).is_ok_and(|idle| idle >= expiry))
+            .map(|(id, _)| id.clone())
+            .collect();
+        for id in expired {
+            let upload = inner.uploads.remove(&id).expect("listed above");
+            info!("Upload {} of '{}' was idle for {:?}, deleting it", id, upload.name, expiry);
+            // The part file goes first, recovery drops a sidecar without one
+            for path in [self.part_path(&id), self.meta_path(&id)] {
+                if let Err(e) = fs::remove_file(&path) {
+                    warn!("Failed to delete {}: {}", path.display(), e);
+                }
+            }
+        }
+        inner
+    }
+
+    /// Starts an upload, or finds the unfinished one with the same name, size and digest.
+    /// Returns the upload id and the offset the next chunk must start at.
+    pub fn begin_upload(&self, name: &str, size: u64, sha256: &[u8]) -> Result<(String, u64), BlobError> {
+        validate_name(name)?;
+        if sha256.len() != DIGEST_LEN {
+            return Err(BlobError::InvalidDigest);
+        }
+
+        let id = upload_id(name, size, sha256);
+        let mut inner = self.lock();
+        if let Some(upload) = inner.uploads.get_mut(&id) {
+            upload.updated = SystemTime::now();
+            info!("Resuming upload {} of '{}' at offset {}", id, name, upload.received);
+            return Ok((id, upload.received));
+        }
+
+        let available = self.config.quota.saturating_sub(inner.used(name));
+        if size > available {
+            return Err(BlobError::QuotaExceeded { requested: size, available });
+        }
+
+        // The sidecar goes first, a part file without one would never be resumed or cleaned up
+        let meta = PartialUpload {
+            name: name.to_string(),
+            size,
+            sha256: sha256.to_vec(),
+        };
+        let mut meta_file = File::create(self.meta_path(&id))?;
+        meta_file.write_all(&meta.encode_to_vec())?;
+        meta_file.sync_all()?;
+        File::create(self.part_path(&id))?;
+        sync_directory(&self.config.directory.join(PARTIAL_DIR))?;
+
+        let upload = Upload {
+            name: meta.name,
+            size,
+            sha256: meta.sha256,
+            received: 0,
+            updated: SystemTime::now(),
+            committing: false,
+        };
+        inner.uploads.insert(id.clone(), upload);
+        info!("Started upload {} of '{}', {} bytes", id, name, size);
+        Ok((id, 0))
+    }
+
+    /// Appends a chunk to an upload, returns the offset the next chunk must start at
+    pub fn write_chunk(&self, id: &str, offset: u64, data: &[u8]) -> Result<u64, BlobError> {
+        let mut inner = self.lock();
+        let upload = inner
+            .uploads
+            .get_mut(id)
+            .ok_or_else(|| BlobError::UnknownUpload(id.to_string()))?;
+        if upload.committing {
+            return Err(BlobError::Committing(id.to_string()));
+        }
+        upload.updated = SystemTime::now();
+
+        if offset != upload.received {
+            return Err(BlobError::WrongOffset {
+                expected: upload.received,
+                received: offset,
+            });
+        }
+        let end = offset + data.len() as u64;
+        if end > upload.size {
+            return Err(BlobError::OutOfRange { offset: end, size: upload.size });
+        }
+
+        let path = self.part_path(id);
+        let written = OpenOptions::new().append(true).open(&path).and_then(|mut part| part.write_all(data));
+        if let Err(e) = written {
+            // Part of the chunk may have landed, the file length stays the source of truth
+            upload.received = fs::metadata(&path).map_or(upload.received, |part| part.len());
+            return Err(e.into());
+        }
+        upload.received = end;
+        Ok(end)
+    }
+
+    /// Verifies a fully received upload and stores it under its name, replacing any older file.
+    /// Returns the name and size. A digest mismatch discards the upload.
+    pub fn commit_upload(&self, id: &str) -> Result<(String, u64), BlobError> {
+        let (name, size, sha256) = {
+            let mut inner = self.lock();
+            let upload = inner
+                .uploads
+                .get_mut(id)
+                .ok_or_else(|| BlobError::UnknownUpload(id.to_string()))?;
+            if upload.committing {
+                return Err(BlobError::Committing(id.to_string()));
+            }
+            if upload.received < upload.size {
+                return Err(BlobError::Incomplete {
+                    received: upload.received,
+                    size: upload.size,
+                });
+            }
+            upload.committing = true;
+            (upload.name.clone(), upload.size, upload.sha256.clone())
+        };
+
+        // Hashed outside the lock, the part file may be large and no chunk can change it now
+        let part_path = self.part_path(id);
+        let verified = File::open(&part_path).and_then(|part| Ok((digest_of(&part)? == sha256, part)));
+        let mut inner = self.lock();
+        let part = match verified {
+            Ok((true, part)) => part,
+            Ok((false, _)) => {
+                warn!("Upload {} of '{}' failed verification, discarding it", id, name);
+                inner.uploads.remove(id);
+                fs::remove_file(self.meta_path(id))?;
+                fs::remove_file(&part_path)?;
+                return Err(BlobError::ChecksumMismatch);
+            }
+            Err(e) => {
+                if let Some(upload) = inner.uploads.get_mut(id) {
+                    upload.committing = false; // May be committed again
+                }
+                return Err(e.into());
+            }
+        };
+
+        // Renamed under the lock, so the recorded file is the one on disk when two uploads share a name
+        let stored = part
+            .sync_all()
+            .and_then(|()| fs::rename(&part_path, self.file_path(&name)))
+            .and_then(|()| sync_directory(&self.config.directory.join(FILES_DIR)));
+        if let Err(e) = stored {
+            if let Some(upload) = inner.uploads.get_mut(id) {
+                upload.committing = false;
+            }
+            return Err(e.into());
+        }
+        inner.uploads.remove(id);
+        fs::remove_file(self.meta_path(id))?; // Recovery drops a sidecar whose part file is gone
+
+        info!("Committed upload {} as '{}', {} bytes", id, name, size);
+        inner.files.insert(name.clone(), Stored { size, sha256: Some(sha256) });
+        Ok((name, size))
+    }
+
+    /// Returns the size and SHA-256 digest of a committed file
+    pub fn describe(&self, name: &str) -> Result<(u64, Vec<u8>), BlobError> {
+        validate_name(name)?;
+        {
+            let inner = self.inner.lock().unwrap();
+            match inner.files.get(name) {
+                Some(Stored { size, sha256: Some(sha256) }) => return Ok((*size, sha256.clone())),
+                Some(_) => {}
+                None => return Err(BlobError::NotFound(name.to_string())),
+            }
+        }
+
+        // Hashed outside the lock, a file found on disk may be large
+        let file = File::open(self.file_path(name))?;
+        let size = file.metadata()?.len();
+        let sha256 = digest_of(file)?;
+        if let Some(stored) = self.inner.lock().unwrap().files.get_mut(name) {
+            if stored.size == size {
+                stored.sha256 = Some(sha256.clone()); // Not replaced meanwhile
+            }
+        }
+        Ok((size, sha256))
+    }
+
+    /// Reads up to `length` bytes of a committed file from `offset`, 0 or more than
+    /// `MAX_CHUNK_LEN` reads `MAX_CHUNK_LEN`. Returns an empty chunk at the end of the file.
+    pub fn read_chunk(&self, name: &str, offset: u64, length: usize) -> Result<Vec<u8>, BlobError> {
+        validate_name(name)?;
+        let length = if length == 0 { MAX_CHUNK_LEN } else { length.min(MAX_CHUNK_LEN) };
+
+        let mut file = match File::open(self.file_path(name)) {
+            Ok(file) => file,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Err(BlobError::NotFound(name.to_string())),
+            Err(e) => return Err(e.into()),
+        };
+        let size = file.metadata()?.len();
+        if offset > size {
+            return Err(BlobError::OutOfRange { offset, size });
+        }
+
+        file.seek(SeekFrom::Start(offset))?;
+        let mut data = Vec::with_capacity(length.min((size - offset) as usize));
+        file.take(length as u64).read_to_end(&mut data)?;
+        Ok(data)
+    }
+
+    /// File name the unfinished upload `id` was begun with
+    pub fn upload_name(&self, id: &str) -> Option<String> {
+        self.lock().uploads.get(id).map(|upload| upload.name.clone())
+    }
+
+    /// Bytes of the quota in use by committed files and unfinished uploads
+    pub fn used(&self) -> u64 {
+        self.lock().used("")
+    }
+
+    /// Number of uploads begun but not committed
+    pub fn pending_uploads(&self) -> usize {
+        self.lock().uploads.len()
+    }
+}
@@ -0,0 +1,340 @@
+// Recording and replaying connections.
+//
+// With capture enabled, every connection gets a file in the capture
+// directory holding the frame payloads it received and sent, byte for byte
+// as they crossed the socket, with their time since the connection was
+// accepted. Records are protobuf messages from `storage.proto`, each with a
+// varint length prefix, a `CaptureHeader` first and a `CapturedFrame` per
+// frame after it.
+//
+// `replay` sends the inbound frames of a capture to a server again and
+// compares what comes back with the recorded replies, request id by request
+// id. Replies that depend on more than the connection's own requests, such
+// as challenge nonces, publications from other clients or versions in a
+// store that was not empty, will differ and show up in the report.
+//
+// Credentials never reach a capture file: an `Authenticate` is recorded with
+// its token or HMAC removed, so replaying it is refused as unauthenticated.
+use crate::codec::{CompressionStats, FrameCodec};
+use crate::frame;
+use crate::message::{client_message, server_message, ClientMessage, ServerMessage};
+use crate::storage::{self, CaptureDirection, CaptureHeader};
+use log::error;
+use prost::Message;
+use std::{
+    collections::BTreeMap,
+    fmt,
+    fs::{self, File},
+    io::{self, ErrorKind, Write},
+    net::{Shutdown, SocketAddr, TcpStream, ToSocketAddrs},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    thread,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+/// Where connection captures are written
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CaptureConfig {
+    /// Directory for capture files, created if missing
+    pub directory: PathBuf,
+}
+
+impl CaptureConfig {
+    /// Writes captures to `directory`
+    pub fn new(directory: impl Into<PathBuf>) -> Self {
+        CaptureConfig {
+            directory: directory.into(),
+        }
+    }
+}
+
+/// Which way a frame went, seen from the server
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    /// Sent by the client
+    Inbound,
+    /// Sent by the server
+    Outbound,
+}
+
+/// One recorded frame payload
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CapturedFrame {
+    /// Time since the connection was accepted
+    pub offset: Duration,
+    pub direction: Direction,
+    /// The payload as it crossed the connection, still in the negotiated format
+    pub payload: Vec<u8>,
+}
+
+/// Records the frames of one connection as they happen
+pub struct CaptureWriter {
+    file: Mutex<File>,
+    started: Instant,
+    path: PathBuf,
+}
+
+impl fmt::Debug for CaptureWriter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("CaptureWriter").field("path", &self.path).finish()
+    }
+}
+
+fn now_ms() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |since| since.as_millis() as u64)
+}
+
+impl CaptureWriter {
+    /// Creates the capture file of connection `connection`, named after the start time and the id
+    pub fn create(config: &CaptureConfig, connection: u64, peer: Option<SocketAddr>) -> io::Result<Self> {
+        fs::create_dir_all(&config.directory)?;
+        let started_ms = now_ms();
+        let path = config.directory.join(format!("{}-{}.cap", started_ms, connection));
+        let mut file = File::create(&path)?;
+        let header = CaptureHeader {
+            connection,
+            peer: peer.map(|peer| peer.to_string()).unwrap_or_default(),
+            started_ms,
+        };
+        file.write_all(&header.encode_length_delimited_to_vec())?;
+        Ok(CaptureWriter {
+            file: Mutex::new(file),
+            started: Instant::now(),
+            path,
+        })
+    }
+
+    /// Path of the capture file
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Appends one frame payload. A failed write is logged, the connection carries on.
+    pub fn record(&self, direction: Direction, payload: &[u8]) {
+        let frame = storage::CapturedFrame {
+            offset_us: self.started.elapsed().as_micros() as u64,
+            direction: match direction {
+                Direction::Inbound => CaptureDirection::Inbound,
+                Direction::Outbound => CaptureDirection::Outbound,
+            } as i32,
+            payload: payload.to_vec(),
+        };
+        // One write per record, so concurrent readers and writers never interleave within one
+        if let Err(e) = self.file.lock().unwrap().write_all(&frame.encode_length_delimited_to_vec()) {
+            error!("Failed to write to capture {}: {}", self.path.display(), e);
+        }
+    }
+}
+
+/// Returns `message` without its credential if it is an `Authenticate`, `None` if it holds nothing to redact
+pub fn redact(message: &ClientMessage) -> Option<ClientMessage> {
+    let Some(client_message::Message::Authenticate(authenticate)) = &message.message else {
+        return None;
+    };
+    authenticate.credential.as_ref()?;
+    let mut authenticate = authenticate.clone();
+    authenticate.credential = None;
+    Some(ClientMessage {
+        message: Some(client_message::Message::Authenticate(authenticate)),
+        ..message.clone()
+    })
+}
+
+/// A recorded connection
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Capture {
+    /// Id the connection had on the recording server
+    pub connection: u64,
+    /// Remote address of the client, empty if unknown
+    pub peer: String,
+    /// Milliseconds since the Unix epoch when the connection was accepted
+    pub started_ms: u64,
+    /// Frames in the order they were recorded
+    pub frames: Vec<CapturedFrame>,
+}
+
+impl Capture {
+    /// Reads a capture file. A record cut short at the end, as left by a crash, is ignored.
+    pub fn load(path: impl AsRef<Path>) -> io::Result<Self> {
+        let contents = fs::read(path)?;
+        let mut buffer = contents.as_slice();
+        let invalid = |e: prost::DecodeError| io::Error::new(ErrorKind::InvalidData, e);
+        let header = CaptureHeader::decode_length_delimited(&mut buffer).map_err(invalid)?;
+
+        let mut frames = Vec::new();
+        while !buffer.is_empty() {
+            let Ok(frame) = storage::CapturedFrame::decode_length_delimited(&mut buffer) else {
+                break; // Torn tail
+            };
+            frames.push(CapturedFrame {
+                offset: Duration::from_micros(frame.offset_us),
+                direction: match frame.direction() {
+                    CaptureDirection::Inbound => Direction::Inbound,
+                    CaptureDirection::Outbound => Direction::Outbound,
+                },
+                payload: frame.payload,
+            });
+        }
+        Ok(Capture {
+            connection: header.connection,
+            peer: header.peer,
+            started_ms: header.started_ms,
+            frames,
+        })
+    }
+
+    /// Frames the client sent
+    pub fn inbound(&self) -> impl Iterator<Item = &CapturedFrame> {
+        self.frames.iter().filter(|frame| frame.direction == Direction::Inbound)
+    }
+
+    /// Decodes the frames the server sent
+    pub fn replies(&self) -> io::Result<Vec<ServerMessage>> {
+        decode_replies(
+            self.frames
+                .iter()
+                .filter(|frame| frame.direction == Direction::Outbound)
+                .map(|frame| frame.payload.as_slice()),
+        )
+    }
+}
+
+/// Decodes server frame payloads in order, following the switch of format after a `HelloResponse`
+pub fn decode_replies<'a>(payloads: impl IntoIterator<Item = &'a [u8]>) -> io::Result<Vec<ServerMessage>> {
+    let stats = CompressionStats::default();
+    let mut codec = FrameCodec::default();
+    let mut replies = Vec::new();
+    for payload in payloads {
+        let message = codec.decode(payload.to_vec(), &stats)?;
+        let reply = ServerMessage::decode(message.as_slice()).map_err(|e| io::Error::new(ErrorKind::InvalidData, e))?;
+        if let Some(server_message::Message::HelloResponse(hello)) = &reply.message {
+            codec = FrameCodec::new(hello.compression(), usize::MAX).with_checksums(hello.checksums);
+        }
+        replies.push(reply);
+    }
+    Ok(replies)
+}
+
+/// How `replay` sends a capture
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ReplayOptions {
+    /// Keep the recorded gaps between inbound frames instead of sending them back to back
+    pub timing: bool,
+    /// Replies are collected until the server has been quiet this long after the last frame
+    pub idle_timeout: Duration,
+}
+
+impl Default for ReplayOptions {
+    fn default() -> Self {
+        ReplayOptions {
+            timing: false,
+            idle_timeout: Duration::from_millis(500),
+        }
+    }
+}
+
+/// Replies to one request id that differ between the capture and the replay
+#[derive(Debug, Clone, PartialEq)]
+pub struct Mismatch {
+    pub request_id: u64,
+    /// Replies recorded in the capture, in order
+    pub expected: Vec<ServerMessage>,
+    /// Replies received during the replay, in order
+    pub received: Vec<ServerMessage>,
+}
+
+/// Result of replaying a capture
+#[derive(Debug, Clone, PartialEq)]
+pub struct ReplayReport {
+    /// Inbound frames sent
+    pub sent: usize,
+    /// Replies recorded in the capture
+    pub expected: Vec<ServerMessage>,
+    /// Replies received during the replay
+    pub received: Vec<ServerMessage>,
+    /// Request ids whose replies differ, in id order
+    pub mismatches: Vec<Mismatch>,
+}
+
+impl ReplayReport {
+    /// Returns true if every request got the replies it got when recorded
+    pub fn matches(&self) -> bool {
+        self.mismatches.is_empty()
+    }
+}
+
+// Groups replies by request id, keeping their order within each id
+fn by_request(replies: &[ServerMessage]) -> BTreeMap<u64, Vec<ServerMessage>> {
+    let mut grouped: BTreeMap<u64, Vec<ServerMessage>> = BTreeMap::new();
+    for reply in replies {
+        grouped.entry(reply.request_id).or_default().push(reply.clone());
+    }
+    grouped
+}
+
+/// Sends the inbound frames of `capture` to the server at `addr` and compares the replies
+pub fn replay(capture: &Capture, addr: impl ToSocketAddrs, options: &ReplayOptions) -> io::Result<ReplayReport> {
+    let expected = capture.replies()?;
+    let mut stream = TcpStream::connect(addr)?;
+    stream.set_read_timeout(Some(options.idle_timeout))?;
+
+    // Replies are read while frames are still going out, so neither side can block on a full socket
+    let done = Arc::new(AtomicBool::new(false));
+    let mut reader_stream = stream.try_clone()?;
+    let reader_done = done.clone();
+    let reader = thread::spawn(move || -> io::Result<Vec<Vec<u8>>> {
+        let mut payloads = Vec::new();
+        loop {
+            match frame::read_frame(&mut reader_stream) {
+                Ok(Some(payload)) => payloads.push(payload),
+                Ok(None) => return Ok(payloads),
+                Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {
+                    if reader_done.load(Ordering::SeqCst) {
+                        return Ok(payloads); // Quiet for a whole timeout after the last frame
+                    }
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    });
+
+    let started = Instant::now();
+    let mut sent = 0;
+    for frame in capture.inbound() {
+        if options.timing {
+            if let Some(wait) = frame.offset.checked_sub(started.elapsed()) {
+                thread::sleep(wait);
+            }
+        }
+        frame::write_frame(&mut stream, &frame.payload)?;
+        sent += 1;
+    }
+    done.store(true, Ordering::SeqCst);
+
+    let payloads = reader.join().unwrap_or_else(|_| Err(io::Error::other("reader thread panicked")))?;
+    let _ = stream.shutdown(Shutdown::Both);
+    let received = decode_replies(payloads.iter().map(Vec::as_slice))?;
+
+    let (mut expected_by_id, mut received_by_id) = (by_request(&expected), by_request(&received));
+    let ids: Vec<u64> = expected_by_id.keys().chain(received_by_id.keys()).copied().collect();
+    let mut mismatches: Vec<Mismatch> = Vec::new();
+    for request_id in ids {
+        let expected = expected_by_id.remove(&request_id).unwrap_or_default();
+        let received = received_by_id.remove(&request_id).unwrap_or_default();
+        if expected != received {
+            mismatches.push(Mismatch { request_id, expected, received });
+        }
+    }
+    mismatches.sort_by_key(|mismatch| mismatch.request_id);
+
+    Ok(ReplayReport {
+        sent,
+        expected,
+        received,
+        mismatches,
+    })
+}
@@ -0,0 +1,598 @@
+// Blocking client for the framed protocol.
+//
+// Every request is tagged with a fresh request id, and replies are matched
+// back to their request by that id. Frames that arrive while waiting for a
+// different reply (publications, frames of another stream) are kept in a
+// backlog and handed out by `receive` later, so nothing is lost by
+// interleaving.
+//
+// With `with_reconnect`, a client whose connection failed opens a new one,
+// retrying with exponential backoff and jitter. The frame format and
+// authentication are negotiated again on it, and a request that is safe to
+// repeat (see `is_idempotent`) is sent again, so the caller only sees its
+// reply. Other requests fail with the connection's error and the next call
+// reconnects first. Subscriptions and streams are not restored.
+use crate::codec::{CompressionConfig, FrameCodec, FrameStats};
+use crate::message::{client_message, server_message, Cancel, ClientMessage, ServerMessage, StreamEnd, StreamItem};
+use crate::auth;
+use crate::message::{authenticate, AuthChallenge, Authenticate, Hello, HelloResponse, Ping};
+use crate::message::{DownloadBegin, DownloadChunk, UploadBegin, UploadChunk, UploadCommit, UploadCommitResponse};
+use log::{info, warn};
+use sha2::{Digest, Sha256};
+use std::{
+    collections::VecDeque,
+    io::{self, ErrorKind},
+    net::{Shutdown, SocketAddr, TcpStream, ToSocketAddrs},
+    sync::Arc,
+    thread,
+    time::{Duration, Instant},
+};
+
+/// How a client gets its connection back after it failed
+#[derive(Debug, Clone, PartialEq)]
+pub struct ReconnectPolicy {
+    /// Delay after the first failed attempt
+    pub initial_delay: Duration,
+    /// Longest delay between two attempts
+    pub max_delay: Duration,
+    /// Factor the delay grows by after each failed attempt
+    pub multiplier: f64,
+    /// Share of each delay that is random, from 0 (none) to 1 (anywhere between zero and the full delay)
+    pub jitter: f64,
+    /// Attempts before giving up, the first one is made right away
+    pub max_attempts: u32,
+}
+
+impl Default for ReconnectPolicy {
+    /// Up to 8 attempts, waiting 100 ms and doubling up to 10 s, half of it random
+    fn default() -> Self {
+        ReconnectPolicy {
+            initial_delay: Duration::from_millis(100),
+            max_delay: Duration::from_secs(10),
+            multiplier: 2.0,
+            jitter: 0.5,
+            max_attempts: 8,
+        }
+    }
+}
+
+impl ReconnectPolicy {
+    /// How long to wait after failed `attempt` (counted from 1) before the next one
+    pub fn delay(&self, attempt: u32) -> Duration {
+        let exponent = attempt.saturating_sub(1).min(i32::MAX as u32) as i32;
+        let backoff = self.initial_delay.as_secs_f64() * self.multiplier.max(1.0).powi(exponent);
+        let backoff = backoff.min(self.max_delay.as_secs_f64());
+        let jitter = self.jitter.clamp(0.0, 1.0) * random_fraction();
+        Duration::from_secs_f64(backoff * (1.0 - jitter))
+    }
+}
+
+// Uniform in [0, 1), 0 if the system has no randomness to give
+fn random_fraction() -> f64 {
+    let mut bytes = [0u8; 8];
+    match getrandom::getrandom(&mut bytes) {
+        Ok(()) => (u64::from_le_bytes(bytes) >> 11) as f64 / (1u64 << 53) as f64,
+        Err(_) => 0.0,
+    }
+}
+
+/// Changes of a reconnecting client's connection, reported to `Client::on_state_change`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConnectionState {
+    /// A read or write failed, reported once before reconnecting
+    Disconnected,
+    /// Reconnection `attempt` (counted from 1) is about to be made
+    Reconnecting { attempt: u32 },
+    /// A new connection is up, with the frame format and authentication negotiated again
+    Connected,
+    /// Every attempt failed, the next call starts over
+    GaveUp,
+}
+
+// Authentication to repeat on a new connection
+#[derive(Clone, PartialEq)]
+enum Login {
+    Token { identity: String, token: String },
+    Hmac { identity: String, key: Vec<u8> },
+}
+
+/// Whether sending `message` twice has the same effect as sending it once.
+/// A reconnecting client sends these again when their connection fails.
+pub fn is_idempotent(message: &client_message::Message) -> bool {
+    use client_message::Message;
+    matches!(
+        message,
+        Message::EchoMessage(_)
+            | Message::AddRequest(_)
+            | Message::ArithmeticRequest(_)
+            | Message::Get(_)
+            | Message::ListKeys(_)
+            | Message::DownloadBegin(_)
+            | Message::DownloadChunk(_)
+            | Message::Ping(_)
+    )
+}
+
+/// Connection to a server
+pub struct Client {
+    stream: TcpStream,
+    next_request_id: u64,
+    backlog: VecDeque<ServerMessage>, // Received frames nobody has asked for yet
+    sending: FrameCodec,              // Format of outgoing frames
+    receiving: FrameCodec,            // Format of incoming frames
+    stats: FrameStats,
+    broken: bool,                     // A read or write failed, the stream may be out of step
+    deadline_ms: u32,                 // Sent with every request, 0 for none
+    addrs: Vec<SocketAddr>,           // Where to reconnect
+    connect_timeout: Duration,
+    reconnect: Option<ReconnectPolicy>,
+    on_state_change: Option<Arc<dyn Fn(ConnectionState) + Send + Sync>>,
+    hello: Option<(CompressionConfig, bool)>, // Negotiated frame format, replayed on a new connection
+    login: Option<Login>,
+}
+
+impl Client {
+    /// Connects to the first address `addr` resolves to that accepts within `timeout`
+    pub fn connect(addr: impl ToSocketAddrs, timeout: Duration) -> io::Result<Self> {
+        let addrs: Vec<SocketAddr> = addr.to_socket_addrs()?.collect();
+        Ok(Client {
+            stream: open(&addrs, timeout)?,
+            next_request_id: 1,
+            backlog: VecDeque::new(),
+            sending: FrameCodec::default(),
+            receiving: FrameCodec::default(),
+            stats: FrameStats::default(),
+            broken: false,
+            deadline_ms: 0,
+            addrs,
+            connect_timeout: timeout,
+            reconnect: None,
+            on_state_change: None,
+            hello: None,
+            login: None,
+        })
+    }
+
+    /// Reconnects following `policy` whenever the connection fails
+    pub fn with_reconnect(mut self, policy: ReconnectPolicy) -> Self {
+        self.reconnect = Some(policy);
+        self
+    }
+
+    /// Calls `callback` on every change of a reconnecting client's connection
+    pub fn on_state_change(&mut self, callback: impl Fn(ConnectionState) + Send + Sync + 'static) {
+        self.on_state_change = Some(Arc::new(callback));
+    }
+
+    /// Negotiates the frame format, must be the first request on the connection.
+    ///
+    /// Offers `compression.algorithms` in order of preference and compresses
+    /// outgoing messages of at least `compression.threshold` bytes. With
+    /// `checksums`, asks for a CRC32C trailer on every frame. Returns what the
+    /// server agreed to, `Compression::None` if it supports none of the algorithms.
+    pub fn negotiate(&mut self, compression: &CompressionConfig, checksums: bool) -> io::Result<HelloResponse> {
+        let hello = Hello {
+            compression: compression.algorithms.iter().map(|&algorithm| algorithm as i32).collect(),
+            checksums,
+        };
+        let response = match self.request(client_message::Message::Hello(hello))? {
+            server_message::Message::HelloResponse(response) => response,
+            other => return Err(unexpected(other)),
+        };
+        // The server switched its format right after the response, so nothing is in flight in the old one
+        let codec = FrameCodec::new(response.compression(), compression.threshold).with_checksums(response.checksums);
+        self.sending = codec;
+        self.receiving = codec;
+        self.hello = Some((compression.clone(), checksums));
+        Ok(response)
+    }
+
+    /// Authenticates with a static bearer token, returns the identity the server accepted
+    pub fn authenticate_token(&mut self, identity: &str, token: &str) -> io::Result<String> {
+        let accepted = self.authenticate(identity, authenticate::Credential::Token(token.to_string()))?;
+        self.login = Some(Login::Token { identity: identity.to_string(), token: token.to_string() });
+        Ok(accepted)
+    }
+
+    /// Authenticates by answering a server challenge with an HMAC-SHA256 keyed with `key`.
+    /// Returns the identity the server accepted.
+    pub fn authenticate_hmac(&mut self, identity: &str, key: &[u8]) -> io::Result<String> {
+        let nonce = match self.request(client_message::Message::AuthChallenge(AuthChallenge {}))? {
+            server_message::Message::AuthChallengeResponse(response) => response.nonce,
+            other => return Err(unexpected(other)),
+        };
+        let accepted = self.authenticate(identity, authenticate::Credential::HmacSha256(auth::hmac_sha256(key, &nonce)))?;
+        self.login = Some(Login::Hmac { identity: identity.to_string(), key: key.to_vec() });
+        Ok(accepted)
+    }
+
+    fn authenticate(&mut self, identity: &str, credential: authenticate::Credential) -> io::Result<String> {
+        let request = Authenticate {
+            identity: identity.to_string(),
+            credential: Some(credential),
+        };
+        match self.request(client_message::Message::Authenticate(request))? {
+            server_message::Message::AuthenticateResponse(response) => Ok(response.identity),
+            other => Err(unexpected(other)),
+        }
+    }
+
+    /// Checks that the server answers, returns the round-trip time
+    pub fn ping(&mut self) -> io::Result<Duration> {
+        let sent = Instant::now();
+        match self.request(client_message::Message::Ping(Ping {}))? {
+            server_message::Message::Pong(_) => Ok(sent.elapsed()),
+            other => Err(unexpected(other)),
+        }
+    }
+
+    /// Frame and compression counters of this connection
+    pub fn frame_stats(&self) -> &FrameStats {
+        &self.stats
+    }
+
+    /// Asks the server to give up on each request `deadline` after reading it and reply
+    /// `ERROR_CODE_DEADLINE_EXCEEDED` instead, `None` lets requests take as long as they need
+    pub fn set_deadline(&mut self, deadline: Option<Duration>) {
+        self.deadline_ms = deadline.map_or(0, |deadline| deadline.as_millis().clamp(1, u32::MAX.into()) as u32);
+    }
+
+    /// Limits how long a receive may block, `None` waits forever
+    pub fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        self.stream.set_read_timeout(timeout)
+    }
+
+    /// Closes the connection, the server cancels any streams still running
+    pub fn disconnect(self) -> io::Result<()> {
+        self.stream.shutdown(Shutdown::Both)
+    }
+
+    /// Sends a request without waiting for its reply, returns the request id it was tagged with
+    pub fn send(&mut self, message: client_message::Message) -> io::Result<u64> {
+        if self.broken && self.reconnect.is_some() {
+            self.reconnect()?;
+        }
+        let request_id = self.next_request_id;
+        self.next_request_id += 1;
+        let envelope = ClientMessage {
+            request_id,
+            message: Some(message),
+            deadline_ms: self.deadline_ms,
+        };
+        if let Err(e) = self.sending.write_message(&mut self.stream, &envelope, &self.stats.sent) {
+            self.broken = true;
+            return Err(e);
+        }
+        Ok(request_id)
+    }
+
+    /// Returns the next frame that has not been handed out yet, in arrival order
+    pub fn receive(&mut self) -> io::Result<ServerMessage> {
+        match self.backlog.pop_front() {
+            Some(message) => Ok(message),
+            None => self.read(),
+        }
+    }
+
+    /// Sends a request and waits for its reply.
+    /// With `with_reconnect`, an idempotent request is sent again if the connection fails.
+    pub fn request(&mut self, message: client_message::Message) -> io::Result<server_message::Message> {
+        if self.reconnect.is_none() || !is_idempotent(&message) {
+            return self.exchange(message);
+        }
+        match self.exchange(message.clone()) {
+            Err(e) if self.broken => {
+                warn!("Retrying a request after the connection failed: {}", e);
+                self.reconnect()?;
+                self.exchange(message)
+            }
+            result => result,
+        }
+    }
+
+    // Sends one request and waits for its reply, without retrying
+    fn exchange(&mut self, message: client_message::Message) -> io::Result<server_message::Message> {
+        let request_id = self.send(message)?;
+        let reply = self.receive_for(request_id)?;
+        reply
+            .message
+            .ok_or_else(|| io::Error::new(ErrorKind::InvalidData, "reply carries no message"))
+    }
+
+    /// Starts a streaming request, the returned iterator yields its items
+    pub fn stream(&mut self, message: client_message::Message) -> io::Result<ResponseStream<'_>> {
+        let request_id = self.send(message)?;
+        Ok(ResponseStream {
+            client: self,
+            request_id,
+            finished: false,
+            end: None,
+        })
+    }
+
+    /// Asks the server to stop the stream started by `request_id`, or to give up on that
+    /// request if it has not been answered yet. Returns false if it had already ended.
+    pub fn cancel(&mut self, request_id: u64) -> io::Result<bool> {
+        match self.request(client_message::Message::Cancel(Cancel { request_id }))? {
+            server_message::Message::CancelResponse(response) => Ok(response.cancelled),
+            other => Err(unexpected(other)),
+        }
+    }
+
+    /// Uploads `data` as `name` in chunks of `chunk_len` bytes and commits it.
+    ///
+    /// If an earlier attempt to upload the same bytes under the same name was
+    /// interrupted, the upload continues where that attempt stopped.
+    pub fn upload(&mut self, name: &str, data: &[u8], chunk_len: usize) -> io::Result<UploadCommitResponse> {
+        let begin = UploadBegin {
+            name: name.to_string(),
+            size: data.len() as u64,
+            sha256: Sha256::digest(data).to_vec(),
+        };
+        let (upload_id, mut offset) = match self.request(client_message::Message::UploadBegin(begin))? {
+            server_message::Message::UploadBeginResponse(response) => (response.upload_id, response.offset),
+            other => return Err(unexpected(other)),
+        };
+
+        while offset < data.len() as u64 {
+            let start = offset as usize;
+            let end = data.len().min(start + chunk_len.max(1));
+            let chunk = UploadChunk {
+                upload_id: upload_id.clone(),
+                offset,
+                data: data[start..end].to_vec(),
+            };
+            offset = match self.request(client_message::Message::UploadChunk(chunk))? {
+                server_message::Message::UploadChunkResponse(response) => response.offset,
+                other => return Err(unexpected(other)),
+            };
+        }
+
+        match self.request(client_message::Message::UploadCommit(UploadCommit { upload_id }))? {
+            server_message::Message::UploadCommitResponse(response) => Ok(response),
+            other => Err(unexpected(other)),
+        }
+    }
+
+    /// Downloads the file `name` and checks it against the digest the server reports
+    pub fn download(&mut self, name: &str) -> io::Result<Vec<u8>> {
+        let begin = DownloadBegin { name: name.to_string() };
+        let (size, sha256) = match self.request(client_message::Message::DownloadBegin(begin))? {
+            server_message::Message::DownloadBeginResponse(response) => (response.size, response.sha256),
+            other => return Err(unexpected(other)),
+        };
+
+        let mut data = Vec::with_capacity(size as usize);
+        while (data.len() as u64) < size {
+            let chunk = DownloadChunk {
+                name: name.to_string(),
+                offset: data.len() as u64,
+                length: 0, // As much as the server sends at once
+            };
+            match self.request(client_message::Message::DownloadChunk(chunk))? {
+                server_message::Message::DownloadChunkResponse(response) if !response.data.is_empty() => {
+                    data.extend_from_slice(&response.data)
+                }
+                server_message::Message::DownloadChunkResponse(_) => {
+                    return Err(io::Error::new(ErrorKind::UnexpectedEof, "file shrank during download"))
+                }
+                other => return Err(unexpected(other)),
+            }
+        }
+
+        if Sha256::digest(&data).as_slice() != sha256.as_slice() {
+            return Err(io::Error::new(ErrorKind::InvalidData, "downloaded file does not match its sha256"));
+        }
+        Ok(data)
+    }
+
+    // Returns the next frame tagged with `request_id`, keeping everything else for later
+    fn receive_for(&mut self, request_id: u64) -> io::Result<ServerMessage> {
+        if let Some(index) = self.backlog.iter().position(|message| message.request_id == request_id) {
+            return Ok(self.backlog.remove(index).unwrap());
+        }
+        loop {
+            let message = self.read()?;
+            if message.request_id == request_id {
+                return Ok(message);
+            }
+            self.backlog.push_back(message);
+        }
+    }
+
+    // Reads one frame from the socket
+    fn read(&mut self) -> io::Result<ServerMessage> {
+        let result = match self.receiving.read_message(&mut self.stream, &self.stats.received) {
+            Ok(Some(message)) => Ok(message),
+            Ok(None) => Err(io::Error::new(ErrorKind::ConnectionAborted, "Server disconnected")),
+            Err(e) => Err(e),
+        };
+        self.broken |= result.is_err(); // Also after a timeout: the reply may still arrive
+        result
+    }
+
+    // Opens a new connection with backoff, giving up after `max_attempts`
+    fn reconnect(&mut self) -> io::Result<()> {
+        let policy = self.reconnect.clone().expect("only called with a reconnect policy");
+        self.notify(ConnectionState::Disconnected);
+        let mut last_error = io::Error::new(ErrorKind::NotConnected, "no reconnection attempt was made");
+        for attempt in 1..=policy.max_attempts {
+            if attempt > 1 {
+                thread::sleep(policy.delay(attempt - 1));
+            }
+            self.notify(ConnectionState::Reconnecting { attempt });
+            match self.reopen() {
+                Ok(()) => {
+                    info!("Reconnected to {:?} after {} attempt(s)", self.addrs, attempt);
+                    self.notify(ConnectionState::Connected);
+                    return Ok(());
+                }
+                Err(e) => {
+                    warn!("Reconnection attempt {} failed: {}", attempt, e);
+                    last_error = e;
+                }
+            }
+        }
+        self.notify(ConnectionState::GaveUp);
+        Err(last_error)
+    }
+
+    // Replaces the stream and sets it up like the old one
+    fn reopen(&mut self) -> io::Result<()> {
+        let read_timeout = self.stream.read_timeout().unwrap_or(None);
+        let _ = self.stream.shutdown(Shutdown::Both);
+        self.stream = open(&self.addrs, self.connect_timeout)?;
+        self.stream.set_read_timeout(read_timeout)?;
+        self.sending = FrameCodec::default();
+        self.receiving = FrameCodec::default();
+        self.broken = false;
+
+        if let Some((compression, checksums)) = self.hello.clone() {
+            self.negotiate(&compression, checksums)?;
+        }
+        match self.login.clone() {
+            Some(Login::Token { identity, token }) => self.authenticate_token(&identity, &token).map(drop),
+            Some(Login::Hmac { identity, key }) => self.authenticate_hmac(&identity, &key).map(drop),
+            None => Ok(()),
+        }
+    }
+
+    fn notify(&self, state: ConnectionState) {
+        if let Some(callback) = &self.on_state_change {
+            callback(state);
+        }
+    }
+
+    // Whether another caller can use the connection: it never failed and nothing unread is waiting
+    pub(crate) fn is_reusable(&self) -> bool {
+        !self.broken && self.backlog.is_empty()
+    }
+
+    // Settings of the connection right now, for `restore` to go back to
+    pub(crate) fn baseline(&self) -> Baseline {
+        Baseline {
+            deadline_ms: self.deadline_ms,
+            read_timeout: self.stream.read_timeout().unwrap_or(None),
+            hello: self.hello.clone(),
+            login: self.login.clone(),
+        }
+    }
+
+    // Puts the deadline and read timeout back to `baseline`. Returns false if the
+    // frame format or the identity changed since, which cannot be undone.
+    pub(crate) fn restore(&mut self, baseline: &Baseline) -> bool {
+        if self.hello != baseline.hello || self.login != baseline.login {
+            return false;
+        }
+        self.deadline_ms = baseline.deadline_ms;
+        self.stream.set_read_timeout(baseline.read_timeout).is_ok()
+    }
+}
+
+// What a pooled connection looked like when it was set up
+#[derive(Clone)]
+pub(crate) struct Baseline {
+    deadline_ms: u32,
+    read_timeout: Option<Duration>,
+    hello: Option<(CompressionConfig, bool)>,
+    login: Option<Login>,
+}
+
+// Connects to the first of `addrs` that accepts within `timeout`
+fn open(addrs: &[SocketAddr], timeout: Duration) -> io::Result<TcpStream> {
+    let mut last_error = io::Error::new(ErrorKind::InvalidInput, "address resolved to nothing");
+    for addr in addrs {
+        match TcpStream::connect_timeout(addr, timeout) {
+            Ok(stream) => return Ok(stream),
+            Err(e) => last_error = e,
+        }
+    }
+    Err(last_error)
+}
+
+// Turns a reply of the wrong type into an error, `Error` replies keep their message
+fn unexpected(message: server_message::Message) -> io::Error {
+    match message {
+        server_message::Message::Error(error) => io::Error::other(format!("server error {}: {}", error.code, error.message)),
+        other => io::Error::new(ErrorKind::InvalidData, format!("unexpected reply {:?}", other)),
+    }
+}
+
+/// Items of a streaming request, in the order the server sent them.
+///
+/// The iterator ends after the server's `StreamEnd`, which `end` then returns.
+/// An error reply ends it with an `Err` item. Dropping an unfinished stream
+/// cancels it on the server.
+pub struct ResponseStream<'a> {
+    client: &'a mut Client,
+    request_id: u64,
+    finished: bool, // No more frames will arrive for this stream
+    end: Option<StreamEnd>,
+}
+
+impl ResponseStream<'_> {
+    /// Request id the stream's frames are tagged with
+    pub fn request_id(&self) -> u64 {
+        self.request_id
+    }
+
+    /// How the stream ended, `None` while it is still running or if it was rejected
+    pub fn end(&self) -> Option<&StreamEnd> {
+        self.end.as_ref()
+    }
+
+    /// Stops the stream and discards the items already in flight.
+    /// Returns how the stream ended, which may be completed if it was about to.
+    pub fn cancel(&mut self) -> io::Result<StreamEnd> {
+        if !self.finished {
+            let cancel_id = self.client.send(client_message::Message::Cancel(Cancel {
+                request_id: self.request_id,
+            }))?;
+            let drained: io::Result<Vec<_>> = self.by_ref().collect();
+            self.client.receive_for(cancel_id)?; // Whether it cancelled is told by the end frame
+            drained?;
+        }
+        self.end
+            .ok_or_else(|| io::Error::new(ErrorKind::InvalidData, "stream ended without a StreamEnd"))
+    }
+}
+
+impl Iterator for ResponseStream<'_> {
+    type Item = io::Result<StreamItem>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.finished {
+            return None;
+        }
+        let message = match self.client.receive_for(self.request_id) {
+            Ok(message) => message.message,
+            Err(e) => {
+                self.finished = true; // The connection failed, the rest of the stream is lost
+                return Some(Err(e));
+            }
+        };
+        match message {
+            Some(server_message::Message::StreamItem(item)) => Some(Ok(item)),
+            Some(server_message::Message::StreamEnd(end)) => {
+                self.finished = true;
+                self.end = Some(end);
+                None
+            }
+            Some(other) => {
+                self.finished = true; // Rejected, the error reply is the only frame
+                Some(Err(unexpected(other)))
+            }
+            None => {
+                self.finished = true;
+                Some(Err(io::Error::new(ErrorKind::InvalidData, "stream frame carries no message")))
+            }
+        }
+    }
+}
+
+impl Drop for ResponseStream<'_> {
+    fn drop(&mut self) {
+        // Leave no stray frames behind for the next request, best effort
+        let _ = self.cancel();
+    }
+}
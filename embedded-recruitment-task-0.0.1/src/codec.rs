@@ -0,0 +1,332 @@
+// Negotiated frame format.
+//
+// A connection starts with the plain framing of `frame`. A client may send a
+// `Hello` as its first message; once the server has answered with a
+// `HelloResponse`, both directions switch to the format agreed on there.
+//
+// With compression negotiated, every frame payload starts with a flag byte:
+//
+//     [u8 0][message]                 sent as is
+//     [u8 1][compressed message]      compressed with the negotiated algorithm
+//
+// Each sender compresses only messages of at least its own threshold, and
+// only when that makes them smaller, so small replies cost a single byte.
+//
+// With checksums negotiated, every frame payload (after compression) is
+// followed by a 4-byte big-endian CRC32C of the bytes before it. The length
+// prefix stays outside, so a corrupt frame can be skipped without losing
+// track of where the next one starts.
+use crate::frame::{self, MAX_FRAME_LEN};
+use crate::message::Compression;
+use prost::Message;
+use std::{
+    fmt,
+    io::{self, ErrorKind, Read, Write},
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+const FLAG_RAW: u8 = 0;
+const FLAG_COMPRESSED: u8 = 1;
+
+/// Size of the CRC32C trailer of a checksummed frame
+pub const CHECKSUM_LEN: usize = 4;
+
+/// A frame failed its CRC32C check, carried inside an `io::Error`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ChecksumError;
+
+impl fmt::Display for ChecksumError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "frame failed its CRC32C check")
+    }
+}
+
+impl std::error::Error for ChecksumError {}
+
+/// Returns true if `error` reports a frame that failed its checksum
+pub fn is_checksum_error(error: &io::Error) -> bool {
+    error.get_ref().is_some_and(|inner| inner.is::<ChecksumError>())
+}
+
+/// Compression algorithms built into this binary, most preferred first
+pub fn supported() -> Vec<Compression> {
+    [
+        (cfg!(feature = "zstd"), Compression::Zstd),
+        (cfg!(feature = "lz4"), Compression::Lz4),
+    ]
+    .into_iter()
+    .filter_map(|(built_in, compression)| built_in.then_some(compression))
+    .collect()
+}
+
+/// Picks the first algorithm in the client's `offered` list that the server `accepts`
+pub fn negotiate(offered: &[i32], accepts: &[Compression]) -> Compression {
+    offered
+        .iter()
+        .filter_map(|&value| Compression::try_from(value).ok()) // Unknown values come from newer peers
+        .find(|compression| *compression != Compression::None && accepts.contains(compression))
+        .unwrap_or(Compression::None)
+}
+
+/// Which algorithms a side accepts and which messages it compresses
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CompressionConfig {
+    /// Algorithms accepted during negotiation, most preferred first, empty disables compression
+    pub algorithms: Vec<Compression>,
+    /// Messages smaller than this many bytes are sent uncompressed
+    pub threshold: usize,
+}
+
+impl Default for CompressionConfig {
+    /// Every built-in algorithm, compressing messages of 256 bytes or more
+    fn default() -> Self {
+        CompressionConfig {
+            algorithms: supported(),
+            threshold: 256,
+        }
+    }
+}
+
+// Compresses a message with a built-in algorithm
+#[cfg_attr(not(any(feature = "zstd", feature = "lz4")), allow(unused_variables))] // Nothing to (de)compress with
+fn compress(compression: Compression, data: &[u8]) -> io::Result<Vec<u8>> {
+    match compression {
+        #[cfg(feature = "zstd")]
+        Compression::Zstd => zstd::bulk::compress(data, 0), // 0 is zstd's default level
+        #[cfg(feature = "lz4")]
+        Compression::Lz4 => Ok(lz4_flex::block::compress_prepend_size(data)),
+        other => Err(io::Error::new(
+            ErrorKind::Unsupported,
+            format!("{:?} compression is not built in", other),
+        )),
+    }
+}
+
+// Decompresses a frame, refusing output larger than the frame limit
+#[cfg_attr(not(any(feature = "zstd", feature = "lz4")), allow(unused_variables))] // Nothing to (de)compress with
+fn decompress(compression: Compression, data: &[u8]) -> io::Result<Vec<u8>> {
+    let invalid = |e: &dyn std::fmt::Display| io::Error::new(ErrorKind::InvalidData, e.to_string());
+    match compression {
+        #[cfg(feature = "zstd")]
+        Compression::Zstd => zstd::bulk::decompress(data, MAX_FRAME_LEN),
+        #[cfg(feature = "lz4")]
+        Compression::Lz4 => {
+            // The prepended size is checked first so a forged one cannot force a huge allocation
+            let (size, _) = lz4_flex::block::uncompressed_size(data).map_err(|e| invalid(&e))?;
+            if size > MAX_FRAME_LEN {
+                return Err(invalid(&format!("decompressed frame of {} bytes exceeds the limit", size)));
+            }
+            lz4_flex::block::decompress_size_prepended(data).map_err(|e| invalid(&e))
+        }
+        other => Err(invalid(&format!("{:?} compression is not built in", other))),
+    }
+}
+
+/// Counters for the frames sent or received in one direction
+#[derive(Debug, Default)]
+pub struct CompressionStats {
+    frames: AtomicU64,
+    compressed_frames: AtomicU64,
+    message_bytes: AtomicU64, // Encoded messages before compression
+    wire_bytes: AtomicU64,    // Frame payloads as they crossed the connection
+}
+
+/// Point-in-time copy of `CompressionStats`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CompressionSnapshot {
+    pub frames: u64,
+    pub compressed_frames: u64,
+    pub message_bytes: u64,
+    pub wire_bytes: u64,
+}
+
+impl CompressionSnapshot {
+    /// Message bytes per byte on the wire, above 1 when compression saves space
+    pub fn ratio(&self) -> f64 {
+        if self.wire_bytes == 0 {
+            1.0
+        } else {
+            self.message_bytes as f64 / self.wire_bytes as f64
+        }
+    }
+}
+
+impl CompressionStats {
+    fn record(&self, message_len: usize, wire_len: usize, compressed: bool) {
+        self.frames.fetch_add(1, Ordering::Relaxed);
+        if compressed {
+            self.compressed_frames.fetch_add(1, Ordering::Relaxed);
+        }
+        self.message_bytes.fetch_add(message_len as u64, Ordering::Relaxed);
+        self.wire_bytes.fetch_add(wire_len as u64, Ordering::Relaxed);
+    }
+
+    /// Returns the current counter values
+    pub fn snapshot(&self) -> CompressionSnapshot {
+        CompressionSnapshot {
+            frames: self.frames.load(Ordering::Relaxed),
+            compressed_frames: self.compressed_frames.load(Ordering::Relaxed),
+            message_bytes: self.message_bytes.load(Ordering::Relaxed),
+            wire_bytes: self.wire_bytes.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// Counters for both directions of one or more connections
+#[derive(Debug, Default)]
+pub struct FrameStats {
+    pub sent: CompressionStats,
+    pub received: CompressionStats,
+}
+
+/// Frame format of one direction of a connection
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FrameCodec {
+    compression: Compression, // `None` is the plain format without a flag byte
+    threshold: usize,
+    checksums: bool, // Append and verify a CRC32C trailer
+}
+
+impl Default for FrameCodec {
+    /// The plain format every connection starts with
+    fn default() -> Self {
+        FrameCodec {
+            compression: Compression::None,
+            threshold: usize::MAX,
+            checksums: false,
+        }
+    }
+}
+
+impl FrameCodec {
+    /// Format agreed on in a `HelloResponse`, compressing messages of at least `threshold` bytes
+    pub fn new(compression: Compression, threshold: usize) -> Self {
+        FrameCodec {
+            compression,
+            threshold,
+            checksums: false,
+        }
+    }
+
+    /// Same format with or without the CRC32C trailer
+    pub fn with_checksums(self, checksums: bool) -> Self {
+        FrameCodec { checksums, ..self }
+    }
+
+    /// Negotiated compression algorithm
+    pub fn compression(&self) -> Compression {
+        self.compression
+    }
+
+    /// Returns true if frames carry a CRC32C trailer
+    pub fn checksums(&self) -> bool {
+        self.checksums
+    }
+
+    /// Turns an encoded message into a frame payload
+    pub fn encode(&self, message: Vec<u8>, stats: &CompressionStats) -> io::Result<Vec<u8>> {
+        if message.len() > MAX_FRAME_LEN {
+            // Checked before compressing so the receiver's decompression limit always holds
+            return Err(io::Error::new(
+                ErrorKind::InvalidInput,
+                format!("message of {} bytes exceeds the {} byte limit", message.len(), MAX_FRAME_LEN),
+            ));
+        }
+        let message_len = message.len();
+        let (mut payload, compressed) = self.pack(message)?;
+        if self.checksums {
+            let checksum = crc32c::crc32c(&payload);
+            payload.extend_from_slice(&checksum.to_be_bytes());
+        }
+        if payload.len() > MAX_FRAME_LEN {
+            // The flag byte and the trailer can push a message that fits over the frame limit
+            return Err(io::Error::new(
+                ErrorKind::InvalidInput,
+                format!("frame of {} bytes exceeds the {} byte limit", payload.len(), MAX_FRAME_LEN),
+            ));
+        }
+        stats.record(message_len, payload.len(), compressed);
+        Ok(payload)
+    }
+
+    // Applies compression, returns the payload and whether it is compressed
+    fn pack(&self, message: Vec<u8>) -> io::Result<(Vec<u8>, bool)> {
+        if self.compression == Compression::None {
+            return Ok((message, false));
+        }
+        if message.len() >= self.threshold {
+            let compressed = compress(self.compression, &message)?;
+            if compressed.len() < message.len() {
+                let mut payload = Vec::with_capacity(1 + compressed.len() + CHECKSUM_LEN);
+                payload.push(FLAG_COMPRESSED);
+                payload.extend_from_slice(&compressed);
+                return Ok((payload, true));
+            }
+        }
+        let mut payload = Vec::with_capacity(1 + message.len() + CHECKSUM_LEN);
+        payload.push(FLAG_RAW);
+        payload.extend_from_slice(&message);
+        Ok((payload, false))
+    }
+
+    /// Turns a frame payload back into the encoded message.
+    ///
+    /// A payload that fails its checksum is reported as a `ChecksumError`, see `is_checksum_error`.
+    pub fn decode(&self, mut payload: Vec<u8>, stats: &CompressionStats) -> io::Result<Vec<u8>> {
+        let wire_len = payload.len();
+        if self.checksums {
+            let Some(body_len) = payload.len().checked_sub(CHECKSUM_LEN) else {
+                return Err(io::Error::new(ErrorKind::InvalidData, ChecksumError));
+            };
+            let expected = u32::from_be_bytes(payload[body_len..].try_into().unwrap());
+            payload.truncate(body_len);
+            if crc32c::crc32c(&payload) != expected {
+                return Err(io::Error::new(ErrorKind::InvalidData, ChecksumError));
+            }
+        }
+
+        let (message, compressed) = self.unpack(payload)?;
+        stats.record(message.len(), wire_len, compressed);
+        Ok(message)
+    }
+
+    // Undoes `pack`
+    fn unpack(&self, payload: Vec<u8>) -> io::Result<(Vec<u8>, bool)> {
+        if self.compression == Compression::None {
+            return Ok((payload, false));
+        }
+        match payload.first() {
+            Some(&FLAG_RAW) => Ok((payload[1..].to_vec(), false)),
+            Some(&FLAG_COMPRESSED) => Ok((decompress(self.compression, &payload[1..])?, true)),
+            Some(flag) => Err(io::Error::new(ErrorKind::InvalidData, format!("unknown frame flag {}", flag))),
+            None => Err(io::Error::new(ErrorKind::InvalidData, "frame without a flag byte")),
+        }
+    }
+
+    /// Encodes `message` and writes it as a single frame
+    pub fn write_message<W: Write, M: Message>(
+        &self,
+        writer: &mut W,
+        message: &M,
+        stats: &CompressionStats,
+    ) -> io::Result<()> {
+        frame::write_frame(writer, &self.encode(message.encode_to_vec(), stats)?)
+    }
+
+    /// Reads and decodes the next message, `Ok(None)` on clean disconnect
+    pub fn read_message<R: Read, M: Message + Default>(
+        &self,
+        reader: &mut R,
+        stats: &CompressionStats,
+    ) -> io::Result<Option<M>> {
+        match frame::read_frame(reader)? {
+            Some(payload) => {
+                let message = self.decode(payload, stats)?;
+                M::decode(message.as_slice())
+                    .map(Some)
+                    .map_err(|e| io::Error::new(ErrorKind::InvalidData, e))
+            }
+            None => Ok(None),
+        }
+    }
+}
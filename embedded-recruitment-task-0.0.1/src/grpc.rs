@@ -0,0 +1,319 @@
+// gRPC server for the `Gateway` service of `messages.proto`, built with the
+// `grpc` feature.
+//
+// Every call gets a session of its own and goes through the same `Handler`
+// as TCP connections, so authentication, policy and the audit log apply
+// unchanged. Credentials travel as `authorization: Bearer <identity>:<token>`
+// metadata and are checked before the first request of the call.
+//
+// The handlers block, so each request runs on tokio's blocking pool. `Echo`
+// and `Add` map an `Error` reply to a `Status` with a matching code; the
+// streaming variants end their stream with that status. `Session` carries
+// whole `ClientMessage`s and `ServerMessage`s, errors included, like a TCP
+// connection does.
+#![allow(clippy::result_large_err)] // `Status` is the error of every tonic API
+
+use crate::audit::Outcome;
+use crate::auth;
+use crate::handler::{Handler, Session};
+use crate::message::client_message::Message as ClientMessageType;
+use crate::message::gateway_server::{Gateway, GatewayServer};
+use crate::message::{server_message, AddRequest, AddResponse, Authenticate, ClientMessage, EchoMessage, ErrorCode, ServerMessage};
+use crate::outbound::OutboundReceiver;
+use crate::policy;
+use crate::server::ConnectionOptions;
+use log::{error, info};
+use std::{
+    io,
+    net::{SocketAddr, TcpListener},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    thread,
+    time::{Instant, SystemTime},
+};
+use tokio::sync::{mpsc as channel, oneshot};
+use tokio_stream::wrappers::{ReceiverStream, TcpListenerStream};
+use tonic::{metadata::MetadataMap, Code, Request, Response, Status, Streaming};
+
+/// `Status` for a reply carrying an `Error` of `code`
+pub fn status(code: ErrorCode, message: impl Into<String>) -> Status {
+    let code = match code {
+        ErrorCode::InvalidArgument | ErrorCode::DivisionByZero | ErrorCode::ChecksumMismatch => Code::InvalidArgument,
+        ErrorCode::Overflow => Code::OutOfRange,
+        ErrorCode::Unauthenticated => Code::Unauthenticated,
+        ErrorCode::PermissionDenied => Code::PermissionDenied,
+        ErrorCode::NotFound => Code::NotFound,
+        ErrorCode::Aborted => Code::Aborted,
+        ErrorCode::QuotaExceeded => Code::ResourceExhausted,
+        ErrorCode::Unavailable => Code::Unavailable,
+        ErrorCode::DeadlineExceeded => Code::DeadlineExceeded,
+        ErrorCode::Cancelled => Code::Cancelled,
+        ErrorCode::Internal => Code::Internal,
+        ErrorCode::Unknown => Code::Unknown,
+    };
+    Status::new(code, message)
+}
+
+// Runs blocking handler code off the async workers
+async fn blocking<T: Send + 'static>(work: impl FnOnce() -> Result<T, Status> + Send + 'static) -> Result<T, Status> {
+    tokio::task::spawn_blocking(work).await.map_err(|e| Status::internal(e.to_string()))?
+}
+
+// Token credentials from the `authorization` metadata, if sent
+fn bearer(metadata: &MetadataMap) -> Option<Authenticate> {
+    auth::parse_bearer(metadata.get("authorization")?.to_str().ok()?)
+}
+
+// The session of one call, released when the call ends
+struct Call {
+    session: Session,
+    handler: Arc<Handler>,
+    options: ConnectionOptions,
+    peer: Option<SocketAddr>,
+}
+
+impl Call {
+    // Handles one request and records it, like a request on a connection
+    fn handle(&self, message: ClientMessage) -> Option<ServerMessage> {
+        let arrived = (SystemTime::now(), Instant::now());
+        let request_id = message.request_id;
+        let operation = message.message.as_ref().map_or("empty", policy::message_name);
+        let response = self.handler.handle(&self.session, message);
+        self.options.audit(&self.session, self.peer, request_id, operation, Outcome::of(response.as_ref()), arrived);
+        response
+    }
+
+    // Handles a request that has a single reply, turning an `Error` into a `Status`
+    fn unary(&self, message: ClientMessageType) -> Result<server_message::Message, Status> {
+        let response = self.handle(ClientMessage { request_id: 0, message: Some(message), deadline_ms: 0 });
+        match response.and_then(|response| response.message) {
+            Some(server_message::Message::Error(error)) => Err(status(error.code(), error.message)),
+            Some(message) => Ok(message),
+            None => Err(Status::internal("request was not answered")),
+        }
+    }
+
+    // Authenticates the session when the call carried credentials
+    fn authenticate(&self, credentials: Option<Authenticate>) -> Result<(), Status> {
+        match credentials {
+            Some(credentials) => self.unary(ClientMessageType::Authenticate(credentials)).map(|_| ()),
+            None => Ok(()),
+        }
+    }
+}
+
+impl Drop for Call {
+    fn drop(&mut self) {
+        self.handler.disconnect(&self.session); // Ends streams and subscriptions the call left behind
+    }
+}
+
+// Unwraps the reply to an `Echo`
+fn echo_reply(message: server_message::Message) -> Result<EchoMessage, Status> {
+    match message {
+        server_message::Message::EchoMessage(echo) => Ok(echo),
+        other => Err(Status::internal(format!("unexpected reply {:?}", other))),
+    }
+}
+
+// Unwraps the reply to an `Add`
+fn add_reply(message: server_message::Message) -> Result<AddResponse, Status> {
+    match message {
+        server_message::Message::AddResponse(add) => Ok(add),
+        other => Err(Status::internal(format!("unexpected reply {:?}", other))),
+    }
+}
+
+struct GatewayService {
+    handler: Arc<Handler>,
+    options: ConnectionOptions,
+    ids: Arc<AtomicU64>, // Calls draw session ids from the connection ids
+}
+
+impl GatewayService {
+    // Opens the session of a call, returning the queue of what the handlers push to it
+    fn open<T>(&self, request: &Request<T>) -> (Arc<Call>, OutboundReceiver) {
+        let (outbound, queue) = self.options.outbound();
+        let call = Call {
+            session: Session::new(self.ids.fetch_add(1, Ordering::Relaxed), outbound),
+            handler: self.handler.clone(),
+            options: self.options.clone(),
+            peer: request.remote_addr(),
+        };
+        (Arc::new(call), queue)
+    }
+
+    // Answers each request of a client stream in order, ending with the first error
+    fn each<Req, Resp>(
+        &self,
+        request: Request<Streaming<Req>>,
+        wrap: fn(Req) -> ClientMessageType,
+        unwrap: fn(server_message::Message) -> Result<Resp, Status>,
+    ) -> Response<ReceiverStream<Result<Resp, Status>>>
+    where
+        Req: Send + 'static,
+        Resp: Send + 'static,
+    {
+        let (call, _) = self.open(&request);
+        let credentials = bearer(request.metadata());
+        let mut inbound = request.into_inner();
+        let (replies, stream) = channel::channel(16);
+        tokio::spawn(async move {
+            let first = call.clone();
+            if let Err(status) = blocking(move || first.authenticate(credentials)).await {
+                let _ = replies.send(Err(status)).await;
+                return;
+            }
+            loop {
+                let reply = match inbound.message().await {
+                    Ok(Some(item)) => {
+                        let call = call.clone();
+                        blocking(move || call.unary(wrap(item)).and_then(unwrap)).await
+                    }
+                    Ok(None) => return, // The client closed its side
+                    Err(status) => Err(status),
+                };
+                let failed = reply.is_err();
+                if replies.send(reply).await.is_err() || failed {
+                    return;
+                }
+            }
+        });
+        Response::new(ReceiverStream::new(stream))
+    }
+}
+
+#[tonic::async_trait]
+impl Gateway for GatewayService {
+    async fn echo(&self, request: Request<EchoMessage>) -> Result<Response<EchoMessage>, Status> {
+        let (call, _) = self.open(&request);
+        let credentials = bearer(request.metadata());
+        let echo = request.into_inner();
+        blocking(move || {
+            call.authenticate(credentials)?;
+            call.unary(ClientMessageType::EchoMessage(echo)).and_then(echo_reply)
+        })
+        .await
+        .map(Response::new)
+    }
+
+    async fn add(&self, request: Request<AddRequest>) -> Result<Response<AddResponse>, Status> {
+        let (call, _) = self.open(&request);
+        let credentials = bearer(request.metadata());
+        let add = request.into_inner();
+        blocking(move || {
+            call.authenticate(credentials)?;
+            call.unary(ClientMessageType::AddRequest(add)).and_then(add_reply)
+        })
+        .await
+        .map(Response::new)
+    }
+
+    type EchoStreamStream = ReceiverStream<Result<EchoMessage, Status>>;
+
+    async fn echo_stream(&self, request: Request<Streaming<EchoMessage>>) -> Result<Response<Self::EchoStreamStream>, Status> {
+        Ok(self.each(request, ClientMessageType::EchoMessage, echo_reply))
+    }
+
+    type AddStreamStream = ReceiverStream<Result<AddResponse, Status>>;
+
+    async fn add_stream(&self, request: Request<Streaming<AddRequest>>) -> Result<Response<Self::AddStreamStream>, Status> {
+        Ok(self.each(request, ClientMessageType::AddRequest, add_reply))
+    }
+
+    type SessionStream = ReceiverStream<Result<ServerMessage, Status>>;
+
+    async fn session(&self, request: Request<Streaming<ClientMessage>>) -> Result<Response<Self::SessionStream>, Status> {
+        let (call, queue) = self.open(&request);
+        let credentials = bearer(request.metadata());
+        let mut inbound = request.into_inner();
+        let (replies, stream) = channel::channel(64);
+
+        // Everything for the session, replies, stream frames and publications, goes through its queue.
+        // The queue ends once the call and every stream it started have let go of the session.
+        let forward = replies.clone();
+        thread::spawn(move || {
+            for message in queue {
+                if forward.blocking_send(Ok(message)).is_err() {
+                    return; // The client went away
+                }
+            }
+        });
+
+        tokio::spawn(async move {
+            let first = call.clone();
+            if let Err(status) = blocking(move || first.authenticate(credentials)).await {
+                let _ = replies.send(Err(status)).await;
+                return;
+            }
+            loop {
+                match inbound.message().await {
+                    Ok(Some(message)) => {
+                        let call = call.clone();
+                        let handled = blocking(move || {
+                            if let Some(response) = call.handle(message) {
+                                let _ = call.session.outbound.send(response);
+                            }
+                            Ok(())
+                        });
+                        if handled.await.is_err() {
+                            return;
+                        }
+                    }
+                    Ok(None) => return,
+                    Err(status) => {
+                        info!("gRPC session ended: {}", status);
+                        return;
+                    }
+                }
+            }
+        });
+        Ok(Response::new(ReceiverStream::new(stream)))
+    }
+}
+
+/// A running gRPC server, shut down when dropped
+pub(crate) struct GrpcServer {
+    addr: SocketAddr,
+    shutdown: Option<oneshot::Sender<()>>,
+}
+
+impl GrpcServer {
+    /// Serves the `Gateway` service on `listener` from a runtime on a thread of its own
+    pub(crate) fn start(listener: TcpListener, handler: Arc<Handler>, options: ConnectionOptions, ids: Arc<AtomicU64>) -> io::Result<Self> {
+        let addr = listener.local_addr()?;
+        listener.set_nonblocking(true)?; // Required by tokio
+        let runtime = tokio::runtime::Builder::new_multi_thread().enable_all().build()?;
+        let listener = runtime.block_on(async { tokio::net::TcpListener::from_std(listener) })?;
+        let (shutdown, stop) = oneshot::channel::<()>();
+        let service = GatewayService { handler, options, ids };
+        thread::spawn(move || {
+            let served = runtime.block_on(
+                tonic::transport::Server::builder()
+                    .add_service(GatewayServer::new(service))
+                    .serve_with_incoming_shutdown(TcpListenerStream::new(listener), async {
+                        let _ = stop.await;
+                    }),
+            );
+            if let Err(e) = served {
+                error!("gRPC server failed: {}", e);
+            }
+        });
+        Ok(GrpcServer { addr, shutdown: Some(shutdown) })
+    }
+
+    /// Address the server is bound to
+    pub(crate) fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+}
+
+impl Drop for GrpcServer {
+    fn drop(&mut self) {
+        if let Some(shutdown) = self.shutdown.take() {
+            let _ = shutdown.send(());
+        }
+    }
+}
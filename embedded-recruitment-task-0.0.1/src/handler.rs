@@ -0,0 +1,776 @@
+// Request handlers shared by every connection.
+//
+// `Handler::handle` turns one `ClientMessage` into its `ServerMessage`
+// reply without knowing how the bytes travel, so the socket code in `server`
+// only has to deal with framing and connection lifetime. Streaming requests
+// are the exception: their frames are pushed to the session's outbound queue
+// from a thread of their own. A request's deadline and cancellation reach the
+// handlers through a `CancelToken`; batches and streams stop early when it fires.
+use crate::arithmetic::{self, ArithmeticError};
+use crate::auth::{AuthError, Authenticator, SessionAuth};
+use crate::inflight::InFlight;
+use crate::outbound::Outbound;
+use crate::blob::{BlobError, BlobStore};
+use crate::message::client_message::Message as ClientMessageType;
+use crate::message::server_message;
+use crate::message::{AddResponse, ArithmeticOperation, ArithmeticResponse, ClientMessage, ErrorCode, ServerMessage};
+use crate::message::{AuthChallengeResponse, Authenticate, AuthenticateResponse, BatchRequest, BatchResponse};
+use crate::message::{DownloadBeginResponse, DownloadChunkResponse, UploadBeginResponse, UploadChunkResponse, UploadCommitResponse};
+use crate::message::{stream_item, CancelResponse, CountTo, StreamEnd, StreamItem, StreamStatus};
+use crate::message::{CompareAndSwapResponse, DeleteResponse, GetResponse, ListKeysResponse, PutResponse};
+use crate::message::{Pong, PublishResponse, SubscribeResponse, UnsubscribeResponse};
+use crate::policy::Policy;
+use crate::pubsub::{self, TopicRegistry};
+use crate::store::{KeyValueAccess, KeyValueStore, StoreError, SwapOutcome};
+use crate::stream::{ActiveStreams, CancelToken, CountRange, Interrupt};
+use log::{error, info, warn};
+use rayon::prelude::*;
+use std::{
+    sync::Arc,
+    thread,
+    time::{Duration, Instant},
+};
+
+/// Builds a `ServerMessage` carrying an `Error` reply
+pub fn error_message(code: ErrorCode, message: impl Into<String>) -> ServerMessage {
+    reply(server_message::Message::Error(crate::message::Error {
+        code: code as i32,
+        message: message.into(),
+    }))
+}
+
+/// Wraps a reply in a `ServerMessage`, the request id is filled in by `Handler::handle`
+pub fn reply(message: server_message::Message) -> ServerMessage {
+    ServerMessage {
+        request_id: 0,
+        message: Some(message),
+    }
+}
+
+/// Builds the error reply for a request stopped before it finished
+pub fn interrupt_message(interrupt: Interrupt) -> ServerMessage {
+    let code = match interrupt {
+        Interrupt::Cancelled => ErrorCode::Cancelled,
+        Interrupt::DeadlineExceeded => ErrorCode::DeadlineExceeded,
+    };
+    error_message(code, interrupt.to_string())
+}
+
+// Maps a failed arithmetic request to the error reply sent to the client
+fn arithmetic_error_message(error: ArithmeticError) -> ServerMessage {
+    let code = match error {
+        ArithmeticError::DivisionByZero => ErrorCode::DivisionByZero,
+        ArithmeticError::Overflow => ErrorCode::Overflow,
+        ArithmeticError::MissingOperand
+        | ArithmeticError::UnknownOperation(_)
+        | ArithmeticError::NegativeExponent(_)
+        | ArithmeticError::NotANumber => ErrorCode::InvalidArgument,
+    };
+    error_message(code, error.to_string())
+}
+
+// Maps a rejected store request to the error reply sent to the client
+fn store_error_message(error: StoreError) -> ServerMessage {
+    let code = match error {
+        StoreError::EmptyKey => ErrorCode::InvalidArgument,
+        StoreError::Persistence(_) => ErrorCode::Internal,
+    };
+    error_message(code, error.to_string())
+}
+
+// Maps a rejected transfer request to the error reply sent to the client
+fn blob_error_message(error: BlobError) -> ServerMessage {
+    let code = match error {
+        BlobError::InvalidName(_)
+        | BlobError::InvalidDigest
+        | BlobError::WrongOffset { .. }
+        | BlobError::OutOfRange { .. }
+        | BlobError::Incomplete { .. } => ErrorCode::InvalidArgument,
+        BlobError::QuotaExceeded { .. } => ErrorCode::QuotaExceeded,
+        BlobError::UnknownUpload(_) | BlobError::NotFound(_) => ErrorCode::NotFound,
+        BlobError::ChecksumMismatch => ErrorCode::ChecksumMismatch,
+        BlobError::Committing(_) => ErrorCode::Aborted,
+        BlobError::Io(_) => ErrorCode::Internal,
+    };
+    error_message(code, error.to_string())
+}
+
+// Maps a rejected authentication attempt to the error reply sent to the client
+fn auth_error_message(error: AuthError) -> ServerMessage {
+    match error {
+        AuthError::AlreadyAuthenticated(_) => error_message(ErrorCode::InvalidArgument, error.to_string()),
+        AuthError::NoChallenge | AuthError::TooManyAttempts => error_message(ErrorCode::Unauthenticated, error.to_string()),
+        // Which part was wrong is only logged, it would help someone guessing
+        AuthError::UnknownIdentity(_) | AuthError::WrongMethod | AuthError::InvalidCredential => {
+            error_message(ErrorCode::Unauthenticated, "authentication failed")
+        }
+    }
+}
+
+// How a request interacts with shared state, decides how batches may run it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum RequestKind {
+    Pure,       // Only looks at its own fields
+    StoreRead,  // Reads the key-value store
+    StoreWrite, // Mutates the key-value store
+    Deferred,   // Changes subscriptions, delivers publications or cancels streams, cannot be undone
+    Stream,     // Answered by many frames, needs a request of its own
+    Transfer,   // Reads or writes transferred files, which have no transactions
+    Auth,       // Authenticates the connection, needs a request of its own
+    Batch,      // Batches do not nest
+}
+
+fn request_kind(message: &ClientMessageType) -> RequestKind {
+    match message {
+        ClientMessageType::EchoMessage(_)
+        | ClientMessageType::AddRequest(_)
+        | ClientMessageType::ArithmeticRequest(_)
+        | ClientMessageType::Hello(_)
+        | ClientMessageType::Ping(_) => RequestKind::Pure,
+        ClientMessageType::Get(_) | ClientMessageType::ListKeys(_) => RequestKind::StoreRead,
+        ClientMessageType::Put(_) | ClientMessageType::Delete(_) | ClientMessageType::CompareAndSwap(_) => {
+            RequestKind::StoreWrite
+        }
+        ClientMessageType::Subscribe(_)
+        | ClientMessageType::Unsubscribe(_)
+        | ClientMessageType::Publish(_)
+        | ClientMessageType::Cancel(_) => RequestKind::Deferred,
+        ClientMessageType::CountTo(_) => RequestKind::Stream,
+        ClientMessageType::UploadBegin(_)
+        | ClientMessageType::UploadChunk(_)
+        | ClientMessageType::UploadCommit(_)
+        | ClientMessageType::DownloadBegin(_)
+        | ClientMessageType::DownloadChunk(_) => RequestKind::Transfer,
+        ClientMessageType::AuthChallenge(_) | ClientMessageType::Authenticate(_) => RequestKind::Auth,
+        ClientMessageType::BatchRequest(_) => RequestKind::Batch,
+    }
+}
+
+/// True if `message` changes nothing, so it may be stopped and answered with an error while it runs.
+///
+/// Writes, pub/sub, uploads, authentication and batches holding any of them
+/// must be left to produce their own reply once started, or a write that went
+/// through could be reported as failed.
+pub fn is_preemptible(message: &ClientMessageType) -> bool {
+    match message {
+        ClientMessageType::BatchRequest(batch) => batch.requests.iter().all(|request| request.message.as_ref().is_none_or(is_preemptible)),
+        ClientMessageType::DownloadBegin(_) | ClientMessageType::DownloadChunk(_) => true,
+        message => matches!(request_kind(message), RequestKind::Pure | RequestKind::StoreRead | RequestKind::Stream),
+    }
+}
+
+/// True if `message` is answered by a stream of frames rather than one reply
+pub fn is_stream(message: &ClientMessageType) -> bool {
+    request_kind(message) == RequestKind::Stream
+}
+
+// True if the reply means the request did not take effect
+fn is_failure(response: &ServerMessage) -> bool {
+    matches!(
+        &response.message,
+        Some(server_message::Message::Error(_))
+            | Some(server_message::Message::CompareAndSwapResponse(CompareAndSwapResponse { swapped: false, .. }))
+    )
+}
+
+// Runs a store request against the live store or an open transaction
+fn store_request(store: &mut impl KeyValueAccess, message: ClientMessageType) -> ServerMessage {
+    let response = match message {
+        ClientMessageType::Get(get) => {
+            info!("Received Get: key={}", get.key);
+            store.get(&get.key).map(|entry| {
+                let found = entry.is_some();
+                let (value, version) = entry.map_or((Vec::new(), 0), |entry| (entry.value, entry.version));
+                server_message::Message::GetResponse(GetResponse {
+                    key: get.key,
+                    found,
+                    value,
+                    version,
+                })
+            })
+        }
+        ClientMessageType::Put(put) => {
+            info!("Received Put: key={}, {} bytes", put.key, put.value.len());
+            store
+                .put(&put.key, put.value)
+                .map(|version| server_message::Message::PutResponse(PutResponse { key: put.key, version }))
+        }
+        ClientMessageType::Delete(delete) => {
+            info!("Received Delete: key={}", delete.key);
+            store
+                .delete(&delete.key)
+                .map(|deleted| server_message::Message::DeleteResponse(DeleteResponse { key: delete.key, deleted }))
+        }
+        ClientMessageType::CompareAndSwap(cas) => {
+            info!("Received CompareAndSwap: key={}, expected_version={}", cas.key, cas.expected_version);
+            store.compare_and_swap(&cas.key, cas.expected_version, cas.value).map(|outcome| {
+                let (swapped, version) = match outcome {
+                    SwapOutcome::Swapped(version) => (true, version),
+                    SwapOutcome::Conflict(version) => (false, version),
+                };
+                server_message::Message::CompareAndSwapResponse(CompareAndSwapResponse {
+                    key: cas.key,
+                    swapped,
+                    version,
+                })
+            })
+        }
+        ClientMessageType::ListKeys(list_keys) => {
+            info!("Received ListKeys: prefix={}, limit={}", list_keys.prefix, list_keys.limit);
+            let keys = store.list_keys(&list_keys.prefix, list_keys.limit as usize);
+            Ok(server_message::Message::ListKeysResponse(ListKeysResponse { keys }))
+        }
+        other => unreachable!("{:?} is not a store request", other),
+    };
+
+    match response {
+        Ok(message) => reply(message),
+        Err(e) => store_error_message(e),
+    }
+}
+
+// Runs an upload or download request against the file storage
+fn transfer_request(blobs: &BlobStore, message: ClientMessageType) -> ServerMessage {
+    let response = match message {
+        ClientMessageType::UploadBegin(begin) => {
+            info!("Received UploadBegin: name={}, size={}", begin.name, begin.size);
+            blobs.begin_upload(&begin.name, begin.size, &begin.sha256).map(|(upload_id, offset)| {
+                server_message::Message::UploadBeginResponse(UploadBeginResponse { upload_id, offset })
+            })
+        }
+        ClientMessageType::UploadChunk(chunk) => blobs
+            .write_chunk(&chunk.upload_id, chunk.offset, &chunk.data)
+            .map(|offset| {
+                server_message::Message::UploadChunkResponse(UploadChunkResponse {
+                    upload_id: chunk.upload_id,
+                    offset,
+                })
+            }),
+        ClientMessageType::UploadCommit(commit) => {
+            info!("Received UploadCommit: upload_id={}", commit.upload_id);
+            blobs
+                .commit_upload(&commit.upload_id)
+                .map(|(name, size)| server_message::Message::UploadCommitResponse(UploadCommitResponse { name, size }))
+        }
+        ClientMessageType::DownloadBegin(begin) => {
+            info!("Received DownloadBegin: name={}", begin.name);
+            blobs.describe(&begin.name).map(|(size, sha256)| {
+                server_message::Message::DownloadBeginResponse(DownloadBeginResponse {
+                    name: begin.name,
+                    size,
+                    sha256,
+                })
+            })
+        }
+        ClientMessageType::DownloadChunk(chunk) => blobs
+            .read_chunk(&chunk.name, chunk.offset, chunk.length as usize)
+            .map(|data| {
+                server_message::Message::DownloadChunkResponse(DownloadChunkResponse {
+                    name: chunk.name,
+                    offset: chunk.offset,
+                    data,
+                })
+            }),
+        other => unreachable!("{:?} is not a transfer request", other),
+    };
+
+    match response {
+        Ok(message) => reply(message),
+        Err(e) => blob_error_message(e),
+    }
+}
+
+// Why an all-or-nothing batch was rolled back
+enum Abort {
+    Item(usize, ServerMessage), // The item at this index failed with this reply
+    Store(StoreError),          // The staged writes could not be committed
+}
+
+impl From<StoreError> for Abort {
+    fn from(error: StoreError) -> Self {
+        Abort::Store(error)
+    }
+}
+
+/// The connection a request arrived on
+pub struct Session {
+    /// Unique connection id, used as the key for per-connection state
+    pub id: u64,
+    /// Queue of messages to send to this connection, also used for pushed publications and stream frames
+    pub outbound: Outbound,
+    /// Streams started by this connection that are still running
+    pub streams: Arc<ActiveStreams>,
+    /// Requests read from this connection and not answered yet, only tracked by framed TCP connections
+    pub requests: Arc<InFlight>,
+    /// Who the connection authenticated as
+    pub auth: SessionAuth,
+}
+
+impl Session {
+    /// Creates an unauthenticated session with no running streams
+    pub fn new(id: u64, outbound: Outbound) -> Self {
+        Session {
+            id,
+            outbound,
+            streams: Arc::new(ActiveStreams::new()),
+            requests: Arc::new(InFlight::new()),
+            auth: SessionAuth::new(),
+        }
+    }
+}
+
+/// Request handlers and the state they share across connections
+pub struct Handler {
+    topics: Arc<TopicRegistry>,     // Pub/sub subscriptions
+    store: Arc<KeyValueStore>,      // Key-value data
+    blobs: Option<Arc<BlobStore>>,  // Transferred files, `None` when file transfer is disabled
+    authenticator: Option<Arc<dyn Authenticator>>, // Checks credentials, `None` lets everyone in
+    policy: Option<Arc<Policy>>,    // What each identity may do, `None` allows everything
+}
+
+impl Handler {
+    /// Creates handlers over the given shared state
+    pub fn new(topics: Arc<TopicRegistry>, store: Arc<KeyValueStore>) -> Self {
+        Handler {
+            topics,
+            store,
+            blobs: None,
+            authenticator: None,
+            policy: None,
+        }
+    }
+
+    /// Enables file transfer requests, storing files in `blobs`
+    pub fn with_blobs(mut self, blobs: Arc<BlobStore>) -> Self {
+        self.blobs = Some(blobs);
+        self
+    }
+
+    /// Requires every connection to authenticate with `authenticator` before other requests
+    pub fn with_authenticator(mut self, authenticator: Arc<dyn Authenticator>) -> Self {
+        self.authenticator = Some(authenticator);
+        self
+    }
+
+    /// Only lets connections perform what `policy` allows their identity
+    pub fn with_policy(mut self, policy: Arc<Policy>) -> Self {
+        self.policy = Some(policy);
+        self
+    }
+
+    /// Returns the topic registry used to route publications
+    pub fn topics(&self) -> &Arc<TopicRegistry> {
+        &self.topics
+    }
+
+    /// Returns the key-value store shared by all connections
+    pub fn store(&self) -> &Arc<KeyValueStore> {
+        &self.store
+    }
+
+    /// Returns the file storage, `None` if file transfer is disabled
+    pub fn blobs(&self) -> Option<&Arc<BlobStore>> {
+        self.blobs.as_ref()
+    }
+
+    /// Releases everything held on behalf of a closed connection
+    pub fn disconnect(&self, session: &Session) {
+        self.topics.remove_connection(session.id);
+        session.streams.cancel_all();
+    }
+
+    /// Handles one decoded request and returns its reply tagged with the request id.
+    ///
+    /// Returns `None` when there is nothing to send right away: for an empty
+    /// message, or for a stream whose frames are pushed to `session.outbound`.
+    /// The request's `deadline_ms` counts from now.
+    pub fn handle(&self, session: &Session, request: ClientMessage) -> Option<ServerMessage> {
+        let deadline = (request.deadline_ms > 0).then(|| Instant::now() + Duration::from_millis(request.deadline_ms.into()));
+        self.handle_interruptible(session, request, &CancelToken::with_deadline(deadline))
+    }
+
+    /// Like `handle`, stopping early when `token` is cancelled or its deadline passes.
+    ///
+    /// The reply of a preemptible request that comes too late is replaced by the
+    /// matching error, and so is that of an atomic batch rolled back because of
+    /// the token. Any other reply is kept, since what it reports has happened.
+    pub fn handle_interruptible(&self, session: &Session, request: ClientMessage, token: &CancelToken) -> Option<ServerMessage> {
+        let request_id = request.request_id;
+        let preemptible = request.message.as_ref().is_some_and(is_preemptible);
+        let mut response = match request.message {
+            Some(message) if !self.may_send(session, &message) => {
+                warn!("Connection {} sent a request before authenticating", session.id);
+                error_message(ErrorCode::Unauthenticated, "authenticate before sending other requests")
+            }
+            Some(message) => match self.authorize(session, &message) {
+                Err(denied) => denied,
+                Ok(()) => match message {
+                    ClientMessageType::AuthChallenge(_) => self.challenge(session),
+                    ClientMessageType::Authenticate(authenticate) => self.authenticate(session, authenticate),
+                    ClientMessageType::CountTo(count_to) => self.count_to(session, request_id, count_to, token.deadline())?,
+                    ClientMessageType::BatchRequest(batch) => self.batch(session, batch, token),
+                    message => self.dispatch(session, message),
+                },
+            },
+            None => {
+                error!("Received an empty or unsupported ClientMessage."); // Log error if no valid message
+                return None;
+            }
+        };
+        let rolled_back = matches!(&response.message, Some(server_message::Message::BatchResponse(batch)) if !batch.committed);
+        if let Some(interrupt) = token.interrupted().filter(|_| preemptible || rolled_back) {
+            warn!("Connection {} request {} stopped: {}", session.id, request_id, interrupt);
+            response = interrupt_message(interrupt);
+        }
+        response.request_id = request_id;
+        Some(response)
+    }
+
+    /// Produces the reply for a single request
+    pub fn dispatch(&self, session: &Session, message: ClientMessageType) -> ServerMessage {
+        match message {
+            ClientMessageType::EchoMessage(echo_message) => {
+                info!("Received EchoMessage: {}", echo_message.content); // Log EchoMessage content
+                reply(server_message::Message::EchoMessage(echo_message))
+            }
+            ClientMessageType::AddRequest(add_request) => {
+                info!("Received AddRequest: a={}, b={}", add_request.a, add_request.b); // Log AddRequest
+                // Alias for an int ADD, the int32 result must still fit the legacy response
+                let sum = arithmetic::evaluate_int(ArithmeticOperation::Add, add_request.a.into(), add_request.b.into())
+                    .and_then(|sum| i32::try_from(sum).map_err(|_| ArithmeticError::Overflow));
+                match sum {
+                    Ok(result) => reply(server_message::Message::AddResponse(AddResponse { result })),
+                    Err(e) => arithmetic_error_message(e),
+                }
+            }
+            ClientMessageType::ArithmeticRequest(request) => {
+                info!("Received ArithmeticRequest: {:?}", request);
+                match arithmetic::evaluate_raw(request.operation, request.lhs, request.rhs) {
+                    Ok(result) => reply(server_message::Message::ArithmeticResponse(ArithmeticResponse {
+                        result: Some(result.into()),
+                    })),
+                    Err(e) => arithmetic_error_message(e),
+                }
+            }
+            ClientMessageType::Subscribe(subscribe) => {
+                info!("Connection {} subscribing to '{}'", session.id, subscribe.pattern);
+                match self.topics.subscribe(session.id, &subscribe.pattern, &session.outbound) {
+                    Ok(created) => reply(server_message::Message::SubscribeResponse(SubscribeResponse {
+                        pattern: subscribe.pattern,
+                        created,
+                    })),
+                    Err(e) => error_message(ErrorCode::InvalidArgument, e.to_string()),
+                }
+            }
+            ClientMessageType::Unsubscribe(unsubscribe) => {
+                info!("Connection {} unsubscribing from '{}'", session.id, unsubscribe.pattern);
+                let removed = self.topics.unsubscribe(session.id, &unsubscribe.pattern);
+                reply(server_message::Message::UnsubscribeResponse(UnsubscribeResponse {
+                    pattern: unsubscribe.pattern,
+                    removed,
+                }))
+            }
+            ClientMessageType::Publish(publish) => {
+                info!("Connection {} publishing {} bytes to '{}'", session.id, publish.payload.len(), publish.topic);
+                match self.topics.publish(&publish.topic, &publish.payload) {
+                    Ok(delivered) => reply(server_message::Message::PublishResponse(PublishResponse { delivered })),
+                    Err(e) => error_message(ErrorCode::InvalidArgument, e.to_string()),
+                }
+            }
+            message @ (ClientMessageType::Get(_)
+            | ClientMessageType::Put(_)
+            | ClientMessageType::Delete(_)
+            | ClientMessageType::CompareAndSwap(_)
+            | ClientMessageType::ListKeys(_)) => store_request(&mut &*self.store, message),
+            ClientMessageType::Cancel(cancel) => {
+                info!("Connection {} cancelling request {}", session.id, cancel.request_id);
+                let cancelled = session.streams.cancel(cancel.request_id)
+                    || session.requests.cancel(cancel.request_id, &session.outbound);
+                reply(server_message::Message::CancelResponse(CancelResponse {
+                    request_id: cancel.request_id,
+                    cancelled,
+                }))
+            }
+            ClientMessageType::CountTo(_) => {
+                error_message(ErrorCode::InvalidArgument, "streaming requests must be sent on their own")
+            }
+            message @ (ClientMessageType::UploadBegin(_)
+            | ClientMessageType::UploadChunk(_)
+            | ClientMessageType::UploadCommit(_)
+            | ClientMessageType::DownloadBegin(_)
+            | ClientMessageType::DownloadChunk(_)) => match &self.blobs {
+                Some(blobs) => transfer_request(blobs, message),
+                None => error_message(ErrorCode::Unavailable, "file transfer is not enabled on this server"),
+            },
+            ClientMessageType::Hello(_) => {
+                error_message(ErrorCode::InvalidArgument, "Hello must be the first message on a connection")
+            }
+            ClientMessageType::AuthChallenge(_) | ClientMessageType::Authenticate(_) => {
+                error_message(ErrorCode::InvalidArgument, "authentication requests must be sent on their own")
+            }
+            ClientMessageType::BatchRequest(batch) => self.batch(session, batch, &CancelToken::new()),
+            ClientMessageType::Ping(_) => reply(server_message::Message::Pong(Pong {})),
+        }
+    }
+
+    // True if the connection may send `message` in its current authentication state
+    fn may_send(&self, session: &Session, message: &ClientMessageType) -> bool {
+        self.authenticator.is_none()
+            || matches!(
+                message,
+                ClientMessageType::Hello(_)
+                    | ClientMessageType::AuthChallenge(_)
+                    | ClientMessageType::Authenticate(_)
+                    | ClientMessageType::Ping(_)
+            )
+            || session.auth.identity().is_some()
+    }
+
+    // Checks the request, and every item of a batch, against the policy.
+    // Denials are written to the audit log.
+    fn authorize(&self, session: &Session, message: &ClientMessageType) -> Result<(), ServerMessage> {
+        let Some(policy) = &self.policy else {
+            return Ok(());
+        };
+        let upload_name = |id: &str| self.blobs.as_ref().and_then(|blobs| blobs.upload_name(id));
+        policy.check_with(session.auth.identity().as_deref(), message, &upload_name).map_err(|denial| {
+            warn!(target: "audit", "Connection {} denied: {}", session.id, denial);
+            error_message(ErrorCode::PermissionDenied, denial.to_string())
+        })
+    }
+
+    fn challenge(&self, session: &Session) -> ServerMessage {
+        if self.authenticator.is_none() {
+            return error_message(ErrorCode::Unavailable, "authentication is not enabled on this server");
+        }
+        match session.auth.challenge() {
+            Ok(nonce) => reply(server_message::Message::AuthChallengeResponse(AuthChallengeResponse { nonce })),
+            Err(e) => {
+                error!("Failed to generate a nonce: {}", e);
+                error_message(ErrorCode::Internal, "could not generate a challenge")
+            }
+        }
+    }
+
+    fn authenticate(&self, session: &Session, request: Authenticate) -> ServerMessage {
+        let Some(authenticator) = &self.authenticator else {
+            return error_message(ErrorCode::Unavailable, "authentication is not enabled on this server");
+        };
+        match session.auth.authenticate(authenticator.as_ref(), &request) {
+            Ok(identity) => {
+                info!("Connection {} authenticated as '{}'", session.id, identity);
+                reply(server_message::Message::AuthenticateResponse(AuthenticateResponse { identity }))
+            }
+            Err(e) => {
+                warn!("Connection {} failed to authenticate as '{}': {}", session.id, request.identity, e);
+                auth_error_message(e)
+            }
+        }
+    }
+
+    // Starts a CountTo stream, returns an error reply if it cannot be started
+    fn count_to(&self, session: &Session, request_id: u64, request: CountTo, deadline: Option<Instant>) -> Option<ServerMessage> {
+        info!("Received CountTo: {:?}, request_id={}", request, request_id);
+        let range = match CountRange::new(&request) {
+            Ok(range) => range,
+            Err(e) => return Some(error_message(ErrorCode::InvalidArgument, e.to_string())),
+        };
+        let interval = Duration::from_millis(request.interval_ms.into());
+        let values = range.map(stream_item::Value::Number);
+        self.start_stream(session, request_id, values, interval, deadline)
+    }
+
+    // Registers a stream and sends its items from a new thread, `interval` apart, until `deadline`.
+    // Returns an error reply if the stream cannot be started.
+    fn start_stream<I>(
+        &self,
+        session: &Session,
+        request_id: u64,
+        values: I,
+        interval: Duration,
+        deadline: Option<Instant>,
+    ) -> Option<ServerMessage>
+    where
+        I: Iterator<Item = stream_item::Value> + Send + 'static,
+    {
+        // Frames are matched to the stream by id, 0 would be indistinguishable from untagged messages
+        if request_id == 0 {
+            return Some(error_message(ErrorCode::InvalidArgument, "streaming requests need a non-zero request_id"));
+        }
+        let Some(token) = session.streams.start(request_id, deadline) else {
+            return Some(error_message(
+                ErrorCode::InvalidArgument,
+                format!("request_id {} is already streaming", request_id),
+            ));
+        };
+
+        let outbound = session.outbound.clone();
+        let streams = session.streams.clone();
+        thread::spawn(move || {
+            let (items, status) = run_stream(&token, &outbound, request_id, values, interval);
+            streams.finish(request_id); // The id may be reused as soon as the end frame arrives
+            let end = server_message::Message::StreamEnd(StreamEnd { items, status: status as i32 });
+            let _ = outbound.send(ServerMessage { request_id, ..reply(end) }); // Connection may be gone already
+            info!("Stream {} ended after {} items: {:?}", request_id, items, status);
+        });
+        None
+    }
+
+    // Handles a batch item, which unlike a top-level request may be empty or another batch.
+    // Once `token` fires the remaining items are not run.
+    fn dispatch_item(&self, session: &Session, request: ClientMessage, token: &CancelToken) -> ServerMessage {
+        if let Some(interrupt) = token.interrupted() {
+            return interrupt_message(interrupt);
+        }
+        match request.message {
+            Some(ClientMessageType::BatchRequest(_)) => {
+                error_message(ErrorCode::InvalidArgument, "batches cannot be nested")
+            }
+            Some(message) => self.dispatch(session, message),
+            None => error_message(ErrorCode::InvalidArgument, "empty request in batch"),
+        }
+    }
+
+    fn batch(&self, session: &Session, batch: BatchRequest, token: &CancelToken) -> ServerMessage {
+        info!("Received BatchRequest: {} requests, atomic={}", batch.requests.len(), batch.atomic);
+        let response = if batch.atomic {
+            self.atomic_batch(session, batch.requests, token)
+        } else {
+            BatchResponse {
+                responses: self.independent_batch(session, batch.requests, token),
+                committed: true,
+            }
+        };
+        reply(server_message::Message::BatchResponse(response))
+    }
+
+    // Every item runs on its own, a failure does not affect the others
+    fn independent_batch(&self, session: &Session, requests: Vec<ClientMessage>, token: &CancelToken) -> Vec<ServerMessage> {
+        // Reads and pure computations cannot observe each other, so their order does not matter
+        let parallel = requests.iter().all(|request| {
+            request.message.as_ref().map(request_kind).is_none_or(|kind| {
+                matches!(kind, RequestKind::Pure | RequestKind::StoreRead)
+            })
+        });
+
+        if parallel {
+            requests
+                .into_par_iter()
+                .map(|request| self.dispatch_item(session, request, token))
+                .collect()
+        } else {
+            requests
+                .into_iter()
+                .map(|request| self.dispatch_item(session, request, token))
+                .collect()
+        }
+    }
+
+    // Either every item takes effect or none does.
+    //
+    // Store requests run in one store transaction. Pub/sub and cancel requests
+    // are only validated inside it and performed once the transaction has
+    // committed, since a delivered publication cannot be taken back. A batch
+    // stopped by `token` before its last item is rolled back.
+    fn atomic_batch(&self, session: &Session, requests: Vec<ClientMessage>, token: &CancelToken) -> BatchResponse {
+        let count = requests.len();
+        let outcome = self.store.transaction(|transaction| {
+            let mut responses = Vec::with_capacity(count);
+            let mut deferred = Vec::new();
+
+            for (index, request) in requests.into_iter().enumerate() {
+                if let Some(interrupt) = token.interrupted() {
+                    return Err(Abort::Item(index, interrupt_message(interrupt)));
+                }
+                let response = match request.message {
+                    Some(message) => match request_kind(&message) {
+                        RequestKind::Pure => self.dispatch(session, message),
+                        RequestKind::StoreRead | RequestKind::StoreWrite => store_request(transaction, message),
+                        RequestKind::Deferred => match validate_deferred(&message) {
+                            Ok(()) => {
+                                deferred.push((index, message));
+                                ServerMessage::default() // Filled in after the commit
+                            }
+                            Err(e) => e,
+                        },
+                        RequestKind::Stream | RequestKind::Auth => self.dispatch(session, message), // Rejected, need their own request
+                        RequestKind::Transfer => {
+                            error_message(ErrorCode::InvalidArgument, "file transfers cannot be rolled back")
+                        }
+                        RequestKind::Batch => error_message(ErrorCode::InvalidArgument, "batches cannot be nested"),
+                    },
+                    None => error_message(ErrorCode::InvalidArgument, "empty request in batch"),
+                };
+
+                if is_failure(&response) {
+                    return Err(Abort::Item(index, response));
+                }
+                responses.push(response);
+            }
+            Ok((responses, deferred))
+        });
+
+        match outcome {
+            Ok((mut responses, deferred)) => {
+                for (index, message) in deferred {
+                    responses[index] = self.dispatch(session, message);
+                }
+                BatchResponse { responses, committed: true }
+            }
+            Err(Abort::Item(failed, response)) => {
+                warn!("Atomic batch rolled back, item {} failed", failed);
+                let mut responses: Vec<_> = (0..count)
+                    .map(|_| error_message(ErrorCode::Aborted, format!("batch rolled back, item {} failed", failed)))
+                    .collect();
+                responses[failed] = response;
+                BatchResponse { responses, committed: false }
+            }
+            Err(Abort::Store(e)) => {
+                warn!("Atomic batch could not be committed: {}", e);
+                let responses = (0..count).map(|_| store_error_message(e.clone())).collect();
+                BatchResponse { responses, committed: false }
+            }
+        }
+    }
+}
+
+// Checks a deferred request up front so performing it after a commit cannot fail
+fn validate_deferred(message: &ClientMessageType) -> Result<(), ServerMessage> {
+    let result = match message {
+        ClientMessageType::Subscribe(subscribe) => pubsub::validate_pattern(&subscribe.pattern),
+        ClientMessageType::Publish(publish) => pubsub::validate_topic(&publish.topic),
+        _ => Ok(()), // Unsubscribing and cancelling cannot fail
+    };
+    result.map_err(|e| error_message(ErrorCode::InvalidArgument, e.to_string()))
+}
+
+// Sends the stream's items until they run out, the stream is cancelled or out of time, or the connection closes.
+// Returns the number of items sent and how the stream ended.
+fn run_stream(
+    token: &CancelToken,
+    outbound: &Outbound,
+    request_id: u64,
+    values: impl Iterator<Item = stream_item::Value>,
+    interval: Duration,
+) -> (u64, StreamStatus) {
+    let mut items = 0;
+    for value in values {
+        // Sleeping on the token lets a Cancel or the deadline interrupt the interval
+        if items > 0 && !interval.is_zero() {
+            token.wait(interval);
+        }
+        match token.interrupted() {
+            Some(Interrupt::Cancelled) => return (items, StreamStatus::Cancelled),
+            Some(Interrupt::DeadlineExceeded) => return (items, StreamStatus::DeadlineExceeded),
+            None => {}
+        }
+
+        // A client that is not reading holds the stream back, rather than its queue growing
+        let item = server_message::Message::StreamItem(StreamItem { sequence: items, value: Some(value) });
+        if outbound.wait_for_room().is_err() || outbound.send(ServerMessage { request_id, ..reply(item) }).is_err() {
+            return (items, StreamStatus::Cancelled); // Writer thread gone, nobody is listening
+        }
+        items += 1;
+    }
+    (items, StreamStatus::Completed)
+}
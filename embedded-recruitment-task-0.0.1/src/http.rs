@@ -0,0 +1,247 @@
+// HTTP gateway to the request handlers.
+//
+//     POST /echo     {"content":"hi"}                  -> {"content":"hi"}
+//     POST /add      {"a":1,"b":2}                     -> {"result":3}
+//     POST /rpc      any JSON ClientMessage            -> its JSON ServerMessage
+//     GET  /healthz  200 while the process answers
+//     GET  /readyz   200 while `Server::run` accepts work, 503 before and after
+//
+// Bodies use the proto3 JSON mapping of `json`. Every request gets a session
+// of its own and goes through the same `Handler` as TCP connections, so
+// authentication, policy and the audit log apply unchanged. With an
+// authenticator configured, requests carry `Authorization: Bearer
+// <identity>:<token>`, checked as a token `Authenticate` before the request.
+//
+// `/echo` and `/add` answer errors with a matching HTTP status and the JSON
+// `Error` as body. `/rpc` always answers 200 with the `ServerMessage`, errors
+// included, unless the body cannot be read; a stream answers with one JSON
+// `ServerMessage` per line up to and including its `StreamEnd`. Its body is
+// chunked and written as the frames come, so a client that reads slowly holds
+// the stream back instead of making the server buffer it.
+//
+// Requests are answered by a fixed pool of `WORKERS` threads. While all of
+// them are busy, further requests wait.
+use crate::audit::Outcome;
+use crate::auth;
+use crate::handler::{Handler, Session};
+use crate::json::{self, JsonError};
+use crate::message::client_message::Message as ClientMessageType;
+use crate::message::{server_message, AddRequest, Authenticate, ClientMessage, EchoMessage, ErrorCode, ServerMessage};
+use crate::outbound::OutboundReceiver;
+use crate::policy;
+use crate::server::ConnectionOptions;
+use log::{error, warn};
+use serde::Serialize;
+use std::{
+    io::{self, Cursor, Read, Write},
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        mpsc, Arc, Mutex,
+    },
+    thread,
+    time::{Instant, SystemTime},
+};
+use tiny_http::{Header, HTTPVersion, Method, Request, Response};
+
+// Requests answered at the same time
+const WORKERS: usize = 16;
+
+/// HTTP status for a reply carrying an `Error` of `code`
+pub fn status_code(code: ErrorCode) -> u16 {
+    match code {
+        ErrorCode::InvalidArgument | ErrorCode::DivisionByZero | ErrorCode::Overflow | ErrorCode::ChecksumMismatch => 400,
+        ErrorCode::Unauthenticated => 401,
+        ErrorCode::PermissionDenied => 403,
+        ErrorCode::NotFound => 404,
+        ErrorCode::Aborted => 409,
+        ErrorCode::QuotaExceeded => 429,
+        ErrorCode::Unavailable => 503,
+        ErrorCode::DeadlineExceeded => 504,
+        ErrorCode::Cancelled => 499, // Client closed request, as nginx reports it
+        ErrorCode::Unknown | ErrorCode::Internal => 500,
+    }
+}
+
+fn respond_with(status: u16, content_type: &str, body: String) -> Response<Cursor<Vec<u8>>> {
+    let header = Header::from_bytes("Content-Type", content_type).expect("static header is valid");
+    Response::from_string(body).with_status_code(status).with_header(header)
+}
+
+fn json_response(status: u16, body: &impl Serialize) -> Response<Cursor<Vec<u8>>> {
+    let body = serde_json::to_string(body).expect("generated serializers do not fail");
+    respond_with(status, "application/json", body)
+}
+
+fn error_response(code: ErrorCode, message: impl Into<String>) -> Response<Cursor<Vec<u8>>> {
+    let error = crate::message::Error {
+        code: code as i32,
+        message: message.into(),
+    };
+    json_response(status_code(code), &error)
+}
+
+// Reads the body as JSON of `T`
+fn read_json<T: serde::de::DeserializeOwned>(request: &mut Request) -> Result<T, String> {
+    let mut body = String::new();
+    let limit = json::MAX_LINE_LEN as u64 + 1;
+    request.as_reader().take(limit).read_to_string(&mut body).map_err(|e| e.to_string())?;
+    if body.len() > json::MAX_LINE_LEN {
+        return Err(format!("bodies are limited to {} bytes", json::MAX_LINE_LEN));
+    }
+    serde_json::from_str(&body).map_err(|e| JsonError::from(e).to_string())
+}
+
+// Credentials from `Authorization: Bearer <identity>:<token>`, if sent
+fn bearer(request: &Request) -> Option<Authenticate> {
+    let header = request.headers().iter().find(|header| header.field.equiv("Authorization"))?;
+    auth::parse_bearer(header.value.as_str())
+}
+
+/// Answers HTTP requests on `WORKERS` threads until `http` is unblocked
+pub(crate) fn serve(http: Arc<tiny_http::Server>, handler: Arc<Handler>, options: ConnectionOptions, ids: Arc<AtomicU64>, ready: Arc<AtomicBool>) {
+    // Hands each request to an idle worker, waiting for one if all are busy
+    let (queue, requests) = mpsc::sync_channel::<(Request, u64)>(0);
+    let requests = Arc::new(Mutex::new(requests));
+    for _ in 0..WORKERS {
+        let (requests, handler, options, ready) = (requests.clone(), handler.clone(), options.clone(), ready.clone());
+        thread::spawn(move || loop {
+            let next = requests.lock().unwrap().recv();
+            let Ok((request, id)) = next else {
+                return; // The gateway was unblocked
+            };
+            respond(request, id, &handler, &options, ready.load(Ordering::SeqCst));
+        });
+    }
+    for request in http.incoming_requests() {
+        let id = ids.fetch_add(1, Ordering::Relaxed); // HTTP requests draw from the connection ids
+        if queue.send((request, id)).is_err() {
+            break;
+        }
+    }
+}
+
+// Answers one HTTP request on a session of its own.
+// `ready` is whether the server accepts work, reported by `/readyz`.
+fn respond(mut request: Request, id: u64, handler: &Handler, options: &ConnectionOptions, ready: bool) {
+    let method = request.method().clone();
+    let path = request.url().split('?').next().unwrap_or_default().to_string();
+    let mut session = None;
+    let reply = match (&method, path.as_str()) {
+        (Method::Get, "/healthz") => Reply::Full(respond_with(200, "text/plain", "ok\n".to_string())),
+        (Method::Get, "/readyz") if ready => Reply::Full(respond_with(200, "text/plain", "ready\n".to_string())),
+        (Method::Get, "/readyz") => Reply::Full(respond_with(503, "text/plain", "not ready\n".to_string())),
+        (Method::Post, "/echo" | "/add" | "/rpc") => {
+            let (outbound, queue) = options.outbound();
+            gateway(&mut request, &path, handler, options, session.insert(Session::new(id, outbound)), queue)
+        }
+        (_, "/echo" | "/add" | "/rpc" | "/healthz" | "/readyz") => Reply::Full(
+            error_response(ErrorCode::InvalidArgument, format!("{} does not take {}", path, method)).with_status_code(405),
+        ),
+        _ => Reply::Full(error_response(ErrorCode::NotFound, format!("no such endpoint: {}", path))),
+    };
+    let sent = match reply {
+        Reply::Full(response) => request.respond(response),
+        Reply::Stream(request_id, queue) => {
+            let version = request.http_version().clone();
+            write_stream(request.into_writer(), version, request_id, &queue)
+        }
+    };
+    if let Err(e) = sent {
+        warn!("Failed to answer HTTP request {} {}: {}", method, path, e);
+    }
+    if let Some(session) = session {
+        handler.disconnect(&session); // Ends whatever the request left behind, such as a stream the client stopped reading
+    }
+}
+
+// What a request is answered with
+enum Reply {
+    Full(Response<Cursor<Vec<u8>>>),
+    Stream(u64, OutboundReceiver), // The frames of the stream started by this request id
+}
+
+// Runs a POST to one of the gateway endpoints through the handler
+fn gateway(
+    request: &mut Request,
+    path: &str,
+    handler: &Handler,
+    options: &ConnectionOptions,
+    session: &Session,
+    queue: OutboundReceiver,
+) -> Reply {
+    let peer = request.remote_addr().copied();
+    let arrived = (SystemTime::now(), Instant::now());
+    // Handles one request and records it, like a request on a connection
+    let call = |message: ClientMessage| -> Option<ServerMessage> {
+        let request_id = message.request_id;
+        let operation = message.message.as_ref().map_or("empty", policy::message_name);
+        let response = handler.handle(session, message);
+        options.audit(session, peer, request_id, operation, Outcome::of(response.as_ref()), arrived);
+        response
+    };
+    let single = |message: ClientMessageType| call(ClientMessage { request_id: 0, message: Some(message), deadline_ms: 0 });
+
+    if let Some(credentials) = bearer(request) {
+        if let Some(ServerMessage { message: Some(server_message::Message::Error(error)), .. }) =
+            single(ClientMessageType::Authenticate(credentials))
+        {
+            return Reply::Full(json_response(status_code(error.code()), &error));
+        }
+    }
+
+    let invalid = |e: String| error_response(ErrorCode::InvalidArgument, e);
+    let response = match path {
+        "/echo" => read_json::<EchoMessage>(request).map_or_else(invalid, |echo| unary(single(ClientMessageType::EchoMessage(echo)))),
+        "/add" => read_json::<AddRequest>(request).map_or_else(invalid, |add| unary(single(ClientMessageType::AddRequest(add)))),
+        _ => match read_json::<ClientMessage>(request) {
+            Ok(message) => {
+                let request_id = message.request_id;
+                match call(message) {
+                    Some(response) => json_response(200, &response),
+                    None => return Reply::Stream(request_id, queue),
+                }
+            }
+            Err(e) => invalid(e),
+        },
+    };
+    Reply::Full(response)
+}
+
+// Body of a typed endpoint: the reply's inner message, or its error with a matching status
+fn unary(response: Option<ServerMessage>) -> Response<Cursor<Vec<u8>>> {
+    match response.and_then(|response| response.message) {
+        Some(server_message::Message::EchoMessage(echo)) => json_response(200, &echo),
+        Some(server_message::Message::AddResponse(add)) => json_response(200, &add),
+        Some(server_message::Message::Error(error)) => json_response(status_code(error.code()), &error),
+        other => {
+            error!("Unexpected reply to an HTTP request: {:?}", other);
+            error_response(ErrorCode::Internal, "unexpected reply")
+        }
+    }
+}
+
+// Writes the frames of a stream started by request `request_id` as they come, one JSON line each,
+// up to and including its `StreamEnd`. The body is chunked for HTTP/1.1 and ends with the connection otherwise.
+fn write_stream(mut writer: Box<dyn Write + Send>, version: HTTPVersion, request_id: u64, queue: &OutboundReceiver) -> io::Result<()> {
+    let chunked = version >= HTTPVersion(1, 1);
+    let framing = if chunked { "Transfer-Encoding: chunked" } else { "Connection: close" };
+    write!(writer, "HTTP/{} 200 OK\r\nContent-Type: application/x-ndjson\r\n{}\r\n\r\n", version, framing)?;
+    writer.flush()?;
+    for message in queue.iter().filter(|message| message.request_id == request_id) {
+        let mut line = json::encode_server_message(&message);
+        line.push('\n');
+        if chunked {
+            write!(writer, "{:x}\r\n{}\r\n", line.len(), line)?;
+        } else {
+            writer.write_all(line.as_bytes())?;
+        }
+        writer.flush()?; // Each frame goes out as it arrives, and a slow client holds the stream back
+        if matches!(message.message, Some(server_message::Message::StreamEnd(_))) {
+            break;
+        }
+    }
+    if chunked {
+        writer.write_all(b"0\r\n\r\n")?;
+    }
+    writer.flush()
+}
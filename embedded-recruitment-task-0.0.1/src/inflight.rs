@@ -0,0 +1,162 @@
+// Requests a framed connection has read but not answered yet.
+//
+// A framed TCP connection reads requests on one thread and runs them in
+// arrival order on another, so it keeps reading while a request runs. Each
+// request is registered here with its deadline. A `Cancel` naming it, or its
+// deadline passing, answers it at once with `ERROR_CODE_CANCELLED` or
+// `ERROR_CODE_DEADLINE_EXCEEDED`. A queued request is then skipped, and a
+// running one is told through its `CancelToken`; its reply is dropped if it
+// finishes anyway. Whoever answers first claims the request, so it gets
+// exactly one reply.
+//
+// A request that changes something is only answered early while it is still
+// queued. Once it starts it is protected: its token is still signalled, but
+// only the request itself decides what its reply is. Otherwise a write that
+// went through could be reported as failed, and a retry would apply it twice.
+use crate::handler;
+use crate::outbound::Outbound;
+use crate::stream::{CancelToken, Interrupt};
+use std::{
+    sync::{
+        atomic::{AtomicU8, Ordering},
+        Arc, Condvar, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+/// A request read from a connection and not answered yet
+#[derive(Debug)]
+pub struct PendingRequest {
+    pub request_id: u64,
+    /// Cancelled by a `Cancel`, and carrying the request's deadline
+    pub token: CancelToken,
+    state: AtomicU8, // One of the states below
+}
+
+const QUEUED: u8 = 0; // May be answered early
+const PROTECTED: u8 = 1; // Running a request that changes something, only its own reply counts
+const ANSWERED: u8 = 2;
+
+impl PendingRequest {
+    /// Starts a request that must not be answered early once it runs.
+    /// Returns false if it was already answered, in which case it must not run.
+    pub fn protect(&self) -> bool {
+        self.state.compare_exchange(QUEUED, PROTECTED, Ordering::SeqCst, Ordering::SeqCst).is_ok()
+    }
+
+    /// Claims the right to answer the request, returns false if someone already did
+    pub fn claim(&self) -> bool {
+        self.state.swap(ANSWERED, Ordering::SeqCst) != ANSWERED
+    }
+
+    fn is_queued(&self) -> bool {
+        self.state.load(Ordering::SeqCst) == QUEUED
+    }
+
+    // Sends the error for `interrupt` as the reply, unless the request was already answered or is protected
+    fn interrupt(&self, interrupt: Interrupt, outbound: &Outbound) -> bool {
+        if self.state.compare_exchange(QUEUED, ANSWERED, Ordering::SeqCst, Ordering::SeqCst).is_err() {
+            return false;
+        }
+        let mut error = handler::interrupt_message(interrupt);
+        error.request_id = self.request_id;
+        let _ = outbound.send(error); // Connection may be gone already
+        true
+    }
+}
+
+#[derive(Debug, Default)]
+struct State {
+    requests: Vec<Arc<PendingRequest>>, // In arrival order
+    closed: bool,
+}
+
+/// Unanswered requests of one connection
+#[derive(Debug, Default)]
+pub struct InFlight {
+    state: Mutex<State>,
+    changed: Condvar, // Wakes `watch` for a new deadline or the end of the connection
+}
+
+impl InFlight {
+    /// Creates an empty registry
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Registers a request read just now, due within `deadline_ms` unless that is 0
+    pub fn register(&self, request_id: u64, deadline_ms: u32) -> Arc<PendingRequest> {
+        let deadline = (deadline_ms > 0).then(|| Instant::now() + Duration::from_millis(deadline_ms.into()));
+        let request = Arc::new(PendingRequest {
+            request_id,
+            token: CancelToken::with_deadline(deadline),
+            state: AtomicU8::new(QUEUED),
+        });
+        self.state.lock().unwrap().requests.push(request.clone());
+        self.changed.notify_all();
+        request
+    }
+
+    /// Forgets a request that was answered, or handed over to a stream
+    pub fn finish(&self, request: &Arc<PendingRequest>) {
+        self.state.lock().unwrap().requests.retain(|pending| !Arc::ptr_eq(pending, request));
+    }
+
+    /// Cancels the oldest unanswered request tagged `request_id`, sending its error to `outbound`.
+    /// Returns false if there is no such request, or if it is a protected one: that
+    /// one is only told through its token and replies on its own.
+    pub fn cancel(&self, request_id: u64, outbound: &Outbound) -> bool {
+        if request_id == 0 {
+            return false; // Untagged requests cannot be told apart
+        }
+        let found = self
+            .state
+            .lock()
+            .unwrap()
+            .requests
+            .iter()
+            .find(|request| request.request_id == request_id && request.state.load(Ordering::SeqCst) != ANSWERED)
+            .cloned();
+        match found {
+            Some(request) => {
+                request.token.cancel();
+                request.interrupt(Interrupt::Cancelled, outbound)
+            }
+            None => false,
+        }
+    }
+
+    /// Answers requests as their deadlines pass, until `close` is called
+    pub fn watch(&self, outbound: &Outbound) {
+        let mut state = self.state.lock().unwrap();
+        while !state.closed {
+            let now = Instant::now();
+            let (expired, waiting): (Vec<_>, Vec<_>) = state
+                .requests
+                .iter()
+                .filter(|request| request.is_queued())
+                .filter_map(|request| request.token.deadline().map(|deadline| (request.clone(), deadline)))
+                .partition(|(_, deadline)| *deadline <= now);
+            let next = waiting.iter().map(|(_, deadline)| *deadline).min();
+
+            if !expired.is_empty() {
+                drop(state); // Sending must not hold up the connection's threads
+                for (request, _) in expired {
+                    request.interrupt(Interrupt::DeadlineExceeded, outbound);
+                }
+                state = self.state.lock().unwrap();
+                continue;
+            }
+            state = match next {
+                Some(next) => self.changed.wait_timeout(state, next.saturating_duration_since(now)).unwrap().0,
+                None => self.changed.wait(state).unwrap(),
+            };
+        }
+    }
+
+    /// Stops `watch`, once the connection has no more requests to run
+    pub fn close(&self) {
+        self.state.lock().unwrap().closed = true;
+        self.changed.notify_all();
+    }
+}
@@ -0,0 +1,74 @@
+// JSON transcoding of the wire messages.
+//
+// `ClientMessage` and `ServerMessage` map to JSON following the proto3 JSON
+// mapping, generated by `pbjson-build` in the wire crate: fields in lowerCamelCase,
+// a oneof as the field that is set, enums by name, 64-bit integers as strings
+// and bytes as base64. For example
+//
+//     {"requestId":"1","echoMessage":{"content":"hello"}}
+//
+// Integers are also accepted as JSON numbers when decoding. The JSON listener
+// of the server reads one such object per line and writes every reply as one
+// line, so a connection can be driven with `nc` or any JSON library.
+use crate::frame::MAX_FRAME_LEN;
+use crate::handler;
+use crate::message::{ClientMessage, ErrorCode, ServerMessage};
+use std::{fmt, io};
+
+/// Longest line the JSON listener accepts, leaving room for base64 and field names
+pub const MAX_LINE_LEN: usize = 2 * MAX_FRAME_LEN;
+
+/// A line that is not a valid JSON `ClientMessage`
+#[derive(Debug)]
+pub struct JsonError(serde_json::Error);
+
+impl fmt::Display for JsonError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "invalid JSON message: {}", self.0)
+    }
+}
+
+impl std::error::Error for JsonError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        Some(&self.0)
+    }
+}
+
+impl From<serde_json::Error> for JsonError {
+    fn from(e: serde_json::Error) -> Self {
+        JsonError(e)
+    }
+}
+
+impl From<JsonError> for io::Error {
+    fn from(e: JsonError) -> Self {
+        io::Error::new(io::ErrorKind::InvalidData, e)
+    }
+}
+
+/// Parses one JSON `ClientMessage`
+pub fn decode_client_message(json: &str) -> Result<ClientMessage, JsonError> {
+    serde_json::from_str(json).map_err(JsonError)
+}
+
+/// Parses one JSON `ServerMessage`
+pub fn decode_server_message(json: &str) -> Result<ServerMessage, JsonError> {
+    serde_json::from_str(json).map_err(JsonError)
+}
+
+/// A `ClientMessage` as single-line JSON
+pub fn encode_client_message(message: &ClientMessage) -> String {
+    serde_json::to_string(message).expect("generated serializers do not fail")
+}
+
+/// A `ServerMessage` as single-line JSON
+pub fn encode_server_message(message: &ServerMessage) -> String {
+    serde_json::to_string(message).expect("generated serializers do not fail")
+}
+
+/// The `ERROR_CODE_INVALID_ARGUMENT` reply to a line that could not be parsed.
+///
+/// Its request id is 0, since the id of an unreadable request is unknown.
+pub fn invalid_message(e: &JsonError) -> ServerMessage {
+    handler::error_message(ErrorCode::InvalidArgument, e.to_string())
+}
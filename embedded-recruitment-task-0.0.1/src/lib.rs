@@ -1,5 +1,38 @@
+pub mod arithmetic;
+pub mod audit;
+pub mod auth;
+pub mod blob;
+pub mod capture;
+pub mod client;
+pub mod codec;
+#[cfg(feature = "grpc")]
+pub mod grpc;
+pub mod handler;
+pub mod http;
+pub mod inflight;
+pub mod json;
+pub mod outbound;
+pub mod persistence;
+pub mod policy;
+pub mod pool;
+pub mod proxy;
+pub mod pubsub;
+pub mod serial;
 pub mod server;
+pub mod store;
+pub mod stream;
+pub mod udp;
+
+pub use wire::frame; // Shared with device firmware
 
 pub mod message {
+    pub use wire::message::*;
+    // The `Gateway` service, generated against the messages of the wire crate
+    #[cfg(feature = "grpc")]
     include!(concat!(env!("OUT_DIR"), "/messages.rs"));
 }
+
+// On-disk record formats used by `persistence`, `blob` and `capture`
+mod storage {
+    include!(concat!(env!("OUT_DIR"), "/storage.rs"));
+}
@@ -0,0 +1,329 @@
+// Bounded queue of the messages waiting to be written to one connection.
+//
+// Replies, stream frames and publications pushed by other connections all go
+// through the outbound queue of a session, drained by whatever writes to its
+// transport. A client that stops reading would make that queue grow without
+// limit, so it is bounded by the encoded size of what it holds:
+//
+// - Above `high_watermark` the connection is congested. Producers that can
+//   wait, the connection's read loop and its streams, block in
+//   `wait_for_room` until the writer has drained the queue below
+//   `low_watermark`. While reads are paused, new requests stay in the socket,
+//   so a pipelining client is held back as well.
+// - Messages that cannot wait, such as publications, are still queued up to
+//   `max_queued`. One that would go past it disconnects the client.
+// - So does staying congested for longer than `max_congestion`.
+//
+// Disconnecting drops what is queued, makes every later send fail and runs
+// the hook set with `on_disconnect`, which closes the socket so the writer and
+// the read loop of the connection both stop.
+use crate::message::ServerMessage;
+use log::warn;
+use prost::Message;
+use std::{
+    collections::VecDeque,
+    fmt,
+    sync::{Arc, Condvar, Mutex, MutexGuard},
+    time::{Duration, Instant},
+};
+
+/// Limits of each connection's outbound queue, in encoded bytes
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BackpressureConfig {
+    /// Reads and streams pause once this much is queued
+    pub high_watermark: usize,
+    /// They resume once the queue has drained below this
+    pub low_watermark: usize,
+    /// A message that would take the queue past this disconnects the client
+    pub max_queued: usize,
+    /// A client whose queue stays above `high_watermark` this long is disconnected
+    pub max_congestion: Duration,
+}
+
+impl Default for BackpressureConfig {
+    /// Pause at 1 MiB, resume at 256 KiB, disconnect at 8 MiB or after 30 seconds of congestion
+    fn default() -> Self {
+        BackpressureConfig {
+            high_watermark: 1024 * 1024,
+            low_watermark: 256 * 1024,
+            max_queued: 8 * 1024 * 1024,
+            max_congestion: Duration::from_secs(30),
+        }
+    }
+}
+
+impl BackpressureConfig {
+    /// Checks that `low_watermark <= high_watermark <= max_queued`
+    pub fn validate(&self) -> Result<(), String> {
+        if self.low_watermark > self.high_watermark || self.high_watermark > self.max_queued {
+            return Err(format!(
+                "backpressure limits need low_watermark <= high_watermark <= max_queued, got {} / {} / {}",
+                self.low_watermark, self.high_watermark, self.max_queued
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// Why a message was not queued
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum QueueError {
+    /// The connection is closing, or its writer stopped
+    Closed,
+    /// The client was disconnected for not reading what it was sent
+    Overloaded,
+}
+
+impl fmt::Display for QueueError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            QueueError::Closed => write!(f, "connection closed"),
+            QueueError::Overloaded => write!(f, "client disconnected for not reading its messages"),
+        }
+    }
+}
+
+impl std::error::Error for QueueError {}
+
+#[derive(Default)]
+struct State {
+    messages: VecDeque<(ServerMessage, usize)>, // With their encoded length
+    bytes: usize,                               // Sum of the encoded lengths
+    congested_since: Option<Instant>,           // Set above the high watermark, cleared below the low one
+    senders: usize,                             // Live `Outbound` handles, the queue ends when none are left
+    closed: Option<QueueError>,                 // Why nothing more is queued
+}
+
+type DisconnectHook = Box<dyn FnOnce() + Send>;
+
+struct Shared {
+    config: BackpressureConfig,
+    state: Mutex<State>,
+    changed: Condvar, // Wakes the receiver for a message, and waiting producers for room or the end
+    on_disconnect: Mutex<Option<DisconnectHook>>,
+}
+
+impl Shared {
+    // Gives up on a client that does not read: drops its messages and runs the hook
+    fn disconnect(&self, mut state: MutexGuard<'_, State>, reason: &str) -> QueueError {
+        warn!("Disconnecting a client with {} bytes queued: {}", state.bytes, reason);
+        state.closed = Some(QueueError::Overloaded);
+        state.messages.clear();
+        state.bytes = 0;
+        state.congested_since = None;
+        drop(state);
+        self.changed.notify_all();
+        if let Some(hook) = self.on_disconnect.lock().unwrap().take() {
+            hook();
+        }
+        QueueError::Overloaded
+    }
+
+    // Disconnects the client if it has been congested for too long
+    fn check_congestion<'a>(&'a self, state: MutexGuard<'a, State>) -> Result<MutexGuard<'a, State>, QueueError> {
+        match state.congested_since {
+            Some(since) if since.elapsed() >= self.config.max_congestion => {
+                Err(self.disconnect(state, "congested for too long"))
+            }
+            _ => Ok(state),
+        }
+    }
+}
+
+/// Creates the outbound queue of one connection
+pub fn channel(config: BackpressureConfig) -> (Outbound, OutboundReceiver) {
+    let shared = Arc::new(Shared {
+        config,
+        state: Mutex::new(State { senders: 1, ..State::default() }),
+        changed: Condvar::new(),
+        on_disconnect: Mutex::new(None),
+    });
+    (Outbound { shared: shared.clone() }, OutboundReceiver { shared })
+}
+
+/// Sending side of an outbound queue, cloned by everything that pushes to the connection
+pub struct Outbound {
+    shared: Arc<Shared>,
+}
+
+impl Outbound {
+    /// Queues `message` without waiting.
+    ///
+    /// Fails if the connection is closed, or disconnects the client and fails
+    /// if the message would take the queue past `max_queued` or the queue has
+    /// been congested for longer than `max_congestion`.
+    pub fn send(&self, message: ServerMessage) -> Result<(), QueueError> {
+        let shared = &self.shared;
+        let state = shared.state.lock().unwrap();
+        if let Some(error) = state.closed {
+            return Err(error);
+        }
+        let mut state = shared.check_congestion(state)?;
+        let len = message.encoded_len();
+        if state.bytes > 0 && state.bytes + len > shared.config.max_queued {
+            return Err(shared.disconnect(state, "outbound queue full"));
+        }
+        state.messages.push_back((message, len));
+        state.bytes += len;
+        if state.bytes > shared.config.high_watermark && state.congested_since.is_none() {
+            state.congested_since = Some(Instant::now());
+        }
+        drop(state);
+        self.shared.changed.notify_all();
+        Ok(())
+    }
+
+    /// Blocks while the queue is congested, until it drains below the low watermark.
+    ///
+    /// Fails once the connection is closed, or disconnects the client and
+    /// fails if the queue does not drain within `max_congestion`.
+    pub fn wait_for_room(&self) -> Result<(), QueueError> {
+        let shared = &self.shared;
+        let mut state = shared.state.lock().unwrap();
+        loop {
+            if let Some(error) = state.closed {
+                return Err(error);
+            }
+            let Some(since) = state.congested_since else {
+                return Ok(());
+            };
+            let remaining = shared.config.max_congestion.saturating_sub(since.elapsed());
+            if remaining.is_zero() {
+                return Err(shared.disconnect(state, "congested for too long"));
+            }
+            state = shared.changed.wait_timeout(state, remaining).unwrap().0;
+        }
+    }
+
+    /// Encoded bytes waiting to be written
+    pub fn queued_bytes(&self) -> usize {
+        self.shared.state.lock().unwrap().bytes
+    }
+
+    /// Whether the queue went above the high watermark and has not drained below the low one since
+    pub fn is_congested(&self) -> bool {
+        self.shared.state.lock().unwrap().congested_since.is_some()
+    }
+
+    /// Runs `hook` when the client is disconnected for not reading, e.g. to close its socket
+    pub fn on_disconnect(&self, hook: impl FnOnce() + Send + 'static) {
+        *self.shared.on_disconnect.lock().unwrap() = Some(Box::new(hook));
+    }
+}
+
+impl Clone for Outbound {
+    fn clone(&self) -> Self {
+        self.shared.state.lock().unwrap().senders += 1;
+        Outbound { shared: self.shared.clone() }
+    }
+}
+
+impl Drop for Outbound {
+    fn drop(&mut self) {
+        self.shared.state.lock().unwrap().senders -= 1;
+        self.shared.changed.notify_all(); // The receiver may be waiting for the last one
+    }
+}
+
+impl fmt::Debug for Outbound {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let state = self.shared.state.lock().unwrap();
+        f.debug_struct("Outbound").field("bytes", &state.bytes).field("closed", &state.closed).finish()
+    }
+}
+
+/// Receiving side of an outbound queue, held by the connection's writer.
+/// Dropping it closes the queue.
+pub struct OutboundReceiver {
+    shared: Arc<Shared>,
+}
+
+impl OutboundReceiver {
+    /// Waits for the next message. Returns `None` once the client was
+    /// disconnected, or every sender is gone and the queue is empty.
+    pub fn recv(&self) -> Option<ServerMessage> {
+        let mut state = self.shared.state.lock().unwrap();
+        loop {
+            if state.closed.is_some() {
+                return None;
+            }
+            if let Some(message) = self.pop(&mut state) {
+                return Some(message);
+            }
+            if state.senders == 0 {
+                return None;
+            }
+            state = self.shared.changed.wait(state).unwrap();
+        }
+    }
+
+    /// Like `recv`, but also returns `None` if nothing arrives within `timeout`
+    pub fn recv_timeout(&self, timeout: Duration) -> Option<ServerMessage> {
+        let deadline = Instant::now() + timeout;
+        let mut state = self.shared.state.lock().unwrap();
+        loop {
+            if state.closed.is_some() {
+                return None;
+            }
+            if let Some(message) = self.pop(&mut state) {
+                return Some(message);
+            }
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if state.senders == 0 || remaining.is_zero() {
+                return None;
+            }
+            state = self.shared.changed.wait_timeout(state, remaining).unwrap().0;
+        }
+    }
+
+    /// Returns the next message if one is queued, without waiting
+    pub fn try_recv(&self) -> Option<ServerMessage> {
+        let mut state = self.shared.state.lock().unwrap();
+        if state.closed.is_some() {
+            return None;
+        }
+        self.pop(&mut state)
+    }
+
+    /// Iterates over messages as they arrive, like `recv`
+    pub fn iter(&self) -> impl Iterator<Item = ServerMessage> + '_ {
+        std::iter::from_fn(|| self.recv())
+    }
+
+    /// Iterates over the messages queued right now, like `try_recv`
+    pub fn try_iter(&self) -> impl Iterator<Item = ServerMessage> + '_ {
+        std::iter::from_fn(|| self.try_recv())
+    }
+
+    // Takes the oldest message, and ends congestion once below the low watermark
+    fn pop(&self, state: &mut State) -> Option<ServerMessage> {
+        let (message, len) = state.messages.pop_front()?;
+        state.bytes -= len;
+        if state.congested_since.is_some() && state.bytes <= self.shared.config.low_watermark {
+            state.congested_since = None;
+            self.shared.changed.notify_all(); // Resumes paused reads and streams
+        }
+        Some(message)
+    }
+}
+
+impl Iterator for OutboundReceiver {
+    type Item = ServerMessage;
+
+    fn next(&mut self) -> Option<ServerMessage> {
+        self.recv()
+    }
+}
+
+impl Drop for OutboundReceiver {
+    fn drop(&mut self) {
+        let mut state = self.shared.state.lock().unwrap();
+        if state.closed.is_none() {
+            state.closed = Some(QueueError::Closed);
+        }
+        state.messages.clear();
+        state.bytes = 0;
+        drop(state);
+        self.shared.changed.notify_all(); // Waiting producers find the queue closed
+    }
+}
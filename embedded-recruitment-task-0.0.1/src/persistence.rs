@@ -0,0 +1,347 @@
+// Durable storage for the key-value store: an append-only write-ahead log plus
+// periodic snapshots, both kept in one directory.
+//
+// On-disk record layout, used for WAL entries and the snapshot file alike:
+//
+//     [u32 BE payload length][u32 BE CRC32C of payload][protobuf payload]
+//
+// Recovery loads the snapshot, then replays WAL records newer than the
+// snapshot. A record that is cut short or fails its checksum marks the end of
+// the valid log: everything from there on is the remains of an interrupted
+// write and is truncated away.
+//
+// A running store never leaves such a tail behind: an append whose write or
+// fsync fails is cut off again, so a later record is not hidden behind it and
+// a write reported as failed does not come back after a restart. If even that
+// truncation fails, the log is in an unknown state and refuses every further
+// append until the store is reopened.
+use crate::storage::{wal_record, Snapshot, SnapshotEntry, WalRecord};
+use crate::store::Entry;
+use log::{info, warn};
+use prost::Message;
+use std::{
+    collections::BTreeMap,
+    fs::{self, File, OpenOptions},
+    io::{self, ErrorKind, Read, Write},
+    path::{Path, PathBuf},
+    time::{Duration, Instant},
+};
+
+/// Name of the write-ahead log inside the persistence directory
+pub const WAL_FILE: &str = "wal.log";
+/// Name of the latest snapshot inside the persistence directory
+pub const SNAPSHOT_FILE: &str = "snapshot.bin";
+// Snapshots are written here first and renamed into place once complete
+const SNAPSHOT_TMP_FILE: &str = "snapshot.tmp";
+
+// Length and checksum in front of every record
+const RECORD_HEADER_LEN: usize = 8;
+
+/// When appended WAL records are forced to stable storage
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FsyncPolicy {
+    /// fsync after every record, no acknowledged write is ever lost
+    Always,
+    /// fsync when at least this long has passed since the last fsync.
+    /// Checked on each append, so a crash can lose writes from the last interval.
+    Interval(Duration),
+    /// Leave flushing to the operating system
+    Never,
+}
+
+/// Where and how the store is persisted
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PersistenceConfig {
+    /// Directory holding the WAL and snapshot, created if missing
+    pub directory: PathBuf,
+    /// Durability of individual WAL appends
+    pub fsync: FsyncPolicy,
+    /// Write a snapshot and reset the WAL after this many records, 0 disables snapshots
+    pub snapshot_every: u64,
+}
+
+impl PersistenceConfig {
+    /// Persists into `directory`, fsyncing every write and snapshotting every 1000 records
+    pub fn new(directory: impl Into<PathBuf>) -> Self {
+        PersistenceConfig {
+            directory: directory.into(),
+            fsync: FsyncPolicy::Always,
+            snapshot_every: 1000,
+        }
+    }
+}
+
+/// File the WAL is appended to, a `File` unless `KeyValueStore::open_with` wraps it
+pub trait WalFile: Write + Send + Sync {
+    /// Truncates the file to `len` bytes
+    fn set_len(&mut self, len: u64) -> io::Result<()>;
+    /// Forces the written data to stable storage
+    fn sync_data(&mut self) -> io::Result<()>;
+}
+
+impl WalFile for File {
+    fn set_len(&mut self, len: u64) -> io::Result<()> {
+        File::set_len(self, len)
+    }
+
+    fn sync_data(&mut self) -> io::Result<()> {
+        File::sync_data(self)
+    }
+}
+
+/// State rebuilt from disk when the store is opened
+pub(crate) struct Recovered {
+    pub entries: BTreeMap<String, Entry>,
+    pub last_version: u64,
+}
+
+/// Open write-ahead log of a persisted store
+pub(crate) struct Persistence {
+    config: PersistenceConfig,
+    wal: Box<dyn WalFile>,
+    wal_len: u64, // Length of the complete records, where the next one starts
+    records_since_snapshot: u64,
+    last_sync: Instant,
+    unsynced: bool,
+    poisoned: Option<String>, // Why the end of the WAL is unknown, refuses appends when set
+}
+
+// Frames a payload with its length and checksum
+fn encode_record(payload: &[u8]) -> Vec<u8> {
+    let mut record = Vec::with_capacity(RECORD_HEADER_LEN + payload.len());
+    record.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+    record.extend_from_slice(&crc32c::crc32c(payload).to_be_bytes());
+    record.extend_from_slice(payload);
+    record
+}
+
+/// Applies a logged mutation (or batch of mutations) to `entries`
+pub(crate) fn apply_record(entries: &mut BTreeMap<String, Entry>, record: WalRecord) {
+    match record.operation {
+        Some(wal_record::Operation::Put(value)) => {
+            entries.insert(record.key, Entry { value, version: record.version });
+        }
+        Some(wal_record::Operation::Delete(_)) => {
+            entries.remove(&record.key);
+        }
+        Some(wal_record::Operation::Batch(batch)) => {
+            for record in batch.records {
+                apply_record(entries, record);
+            }
+        }
+        None => {}
+    }
+}
+
+// Splits `data` into verified record payloads.
+// Returns the payloads and the length of the valid prefix of `data`.
+fn decode_records(data: &[u8]) -> (Vec<&[u8]>, usize) {
+    let mut payloads = Vec::new();
+    let mut offset = 0;
+    while data.len() - offset >= RECORD_HEADER_LEN {
+        let header = &data[offset..offset + RECORD_HEADER_LEN];
+        let len = u32::from_be_bytes(header[..4].try_into().unwrap()) as usize;
+        let checksum = u32::from_be_bytes(header[4..].try_into().unwrap());
+
+        let start = offset + RECORD_HEADER_LEN;
+        let Some(payload) = data.get(start..start + len) else {
+            break; // Cut short by a crash mid-write
+        };
+        if crc32c::crc32c(payload) != checksum {
+            break; // Torn or corrupted write
+        }
+        payloads.push(payload);
+        offset = start + len;
+    }
+    (payloads, offset)
+}
+
+// Reads the snapshot file, a missing file is an empty store
+fn read_snapshot(path: &Path) -> io::Result<Snapshot> {
+    let data = match fs::read(path) {
+        Ok(data) => data,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(Snapshot::default()),
+        Err(e) => return Err(e),
+    };
+
+    // Snapshots are renamed into place only once complete, so damage here is not a torn write
+    let (payloads, valid_len) = decode_records(&data);
+    if payloads.len() != 1 || valid_len != data.len() {
+        return Err(io::Error::new(
+            ErrorKind::InvalidData,
+            format!("snapshot {} is corrupted", path.display()),
+        ));
+    }
+    Snapshot::decode(payloads[0]).map_err(|e| io::Error::new(ErrorKind::InvalidData, e))
+}
+
+// Makes a rename or file creation in `directory` durable
+fn sync_directory(directory: &Path) -> io::Result<()> {
+    #[cfg(unix)]
+    File::open(directory)?.sync_all()?;
+    #[cfg(not(unix))]
+    let _ = directory; // Directory handles cannot be synced on this platform
+    Ok(())
+}
+
+impl Persistence {
+    /// Opens (or creates) the persistence directory and rebuilds the stored state,
+    /// then appends to the WAL through `wrap`
+    pub fn open(config: PersistenceConfig, wrap: impl FnOnce(File) -> Box<dyn WalFile>) -> io::Result<(Self, Recovered)> {
+        fs::create_dir_all(&config.directory)?;
+
+        let snapshot = read_snapshot(&config.directory.join(SNAPSHOT_FILE))?;
+        let mut recovered = Recovered {
+            last_version: snapshot.last_version,
+            entries: snapshot
+                .entries
+                .into_iter()
+                .map(|entry| (entry.key, Entry { value: entry.value, version: entry.version }))
+                .collect(),
+        };
+
+        let wal_path = config.directory.join(WAL_FILE);
+        let mut wal = OpenOptions::new()
+            .read(true)
+            .append(true)
+            .create(true)
+            .open(&wal_path)?;
+        let mut data = Vec::new();
+        wal.read_to_end(&mut data)?;
+
+        let (payloads, valid_len) = decode_records(&data);
+        let mut replayed = 0;
+        for payload in &payloads {
+            let record = WalRecord::decode(*payload).map_err(|e| io::Error::new(ErrorKind::InvalidData, e))?;
+            if record.version <= snapshot.last_version {
+                continue; // Already part of the snapshot, left over from a crash during compaction
+            }
+            if record.operation.is_none() {
+                return Err(io::Error::new(ErrorKind::InvalidData, "WAL record without an operation"));
+            }
+            recovered.last_version = recovered.last_version.max(record.version);
+            apply_record(&mut recovered.entries, record);
+            replayed += 1;
+        }
+
+        if valid_len < data.len() {
+            warn!(
+                "Truncating {} bytes of incomplete records from {}",
+                data.len() - valid_len,
+                wal_path.display()
+            );
+            wal.set_len(valid_len as u64)?;
+            wal.sync_all()?;
+        }
+        info!(
+            "Recovered {} keys from {} ({} WAL records replayed)",
+            recovered.entries.len(),
+            config.directory.display(),
+            replayed
+        );
+
+        let persistence = Persistence {
+            records_since_snapshot: payloads.len() as u64,
+            config,
+            wal: wrap(wal),
+            wal_len: valid_len as u64,
+            last_sync: Instant::now(),
+            unsynced: false,
+            poisoned: None,
+        };
+        Ok((persistence, recovered))
+    }
+
+    /// Appends one record to the WAL and syncs it according to the fsync policy.
+    /// On failure the record is removed again, so it is never recovered.
+    pub fn append(&mut self, record: &WalRecord) -> io::Result<()> {
+        if let Some(reason) = &self.poisoned {
+            return Err(io::Error::other(format!("WAL refuses writes until reopened: {}", reason)));
+        }
+        let record = encode_record(&record.encode_to_vec());
+        if let Err(e) = self.write_record(&record) {
+            self.roll_back();
+            return Err(e);
+        }
+        self.wal_len += record.len() as u64;
+        self.records_since_snapshot += 1;
+        Ok(())
+    }
+
+    // Writes and syncs one encoded record, which may be left partly written on error
+    fn write_record(&mut self, record: &[u8]) -> io::Result<()> {
+        self.unsynced = true;
+        self.wal.write_all(record)?;
+        match self.config.fsync {
+            FsyncPolicy::Always => self.sync(),
+            FsyncPolicy::Interval(interval) if self.last_sync.elapsed() >= interval => self.sync(),
+            FsyncPolicy::Interval(_) | FsyncPolicy::Never => Ok(()),
+        }
+    }
+
+    // Cuts a failed append off the WAL, or poisons it if that fails too
+    fn roll_back(&mut self) {
+        let len = self.wal_len;
+        if let Err(e) = self.wal.set_len(len).and_then(|()| self.wal.sync_data()) {
+            warn!("Failed to truncate the WAL back to {} bytes after a failed append: {}", len, e);
+            self.poisoned = Some(e.to_string());
+        }
+    }
+
+    /// Returns true when enough records were appended to warrant a new snapshot
+    pub fn snapshot_due(&self) -> bool {
+        self.config.snapshot_every > 0 && self.records_since_snapshot >= self.config.snapshot_every
+    }
+
+    /// Writes a snapshot of `entries` and starts a fresh WAL
+    pub fn write_snapshot(&mut self, entries: &BTreeMap<String, Entry>, last_version: u64) -> io::Result<()> {
+        let snapshot = Snapshot {
+            last_version,
+            entries: entries
+                .iter()
+                .map(|(key, entry)| SnapshotEntry {
+                    key: key.clone(),
+                    value: entry.value.clone(),
+                    version: entry.version,
+                })
+                .collect(),
+        };
+
+        let directory = &self.config.directory;
+        let tmp_path = directory.join(SNAPSHOT_TMP_FILE);
+        let mut file = File::create(&tmp_path)?;
+        file.write_all(&encode_record(&snapshot.encode_to_vec()))?;
+        file.sync_all()?;
+        fs::rename(&tmp_path, directory.join(SNAPSHOT_FILE))?;
+        sync_directory(directory)?;
+
+        // Only now is the WAL redundant, a crash before this point replays it on top of the snapshot
+        self.wal.set_len(0)?;
+        self.wal.sync_data()?;
+        self.wal_len = 0;
+        self.poisoned = None;
+        self.records_since_snapshot = 0;
+        self.last_sync = Instant::now();
+        self.unsynced = false;
+        info!("Wrote snapshot of {} keys at version {}", entries.len(), last_version);
+        Ok(())
+    }
+
+    /// Forces appended records to stable storage
+    pub fn sync(&mut self) -> io::Result<()> {
+        if self.unsynced {
+            self.wal.sync_data()?;
+            self.unsynced = false;
+        }
+        self.last_sync = Instant::now();
+        Ok(())
+    }
+}
+
+impl Drop for Persistence {
+    fn drop(&mut self) {
+        if let Err(e) = self.sync() {
+            warn!("Failed to sync the WAL on close: {}", e);
+        }
+    }
+}
@@ -0,0 +1,289 @@
+// Per-operation authorization.
+//
+// A policy grants roles to identities and lets each role perform some
+// operations, optionally only on keys, topics or file names under a prefix.
+// Anything not allowed by some rule of one of the connection's roles is
+// denied. Operations are named after the `ClientMessage` field that carries
+// them (`put`, `subscribe`, `upload_begin`, ...).
+//
+// `Hello`, `AuthChallenge` and `Authenticate` are always allowed, since a
+// connection needs them to get an identity in the first place, and so is
+// `Ping`, which health checks send without credentials. A batch needs
+// `batch_request` and must be allowed every request it carries.
+//
+// Upload chunks and commits name their upload by id, and an id is easy to
+// compute, so they are checked against the name the upload was begun with.
+// Without that name, as for an unknown upload, a prefix never matches.
+use crate::message::client_message::Message as ClientMessageType;
+use std::{
+    collections::HashMap,
+    fmt, fs,
+    io::{self, ErrorKind},
+    path::Path,
+};
+
+/// Operation names a policy can refer to
+pub const OPERATIONS: [&str; 19] = [
+    "echo_message",
+    "add_request",
+    "subscribe",
+    "unsubscribe",
+    "publish",
+    "get",
+    "put",
+    "delete",
+    "compare_and_swap",
+    "list_keys",
+    "arithmetic_request",
+    "batch_request",
+    "count_to",
+    "cancel",
+    "upload_begin",
+    "upload_chunk",
+    "upload_commit",
+    "download_begin",
+    "download_chunk",
+];
+
+// Grants to this identity apply to every connection, authenticated or not
+const EVERYONE: &str = "*";
+
+/// Name a policy uses for the operation `message` performs, `None` for the
+/// messages every connection may send
+pub fn operation(message: &ClientMessageType) -> Option<&'static str> {
+    match message {
+        ClientMessageType::Hello(_)
+        | ClientMessageType::AuthChallenge(_)
+        | ClientMessageType::Authenticate(_)
+        | ClientMessageType::Ping(_) => None,
+        message => Some(message_name(message)),
+    }
+}
+
+/// Name of the `ClientMessage` field carrying `message`
+pub fn message_name(message: &ClientMessageType) -> &'static str {
+    match message {
+        ClientMessageType::EchoMessage(_) => "echo_message",
+        ClientMessageType::AddRequest(_) => "add_request",
+        ClientMessageType::Subscribe(_) => "subscribe",
+        ClientMessageType::Unsubscribe(_) => "unsubscribe",
+        ClientMessageType::Publish(_) => "publish",
+        ClientMessageType::Get(_) => "get",
+        ClientMessageType::Put(_) => "put",
+        ClientMessageType::Delete(_) => "delete",
+        ClientMessageType::CompareAndSwap(_) => "compare_and_swap",
+        ClientMessageType::ListKeys(_) => "list_keys",
+        ClientMessageType::ArithmeticRequest(_) => "arithmetic_request",
+        ClientMessageType::BatchRequest(_) => "batch_request",
+        ClientMessageType::CountTo(_) => "count_to",
+        ClientMessageType::Cancel(_) => "cancel",
+        ClientMessageType::UploadBegin(_) => "upload_begin",
+        ClientMessageType::UploadChunk(_) => "upload_chunk",
+        ClientMessageType::UploadCommit(_) => "upload_commit",
+        ClientMessageType::DownloadBegin(_) => "download_begin",
+        ClientMessageType::DownloadChunk(_) => "download_chunk",
+        ClientMessageType::Hello(_) => "hello",
+        ClientMessageType::AuthChallenge(_) => "auth_challenge",
+        ClientMessageType::Authenticate(_) => "authenticate",
+        ClientMessageType::Ping(_) => "ping",
+    }
+}
+
+/// Key, topic pattern or file name `message` acts on, `None` if it names none.
+///
+/// Upload chunks and commits refer to their upload by id, see `upload_id`.
+pub fn resource(message: &ClientMessageType) -> Option<&str> {
+    match message {
+        ClientMessageType::Subscribe(subscribe) => Some(&subscribe.pattern),
+        ClientMessageType::Unsubscribe(unsubscribe) => Some(&unsubscribe.pattern),
+        ClientMessageType::Publish(publish) => Some(&publish.topic),
+        ClientMessageType::Get(get) => Some(&get.key),
+        ClientMessageType::Put(put) => Some(&put.key),
+        ClientMessageType::Delete(delete) => Some(&delete.key),
+        ClientMessageType::CompareAndSwap(cas) => Some(&cas.key),
+        ClientMessageType::ListKeys(list_keys) => Some(&list_keys.prefix),
+        ClientMessageType::UploadBegin(begin) => Some(&begin.name),
+        ClientMessageType::DownloadBegin(begin) => Some(&begin.name),
+        ClientMessageType::DownloadChunk(chunk) => Some(&chunk.name),
+        _ => None,
+    }
+}
+
+/// Id of the upload a chunk or commit continues, whose file name is the resource it acts on
+pub fn upload_id(message: &ClientMessageType) -> Option<&str> {
+    match message {
+        ClientMessageType::UploadChunk(chunk) => Some(&chunk.upload_id),
+        ClientMessageType::UploadCommit(commit) => Some(&commit.upload_id),
+        _ => None,
+    }
+}
+
+/// A request the policy does not allow
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Denial {
+    /// Identity of the connection, `None` if it has not authenticated
+    pub identity: Option<String>,
+    /// Operation that was refused
+    pub operation: &'static str,
+    /// Key, topic pattern or file name it would have acted on
+    pub resource: Option<String>,
+}
+
+impl fmt::Display for Denial {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.identity {
+            Some(identity) => write!(f, "'{}' may not {}", identity, self.operation)?,
+            None => write!(f, "unauthenticated connections may not {}", self.operation)?,
+        }
+        match &self.resource {
+            Some(resource) => write!(f, " '{}'", resource),
+            None => Ok(()),
+        }
+    }
+}
+
+impl std::error::Error for Denial {}
+
+// One `allow` line
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Rule {
+    operations: Vec<&'static str>, // Empty allows every operation
+    prefix: String,                // Resources must start with this, empty allows all
+}
+
+impl Rule {
+    fn allows(&self, operation: &str, resource: Option<&str>) -> bool {
+        (self.operations.is_empty() || self.operations.contains(&operation))
+            && resource.is_none_or(|resource| resource.starts_with(&self.prefix))
+    }
+}
+
+/// Which identities may perform which operations on what
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Policy {
+    roles: HashMap<String, Vec<Rule>>,
+    grants: HashMap<String, Vec<String>>, // Identity, or `*` for everyone, to role names
+}
+
+impl Policy {
+    /// Creates a policy that denies everything
+    pub fn new() -> Self {
+        Policy::default()
+    }
+
+    /// Reads a policy file, see `parse` for the format
+    pub fn load(path: impl AsRef<Path>) -> io::Result<Self> {
+        Self::parse(&fs::read_to_string(path)?)
+    }
+
+    /// Parses one statement per line, blank lines and lines starting with `#` are skipped:
+    ///
+    /// ```text
+    /// # allow <role> <operations|*> [prefix]
+    /// allow reader     get,list_keys         config/
+    /// allow telemetry  publish,subscribe     telemetry/
+    /// allow admin      *
+    /// # grant <identity|*> <roles>...
+    /// grant sensor-01  telemetry reader
+    /// grant *          reader
+    /// ```
+    ///
+    /// A prefix only restricts operations that name a key, topic pattern or
+    /// file; `list_keys` is checked against the prefix it lists.
+    pub fn parse(text: &str) -> io::Result<Self> {
+        let mut policy = Policy::new();
+        for (index, line) in text.lines().enumerate() {
+            let invalid = |reason: &str| io::Error::new(ErrorKind::InvalidData, format!("line {}: {}", index + 1, reason));
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            match fields[..] {
+                ["allow", role, operations, ref prefix @ ..] if prefix.len() <= 1 => {
+                    let operations = if operations == "*" {
+                        Vec::new()
+                    } else {
+                        operations
+                            .split(',')
+                            .map(|name| {
+                                OPERATIONS
+                                    .into_iter()
+                                    .find(|known| *known == name)
+                                    .ok_or_else(|| invalid(&format!("unknown operation '{}'", name)))
+                            })
+                            .collect::<io::Result<_>>()?
+                    };
+                    let prefix = prefix.first().map_or(String::new(), |prefix| prefix.to_string());
+                    policy.allow(role, Rule { operations, prefix });
+                }
+                ["grant", identity, ref roles @ ..] if !roles.is_empty() => {
+                    for role in roles {
+                        policy.grant(identity, role);
+                    }
+                }
+                _ => return Err(invalid("expected 'allow <role> <operations> [prefix]' or 'grant <identity> <roles>'")),
+            }
+        }
+
+        // A typo in a role name would otherwise silently grant nothing
+        for roles in policy.grants.values() {
+            if let Some(role) = roles.iter().find(|role| !policy.roles.contains_key(*role)) {
+                return Err(io::Error::new(ErrorKind::InvalidData, format!("role '{}' is granted but never allowed anything", role)));
+            }
+        }
+        Ok(policy)
+    }
+
+    fn allow(&mut self, role: &str, rule: Rule) {
+        self.roles.entry(role.to_string()).or_default().push(rule);
+    }
+
+    fn grant(&mut self, identity: &str, role: &str) {
+        self.grants.entry(identity.to_string()).or_default().push(role.to_string());
+    }
+
+    // Rules of every role granted to `identity`, including those granted to everyone
+    fn rules<'a>(&'a self, identity: Option<&'a str>) -> impl Iterator<Item = &'a Rule> + 'a {
+        let own = identity.and_then(|identity| self.grants.get(identity));
+        own.into_iter()
+            .chain(self.grants.get(EVERYONE))
+            .flatten()
+            .filter_map(|role| self.roles.get(role))
+            .flatten()
+    }
+
+    /// Checks whether the connection authenticated as `identity` may send `message`.
+    /// Upload chunks and commits are only allowed where no prefix applies, see `check_with`.
+    pub fn check(&self, identity: Option<&str>, message: &ClientMessageType) -> Result<(), Denial> {
+        self.check_with(identity, message, &|_| None)
+    }
+
+    /// Like `check`, with `upload_name` giving the file name an upload id was begun with
+    pub fn check_with(
+        &self,
+        identity: Option<&str>,
+        message: &ClientMessageType,
+        upload_name: &dyn Fn(&str) -> Option<String>,
+    ) -> Result<(), Denial> {
+        let Some(operation) = operation(message) else {
+            return Ok(());
+        };
+        let name = upload_id(message).map(|id| upload_name(id).unwrap_or_default()); // No name matches no prefix
+        let resource = name.as_deref().or_else(|| resource(message));
+        if !self.rules(identity).any(|rule| rule.allows(operation, resource)) {
+            return Err(Denial {
+                identity: identity.map(str::to_string),
+                operation,
+                resource: resource.map(str::to_string),
+            });
+        }
+
+        if let ClientMessageType::BatchRequest(batch) = message {
+            for item in batch.requests.iter().filter_map(|request| request.message.as_ref()) {
+                self.check_with(identity, item, upload_name)?;
+            }
+        }
+        Ok(())
+    }
+}
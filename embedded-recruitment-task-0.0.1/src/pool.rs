@@ -0,0 +1,347 @@
+// Pool of client connections to one server, shared between threads.
+//
+// `get` checks out an idle connection, opens a new one while fewer than
+// `max_size` are open, or waits up to `checkout_timeout` for another caller
+// to return one. The returned guard derefs to a `Client` and gives the
+// connection back when dropped, unless a read or write on it failed or it
+// still holds unread frames, in which case it is closed.
+//
+// A maintenance thread keeps the pool in shape: it closes connections that
+// stayed idle for longer than `idle_timeout` (never going below `min_size`),
+// pings the other idle ones and closes those that do not answer, and opens
+// connections until `min_size` are open again.
+//
+// Every connection the pool opens, including those replacing connections it
+// closed, runs the configured `Initializer` first, e.g. to negotiate the frame
+// format or authenticate. A returned connection gets the deadline and read
+// timeout it had after that back; one whose frame format or identity a caller
+// changed is closed instead of being handed to the next caller.
+use crate::client::{Baseline, Client};
+use crate::message::{client_message, server_message};
+use log::{info, warn};
+use std::{
+    collections::VecDeque,
+    fmt,
+    io::{self, ErrorKind},
+    net::{SocketAddr, ToSocketAddrs},
+    ops::{Deref, DerefMut},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Condvar, Mutex, MutexGuard,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+/// Settings of a `ConnectionPool`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PoolConfig {
+    /// Connections kept open even when idle
+    pub min_size: usize,
+    /// Connections open at most, checked out or idle
+    pub max_size: usize,
+    /// How long opening a connection, or its answer to a health check, may take
+    pub connect_timeout: Duration,
+    /// How long `get` waits for a connection when all of them are checked out
+    pub checkout_timeout: Duration,
+    /// Idle connections above `min_size` are closed after this long
+    pub idle_timeout: Duration,
+    /// Time between two rounds of health checks and eviction
+    pub health_interval: Duration,
+    /// Sets up each new connection before it is used, `None` leaves it as opened
+    pub initializer: Option<Initializer>,
+}
+
+impl Default for PoolConfig {
+    /// Between 1 and 8 connections, idle ones closed after a minute and checked every 10 seconds
+    fn default() -> Self {
+        PoolConfig {
+            min_size: 1,
+            max_size: 8,
+            connect_timeout: Duration::from_secs(1),
+            checkout_timeout: Duration::from_secs(5),
+            idle_timeout: Duration::from_secs(60),
+            health_interval: Duration::from_secs(10),
+            initializer: None,
+        }
+    }
+}
+
+type Setup = dyn Fn(&mut Client) -> io::Result<()> + Send + Sync;
+
+/// Setup run on every connection a pool opens, such as `Client::negotiate` or `Client::authenticate_token`
+#[derive(Clone)]
+pub struct Initializer(Arc<Setup>);
+
+impl Initializer {
+    /// Runs `setup` on each new connection, a connection it fails on is closed
+    pub fn new(setup: impl Fn(&mut Client) -> io::Result<()> + Send + Sync + 'static) -> Self {
+        Initializer(Arc::new(setup))
+    }
+}
+
+impl fmt::Debug for Initializer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Initializer(..)")
+    }
+}
+
+impl PartialEq for Initializer {
+    // The same setup only if it is the same closure
+    fn eq(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.0, &other.0)
+    }
+}
+
+impl Eq for Initializer {}
+
+/// How many connections a pool holds
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PoolStatus {
+    /// Open connections, checked out or idle
+    pub open: usize,
+    /// Connections waiting in the pool
+    pub idle: usize,
+}
+
+// A connection waiting in the pool
+struct Idle {
+    client: Client,
+    baseline: Baseline, // Settings right after the initializer ran
+    since: Instant,     // When it was last returned
+}
+
+struct State {
+    idle: VecDeque<Idle>, // Most recently returned last
+    open: usize,          // Includes connections being opened or checked
+}
+
+// What the pool and its maintenance thread share
+struct Shared {
+    addrs: Vec<SocketAddr>,
+    config: PoolConfig,
+    state: Mutex<State>,
+    returned: Condvar, // Signalled when a connection is returned or a slot frees up
+    is_running: AtomicBool,
+}
+
+impl Shared {
+    // Opens and initializes a connection
+    fn connect(&self) -> io::Result<(Client, Baseline)> {
+        let mut client = Client::connect(self.addrs.as_slice(), self.config.connect_timeout)?;
+        if let Some(initializer) = &self.config.initializer {
+            (initializer.0)(&mut client)?;
+        }
+        let baseline = client.baseline();
+        Ok((client, baseline))
+    }
+
+    // Gives up a slot whose connection was closed or never opened
+    fn release(&self, state: &mut MutexGuard<'_, State>) {
+        state.open -= 1;
+        self.returned.notify_one();
+    }
+
+    // Pings an idle connection without leaving a timeout behind for the next caller
+    fn check(&self, idle: &mut Idle) -> io::Result<()> {
+        idle.client.set_read_timeout(Some(self.config.connect_timeout))?;
+        idle.client.ping()?;
+        if !idle.client.restore(&idle.baseline) {
+            return Err(io::Error::other("failed to restore the read timeout"));
+        }
+        Ok(())
+    }
+
+    // One round of maintenance: evict, check, then top up
+    fn maintain(&self) {
+        let count = self.state.lock().unwrap().idle.len();
+        for _ in 0..count {
+            let mut state = self.state.lock().unwrap();
+            let Some(mut idle) = state.idle.pop_front() else { break };
+            if idle.since.elapsed() >= self.config.idle_timeout && state.open > self.config.min_size {
+                self.release(&mut state);
+                continue; // Dropping the client closes it
+            }
+            drop(state); // Callers keep checking out the others meanwhile
+            let checked = self.check(&mut idle);
+            let mut state = self.state.lock().unwrap();
+            match checked {
+                Ok(()) => {
+                    state.idle.push_back(idle);
+                    self.returned.notify_one();
+                }
+                Err(e) => {
+                    warn!("Closing a pooled connection that failed its health check: {}", e);
+                    self.release(&mut state);
+                }
+            }
+        }
+
+        loop {
+            let mut state = self.state.lock().unwrap();
+            if state.open >= self.config.min_size {
+                break;
+            }
+            state.open += 1;
+            drop(state);
+            let connected = self.connect();
+            let mut state = self.state.lock().unwrap();
+            match connected {
+                Ok((client, baseline)) => {
+                    state.idle.push_back(Idle { client, baseline, since: Instant::now() });
+                    self.returned.notify_one();
+                }
+                Err(e) => {
+                    warn!("Failed to refill the connection pool: {}", e);
+                    self.release(&mut state);
+                    break; // Tried again next round
+                }
+            }
+        }
+    }
+}
+
+/// Thread-safe pool of connections to one server
+pub struct ConnectionPool {
+    shared: Arc<Shared>,
+    maintenance: Option<thread::JoinHandle<()>>,
+}
+
+impl ConnectionPool {
+    /// Opens `min_size` connections to `addr` and starts the maintenance thread.
+    /// Fails if the configuration is inconsistent or the first connections cannot be opened.
+    pub fn new(addr: impl ToSocketAddrs, config: PoolConfig) -> io::Result<Self> {
+        if config.max_size == 0 || config.min_size > config.max_size {
+            return Err(io::Error::new(ErrorKind::InvalidInput, "pool sizes need 0 <= min_size <= max_size and max_size > 0"));
+        }
+        let addrs: Vec<SocketAddr> = addr.to_socket_addrs()?.collect();
+        let shared = Arc::new(Shared {
+            addrs,
+            state: Mutex::new(State { idle: VecDeque::new(), open: 0 }),
+            returned: Condvar::new(),
+            is_running: AtomicBool::new(true),
+            config,
+        });
+        for _ in 0..shared.config.min_size {
+            let (client, baseline) = shared.connect()?;
+            let mut state = shared.state.lock().unwrap();
+            state.idle.push_back(Idle { client, baseline, since: Instant::now() });
+            state.open += 1;
+        }
+
+        let maintenance = {
+            let shared = shared.clone();
+            thread::spawn(move || {
+                while shared.is_running.load(Ordering::SeqCst) {
+                    let next = Instant::now() + shared.config.health_interval;
+                    while shared.is_running.load(Ordering::SeqCst) && Instant::now() < next {
+                        thread::sleep(Duration::from_millis(20).min(shared.config.health_interval)); // Short naps so dropping the pool is not held up
+                    }
+                    if shared.is_running.load(Ordering::SeqCst) {
+                        shared.maintain();
+                    }
+                }
+            })
+        };
+        info!("Connection pool to {:?} opened with {} connections", shared.addrs, shared.config.min_size);
+        Ok(ConnectionPool { shared, maintenance: Some(maintenance) })
+    }
+
+    /// Checks out a connection, opening one if none is idle and the pool is not full.
+    /// Fails with `ErrorKind::TimedOut` if none is returned within `checkout_timeout`.
+    pub fn get(&self) -> io::Result<PooledClient<'_>> {
+        let shared = &self.shared;
+        let deadline = Instant::now() + shared.config.checkout_timeout;
+        let mut state = shared.state.lock().unwrap();
+        loop {
+            if let Some(idle) = state.idle.pop_back() {
+                return Ok(PooledClient { pool: self, client: Some(idle.client), baseline: idle.baseline });
+            }
+            if state.open < shared.config.max_size {
+                state.open += 1; // Holds the slot while connecting without the lock
+                drop(state);
+                return match shared.connect() {
+                    Ok((client, baseline)) => Ok(PooledClient { pool: self, client: Some(client), baseline }),
+                    Err(e) => {
+                        shared.release(&mut shared.state.lock().unwrap());
+                        Err(e)
+                    }
+                };
+            }
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining.is_zero() {
+                return Err(io::Error::new(ErrorKind::TimedOut, "no pooled connection became available"));
+            }
+            state = shared.returned.wait_timeout(state, remaining).unwrap().0;
+        }
+    }
+
+    /// Sends a request on a pooled connection and waits for its reply
+    pub fn request(&self, message: client_message::Message) -> io::Result<server_message::Message> {
+        self.get()?.request(message)
+    }
+
+    /// Open and idle connections right now
+    pub fn status(&self) -> PoolStatus {
+        let state = self.shared.state.lock().unwrap();
+        PoolStatus { open: state.open, idle: state.idle.len() }
+    }
+
+    // Takes back a connection from a `PooledClient`, as it was set up
+    fn put_back(&self, mut client: Client, baseline: Baseline) {
+        let reusable = client.is_reusable() && client.restore(&baseline);
+        let mut state = self.shared.state.lock().unwrap();
+        if reusable {
+            state.idle.push_back(Idle { client, baseline, since: Instant::now() });
+            self.shared.returned.notify_one();
+        } else {
+            self.shared.release(&mut state);
+        }
+    }
+}
+
+impl Drop for ConnectionPool {
+    fn drop(&mut self) {
+        self.shared.is_running.store(false, Ordering::SeqCst);
+        if let Some(maintenance) = self.maintenance.take() {
+            let _ = maintenance.join();
+        }
+    }
+}
+
+/// A connection checked out of a `ConnectionPool`, returned to it when dropped
+pub struct PooledClient<'a> {
+    pool: &'a ConnectionPool,
+    client: Option<Client>, // Only `None` while being dropped or discarded
+    baseline: Baseline,     // What it goes back to when returned
+}
+
+impl PooledClient<'_> {
+    /// Closes the connection instead of returning it, e.g. after leaving it in an unusual state
+    pub fn discard(mut self) {
+        self.client = None;
+        self.pool.shared.release(&mut self.pool.shared.state.lock().unwrap());
+    }
+}
+
+impl Deref for PooledClient<'_> {
+    type Target = Client;
+
+    fn deref(&self) -> &Client {
+        self.client.as_ref().expect("pooled client already returned")
+    }
+}
+
+impl DerefMut for PooledClient<'_> {
+    fn deref_mut(&mut self) -> &mut Client {
+        self.client.as_mut().expect("pooled client already returned")
+    }
+}
+
+impl Drop for PooledClient<'_> {
+    fn drop(&mut self) {
+        if let Some(client) = self.client.take() {
+            self.pool.put_back(client, self.baseline.clone());
+        }
+    }
+}
@@ -0,0 +1,403 @@
+// Front end that spreads client connections over several servers.
+//
+// A `Proxy` accepts framed connections like a `Server` and pins each one to
+// a backend, chosen round-robin or by fewest connections when the client
+// sends its first request. Requests are forwarded in order and everything the
+// backend sends back (replies, stream frames, publications) is relayed to the
+// client as it arrives. The proxy answers a `Hello` itself with plain frames,
+// so it never has to re-encode compressed or checksummed ones.
+//
+// A health checker pings every backend on a fresh connection. A backend that
+// does not answer, refuses a connection or drops one is marked down and gets
+// no new connections until a ping succeeds again. When the backend of a
+// connection dies, its unanswered requests get `ERROR_CODE_UNAVAILABLE` and
+// the next request fails over to another backend. State kept by the old
+// backend does not move: the client authenticates and subscribes again.
+use crate::client::Client as BackendClient;
+use crate::frame;
+use crate::handler;
+use crate::message::{client_message, server_message, ClientMessage, ErrorCode, HelloResponse, ServerMessage};
+use log::{error, info, warn};
+use prost::Message;
+use std::{
+    collections::HashSet,
+    io::{self, ErrorKind},
+    net::{Shutdown, SocketAddr, TcpListener, TcpStream, ToSocketAddrs},
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        mpsc, Arc, Mutex,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+/// How the backend of a new connection is chosen
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Balance {
+    /// Each healthy backend in turn
+    #[default]
+    RoundRobin,
+    /// The healthy backend with the fewest proxied connections
+    LeastConnections,
+}
+
+/// Settings of a `Proxy`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ProxyConfig {
+    /// Addresses of the backend servers
+    pub backends: Vec<String>,
+    /// How connections are spread over them
+    pub balance: Balance,
+    /// Time between two health checks of a backend
+    pub health_interval: Duration,
+    /// How long connecting to a backend, or its reply to a ping, may take
+    pub timeout: Duration,
+}
+
+impl ProxyConfig {
+    /// Round-robin over `backends`, checked every 5 seconds with a 1 second timeout
+    pub fn new<S: Into<String>>(backends: impl IntoIterator<Item = S>) -> Self {
+        ProxyConfig {
+            backends: backends.into_iter().map(Into::into).collect(),
+            balance: Balance::RoundRobin,
+            health_interval: Duration::from_secs(5),
+            timeout: Duration::from_secs(1),
+        }
+    }
+}
+
+/// What the proxy currently knows about a backend
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BackendStatus {
+    pub addr: String,
+    /// False once a check or a proxied connection failed, until a ping succeeds
+    pub healthy: bool,
+    /// Client connections currently forwarded to it
+    pub connections: usize,
+}
+
+struct Backend {
+    addr: String,
+    healthy: AtomicBool,
+    connections: AtomicUsize,
+}
+
+impl Backend {
+    fn mark_down(&self, reason: &dyn std::fmt::Display) {
+        if self.healthy.swap(false, Ordering::SeqCst) {
+            warn!("Backend {} is down: {}", self.addr, reason);
+        }
+    }
+
+    fn connect(&self, timeout: Duration) -> io::Result<TcpStream> {
+        let mut last_error = io::Error::new(ErrorKind::InvalidInput, "address resolved to nothing");
+        for addr in self.addr.to_socket_addrs()? {
+            match TcpStream::connect_timeout(&addr, timeout) {
+                Ok(stream) => return Ok(stream),
+                Err(e) => last_error = e,
+            }
+        }
+        Err(last_error)
+    }
+
+    // Pings the backend on a connection of its own
+    fn check(&self, timeout: Duration) -> io::Result<Duration> {
+        let mut client = BackendClient::connect(self.addr.as_str(), timeout)?;
+        client.set_read_timeout(Some(timeout))?;
+        let elapsed = client.ping()?;
+        let _ = client.disconnect();
+        Ok(elapsed)
+    }
+}
+
+// The backends and how to choose among them, shared by every proxied connection
+struct Pool {
+    backends: Vec<Backend>,
+    balance: Balance,
+    next: AtomicUsize, // Round-robin position
+    timeout: Duration,
+}
+
+impl Pool {
+    // Backends to try for a new connection, best first: healthy ones in balancing order, then the rest
+    fn candidates(&self) -> Vec<usize> {
+        let count = self.backends.len();
+        let start = self.next.fetch_add(1, Ordering::Relaxed) % count.max(1);
+        let mut order: Vec<usize> = (0..count).map(|offset| (start + offset) % count).collect();
+        if self.balance == Balance::LeastConnections {
+            order.sort_by_key(|&index| self.backends[index].connections.load(Ordering::SeqCst)); // Stable, ties keep the rotation
+        }
+        order.sort_by_key(|&index| !self.backends[index].healthy.load(Ordering::SeqCst));
+        order
+    }
+
+    // Connects to the first candidate that accepts, marking the ones that refuse as down
+    fn connect(&self) -> Option<(usize, TcpStream)> {
+        for index in self.candidates() {
+            let backend = &self.backends[index];
+            match backend.connect(self.timeout) {
+                Ok(stream) => {
+                    backend.healthy.store(true, Ordering::SeqCst); // It answered, whatever the last check said
+                    return Some((index, stream));
+                }
+                Err(e) => backend.mark_down(&e),
+            }
+        }
+        None
+    }
+}
+
+/// A load-balancing front end for several `Server`s
+pub struct Proxy {
+    listener: TcpListener,
+    is_running: Arc<AtomicBool>,
+    pool: Arc<Pool>,
+    health_interval: Duration,
+}
+
+impl Proxy {
+    /// Binds the proxy to `addr`; backends are only contacted once `run` starts
+    pub fn new(addr: &str, config: ProxyConfig) -> io::Result<Self> {
+        if config.backends.is_empty() {
+            return Err(io::Error::new(ErrorKind::InvalidInput, "a proxy needs at least one backend"));
+        }
+        let backends = config
+            .backends
+            .into_iter()
+            .map(|addr| Backend { addr, healthy: AtomicBool::new(true), connections: AtomicUsize::new(0) })
+            .collect();
+        Ok(Proxy {
+            listener: TcpListener::bind(addr)?,
+            is_running: Arc::new(AtomicBool::new(true)), // Like `Server`, a `stop` before `run` is not lost
+            pool: Arc::new(Pool { backends, balance: config.balance, next: AtomicUsize::new(0), timeout: config.timeout }),
+            health_interval: config.health_interval,
+        })
+    }
+
+    /// Returns the address the proxy is bound to, useful when binding to port 0
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+
+    /// Health and load of every backend, in configuration order
+    pub fn backends(&self) -> Vec<BackendStatus> {
+        self.pool
+            .backends
+            .iter()
+            .map(|backend| BackendStatus {
+                addr: backend.addr.clone(),
+                healthy: backend.healthy.load(Ordering::SeqCst),
+                connections: backend.connections.load(Ordering::SeqCst),
+            })
+            .collect()
+    }
+
+    /// Stops accepting connections and checking backends
+    pub fn stop(&self) {
+        self.is_running.store(false, Ordering::SeqCst);
+        info!("Proxy shutdown signal sent.");
+    }
+
+    /// Checks the backends and forwards connections until `stop` is called
+    pub fn run(&self) -> io::Result<()> {
+        info!("Proxy is running on {}", self.listener.local_addr()?);
+        self.listener.set_nonblocking(true)?;
+        let checker = {
+            let (pool, is_running, interval) = (self.pool.clone(), self.is_running.clone(), self.health_interval);
+            thread::spawn(move || check_health(&pool, &is_running, interval))
+        };
+
+        while self.is_running.load(Ordering::SeqCst) {
+            match self.listener.accept() {
+                Ok((stream, addr)) => {
+                    info!("Proxying client {}", addr);
+                    if let Err(e) = stream.set_nonblocking(false) { // Connection threads use blocking reads
+                        warn!("Dropping client {}: {}", addr, e);
+                        continue;
+                    }
+                    let pool = self.pool.clone();
+                    thread::spawn(move || {
+                        if let Err(e) = ProxyConnection::new(stream, pool).handle() {
+                            warn!("Error proxying client {}: {}", addr, e);
+                        }
+                    });
+                }
+                Err(ref e) if e.kind() == ErrorKind::WouldBlock => thread::sleep(Duration::from_millis(100)),
+                Err(e) => error!("Error accepting connection: {}", e),
+            }
+        }
+
+        let _ = checker.join();
+        info!("Proxy stopped.");
+        Ok(())
+    }
+}
+
+// Pings every backend each `interval`, until the proxy stops
+fn check_health(pool: &Pool, is_running: &AtomicBool, interval: Duration) {
+    while is_running.load(Ordering::SeqCst) {
+        for backend in &pool.backends {
+            match backend.check(pool.timeout) {
+                Ok(elapsed) => {
+                    if !backend.healthy.swap(true, Ordering::SeqCst) {
+                        info!("Backend {} is back up, answered a ping in {:?}", backend.addr, elapsed);
+                    }
+                }
+                Err(e) => backend.mark_down(&e),
+            }
+        }
+        let next = Instant::now() + interval;
+        while is_running.load(Ordering::SeqCst) && Instant::now() < next {
+            thread::sleep(Duration::from_millis(20).min(interval)); // Short naps so `stop` is not held up
+        }
+    }
+}
+
+// The backend connection a client is currently forwarded to
+struct Upstream {
+    index: usize, // Into `Pool::backends`
+    stream: TcpStream,
+    alive: Arc<AtomicBool>, // Cleared by the relay thread when the backend goes away
+    pending: Arc<Mutex<HashSet<u64>>>, // Request ids sent but not answered yet
+    relay: thread::JoinHandle<()>,
+}
+
+// One proxied client connection
+struct ProxyConnection {
+    stream: TcpStream,
+    pool: Arc<Pool>,
+    upstream: Option<Upstream>,
+}
+
+impl ProxyConnection {
+    fn new(stream: TcpStream, pool: Arc<Pool>) -> Self {
+        ProxyConnection { stream, pool, upstream: None }
+    }
+
+    // Same shape as a server connection: a writer thread sends what the relays queue
+    fn handle(&mut self) -> io::Result<()> {
+        let (outbound, queue) = mpsc::channel::<ServerMessage>();
+        let mut writer_stream = self.stream.try_clone()?;
+        let writer = thread::spawn(move || -> io::Result<()> {
+            for message in queue {
+                frame::write_message(&mut writer_stream, &message)?;
+            }
+            Ok(())
+        });
+
+        let result = self.serve(&outbound);
+        self.release(false);
+        drop(outbound);
+        let written = writer
+            .join()
+            .unwrap_or_else(|_| Err(io::Error::other("writer thread panicked")));
+        result.and(written)
+    }
+
+    // Read loop: forward every request to the current backend
+    fn serve(&mut self, outbound: &mpsc::Sender<ServerMessage>) -> io::Result<()> {
+        let mut first = true;
+        while let Some(request) = frame::read_message::<_, ClientMessage>(&mut self.stream)? {
+            if first && matches!(request.message, Some(client_message::Message::Hello(_))) {
+                // Plain frames, whatever was offered
+                let response = ServerMessage {
+                    request_id: request.request_id,
+                    message: Some(server_message::Message::HelloResponse(HelloResponse::default())),
+                };
+                let _ = outbound.send(response);
+                first = false;
+                continue;
+            }
+            first = false;
+            self.forward(&request, outbound);
+        }
+        info!("Proxied client disconnected.");
+        Ok(())
+    }
+
+    // Sends `request` to the backend, failing over to another one if it is gone
+    fn forward(&mut self, request: &ClientMessage, outbound: &mpsc::Sender<ServerMessage>) {
+        let payload = request.encode_to_vec();
+        for _ in 0..self.pool.backends.len() {
+            if !self.upstream.as_ref().is_some_and(|upstream| upstream.alive.load(Ordering::SeqCst)) {
+                self.release(false); // Its relay already answered what was in flight
+                match self.pool.connect().map(|(index, stream)| self.open(index, stream, outbound.clone())) {
+                    Some(Ok(upstream)) => self.upstream = Some(upstream),
+                    Some(Err(e)) => {
+                        error!("Failed to relay from a backend: {}", e);
+                        continue;
+                    }
+                    None => break,
+                }
+            }
+            let upstream = self.upstream.as_mut().expect("connected above");
+            if request.request_id != 0 {
+                upstream.pending.lock().unwrap().insert(request.request_id);
+            }
+            match frame::write_frame(&mut upstream.stream, &payload) {
+                Ok(()) => return,
+                Err(e) => {
+                    // Not delivered, so it is safe to send it to another backend
+                    upstream.pending.lock().unwrap().remove(&request.request_id);
+                    self.pool.backends[upstream.index].mark_down(&e);
+                    self.release(true);
+                }
+            }
+        }
+        let mut error = handler::error_message(ErrorCode::Unavailable, "no backend is available");
+        error.request_id = request.request_id;
+        let _ = outbound.send(error);
+    }
+
+    // Starts relaying what the backend sends on `stream` to the client
+    fn open(&self, index: usize, stream: TcpStream, outbound: mpsc::Sender<ServerMessage>) -> io::Result<Upstream> {
+        let mut reader = stream.try_clone()?;
+        let backend = &self.pool.backends[index];
+        backend.connections.fetch_add(1, Ordering::SeqCst);
+        info!("Forwarding a client to backend {}", backend.addr);
+        let alive = Arc::new(AtomicBool::new(true));
+        let pending = Arc::new(Mutex::new(HashSet::new()));
+        let relay = {
+            let (pool, alive, pending) = (self.pool.clone(), alive.clone(), pending.clone());
+            thread::spawn(move || {
+                let ended = loop {
+                    match frame::read_message::<_, ServerMessage>(&mut reader) {
+                        Ok(Some(message)) => {
+                            let finished = !matches!(message.message, Some(server_message::Message::StreamItem(_)));
+                            if finished {
+                                pending.lock().unwrap().remove(&message.request_id);
+                            }
+                            if outbound.send(message).is_err() {
+                                return; // The client is gone
+                            }
+                        }
+                        Ok(None) => break io::Error::new(ErrorKind::ConnectionAborted, "backend closed the connection"),
+                        Err(e) => break e,
+                    }
+                };
+                if !alive.swap(false, Ordering::SeqCst) {
+                    return; // Closed by the proxy
+                }
+                pool.backends[index].mark_down(&ended);
+                for request_id in pending.lock().unwrap().drain() {
+                    let mut error = handler::error_message(ErrorCode::Unavailable, format!("backend went away: {}", ended));
+                    error.request_id = request_id;
+                    let _ = outbound.send(error);
+                }
+            })
+        };
+        Ok(Upstream { index, stream, alive, pending, relay })
+    }
+
+    // Closes the backend connection, if any. Unless it `failed`, requests still in flight are dropped quietly.
+    fn release(&mut self, failed: bool) {
+        if let Some(upstream) = self.upstream.take() {
+            if !failed {
+                upstream.alive.store(false, Ordering::SeqCst); // Tells the relay the proxy closed it
+            }
+            let _ = upstream.stream.shutdown(Shutdown::Both); // Ends the relay's read
+            let _ = upstream.relay.join();
+            self.pool.backends[upstream.index].connections.fetch_sub(1, Ordering::SeqCst);
+        }
+    }
+}
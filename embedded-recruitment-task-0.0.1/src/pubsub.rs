@@ -0,0 +1,210 @@
+// Topic registry that routes published payloads to subscribed connections.
+//
+// Topics are `/`-separated levels such as `sensors/kitchen/temperature`.
+// Subscription patterns may use MQTT-style wildcards: `+` matches exactly one
+// level and `#` (only allowed as the last level) matches any number of
+// remaining levels, including none.
+use crate::message::{server_message, Publication, ServerMessage};
+use crate::outbound::Outbound;
+use log::{debug, warn};
+use std::{
+    collections::{HashMap, HashSet},
+    fmt,
+    sync::Mutex,
+};
+
+/// Separator between topic levels
+pub const LEVEL_SEPARATOR: char = '/';
+/// Wildcard matching exactly one topic level
+pub const SINGLE_LEVEL_WILDCARD: &str = "+";
+/// Wildcard matching all remaining topic levels
+pub const MULTI_LEVEL_WILDCARD: &str = "#";
+
+/// Reasons a topic name or subscription pattern is rejected
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TopicError {
+    /// The topic or pattern is an empty string
+    Empty,
+    /// A topic used for publishing contains a wildcard
+    WildcardInTopic(String),
+    /// A wildcard is mixed with other characters inside one level, e.g. `a/b+`
+    PartialWildcard(String),
+    /// `#` appears somewhere other than the last level
+    MultiLevelNotLast(String),
+}
+
+impl fmt::Display for TopicError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TopicError::Empty => write!(f, "topic must not be empty"),
+            TopicError::WildcardInTopic(topic) => {
+                write!(f, "topic '{}' must not contain wildcards", topic)
+            }
+            TopicError::PartialWildcard(pattern) => {
+                write!(f, "pattern '{}' has a wildcard that does not fill a whole level", pattern)
+            }
+            TopicError::MultiLevelNotLast(pattern) => {
+                write!(f, "pattern '{}' uses '#' before the last level", pattern)
+            }
+        }
+    }
+}
+
+impl std::error::Error for TopicError {}
+
+/// Checks that `topic` is a concrete topic name that can be published to
+pub fn validate_topic(topic: &str) -> Result<(), TopicError> {
+    if topic.is_empty() {
+        return Err(TopicError::Empty);
+    }
+    if topic.contains(['+', '#']) {
+        return Err(TopicError::WildcardInTopic(topic.to_string()));
+    }
+    Ok(())
+}
+
+/// Checks that `pattern` is a well-formed subscription pattern
+pub fn validate_pattern(pattern: &str) -> Result<(), TopicError> {
+    if pattern.is_empty() {
+        return Err(TopicError::Empty);
+    }
+
+    let levels: Vec<&str> = pattern.split(LEVEL_SEPARATOR).collect();
+    for (index, level) in levels.iter().enumerate() {
+        if *level == MULTI_LEVEL_WILDCARD {
+            if index != levels.len() - 1 {
+                return Err(TopicError::MultiLevelNotLast(pattern.to_string()));
+            }
+        } else if *level != SINGLE_LEVEL_WILDCARD && level.contains(['+', '#']) {
+            return Err(TopicError::PartialWildcard(pattern.to_string()));
+        }
+    }
+    Ok(())
+}
+
+/// Returns true when `topic` is matched by the subscription `pattern`
+pub fn topic_matches(pattern: &str, topic: &str) -> bool {
+    let mut pattern_levels = pattern.split(LEVEL_SEPARATOR);
+    let mut topic_levels = topic.split(LEVEL_SEPARATOR);
+
+    loop {
+        match (pattern_levels.next(), topic_levels.next()) {
+            (Some(MULTI_LEVEL_WILDCARD), _) => return true, // Matches everything that is left
+            (Some(SINGLE_LEVEL_WILDCARD), Some(_)) => continue,
+            (Some(expected), Some(actual)) if expected == actual => continue,
+            (None, None) => return true, // Both exhausted at the same level
+            _ => return false,
+        }
+    }
+}
+
+// Per-connection subscription state
+struct Subscriber {
+    outbound: Outbound, // Queue drained by the connection's writer thread
+    patterns: HashSet<String>,       // Patterns this connection is subscribed to
+}
+
+/// Shared registry of subscriptions, keyed by connection id
+#[derive(Default)]
+pub struct TopicRegistry {
+    subscribers: Mutex<HashMap<u64, Subscriber>>,
+}
+
+impl TopicRegistry {
+    /// Creates an empty registry
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Subscribes connection `id` to `pattern`, publications are pushed into `outbound`.
+    ///
+    /// Returns `Ok(false)` if the connection was already subscribed to that exact pattern.
+    pub fn subscribe(
+        &self,
+        id: u64,
+        pattern: &str,
+        outbound: &Outbound,
+    ) -> Result<bool, TopicError> {
+        validate_pattern(pattern)?;
+
+        let mut subscribers = self.subscribers.lock().unwrap();
+        let subscriber = subscribers.entry(id).or_insert_with(|| Subscriber {
+            outbound: outbound.clone(),
+            patterns: HashSet::new(),
+        });
+        let added = subscriber.patterns.insert(pattern.to_string());
+        debug!("Connection {} subscribed to '{}' (new: {})", id, pattern, added);
+        Ok(added)
+    }
+
+    /// Removes the subscription of connection `id` to `pattern`, returns whether it existed
+    pub fn unsubscribe(&self, id: u64, pattern: &str) -> bool {
+        let mut subscribers = self.subscribers.lock().unwrap();
+        let Some(subscriber) = subscribers.get_mut(&id) else {
+            return false;
+        };
+
+        let removed = subscriber.patterns.remove(pattern);
+        if subscriber.patterns.is_empty() {
+            subscribers.remove(&id); // Drop the sender so the writer is not kept alive by us
+        }
+        removed
+    }
+
+    /// Drops every subscription held by connection `id`
+    pub fn remove_connection(&self, id: u64) {
+        if self.subscribers.lock().unwrap().remove(&id).is_some() {
+            debug!("Removed subscriptions of connection {}", id);
+        }
+    }
+
+    /// Routes `payload` to every connection with a pattern matching `topic`.
+    ///
+    /// Each connection receives one copy even if several of its patterns match.
+    /// Returns the number of connections the publication was delivered to.
+    pub fn publish(&self, topic: &str, payload: &[u8]) -> Result<u32, TopicError> {
+        validate_topic(topic)?;
+
+        let publication = ServerMessage {
+            request_id: 0, // Pushed, not a reply to any request
+            message: Some(server_message::Message::Publication(Publication {
+                topic: topic.to_string(),
+                payload: payload.to_vec(),
+            })),
+        };
+
+        let mut subscribers = self.subscribers.lock().unwrap();
+        let mut delivered = 0;
+        let mut disconnected = Vec::new();
+        for (id, subscriber) in subscribers.iter() {
+            if !subscriber.patterns.iter().any(|pattern| topic_matches(pattern, topic)) {
+                continue;
+            }
+            if subscriber.outbound.send(publication.clone()).is_ok() {
+                delivered += 1;
+            } else {
+                disconnected.push(*id); // The connection is closing, or was dropped for not reading
+            }
+        }
+
+        for id in disconnected {
+            warn!("Dropping subscriptions of disconnected connection {}", id);
+            subscribers.remove(&id);
+        }
+        Ok(delivered)
+    }
+
+    /// Number of subscriptions currently held by connection `id`
+    pub fn subscription_count(&self, id: u64) -> usize {
+        self.subscribers
+            .lock()
+            .unwrap()
+            .get(&id)
+            .map_or(0, |subscriber| subscriber.patterns.len())
+    }
+
+    /// Number of connections with at least one subscription
+    pub fn connection_count(&self) -> usize {
+        self.subscribers.lock().unwrap().len()
+    }
+}
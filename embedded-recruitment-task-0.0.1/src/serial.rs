@@ -0,0 +1,160 @@
+// Serial-line transport for a microcontroller on a UART link.
+//
+// Messages are framed with COBS or SLIP and a CRC32C, as implemented by the
+// wire crate and re-exported here; see `wire::serial`. Firmware uses the
+// same crate, without `std`, on the other end of the line.
+//
+// The link is served like one long-lived connection: it keeps a single
+// session, so a device can authenticate once and receive stream frames and
+// publications. A frame that fails its checksum is answered with
+// `ERROR_CODE_CHECKSUM_MISMATCH` and request id 0, like a corrupt TCP frame.
+use crate::audit::Outcome;
+use crate::frame::MAX_FRAME_LEN;
+use crate::handler::{self, Handler, Session};
+use crate::message::{ClientMessage, ErrorCode};
+use crate::policy;
+use crate::server::ConnectionOptions;
+use log::{error, info, warn};
+use prost::Message;
+use serialport::SerialPort;
+use std::{
+    io::{self, ErrorKind, Write},
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    thread,
+    time::{Duration, Instant, SystemTime},
+};
+pub use wire::serial::{encode_frame, encode_frame_into, read_message, write_message, FrameDecoder, FrameError, Framing, CHECKSUM_LEN};
+
+// How often the read loop checks whether the server is still running
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Settings of the serial link
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SerialConfig {
+    /// Device to open, e.g. `/dev/ttyUSB0`
+    pub path: String,
+    /// Line speed in bits per second
+    pub baud_rate: u32,
+    /// Framing used in both directions
+    pub framing: Framing,
+    /// Largest message accepted or sent, in bytes before framing
+    pub max_frame: usize,
+}
+
+impl SerialConfig {
+    /// Opens `path` at 115200 baud with COBS framing and the TCP frame limit
+    pub fn new(path: impl Into<String>) -> Self {
+        SerialConfig {
+            path: path.into(),
+            baud_rate: 115_200,
+            framing: Framing::Cobs,
+            max_frame: MAX_FRAME_LEN,
+        }
+    }
+}
+
+/// The serial port a server answers on, served by `run` on a thread of its own
+pub(crate) struct SerialLink {
+    port: Mutex<Box<dyn SerialPort>>, // Read by `run` only
+    config: SerialConfig,
+    handler: Arc<Handler>,
+    options: ConnectionOptions,
+    ids: Arc<AtomicU64>, // The link draws its session id from the connection ids
+}
+
+impl SerialLink {
+    /// Opens and configures the port
+    pub(crate) fn open(config: SerialConfig, handler: Arc<Handler>, options: ConnectionOptions, ids: Arc<AtomicU64>) -> io::Result<Self> {
+        let port = serialport::new(&config.path, config.baud_rate).timeout(POLL_INTERVAL).open()?;
+        Ok(SerialLink { port: Mutex::new(port), config, handler, options, ids })
+    }
+
+    /// Serves the link as one connection until `is_running` is cleared
+    pub(crate) fn run(&self, is_running: &AtomicBool) -> io::Result<()> {
+        let mut port = self.port.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        let (outbound, queue) = self.options.outbound();
+        let mut writer_port = port.try_clone()?;
+        let (framing, max_frame) = (self.config.framing, self.config.max_frame);
+        let writer = thread::spawn(move || -> io::Result<()> {
+            for message in queue {
+                let mut payload = message.encode_to_vec();
+                if payload.len() > max_frame {
+                    let mut error = handler::error_message(
+                        ErrorCode::InvalidArgument,
+                        format!("reply of {} bytes exceeds the {} byte frame limit", payload.len(), max_frame),
+                    );
+                    error.request_id = message.request_id;
+                    payload = error.encode_to_vec();
+                }
+                writer_port.write_all(&encode_frame(framing, &payload))?; // One write so frames never interleave
+                writer_port.flush()?;
+            }
+            Ok(())
+        });
+
+        let session = Session::new(self.ids.fetch_add(1, Ordering::Relaxed), outbound);
+        let result = self.serve(&mut **port, &session, is_running);
+        self.handler.disconnect(&session); // Same cleanup as a closed connection
+        drop(session);
+        let written = writer
+            .join()
+            .unwrap_or_else(|_| Err(io::Error::other("writer thread panicked")));
+        result.and(written)
+    }
+
+    // Read loop: collect frames, handle them and queue the replies
+    fn serve(&self, port: &mut dyn SerialPort, session: &Session, is_running: &AtomicBool) -> io::Result<()> {
+        let mut decoder = FrameDecoder::new(self.config.framing, self.config.max_frame);
+        let mut buffer = [0u8; 1024];
+        while is_running.load(Ordering::SeqCst) {
+            let len = match port.read(&mut buffer) {
+                Ok(0) => continue, // Nothing arrived within the timeout on some platforms
+                Ok(len) => len,
+                Err(e) if matches!(e.kind(), ErrorKind::TimedOut | ErrorKind::WouldBlock | ErrorKind::Interrupted) => continue,
+                Err(e) => return Err(e),
+            };
+            for &byte in &buffer[..len] {
+                let Some(frame) = decoder.push(byte) else {
+                    continue;
+                };
+                let arrived = (SystemTime::now(), Instant::now());
+                let response = match frame {
+                    Ok(payload) => match ClientMessage::decode(payload) {
+                        Ok(client_message) => {
+                            let request_id = client_message.request_id;
+                            let operation = client_message.message.as_ref().map_or("empty", policy::message_name);
+                            // There is no frame format to negotiate, a Hello is refused by the handler
+                            let response = self.handler.handle(session, client_message);
+                            self.options.audit(session, None, request_id, operation, Outcome::of(response.as_ref()), arrived);
+                            response
+                        }
+                        Err(e) => {
+                            error!("Failed to decode ClientMessage from {}: {}", self.config.path, e);
+                            Some(handler::error_message(ErrorCode::InvalidArgument, format!("invalid ClientMessage: {}", e)))
+                        }
+                    },
+                    Err(FrameError::ChecksumMismatch) => {
+                        // The request id cannot be trusted, so the error carries none
+                        warn!("Corrupt frame on {}", self.config.path);
+                        self.options.audit(session, None, 0, "corrupt_frame", Outcome::Error(ErrorCode::ChecksumMismatch), arrived);
+                        Some(handler::error_message(ErrorCode::ChecksumMismatch, FrameError::ChecksumMismatch.to_string()))
+                    }
+                    Err(e) => {
+                        warn!("Dropped a frame on {}: {}", self.config.path, e);
+                        Some(handler::error_message(ErrorCode::InvalidArgument, e.to_string()))
+                    }
+                };
+                if let Some(response) = response {
+                    if session.outbound.send(response).is_err() {
+                        info!("Serial writer for {} stopped", self.config.path);
+                        return Ok(()); // The writer failed, its error is reported by `run`
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+}
@@ -1,80 +1,247 @@
 // Importing necessary modules and structs for message handling and logging
-use crate::message::{AddResponse, ClientMessage, ServerMessage}; // Import message types
-use crate::message::server_message; // Import the server's message module
-use crate::message::client_message::Message as ClientMessageType; // Import client message type
+use crate::audit::{AuditRecord, AuditSink, Outcome}; // Record of every handled request
+use crate::auth::Authenticator; // Pluggable credential checks
+use crate::blob::{BlobConfig, BlobStore}; // Chunked file transfer storage
+use crate::capture::{self, CaptureConfig, CaptureWriter, Direction}; // Recording of each connection's frames
+use crate::codec::{self, CompressionConfig, CompressionStats, FrameCodec, FrameStats}; // Negotiated frame format
+use crate::frame; // Length-prefixed framing shared with clients
+use crate::handler::{self, Handler, Session}; // Transport-independent request handlers
+use crate::inflight::PendingRequest; // Requests read but not answered yet
+#[cfg(feature = "grpc")]
+use crate::grpc::GrpcServer; // Optional gRPC server
+use crate::http; // Optional HTTP gateway
+use crate::json; // JSON form of the messages for the line-delimited listener
+use crate::message::{client_message, server_message, ClientMessage, ErrorCode, Hello, HelloResponse, ServerMessage}; // Import message types
+use crate::outbound::{self, BackpressureConfig, Outbound, OutboundReceiver}; // Bounded queues of what each connection is sent
+use crate::pubsub::TopicRegistry; // Shared topic subscriptions
+use crate::persistence::PersistenceConfig; // Optional durable storage for the store
+use crate::policy::{self, Policy}; // Per-operation authorization
+use crate::serial::{SerialConfig, SerialLink}; // Framed messages over a UART
+use crate::store::KeyValueStore; // Shared key-value store
+use crate::udp::{UdpConfig, UdpListener}; // Connectionless requests
 use log::{error, info,warn}; // Import logging macros
 use prost::Message; // Import Prost for message encoding/decoding
+use tungstenite::{protocol::WebSocketConfig, Message as WsMessage, WebSocket}; // WebSocket transport for browsers
 use std::{
-    io::{self, ErrorKind, Read, Write}, // Import IO functionality for reading and writing
-    net::{TcpListener, TcpStream}, // Import TCP listener and stream for network communication
-    sync::{atomic::{AtomicBool, Ordering}, Arc}, // Import synchronization tools for atomic operations and shared ownership
+    io::{self, BufRead, BufReader, ErrorKind, Read, Write}, // Import IO functionality for reading and writing
+    net::{Shutdown, SocketAddr, TcpListener, TcpStream}, // Import TCP listener and stream for network communication
+    sync::{atomic::{AtomicBool, AtomicU64, Ordering}, mpsc, Arc}, // Import synchronization tools for atomic operations and shared ownership
     thread, // Import thread handling for concurrent execution
-    time::Duration, // Import duration type for thread sleep
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH}, // Import duration type for thread sleep, clocks for audit records
 };
 
+// How long a WebSocket connection waits for a message before sending what is queued
+const WS_POLL_INTERVAL: Duration = Duration::from_millis(20);
+
+// Requests a framed connection reads ahead of its worker, reading waits beyond that
+const MAX_QUEUED_REQUESTS: usize = 64;
+
+// Settings every connection of a server starts with, also used by the HTTP gateway
+#[derive(Clone)]
+pub(crate) struct ConnectionOptions {
+    compression: CompressionConfig, // What a Hello may negotiate
+    checksums: bool, // Whether a Hello may turn on frame checksums
+    stats: Arc<FrameStats>, // Compression counters shared by all connections
+    audit: Option<Arc<dyn AuditSink>>, // Receives a record of every request
+    capture: Option<CaptureConfig>, // Where to record each connection's frames
+    backpressure: BackpressureConfig, // Limits of each connection's outbound queue
+    handshake_timeout: Duration, // How long a WebSocket or JSON client may take to send anything
+}
+
+impl ConnectionOptions {
+    // Creates the outbound queue of a new connection
+    pub(crate) fn outbound(&self) -> (Outbound, OutboundReceiver) {
+        outbound::channel(self.backpressure.clone())
+    }
+
+    // Same, and closes `stream` when the client is disconnected for not reading, which stops both of its threads
+    fn outbound_for(&self, stream: &TcpStream) -> io::Result<(Outbound, OutboundReceiver)> {
+        let (outbound, queue) = self.outbound();
+        let socket = stream.try_clone()?;
+        outbound.on_disconnect(move || {
+            let _ = socket.shutdown(Shutdown::Both);
+        });
+        Ok((outbound, queue))
+    }
+
+    // Hands a record of one request to the audit sink, if there is one
+    pub(crate) fn audit(&self, session: &Session, peer: Option<SocketAddr>, request_id: u64, operation: &'static str, outcome: Outcome, arrived: (SystemTime, Instant)) {
+        let Some(audit) = &self.audit else {
+            return;
+        };
+        let (time, started) = arrived;
+        audit.record(&AuditRecord {
+            time_ms: time.duration_since(UNIX_EPOCH).map_or(0, |since| since.as_millis() as u64),
+            connection: session.id,
+            peer,
+            identity: session.auth.identity(),
+            request_id,
+            operation,
+            outcome,
+            duration: started.elapsed(),
+        });
+    }
+}
+
 // Define the Client structure with a TCP stream for communication
 struct Client {
+    id: u64, // Unique connection id, used as the key for per-connection state
     stream: TcpStream, // TCP stream to interact with the client
+    handler: Arc<Handler>, // Request handlers shared by every connection
+    options: ConnectionOptions, // Negotiation limits, counters and recording
+    codec: FrameCodec, // Format of incoming frames, plain until a Hello is answered
+    peer: Option<SocketAddr>, // Remote address, for audit records and captures
+    capture: Option<Arc<CaptureWriter>>, // Recording of this connection, shared with the writer thread
 }
 
 impl Client {
     // Client constructor to create a new client from a given TCP stream
-    pub fn new(stream: TcpStream) -> Self {
-        Client { stream } // Return a new Client instance
+    pub fn new(id: u64, stream: TcpStream, handler: Arc<Handler>, options: ConnectionOptions) -> Self {
+        let peer = stream.peer_addr().ok();
+        Client { id, stream, handler, options, codec: FrameCodec::default(), peer, capture: None } // Return a new Client instance
     }
 
     // Handle communication with the client
     pub fn handle(&mut self) -> io::Result<()> {
-        let mut buffer = [0; 512]; // Buffer to store incoming data
+        if let Some(config) = &self.options.capture {
+            match CaptureWriter::create(config, self.id, self.peer) {
+                Ok(capture) => self.capture = Some(Arc::new(capture)),
+                Err(e) => error!("Failed to start capturing connection {}: {}", self.id, e), // Serve it anyway
+            }
+        }
+
+        // Every outbound frame goes through one queue so replies and pushed
+        // publications from other connections never interleave on the socket
+        let (outbound, queue) = self.options.outbound_for(&self.stream)?;
+        let mut writer_stream = self.stream.try_clone()?;
+        let threshold = self.options.compression.threshold;
+        let stats = self.options.stats.clone();
+        let capture = self.capture.clone();
+        let writer = thread::spawn(move || -> io::Result<()> {
+            let mut codec = FrameCodec::default();
+            for message in queue {
+                let payload = match codec.encode(message.encode_to_vec(), &stats.sent) {
+                    Ok(payload) => payload,
+                    Err(e) => { // Too large for a frame, answered with an error so the connection carries on
+                        let mut error = handler::error_message(ErrorCode::InvalidArgument, format!("reply could not be sent: {}", e));
+                        error.request_id = message.request_id;
+                        codec.encode(error.encode_to_vec(), &stats.sent)?
+                    }
+                };
+                if let Some(capture) = &capture {
+                    capture.record(Direction::Outbound, &payload);
+                }
+                frame::write_frame(&mut writer_stream, &payload)?; // Send the encoded response back to the client
+                // The HelloResponse itself goes out in the old format, everything after it in the new one
+                if let Some(server_message::Message::HelloResponse(hello)) = &message.message {
+                    codec = FrameCodec::new(hello.compression(), threshold).with_checksums(hello.checksums);
+                }
+            }
+            Ok(())
+        });
+
+        let session = Session::new(self.id, outbound);
+        let result = self.serve(&session);
+
+        // Cleanup runs however the read loop ended, so no publication is routed to a dead connection
+        // and running streams stop
+        self.handler.disconnect(&session);
+        drop(session); // Once the streams drop their senders too, the writer drains its queue and exits
+        let written = writer
+            .join()
+            .unwrap_or_else(|_| Err(io::Error::other("writer thread panicked")));
+        result.and(written)
+    }
+
+    // Reads requests on this thread and runs them in order on a worker, so a Cancel or a deadline can stop one that is queued or running
+    fn serve(&mut self, session: &Session) -> io::Result<()> {
+        let (handler, options, peer) = (self.handler.clone(), self.options.clone(), self.peer);
+        thread::scope(|scope| {
+            let (queue, requests) = mpsc::sync_channel::<Queued>(MAX_QUEUED_REQUESTS);
+            scope.spawn(|| session.requests.watch(&session.outbound)); // Answers requests whose deadline passes
+            let worker = scope.spawn(move || run_requests(&handler, session, &options, peer, requests));
+            self.read_requests(session, &queue);
+            drop(queue); // What was read before the client left still runs
+            let _ = worker.join();
+            session.requests.close();
+        });
+        Ok(()) // Return success
+    }
+
+    // Read loop: decode framed requests and queue them for the worker
+    fn read_requests(&mut self, session: &Session, queue: &mpsc::SyncSender<Queued>) {
+        let mut first = true; // Only the first message may be a Hello
         loop {
-            // Read data from the client
-            let bytes_read = match self.stream.read(&mut buffer) {
-                Ok(0) => {
+            // Requests stay in the socket while the client is not reading its replies
+            if let Err(e) = session.outbound.wait_for_room() {
+                warn!("Connection {} stopped reading requests: {}", self.id, e);
+                break;
+            }
+
+            // Read one frame from the client
+            let payload = match frame::read_frame(&mut self.stream) {
+                Ok(Some(payload)) => payload,
+                Ok(None) => {
                     info!("Client disconnected."); // Log when the client disconnects
                     break; // Exit the loop if no data is received (client disconnected)
                 }
-                Ok(n) => n, // Successfully read 'n' bytes from the stream
-                Err(ref e) if e.kind() == ErrorKind::WouldBlock => {
-                    thread::sleep(Duration::from_millis(10)); // If no data, sleep briefly and try again
-                    continue;
-                }
                 Err(e) => {
                     error!("Failed to read from client: {}", e); // Log error if reading fails
                     break; // Exit the loop on error
                 }
             };
-    
-            // Decode the received client message
-            match ClientMessage::decode(&buffer[..bytes_read]) {
+
+            let arrived = (SystemTime::now(), Instant::now()); // Wall clock for the record, monotonic for the duration
+
+            // Undo the negotiated frame format, then decode the received client message
+            let recorded = self.capture.as_ref().map(|_| payload.clone());
+            let decoded = self
+                .codec
+                .decode(payload, &self.options.stats.received)
+                .and_then(|message| ClientMessage::decode(message.as_slice()).map_err(io::Error::other));
+            if let (Some(capture), Some(payload)) = (&self.capture, recorded) {
+                // An Authenticate is recorded without its credential, re-encoded in the same frame format
+                match decoded.as_ref().ok().and_then(capture::redact) {
+                    Some(redacted) => match self.codec.encode(redacted.encode_to_vec(), &CompressionStats::default()) {
+                        Ok(payload) => capture.record(Direction::Inbound, &payload),
+                        Err(e) => error!("Failed to redact a frame of connection {}: {}", self.id, e), // Left out of the capture
+                    },
+                    None => capture.record(Direction::Inbound, &payload),
+                }
+            }
+            match decoded {
+                Ok(ClientMessage { request_id, message: Some(client_message::Message::Hello(hello)), .. }) if first => {
+                    first = false;
+                    let sent = self.hello(session, request_id, &hello);
+                    self.options.audit(session, self.peer, request_id, "hello", Outcome::Ok, arrived);
+                    if !sent {
+                        break; // Writer thread failed, the connection is unusable
+                    }
+                }
+                Ok(client_message @ ClientMessage { message: Some(client_message::Message::Cancel(_)), .. }) => {
+                    first = false;
+                    let request_id = client_message.request_id;
+                    // Handled right away, ahead of the requests it may be about
+                    let response = self.handler.handle(session, client_message);
+                    self.options.audit(session, self.peer, request_id, "cancel", Outcome::of(response.as_ref()), arrived);
+                    if response.is_some_and(|response| session.outbound.send(response).is_err()) {
+                        break; // Writer thread failed, the connection is unusable
+                    }
+                }
                 Ok(client_message) => {
-                    match client_message.message { // Match on the decoded client message
-                        Some(ClientMessageType::EchoMessage(echo_message)) => {
-                            info!("Received EchoMessage: {}", echo_message.content); // Log EchoMessage content
-                            let response = ServerMessage {
-                                message: Some(server_message::Message::EchoMessage(echo_message)),
-                            };
-                            let payload = response.encode_to_vec(); // Encode response to byte vector
-                            self.stream.write_all(&payload)?; // Send the encoded response back to the client
-                            info!("EchoMessage sent back successfully.");
-                        }
-                        Some(ClientMessageType::AddRequest(add_request)) => {
-                            info!("Received AddRequest: a={}, b={}", add_request.a, add_request.b); // Log AddRequest
-                            let add_response = AddResponse {
-                                result: add_request.a + add_request.b, // Calculate result of addition
-                            };
-                            let response = ServerMessage {
-                                message: Some(server_message::Message::AddResponse(add_response)),
-                            };
-                            let payload = response.encode_to_vec(); // Encode AddResponse to byte vector
-                            self.stream.write_all(&payload)?; // Send the response to the client
-                            info!(
-                                "AddResponse sent successfully with result: {}",
-                                add_request.a + add_request.b // Log result of the addition
-                            );
-                        }
-                        None => {
-                            error!("Received an empty or unsupported ClientMessage."); // Log error if no valid message
-                        }
+                    first = false;
+                    let pending = session.requests.register(client_message.request_id, client_message.deadline_ms);
+                    if queue.send(Queued { request: client_message, pending, arrived }).is_err() {
+                        break; // Worker stopped because the writer thread failed
+                    }
+                }
+                Err(e) if codec::is_checksum_error(&e) => {
+                    // The frame is dropped, its request id cannot be trusted so the error carries none
+                    warn!("Connection {} sent a corrupt frame", self.id);
+                    let response = handler::error_message(ErrorCode::ChecksumMismatch, e.to_string());
+                    self.options.audit(session, self.peer, 0, "corrupt_frame", Outcome::Error(ErrorCode::ChecksumMismatch), arrived);
+                    if session.outbound.send(response).is_err() {
+                        break; // Writer thread failed, the connection is unusable
                     }
                 }
                 Err(e) => {
@@ -82,33 +249,482 @@ impl Client {
                 }
             }
         }
-        Ok(()) // Return success
+    }
+
+    // Answers a Hello and switches incoming frames to the negotiated format.
+    // Returns false if the reply could not be queued.
+    fn hello(&mut self, session: &Session, request_id: u64, hello: &Hello) -> bool {
+        let compression = codec::negotiate(&hello.compression, &self.options.compression.algorithms);
+        let checksums = hello.checksums && self.options.checksums;
+        info!("Connection {} negotiated {:?} compression, checksums {}", self.id, compression, checksums);
+        self.codec = FrameCodec::new(compression, self.options.compression.threshold).with_checksums(checksums);
+        let response = ServerMessage {
+            request_id,
+            message: Some(server_message::Message::HelloResponse(HelloResponse {
+                compression: compression as i32,
+                checksums,
+            })),
+        };
+        session.outbound.send(response).is_ok()
+    }
+}
+
+// A request waiting for the worker of a framed connection
+struct Queued {
+    request: ClientMessage,
+    pending: Arc<PendingRequest>, // Its entry in `Session::requests`
+    arrived: (SystemTime, Instant), // Wall clock for the record, monotonic for the duration and deadline
+}
+
+// Worker of a framed connection: runs queued requests one at a time, in arrival order
+fn run_requests(handler: &Handler, session: &Session, options: &ConnectionOptions, peer: Option<SocketAddr>, requests: mpsc::Receiver<Queued>) {
+    for Queued { request, pending, arrived } in requests {
+        let request_id = request.request_id;
+        let operation = request.message.as_ref().map_or("empty", policy::message_name);
+        // A request that changes something is protected before it starts, so it is never answered early once it runs
+        let preemptible = request.message.as_ref().is_none_or(handler::is_preemptible);
+        let response = if pending.token.interrupted().is_some() || !(preemptible || pending.protect()) {
+            // Cancelled or out of time while it waited, it is not run at all
+            pending.token.interrupted().map(|interrupt| ServerMessage { request_id, ..handler::interrupt_message(interrupt) })
+        } else {
+            handler.handle_interruptible(session, request, &pending.token) // Streams reply asynchronously and empty messages not at all
+        };
+        let claimed = pending.claim(); // From here on neither a Cancel nor the deadline answers it
+        session.requests.finish(&pending);
+
+        match response {
+            Some(response) if claimed => {
+                options.audit(session, peer, request_id, operation, Outcome::of(Some(&response)), arrived);
+                if session.outbound.send(response).is_err() {
+                    break; // Writer thread failed, the connection is unusable
+                }
+            }
+            Some(late) => {
+                // Already answered with an error, the late reply is dropped. Only preemptible requests get here.
+                let interrupted = pending.token.interrupted().map(handler::interrupt_message).unwrap_or(late);
+                options.audit(session, peer, request_id, operation, Outcome::of(Some(&interrupted)), arrived);
+            }
+            None => options.audit(session, peer, request_id, operation, Outcome::Ok, arrived),
+        }
+    }
+}
+
+// A connection to the JSON listener: one JSON message per line each way
+struct JsonClient {
+    id: u64, // Unique connection id, drawn from the same counter as framed connections
+    stream: TcpStream, // TCP stream to interact with the client
+    handler: Arc<Handler>, // Same request handlers as framed connections
+    options: ConnectionOptions, // Only the audit sink applies, JSON lines are neither compressed nor captured
+    peer: Option<SocketAddr>, // Remote address, for audit records
+}
+
+impl JsonClient {
+    pub fn new(id: u64, stream: TcpStream, handler: Arc<Handler>, options: ConnectionOptions) -> Self {
+        let peer = stream.peer_addr().ok();
+        JsonClient { id, stream, handler, options, peer }
+    }
+
+    // Same shape as `Client::handle`, with a writer thread turning replies into JSON lines
+    pub fn handle(&mut self) -> io::Result<()> {
+        let (outbound, queue) = self.options.outbound_for(&self.stream)?;
+        let mut writer_stream = self.stream.try_clone()?;
+        let writer = thread::spawn(move || -> io::Result<()> {
+            for message in queue {
+                let mut line = json::encode_server_message(&message);
+                line.push('\n');
+                writer_stream.write_all(line.as_bytes())?; // One write per line so lines never interleave
+            }
+            Ok(())
+        });
+
+        let session = Session::new(self.id, outbound);
+        let result = self.serve(&session);
+        self.handler.disconnect(&session); // Same cleanup as framed connections
+        drop(session);
+        let written = writer
+            .join()
+            .unwrap_or_else(|_| Err(io::Error::other("writer thread panicked")));
+        result.and(written)
+    }
+
+    // Read loop: parse JSON lines and queue the replies
+    fn serve(&mut self, session: &Session) -> io::Result<()> {
+        let mut lines = BufReader::new(self.stream.try_clone()?).take(0);
+        self.stream.set_read_timeout(Some(self.options.handshake_timeout))?; // A client that connects and stays silent is dropped
+        let mut first = true; // Once a line arrived the client may stay idle as long as it likes
+        loop {
+            // Same pause as framed connections while replies are not being read
+            if let Err(e) = session.outbound.wait_for_room() {
+                warn!("JSON connection {} stopped reading requests: {}", self.id, e);
+                break;
+            }
+            let mut line = String::new();
+            lines.set_limit(json::MAX_LINE_LEN as u64 + 1); // The limit applies to each line
+            match lines.read_line(&mut line) {
+                Ok(0) => {
+                    info!("JSON client disconnected.");
+                    break;
+                }
+                Ok(_) if !line.ends_with('\n') && line.len() > json::MAX_LINE_LEN => {
+                    warn!("JSON connection {} sent a line over {} bytes", self.id, json::MAX_LINE_LEN);
+                    let response = handler::error_message(ErrorCode::InvalidArgument, format!("lines are limited to {} bytes", json::MAX_LINE_LEN));
+                    let _ = session.outbound.send(response);
+                    break; // The rest of the line cannot be told apart from the next request
+                }
+                Ok(_) => {}
+                Err(e) if first && matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {
+                    warn!("JSON connection {} sent nothing within {:?}", self.id, self.options.handshake_timeout);
+                    break;
+                }
+                Err(e) => {
+                    error!("Failed to read from JSON client: {}", e);
+                    break;
+                }
+            }
+            if first {
+                self.stream.set_read_timeout(None)?;
+                first = false;
+            }
+            if line.trim().is_empty() {
+                continue; // Blank lines are allowed between requests
+            }
+
+            let arrived = (SystemTime::now(), Instant::now());
+            let response = match json::decode_client_message(&line) {
+                Ok(client_message) => {
+                    let request_id = client_message.request_id;
+                    let operation = client_message.message.as_ref().map_or("empty", policy::message_name);
+                    // Hello has nothing to negotiate on a JSON connection, the handler refuses it
+                    let response = self.handler.handle(session, client_message);
+                    self.options.audit(session, self.peer, request_id, operation, Outcome::of(response.as_ref()), arrived);
+                    response
+                }
+                Err(e) => {
+                    warn!("JSON connection {} sent an invalid line: {}", self.id, e);
+                    self.options.audit(session, self.peer, 0, "invalid_json", Outcome::Error(ErrorCode::InvalidArgument), arrived);
+                    Some(json::invalid_message(&e))
+                }
+            };
+            if let Some(response) = response {
+                if session.outbound.send(response).is_err() {
+                    break; // Writer thread failed, the connection is unusable
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+// A connection to the WebSocket listener: one protobuf message per binary WebSocket message
+struct WsClient {
+    id: u64, // Unique connection id, drawn from the same counter as framed connections
+    stream: TcpStream, // TCP stream, handed to the WebSocket once the handshake starts
+    handler: Arc<Handler>, // Same request handlers as framed connections
+    options: ConnectionOptions, // Only the audit sink applies, messages are neither compressed nor captured
+    peer: Option<SocketAddr>, // Remote address, for audit records
+}
+
+impl WsClient {
+    pub fn new(id: u64, stream: TcpStream, handler: Arc<Handler>, options: ConnectionOptions) -> Self {
+        let peer = stream.peer_addr().ok();
+        WsClient { id, stream, handler, options, peer }
+    }
+
+    // A WebSocket cannot be read and written from two threads, so one thread
+    // alternates between short reads and draining the outbound queue
+    pub fn handle(&mut self) -> io::Result<()> {
+        let config = WebSocketConfig {
+            max_message_size: Some(frame::MAX_FRAME_LEN), // Same limit as a frame on the TCP server
+            max_frame_size: Some(frame::MAX_FRAME_LEN),
+            ..WebSocketConfig::default()
+        };
+        let stream = self.stream.try_clone()?;
+        self.stream.set_read_timeout(Some(self.options.handshake_timeout))?; // A client that never finishes the handshake is dropped
+        let mut socket = tungstenite::accept_with_config(stream, Some(config)).map_err(|e| io::Error::other(e.to_string()))?;
+        self.stream.set_read_timeout(Some(WS_POLL_INTERVAL))?; // Lets the loop get back to the queue
+
+        // Reads never pause, this thread drains the queue itself. Streams still wait and the limits still apply.
+        let (outbound, queue) = self.options.outbound_for(&self.stream)?;
+        let session = Session::new(self.id, outbound);
+        let result = self.serve(&session, &mut socket, &queue);
+        self.handler.disconnect(&session); // Same cleanup as framed connections
+        result
+    }
+
+    // Read loop: decode binary messages, send replies and pushed messages as they are queued
+    fn serve(&mut self, session: &Session, socket: &mut WebSocket<TcpStream>, queue: &OutboundReceiver) -> io::Result<()> {
+        loop {
+            for message in queue.try_iter() {
+                socket.write(WsMessage::Binary(message.encode_to_vec())).map_err(ws_error)?;
+            }
+            socket.flush().map_err(ws_error)?; // Also sends pongs and close replies
+
+            let payload = match socket.read() {
+                Ok(WsMessage::Binary(payload)) => payload,
+                Ok(WsMessage::Close(_)) | Err(tungstenite::Error::ConnectionClosed) => {
+                    info!("WebSocket client disconnected.");
+                    return Ok(());
+                }
+                Ok(WsMessage::Text(_)) => {
+                    let response = handler::error_message(ErrorCode::InvalidArgument, "send ClientMessages as binary messages");
+                    socket.write(WsMessage::Binary(response.encode_to_vec())).map_err(ws_error)?;
+                    continue;
+                }
+                Ok(_) => continue, // Pings are answered by the library
+                Err(tungstenite::Error::Io(e)) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => continue,
+                Err(e) => {
+                    error!("Failed to read from WebSocket client: {}", e);
+                    return Ok(());
+                }
+            };
+
+            let arrived = (SystemTime::now(), Instant::now());
+            let response = match ClientMessage::decode(payload.as_slice()) {
+                Ok(client_message) => {
+                    let request_id = client_message.request_id;
+                    let operation = client_message.message.as_ref().map_or("empty", policy::message_name);
+                    // Nothing to negotiate either, a Hello is refused by the handler
+                    let response = self.handler.handle(session, client_message);
+                    self.options.audit(session, self.peer, request_id, operation, Outcome::of(response.as_ref()), arrived);
+                    response
+                }
+                Err(e) => {
+                    error!("Failed to decode ClientMessage: {}", e);
+                    Some(handler::error_message(ErrorCode::InvalidArgument, format!("invalid ClientMessage: {}", e)))
+                }
+            };
+            if let Some(response) = response {
+                socket.write(WsMessage::Binary(response.encode_to_vec())).map_err(ws_error)?;
+            }
+        }
+    }
+}
+
+fn ws_error(e: tungstenite::Error) -> io::Error {
+    match e {
+        tungstenite::Error::Io(e) => e,
+        e => io::Error::other(e.to_string()),
+    }
+}
+
+/// Optional features of a `Server`, `Default` gives a plain in-memory server
+#[derive(Debug, Clone)]
+pub struct ServerConfig {
+    /// Persist the key-value store to a WAL and snapshots, `None` keeps it in memory only
+    pub persistence: Option<PersistenceConfig>,
+    /// Accept file uploads and downloads into this storage, `None` rejects them
+    pub blobs: Option<BlobConfig>,
+    /// Compression a client may negotiate with a `Hello`
+    pub compression: CompressionConfig,
+    /// Let a client turn on CRC32C frame checksums with a `Hello`
+    pub checksums: bool,
+    /// Limits of each connection's outbound queue, for clients that do not read what they are sent
+    pub backpressure: BackpressureConfig,
+    /// WebSocket and JSON connections that have not finished the handshake or sent a first line by then are closed
+    pub handshake_timeout: Duration,
+    /// Require clients to authenticate before other requests, `None` lets everyone in
+    pub authenticator: Option<Arc<dyn Authenticator>>,
+    /// Restrict what each identity may do, `None` allows everything
+    pub policy: Option<Policy>,
+    /// Record every handled request, see `AuditLog` for a file-backed sink
+    pub audit: Option<Arc<dyn AuditSink>>,
+    /// Record every connection's frames for `capture::replay`, `None` records nothing
+    pub capture: Option<CaptureConfig>,
+    /// Also listen on this address for line-delimited JSON requests, see `json`
+    pub json_addr: Option<String>,
+    /// Also serve the HTTP gateway on this address, see `http`
+    pub http_addr: Option<String>,
+    /// Also accept WebSocket connections on this address, one protobuf message per binary WebSocket message
+    pub ws_addr: Option<String>,
+    /// Also answer requests sent as UDP datagrams, see `udp`
+    pub udp: Option<UdpConfig>,
+    /// Also serve a device on this serial port, see `serial`
+    pub serial: Option<SerialConfig>,
+    /// Also serve the gRPC `Gateway` service on this address
+    #[cfg(feature = "grpc")]
+    pub grpc_addr: Option<String>,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        ServerConfig {
+            persistence: None,
+            blobs: None,
+            compression: CompressionConfig::default(),
+            checksums: true, // Only used by clients that ask for it
+            backpressure: BackpressureConfig::default(),
+            handshake_timeout: Duration::from_secs(10),
+            authenticator: None,
+            policy: None,
+            audit: None,
+            capture: None,
+            json_addr: None,
+            http_addr: None,
+            ws_addr: None,
+            udp: None,
+            serial: None,
+            #[cfg(feature = "grpc")]
+            grpc_addr: None,
+        }
     }
 }
 
 // Define the Server structure with a TCP listener and a flag to check if it's running
 pub struct Server {
     listener: TcpListener, // The TCP listener to accept incoming connections
+    json_listener: Option<TcpListener>, // Optional listener for JSON lines
+    ws_listener: Option<TcpListener>, // Optional listener for WebSocket clients
     is_running: Arc<AtomicBool>, // Atomic flag to check if the server is running
+    ready: Arc<AtomicBool>, // Whether `run` is accepting work, reported by the HTTP readiness check
+    next_connection_id: Arc<AtomicU64>, // Source of unique connection ids, shared with the HTTP gateway
+    http: Option<Arc<tiny_http::Server>>, // Optional HTTP gateway, served by a thread of its own
+    udp: Option<Arc<UdpListener>>, // Optional UDP listener, served by a thread of its own while `run` runs
+    serial: Option<Arc<SerialLink>>, // Optional serial port, served like the UDP listener
+    #[cfg(feature = "grpc")]
+    grpc: Option<GrpcServer>, // Optional gRPC server, stopped when the server is dropped
+    handler: Arc<Handler>, // Request handlers and the state shared by all client threads
+    options: ConnectionOptions, // Handed to every accepted connection
 }
 
 impl Server {
     /// Creates a new server instance that listens on the given address
     pub fn new(addr: &str) -> io::Result<Self> {
+        Self::with_config(addr, ServerConfig::default())
+    }
+
+    /// Creates a server with optional features enabled.
+    ///
+    /// With persistence configured, the key-value store is recovered from disk before the listener is bound.
+    pub fn with_config(addr: &str, config: ServerConfig) -> io::Result<Self> {
+        config.backpressure.validate().map_err(|e| io::Error::new(ErrorKind::InvalidInput, e))?;
+        let store = match config.persistence {
+            Some(persistence) => KeyValueStore::open(persistence)?, // Replay snapshot and WAL
+            None => KeyValueStore::new(),
+        };
+        let mut handler = Handler::new(Arc::new(TopicRegistry::new()), Arc::new(store));
+        if let Some(blobs) = config.blobs {
+            handler = handler.with_blobs(Arc::new(BlobStore::open(blobs)?)); // Pick up unfinished uploads
+        }
+        if let Some(authenticator) = config.authenticator {
+            handler = handler.with_authenticator(authenticator);
+        }
+        if let Some(policy) = config.policy {
+            handler = handler.with_policy(Arc::new(policy));
+        }
         let listener = TcpListener::bind(addr)?; // Bind the listener to the provided address
+        let json_listener = config.json_addr.as_deref().map(TcpListener::bind).transpose()?;
+        let ws_listener = config.ws_addr.as_deref().map(TcpListener::bind).transpose()?;
+        #[cfg(feature = "grpc")]
+        let grpc_listener = config.grpc_addr.as_deref().map(TcpListener::bind).transpose()?;
+        let http = match config.http_addr.as_deref() {
+            Some(addr) => Some(Arc::new(tiny_http::Server::http(addr).map_err(io::Error::other)?)),
+            None => None,
+        };
 
-        let is_running = Arc::new(AtomicBool::new(false)); // Create an atomic flag for the server's state
+        // Start in the running state so a `stop` issued before `run` gets scheduled is not lost
+        let is_running = Arc::new(AtomicBool::new(true));
         thread::sleep(Duration::from_millis(1)); // Sleep briefly to ensure the listener is ready
-        Ok(Server {
+        let mut server = Server {
             listener, // Return the server instance with listener
+            json_listener,
+            ws_listener,
             is_running,
-        })
+            ready: Arc::new(AtomicBool::new(false)),
+            next_connection_id: Arc::new(AtomicU64::new(1)),
+            http,
+            udp: None,
+            serial: None,
+            #[cfg(feature = "grpc")]
+            grpc: None,
+            handler: Arc::new(handler),
+            options: ConnectionOptions {
+                compression: config.compression,
+                checksums: config.checksums,
+                stats: Arc::new(FrameStats::default()),
+                audit: config.audit,
+                capture: config.capture,
+                backpressure: config.backpressure,
+                handshake_timeout: config.handshake_timeout,
+            },
+        };
+        // Health checks are answered from here on, readiness only once `run` accepts work
+        if let Some(gateway) = &server.http {
+            let (gateway, handler, options) = (gateway.clone(), server.handler.clone(), server.options.clone());
+            let (ids, ready) = (server.next_connection_id.clone(), server.ready.clone());
+            thread::spawn(move || http::serve(gateway, handler, options, ids, ready));
+        }
+        if let Some(config) = config.udp {
+            let (handler, options, ids) = (server.handler.clone(), server.options.clone(), server.next_connection_id.clone());
+            server.udp = Some(Arc::new(UdpListener::bind(config, handler, options, ids)?));
+        }
+        if let Some(config) = config.serial {
+            let (handler, options, ids) = (server.handler.clone(), server.options.clone(), server.next_connection_id.clone());
+            server.serial = Some(Arc::new(SerialLink::open(config, handler, options, ids)?));
+        }
+        #[cfg(feature = "grpc")]
+        if let Some(listener) = grpc_listener {
+            let (handler, options, ids) = (server.handler.clone(), server.options.clone(), server.next_connection_id.clone());
+            server.grpc = Some(GrpcServer::start(listener, handler, options, ids)?);
+        }
+        Ok(server)
+    }
+
+    /// Returns the address the server is bound to, useful when binding to port 0
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+
+    /// Returns the address of the JSON listener, `None` if it is disabled
+    pub fn json_addr(&self) -> Option<SocketAddr> {
+        self.json_listener.as_ref().and_then(|listener| listener.local_addr().ok())
+    }
+
+    /// Returns the address of the WebSocket listener, `None` if it is disabled
+    pub fn ws_addr(&self) -> Option<SocketAddr> {
+        self.ws_listener.as_ref().and_then(|listener| listener.local_addr().ok())
+    }
+
+    /// Returns the address of the gRPC server, `None` if it is disabled
+    #[cfg(feature = "grpc")]
+    pub fn grpc_addr(&self) -> Option<SocketAddr> {
+        self.grpc.as_ref().map(GrpcServer::addr)
+    }
+
+    /// Returns the address of the UDP listener, `None` if it is disabled
+    pub fn udp_addr(&self) -> Option<SocketAddr> {
+        self.udp.as_ref().and_then(|udp| udp.local_addr().ok())
+    }
+
+    /// Returns the address of the HTTP gateway, `None` if it is disabled
+    pub fn http_addr(&self) -> Option<SocketAddr> {
+        self.http.as_ref().and_then(|http| http.server_addr().to_ip())
+    }
+
+    /// Returns the topic registry used to route publications
+    pub fn topics(&self) -> &Arc<TopicRegistry> {
+        self.handler.topics()
+    }
+
+    /// Returns the key-value store shared by all connections
+    pub fn store(&self) -> &Arc<KeyValueStore> {
+        self.handler.store()
+    }
+
+    /// Returns the file transfer storage, `None` if file transfer is disabled
+    pub fn blobs(&self) -> Option<&Arc<BlobStore>> {
+        self.handler.blobs()
+    }
+
+    /// Returns frame and compression counters summed over all connections
+    pub fn frame_stats(&self) -> &FrameStats {
+        &self.options.stats
     }
 
     /// Stops the server by setting the `is_running` flag to `false`
     pub fn stop(&self) {
         if self.is_running.load(Ordering::SeqCst) { // Check if the server is currently running
             self.is_running.store(false, Ordering::SeqCst); // Stop the server
+            self.ready.store(false, Ordering::SeqCst); // Fail readiness checks right away
             info!("Shutdown signal sent."); // Log server shutdown
         } else {
             warn!("Server was already stopped or not running."); // Log if the server is already stopped
@@ -117,16 +733,47 @@ impl Server {
 
     /// Runs the server, listens for incoming connections, and handles them
     pub fn run(&self) -> io::Result<()> {
-        self.is_running.store(true, Ordering::SeqCst); // Set the server as running
         println!("Server is running on {}", self.listener.local_addr()?); // Log the server's address
 
         self.listener.set_nonblocking(true)?; // Set the listener to non-blocking mode
+        if let Some(json_listener) = &self.json_listener {
+            println!("JSON listener is running on {}", json_listener.local_addr()?);
+            json_listener.set_nonblocking(true)?; // Polled in the same loop as the framed listener
+        }
+        if let Some(ws_listener) = &self.ws_listener {
+            println!("WebSocket listener is running on {}", ws_listener.local_addr()?);
+            ws_listener.set_nonblocking(true)?;
+        }
+        let udp = self.udp.clone().map(|udp| {
+            let is_running = self.is_running.clone();
+            thread::spawn(move || udp.run(&is_running)) // Datagrams need no accept, so they get a loop of their own
+        });
+        let serial = self.serial.clone().map(|serial| {
+            let is_running = self.is_running.clone();
+            thread::spawn(move || serial.run(&is_running)) // The port is a single connection that is already open
+        });
 
+        self.ready.store(self.is_running.load(Ordering::SeqCst), Ordering::SeqCst);
         while self.is_running.load(Ordering::SeqCst) { // Keep running while the server is active
+            if let Some(json_listener) = &self.json_listener {
+                self.accept_pending(json_listener, "JSON", |id, stream, handler, options| {
+                    JsonClient::new(id, stream, handler, options).handle()
+                })?;
+            }
+            if let Some(ws_listener) = &self.ws_listener {
+                self.accept_pending(ws_listener, "WebSocket", |id, stream, handler, options| {
+                    WsClient::new(id, stream, handler, options).handle()
+                })?;
+            }
             match self.listener.accept() { // Accept new connections
                 Ok((stream, addr)) => {
                     println!("New client connected: {}", addr); // Log new client connection
-                    let mut client = Client::new(stream); // Create a new client instance
+                    if let Err(e) = stream.set_nonblocking(false) { // Client threads use blocking reads
+                        println!("Error configuring client {}: {}", addr, e); // Drop this client, keep serving the rest
+                        continue;
+                    }
+                    let id = self.next_connection_id.fetch_add(1, Ordering::Relaxed);
+                    let mut client = Client::new(id, stream, self.handler.clone(), self.options.clone()); // Create a new client instance
                     thread::spawn(move || { // Spawn a new thread to handle the client
                         if let Err(e) = client.handle() { // Handle client communication
                             println!("Error handling client: {}", e); // Log any error that occurs
@@ -142,7 +789,67 @@ impl Server {
             }
         }
 
+        self.ready.store(false, Ordering::SeqCst);
+        if let Some(udp) = udp {
+            match udp.join() {
+                Ok(Err(e)) => error!("UDP listener failed: {}", e),
+                Err(_) => error!("UDP listener panicked"),
+                Ok(Ok(())) => {}
+            }
+        }
+        if let Some(serial) = serial {
+            match serial.join() {
+                Ok(Err(e)) => error!("Serial link failed: {}", e),
+                Err(_) => error!("Serial link panicked"),
+                Ok(Ok(())) => {}
+            }
+        }
+        if let Err(e) = self.store().sync() { // Flush WAL records the fsync policy left pending
+            error!("Failed to sync the key-value store: {}", e);
+        }
+        self.is_running.store(true, Ordering::SeqCst); // Re-armed, so the server can `run` again and a later `stop` still applies
         println!("Server stopped."); // Log when the server stops
         Ok(()) // Return success
     }
+
+    // Accepts every pending connection of another transport, each served by `serve` on its own thread
+    fn accept_pending(
+        &self,
+        listener: &TcpListener,
+        transport: &str,
+        serve: fn(u64, TcpStream, Arc<Handler>, ConnectionOptions) -> io::Result<()>,
+    ) -> io::Result<()> {
+        loop {
+            match listener.accept() {
+                Ok((stream, addr)) => {
+                    println!("New {} client connected: {}", transport, addr);
+                    if let Err(e) = stream.set_nonblocking(false) { // Client threads use blocking reads
+                        println!("Error configuring {} client {}: {}", transport, addr, e); // Drop this client, keep accepting
+                        continue;
+                    }
+                    let id = self.next_connection_id.fetch_add(1, Ordering::Relaxed);
+                    let (handler, options, transport) = (self.handler.clone(), self.options.clone(), transport.to_string());
+                    thread::spawn(move || {
+                        if let Err(e) = serve(id, stream, handler, options) {
+                            println!("Error handling {} client: {}", transport, e);
+                        }
+                    });
+                }
+                Err(ref e) if e.kind() == ErrorKind::WouldBlock => return Ok(()), // Back to the main loop
+                Err(e) => {
+                    println!("Error accepting {} connection: {}", transport, e);
+                    return Ok(());
+                }
+            }
+        }
+    }
+}
+
+impl Drop for Server {
+    // Ends the HTTP gateway thread, which would otherwise keep the port open
+    fn drop(&mut self) {
+        if let Some(http) = &self.http {
+            http.unblock();
+        }
+    }
 }
@@ -0,0 +1,397 @@
+// Concurrent in-memory key-value store shared by every connection.
+//
+// Every write stamps the key with a version taken from a store-wide counter,
+// so versions only ever grow. A key that is deleted and recreated never gets
+// a version it had before, which keeps compare-and-swap free of ABA problems.
+//
+// A store opened with `KeyValueStore::open` logs every mutation to a
+// write-ahead log before applying it, see `persistence`.
+use crate::persistence::{self, Persistence, PersistenceConfig, WalFile};
+use crate::storage::{wal_record, WalBatch, WalRecord};
+use log::error;
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fmt,
+    fs::File,
+    io,
+    sync::{RwLock, RwLockWriteGuard},
+};
+
+/// Version passed to `compare_and_swap` to require that the key does not exist yet
+pub const ABSENT_VERSION: u64 = 0;
+
+/// A stored value together with the version of the write that produced it
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Entry {
+    pub value: Vec<u8>,
+    pub version: u64,
+}
+
+/// Reasons a store request is rejected before touching any data
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum StoreError {
+    /// Keys must be non-empty strings
+    EmptyKey,
+    /// The mutation could not be written to the WAL and was not applied
+    Persistence(String),
+}
+
+impl fmt::Display for StoreError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StoreError::EmptyKey => write!(f, "key must not be empty"),
+            StoreError::Persistence(e) => write!(f, "failed to persist write: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for StoreError {}
+
+/// Outcome of a compare-and-swap
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SwapOutcome {
+    /// The value was written and now has this version
+    Swapped(u64),
+    /// The expected version did not match, carries the current version (`ABSENT_VERSION` if missing)
+    Conflict(u64),
+}
+
+/// Operations shared by the store itself and by an open `Transaction`,
+/// so request handlers can run against either
+pub trait KeyValueAccess {
+    /// Returns the current value and version of `key`, if present
+    fn get(&self, key: &str) -> Result<Option<Entry>, StoreError>;
+    /// Unconditionally writes `value` under `key` and returns the new version
+    fn put(&mut self, key: &str, value: Vec<u8>) -> Result<u64, StoreError>;
+    /// Removes `key`, returns whether it existed
+    fn delete(&mut self, key: &str) -> Result<bool, StoreError>;
+    /// Writes `value` only if the current version of `key` equals `expected_version`
+    fn compare_and_swap(&mut self, key: &str, expected_version: u64, value: Vec<u8>) -> Result<SwapOutcome, StoreError>;
+    /// Lists keys starting with `prefix` in lexicographic order, at most `limit` if non-zero
+    fn list_keys(&self, prefix: &str, limit: usize) -> Vec<String>;
+}
+
+// Data guarded by the store lock, the version counter and WAL must move with the map
+#[derive(Default)]
+struct Inner {
+    entries: BTreeMap<String, Entry>,
+    last_version: u64,
+    persistence: Option<Persistence>,
+}
+
+impl Inner {
+    // Logs the records (if persistent) as one WAL entry and only applies them once the log accepted it
+    fn commit(&mut self, mut records: Vec<WalRecord>) -> Result<(), StoreError> {
+        let Some(last_version) = records.last().map(|record| record.version) else {
+            return Ok(()); // Nothing to do
+        };
+
+        if let Some(persistence) = self.persistence.as_mut() {
+            let result = if records.len() == 1 {
+                persistence.append(&records[0])
+            } else {
+                // A single checksummed record, so recovery sees the whole batch or none of it
+                let batch = WalRecord {
+                    version: last_version,
+                    key: String::new(),
+                    operation: Some(wal_record::Operation::Batch(WalBatch { records })),
+                };
+                let result = persistence.append(&batch);
+                let Some(wal_record::Operation::Batch(batch)) = batch.operation else {
+                    unreachable!("operation was set to a batch above");
+                };
+                records = batch.records;
+                result
+            };
+            result.map_err(|e| StoreError::Persistence(e.to_string()))?;
+        }
+
+        for record in records {
+            persistence::apply_record(&mut self.entries, record);
+        }
+        self.last_version = last_version;
+        self.snapshot_if_due();
+        Ok(())
+    }
+
+    fn apply(&mut self, key: &str, operation: wal_record::Operation) -> Result<u64, StoreError> {
+        let version = self.last_version + 1;
+        self.commit(vec![WalRecord {
+            version,
+            key: key.to_string(),
+            operation: Some(operation),
+        }])?;
+        Ok(version)
+    }
+
+    fn write(&mut self, key: &str, value: Vec<u8>) -> Result<u64, StoreError> {
+        self.apply(key, wal_record::Operation::Put(value))
+    }
+
+    fn snapshot_if_due(&mut self) {
+        if let Some(persistence) = self.persistence.as_mut() {
+            if persistence.snapshot_due() {
+                // The write itself is already in the WAL, a failed snapshot only delays compaction
+                if let Err(e) = persistence.write_snapshot(&self.entries, self.last_version) {
+                    error!("Failed to write snapshot: {}", e);
+                }
+            }
+        }
+    }
+}
+
+/// Key-value store with per-key versions, safe to share between threads
+#[derive(Default)]
+pub struct KeyValueStore {
+    inner: RwLock<Inner>,
+}
+
+fn check_key(key: &str) -> Result<(), StoreError> {
+    if key.is_empty() {
+        Err(StoreError::EmptyKey)
+    } else {
+        Ok(())
+    }
+}
+
+// Collects up to `limit` (0 = all) keys starting with `prefix` from a sorted key iterator
+fn keys_with_prefix<'a>(keys: impl Iterator<Item = &'a String>, prefix: &str, limit: usize) -> Vec<String> {
+    let keys = keys.take_while(|key| key.starts_with(prefix)).cloned();
+    if limit == 0 {
+        keys.collect()
+    } else {
+        keys.take(limit).collect()
+    }
+}
+
+impl KeyValueStore {
+    /// Creates an empty, memory-only store
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Opens a persistent store, replaying the snapshot and WAL found in the configured directory
+    pub fn open(config: PersistenceConfig) -> io::Result<Self> {
+        Self::open_with(config, |file| Box::new(file))
+    }
+
+    /// Like `open`, with every WAL append going through the file returned by `wrap`,
+    /// e.g. to count or fail writes
+    pub fn open_with(config: PersistenceConfig, wrap: impl FnOnce(File) -> Box<dyn WalFile>) -> io::Result<Self> {
+        let (persistence, recovered) = Persistence::open(config, wrap)?;
+        Ok(KeyValueStore {
+            inner: RwLock::new(Inner {
+                entries: recovered.entries,
+                last_version: recovered.last_version,
+                persistence: Some(persistence),
+            }),
+        })
+    }
+
+    /// Returns true if mutations are written to a WAL
+    pub fn is_persistent(&self) -> bool {
+        self.inner.read().unwrap().persistence.is_some()
+    }
+
+    /// Writes a snapshot now and resets the WAL, a no-op for memory-only stores
+    pub fn snapshot(&self) -> io::Result<()> {
+        let mut inner = self.inner.write().unwrap();
+        let Inner { entries, last_version, persistence } = &mut *inner;
+        match persistence {
+            Some(persistence) => persistence.write_snapshot(entries, *last_version),
+            None => Ok(()),
+        }
+    }
+
+    /// Forces pending WAL records to disk regardless of the fsync policy
+    pub fn sync(&self) -> io::Result<()> {
+        match self.inner.write().unwrap().persistence.as_mut() {
+            Some(persistence) => persistence.sync(),
+            None => Ok(()),
+        }
+    }
+
+    /// Returns the current value and version of `key`, if present
+    pub fn get(&self, key: &str) -> Result<Option<Entry>, StoreError> {
+        check_key(key)?;
+        Ok(self.inner.read().unwrap().entries.get(key).cloned())
+    }
+
+    /// Unconditionally writes `value` under `key` and returns the new version
+    pub fn put(&self, key: &str, value: Vec<u8>) -> Result<u64, StoreError> {
+        check_key(key)?;
+        self.inner.write().unwrap().write(key, value)
+    }
+
+    /// Removes `key`, returns whether it existed
+    pub fn delete(&self, key: &str) -> Result<bool, StoreError> {
+        check_key(key)?;
+        let mut inner = self.inner.write().unwrap();
+        if !inner.entries.contains_key(key) {
+            return Ok(false); // Nothing to log
+        }
+        inner.apply(key, wal_record::Operation::Delete(true))?;
+        Ok(true)
+    }
+
+    /// Writes `value` only if the current version of `key` equals `expected_version`.
+    ///
+    /// Pass `ABSENT_VERSION` to create the key only if it does not exist.
+    pub fn compare_and_swap(
+        &self,
+        key: &str,
+        expected_version: u64,
+        value: Vec<u8>,
+    ) -> Result<SwapOutcome, StoreError> {
+        check_key(key)?;
+
+        // Check and write under one write lock so no other writer can slip in between
+        let mut inner = self.inner.write().unwrap();
+        let current = inner
+            .entries
+            .get(key)
+            .map_or(ABSENT_VERSION, |entry| entry.version);
+        if current != expected_version {
+            return Ok(SwapOutcome::Conflict(current));
+        }
+        Ok(SwapOutcome::Swapped(inner.write(key, value)?))
+    }
+
+    /// Lists keys starting with `prefix` in lexicographic order, at most `limit` if non-zero
+    pub fn list_keys(&self, prefix: &str, limit: usize) -> Vec<String> {
+        let inner = self.inner.read().unwrap();
+        let keys = inner.entries.range(prefix.to_string()..).map(|(key, _)| key);
+        keys_with_prefix(keys, prefix, limit)
+    }
+
+    /// Number of keys currently stored
+    pub fn len(&self) -> usize {
+        self.inner.read().unwrap().entries.len()
+    }
+
+    /// Returns true if the store holds no keys
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Runs `body` against a private view of the store and applies its writes atomically.
+    ///
+    /// Writes are staged and only become visible, in one WAL record, if `body` returns `Ok`.
+    /// Other writers are blocked while the transaction is open.
+    pub fn transaction<T, E>(&self, body: impl FnOnce(&mut Transaction<'_>) -> Result<T, E>) -> Result<T, E>
+    where
+        E: From<StoreError>,
+    {
+        let inner = self.inner.write().unwrap();
+        let mut transaction = Transaction {
+            last_version: inner.last_version,
+            inner,
+            staged: BTreeMap::new(),
+            records: Vec::new(),
+        };
+        let result = body(&mut transaction)?;
+        transaction.commit()?;
+        Ok(result)
+    }
+}
+
+impl KeyValueAccess for &KeyValueStore {
+    fn get(&self, key: &str) -> Result<Option<Entry>, StoreError> {
+        KeyValueStore::get(self, key)
+    }
+
+    fn put(&mut self, key: &str, value: Vec<u8>) -> Result<u64, StoreError> {
+        KeyValueStore::put(self, key, value)
+    }
+
+    fn delete(&mut self, key: &str) -> Result<bool, StoreError> {
+        KeyValueStore::delete(self, key)
+    }
+
+    fn compare_and_swap(&mut self, key: &str, expected_version: u64, value: Vec<u8>) -> Result<SwapOutcome, StoreError> {
+        KeyValueStore::compare_and_swap(self, key, expected_version, value)
+    }
+
+    fn list_keys(&self, prefix: &str, limit: usize) -> Vec<String> {
+        KeyValueStore::list_keys(self, prefix, limit)
+    }
+}
+
+/// Staged writes of an open transaction, see `KeyValueStore::transaction`
+pub struct Transaction<'a> {
+    inner: RwLockWriteGuard<'a, Inner>,
+    staged: BTreeMap<String, Option<Entry>>, // `None` marks a staged delete
+    records: Vec<WalRecord>,                 // Staged writes in order, committed as one batch
+    last_version: u64,
+}
+
+impl Transaction<'_> {
+    fn stage(&mut self, key: &str, operation: wal_record::Operation) -> u64 {
+        self.last_version += 1;
+        let version = self.last_version;
+        let entry = match &operation {
+            wal_record::Operation::Put(value) => Some(Entry { value: value.clone(), version }),
+            _ => None,
+        };
+        self.staged.insert(key.to_string(), entry);
+        self.records.push(WalRecord {
+            version,
+            key: key.to_string(),
+            operation: Some(operation),
+        });
+        version
+    }
+
+    fn commit(mut self) -> Result<(), StoreError> {
+        let records = std::mem::take(&mut self.records);
+        self.inner.commit(records)
+    }
+}
+
+impl KeyValueAccess for Transaction<'_> {
+    fn get(&self, key: &str) -> Result<Option<Entry>, StoreError> {
+        check_key(key)?;
+        match self.staged.get(key) {
+            Some(staged) => Ok(staged.clone()),
+            None => Ok(self.inner.entries.get(key).cloned()),
+        }
+    }
+
+    fn put(&mut self, key: &str, value: Vec<u8>) -> Result<u64, StoreError> {
+        check_key(key)?;
+        Ok(self.stage(key, wal_record::Operation::Put(value)))
+    }
+
+    fn delete(&mut self, key: &str) -> Result<bool, StoreError> {
+        if self.get(key)?.is_none() {
+            return Ok(false);
+        }
+        self.stage(key, wal_record::Operation::Delete(true));
+        Ok(true)
+    }
+
+    fn compare_and_swap(&mut self, key: &str, expected_version: u64, value: Vec<u8>) -> Result<SwapOutcome, StoreError> {
+        let current = self.get(key)?.map_or(ABSENT_VERSION, |entry| entry.version);
+        if current != expected_version {
+            return Ok(SwapOutcome::Conflict(current));
+        }
+        Ok(SwapOutcome::Swapped(self.stage(key, wal_record::Operation::Put(value))))
+    }
+
+    fn list_keys(&self, prefix: &str, limit: usize) -> Vec<String> {
+        // Committed keys overlaid with the staged puts and deletes
+        let committed = self.inner.entries.range(prefix.to_string()..).map(|(key, _)| key);
+        let mut keys: BTreeSet<&String> = committed.take_while(|key| key.starts_with(prefix)).collect();
+        for (key, staged) in self.staged.range(prefix.to_string()..) {
+            if !key.starts_with(prefix) {
+                break;
+            }
+            if staged.is_some() {
+                keys.insert(key);
+            } else {
+                keys.remove(key);
+            }
+        }
+        keys_with_prefix(keys.into_iter(), prefix, limit)
+    }
+}
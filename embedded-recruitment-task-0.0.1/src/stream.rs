@@ -0,0 +1,218 @@
+// Server-streaming requests.
+//
+// A streaming request is answered by any number of `StreamItem` frames
+// followed by one `StreamEnd`, all tagged with the request's id. Each stream
+// runs on its own thread and pushes its frames through the connection's
+// outbound queue, so the connection keeps serving other requests (including
+// the `Cancel` that stops the stream) while it runs. A deadline on the
+// request bounds the whole stream.
+use crate::message::CountTo;
+use std::{
+    collections::HashMap,
+    fmt,
+    sync::{Arc, Condvar, Mutex},
+    time::{Duration, Instant},
+};
+
+/// Why a request or stream was stopped before it finished
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Interrupt {
+    /// The client sent a `Cancel`, or the connection closed
+    Cancelled,
+    /// The request's deadline passed
+    DeadlineExceeded,
+}
+
+impl fmt::Display for Interrupt {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Interrupt::Cancelled => write!(f, "request was cancelled"),
+            Interrupt::DeadlineExceeded => write!(f, "request deadline exceeded"),
+        }
+    }
+}
+
+/// Stop signal shared between a running request or stream and whoever may cancel it
+#[derive(Debug, Default)]
+pub struct CancelToken {
+    cancelled: Mutex<bool>,
+    signal: Condvar,           // Wakes a stream sleeping in `wait`
+    deadline: Option<Instant>, // Stops the work on its own once passed
+}
+
+impl CancelToken {
+    /// Creates a token that is not cancelled and has no deadline
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Creates a token that also stops the work once `deadline` passes
+    pub fn with_deadline(deadline: Option<Instant>) -> Self {
+        CancelToken { deadline, ..Self::default() }
+    }
+
+    /// When the work has to be done by, if ever
+    pub fn deadline(&self) -> Option<Instant> {
+        self.deadline
+    }
+
+    /// Why the work should stop, `None` while it may go on.
+    /// A cancellation wins over a deadline that passed as well.
+    pub fn interrupted(&self) -> Option<Interrupt> {
+        if self.is_cancelled() {
+            Some(Interrupt::Cancelled)
+        } else if self.deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+            Some(Interrupt::DeadlineExceeded)
+        } else {
+            None
+        }
+    }
+
+    /// Asks the stream to stop, returns false if it was already cancelled
+    pub fn cancel(&self) -> bool {
+        let mut cancelled = self.cancelled.lock().unwrap();
+        let first = !*cancelled;
+        *cancelled = true;
+        self.signal.notify_all();
+        first
+    }
+
+    /// Returns true once `cancel` has been called
+    pub fn is_cancelled(&self) -> bool {
+        *self.cancelled.lock().unwrap()
+    }
+
+    /// Sleeps for `duration` or until cancelled or past the deadline, whichever comes first.
+    /// Returns true if the work should stop.
+    pub fn wait(&self, duration: Duration) -> bool {
+        let wake = self.deadline.map_or(Instant::now() + duration, |deadline| deadline.min(Instant::now() + duration));
+        let mut cancelled = self.cancelled.lock().unwrap();
+        while !*cancelled {
+            let remaining = wake.saturating_duration_since(Instant::now());
+            if remaining.is_zero() {
+                break;
+            }
+            cancelled = self.signal.wait_timeout(cancelled, remaining).unwrap().0;
+        }
+        drop(cancelled);
+        self.interrupted().is_some()
+    }
+}
+
+/// Streams running on behalf of one connection, keyed by request id
+#[derive(Debug, Default)]
+pub struct ActiveStreams {
+    streams: Mutex<HashMap<u64, Arc<CancelToken>>>,
+}
+
+impl ActiveStreams {
+    /// Creates an empty registry
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Registers a new stream that has to end by `deadline`, returns `None` if `request_id` is already streaming
+    pub fn start(&self, request_id: u64, deadline: Option<Instant>) -> Option<Arc<CancelToken>> {
+        let mut streams = self.streams.lock().unwrap();
+        if streams.contains_key(&request_id) {
+            return None;
+        }
+        let token = Arc::new(CancelToken::with_deadline(deadline));
+        streams.insert(request_id, token.clone());
+        Some(token)
+    }
+
+    /// Forgets a stream that has ended
+    pub fn finish(&self, request_id: u64) {
+        self.streams.lock().unwrap().remove(&request_id);
+    }
+
+    /// Cancels the stream started by `request_id`, returns false if no such stream is running
+    pub fn cancel(&self, request_id: u64) -> bool {
+        match self.streams.lock().unwrap().get(&request_id) {
+            Some(token) => token.cancel(),
+            None => false,
+        }
+    }
+
+    /// Cancels every running stream, used when the connection closes
+    pub fn cancel_all(&self) {
+        for token in self.streams.lock().unwrap().values() {
+            token.cancel();
+        }
+    }
+
+    /// Number of streams still running
+    pub fn len(&self) -> usize {
+        self.streams.lock().unwrap().len()
+    }
+
+    /// Returns true if no stream is running
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+/// Reasons a `CountTo` request is rejected
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CountToError {
+    /// `step` points away from `end`, the sequence would never reach it
+    WrongDirection { start: i64, end: i64, step: i64 },
+}
+
+impl fmt::Display for CountToError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CountToError::WrongDirection { start, end, step } => {
+                write!(f, "step {} never reaches {} from {}", step, end, start)
+            }
+        }
+    }
+}
+
+impl std::error::Error for CountToError {}
+
+/// Values produced by a `CountTo` request, from `start` to `end` inclusive
+#[derive(Debug, Clone)]
+pub struct CountRange {
+    next: Option<i64>, // None once the range is exhausted
+    end: i64,
+    step: i64,
+}
+
+impl CountRange {
+    /// Validates the request, a `step` of 0 counts by one towards `end`
+    pub fn new(request: &CountTo) -> Result<Self, CountToError> {
+        let CountTo { start, end, step, .. } = *request;
+        let step = match step {
+            0 if end < start => -1,
+            0 => 1,
+            step if start != end && (step > 0) != (end > start) => {
+                return Err(CountToError::WrongDirection { start, end, step });
+            }
+            step => step,
+        };
+        Ok(CountRange { next: Some(start), end, step })
+    }
+
+    /// Number of values still to come
+    pub fn remaining(&self) -> u64 {
+        let Some(next) = self.next else {
+            return 0;
+        };
+        ((i128::from(self.end) - i128::from(next)) / i128::from(self.step) + 1) as u64
+    }
+}
+
+impl Iterator for CountRange {
+    type Item = i64;
+
+    fn next(&mut self) -> Option<i64> {
+        let current = self.next?;
+        // Stop at `end`, and on overflow instead of wrapping around
+        self.next = current
+            .checked_add(self.step)
+            .filter(|next| if self.step > 0 { *next <= self.end } else { *next >= self.end });
+        Some(current)
+    }
+}
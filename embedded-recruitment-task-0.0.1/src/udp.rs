@@ -0,0 +1,330 @@
+// Connectionless requests over UDP.
+//
+// Each datagram carries one protobuf `ClientMessage`, with no length prefix,
+// and its reply goes back to the source address as one datagram. Every
+// datagram is handled on a session of its own, so nothing carries over from
+// one to the next: UDP requests are never authenticated.
+//
+// The source address of a datagram is not verified, so a stream would let
+// one small spoofed request send many frames to someone else. Streaming
+// requests are therefore refused unless `UdpConfig::streams` allows them, and
+// then only up to a number of items and a duration, with a few at a time. An
+// allowed stream's frames are sent to the source as separate datagrams until
+// its `StreamEnd`.
+//
+// Datagrams over `max_datagram` bytes are refused, and so are replies that
+// would not fit. With deduplication on, a retransmitted request (same source
+// and non-zero request id, within the window) gets the reply sent the first
+// time instead of running again, so retrying a `Put` or a `Publish` is safe.
+use crate::audit::Outcome;
+use crate::handler::{self, Handler, Session};
+use crate::message::{client_message::Message as ClientMessageType, server_message, ClientMessage, ErrorCode, ServerMessage};
+use crate::outbound::OutboundReceiver;
+use crate::policy;
+use crate::server::ConnectionOptions;
+use crate::stream::CountRange;
+use log::{error, info, warn};
+use prost::Message;
+use std::{
+    collections::{HashMap, VecDeque},
+    io::{self, ErrorKind},
+    net::{SocketAddr, UdpSocket},
+    sync::{
+        atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+        Arc,
+    },
+    thread,
+    time::{Duration, Instant, SystemTime},
+};
+
+// How often the receive loop checks whether the server is still running
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Settings of the UDP listener
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UdpConfig {
+    /// Address to bind
+    pub addr: String,
+    /// Largest datagram accepted or sent, in bytes
+    pub max_datagram: usize,
+    /// Answer a retransmitted request from the reply cache for this long, `None` runs every datagram
+    pub dedup_window: Option<Duration>,
+    /// Replies kept for deduplication, the oldest are dropped first
+    pub dedup_capacity: usize,
+    /// Serve streaming requests within these limits, `None` refuses them
+    pub streams: Option<UdpStreamLimits>,
+}
+
+/// Bounds on the streams served over UDP
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UdpStreamLimits {
+    /// Streams that would send more items are refused
+    pub max_items: u64,
+    /// Streams are stopped with `DeadlineExceeded` after this long, whatever deadline the request has
+    pub max_duration: Duration,
+    /// Further streaming requests are refused while this many streams run
+    pub max_concurrent: usize,
+}
+
+impl Default for UdpStreamLimits {
+    /// Up to 100 items over at most 10 seconds, 4 streams at a time
+    fn default() -> Self {
+        UdpStreamLimits {
+            max_items: 100,
+            max_duration: Duration::from_secs(10),
+            max_concurrent: 4,
+        }
+    }
+}
+
+impl UdpConfig {
+    /// Listens on `addr` with 1472-byte datagrams, which fit an Ethernet frame, no deduplication and no streams
+    pub fn new(addr: impl Into<String>) -> Self {
+        UdpConfig {
+            addr: addr.into(),
+            max_datagram: 1472,
+            dedup_window: None,
+            dedup_capacity: 1024,
+            streams: None,
+        }
+    }
+}
+
+// Recent replies by source and request id
+struct ReplyCache {
+    window: Duration,
+    capacity: usize,
+    replies: HashMap<(SocketAddr, u64), (Instant, Vec<u8>)>,
+    order: VecDeque<(SocketAddr, u64)>, // Oldest first
+}
+
+impl ReplyCache {
+    fn new(window: Duration, capacity: usize) -> Self {
+        ReplyCache {
+            window,
+            capacity,
+            replies: HashMap::new(),
+            order: VecDeque::new(),
+        }
+    }
+
+    // Reply sent to an earlier copy of this request, if still within the window
+    fn get(&mut self, key: (SocketAddr, u64)) -> Option<&[u8]> {
+        self.expire();
+        self.replies.get(&key).map(|(_, datagram)| datagram.as_slice())
+    }
+
+    fn insert(&mut self, key: (SocketAddr, u64), datagram: Vec<u8>) {
+        if self.replies.insert(key, (Instant::now(), datagram)).is_none() {
+            self.order.push_back(key);
+        }
+        while self.order.len() > self.capacity {
+            if let Some(oldest) = self.order.pop_front() {
+                self.replies.remove(&oldest);
+            }
+        }
+    }
+
+    fn expire(&mut self) {
+        while let Some(oldest) = self.order.front() {
+            match self.replies.get(oldest) {
+                Some((stored, _)) if stored.elapsed() < self.window => break,
+                _ => {
+                    let oldest = self.order.pop_front().expect("front exists");
+                    self.replies.remove(&oldest);
+                }
+            }
+        }
+    }
+}
+
+/// The UDP listener, served by `run` on a thread of its own
+pub(crate) struct UdpListener {
+    socket: UdpSocket,
+    config: UdpConfig,
+    handler: Arc<Handler>,
+    options: ConnectionOptions,
+    ids: Arc<AtomicU64>, // Every datagram draws a session id from the connection ids
+    streams: Arc<AtomicUsize>, // Streams being forwarded
+}
+
+// Holds one of the `max_concurrent` stream slots until dropped
+struct StreamSlot(Arc<AtomicUsize>);
+
+impl Drop for StreamSlot {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+impl UdpListener {
+    /// Binds the socket
+    pub(crate) fn bind(config: UdpConfig, handler: Arc<Handler>, options: ConnectionOptions, ids: Arc<AtomicU64>) -> io::Result<Self> {
+        let socket = UdpSocket::bind(&config.addr)?;
+        Ok(UdpListener { socket, config, handler, options, ids, streams: Arc::new(AtomicUsize::new(0)) })
+    }
+
+    /// Address the socket is bound to
+    pub(crate) fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.socket.local_addr()
+    }
+
+    /// Handles datagrams one after another until `is_running` is cleared
+    pub(crate) fn run(&self, is_running: &AtomicBool) -> io::Result<()> {
+        self.socket.set_read_timeout(Some(POLL_INTERVAL))?;
+        let mut cache = self.config.dedup_window.map(|window| ReplyCache::new(window, self.config.dedup_capacity));
+        let mut buffer = vec![0; self.config.max_datagram + 1]; // One byte more tells an oversized datagram apart
+        while is_running.load(Ordering::SeqCst) {
+            let (len, source) = match self.socket.recv_from(&mut buffer) {
+                Ok(received) => received,
+                Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => continue,
+                Err(e) => {
+                    error!("Failed to receive a UDP datagram: {}", e);
+                    continue; // An ICMP error for an earlier reply, the socket itself is fine
+                }
+            };
+            if len > self.config.max_datagram {
+                warn!("Datagram from {} exceeds {} bytes", source, self.config.max_datagram);
+                let response = handler::error_message(ErrorCode::InvalidArgument, format!("datagrams are limited to {} bytes", self.config.max_datagram));
+                self.send(&response.encode_to_vec(), source);
+                continue;
+            }
+            let request = match ClientMessage::decode(&buffer[..len]) {
+                Ok(request) => request,
+                Err(e) => {
+                    error!("Failed to decode ClientMessage from {}: {}", source, e);
+                    let response = handler::error_message(ErrorCode::InvalidArgument, format!("invalid ClientMessage: {}", e));
+                    self.send(&response.encode_to_vec(), source);
+                    continue;
+                }
+            };
+
+            // Request id 0 means the client does not number its requests, so it cannot be deduplicated
+            let key = (source, request.request_id);
+            let cache = cache.as_mut().filter(|_| request.request_id != 0);
+            match cache {
+                Some(cache) => {
+                    if let Some(datagram) = cache.get(key) {
+                        info!("Answering retransmitted request {} from {} from the cache", key.1, source);
+                        self.send(datagram, source);
+                    } else if let Some(datagram) = self.handle(request, source) {
+                        cache.insert(key, datagram); // Streams are not cached, a retransmission restarts them
+                    }
+                }
+                None => {
+                    self.handle(request, source);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // Runs one request and sends its reply, returning the datagram sent. Streams return `None`.
+    fn handle(&self, mut request: ClientMessage, source: SocketAddr) -> Option<Vec<u8>> {
+        let (outbound, queue) = self.options.outbound();
+        let session = Session::new(self.ids.fetch_add(1, Ordering::Relaxed), outbound);
+        let arrived = (SystemTime::now(), Instant::now());
+        let request_id = request.request_id;
+        let operation = request.message.as_ref().map_or("empty", policy::message_name);
+        let admitted = match &request.message {
+            Some(message) if handler::is_stream(message) => self.admit_stream(&mut request).map(Some),
+            _ => Ok(None),
+        };
+        let (response, slot) = match admitted {
+            Ok(slot) => (self.handler.handle(&session, request), slot),
+            Err(refused) => {
+                warn!("Refusing a stream requested by {}", source);
+                (Some(ServerMessage { request_id, ..refused }), None)
+            }
+        };
+        self.options.audit(&session, Some(source), request_id, operation, Outcome::of(response.as_ref()), arrived);
+
+        let Some(response) = response else {
+            self.forward_stream(session, queue, source, slot);
+            return None;
+        };
+        let datagram = fit(response, self.config.max_datagram);
+        self.send(&datagram, source);
+        self.handler.disconnect(&session); // Drops anything the request left behind, such as a subscription
+        Some(datagram)
+    }
+
+    // Checks a streaming request against the limits and takes a slot for it,
+    // shortening its deadline to `max_duration`. Returns the error reply if refused.
+    fn admit_stream(&self, request: &mut ClientMessage) -> Result<StreamSlot, ServerMessage> {
+        let Some(limits) = &self.config.streams else {
+            return Err(handler::error_message(ErrorCode::Unavailable, "streaming requests are not served over UDP"));
+        };
+        if let Some(ClientMessageType::CountTo(count)) = &request.message {
+            // An invalid request gets its usual error from the handler
+            let items = CountRange::new(count).map_or(0, |range| range.remaining());
+            if items > limits.max_items {
+                return Err(handler::error_message(
+                    ErrorCode::InvalidArgument,
+                    format!("UDP streams are limited to {} items, this one has {}", limits.max_items, items),
+                ));
+            }
+        }
+        let taken = self.streams.fetch_update(Ordering::SeqCst, Ordering::SeqCst, |running| {
+            (running < limits.max_concurrent).then_some(running + 1)
+        });
+        if taken.is_err() {
+            return Err(handler::error_message(
+                ErrorCode::Unavailable,
+                format!("{} UDP streams are already running", limits.max_concurrent),
+            ));
+        }
+        let max_ms = limits.max_duration.as_millis().clamp(1, u32::MAX.into()) as u32;
+        if request.deadline_ms == 0 || request.deadline_ms > max_ms {
+            request.deadline_ms = max_ms;
+        }
+        Ok(StreamSlot(self.streams.clone()))
+    }
+
+    // Sends a stream's frames as they come, on a thread of their own so the listener keeps serving.
+    // The stream keeps its slot until it ends.
+    fn forward_stream(&self, session: Session, queue: OutboundReceiver, destination: SocketAddr, slot: Option<StreamSlot>) {
+        let socket = match self.socket.try_clone() {
+            Ok(socket) => socket,
+            Err(e) => {
+                error!("Failed to forward a stream to {}: {}", destination, e);
+                self.handler.disconnect(&session);
+                return;
+            }
+        };
+        let (handler, max_datagram) = (self.handler.clone(), self.config.max_datagram);
+        thread::spawn(move || {
+            for frame in queue.iter() {
+                let end = matches!(frame.message, Some(server_message::Message::StreamEnd(_)));
+                if let Err(e) = socket.send_to(&fit(frame, max_datagram), destination) {
+                    warn!("Failed to send a stream frame to {}: {}", destination, e);
+                }
+                if end {
+                    break;
+                }
+            }
+            handler.disconnect(&session);
+            drop(slot);
+        });
+    }
+
+    fn send(&self, datagram: &[u8], destination: SocketAddr) {
+        if let Err(e) = self.socket.send_to(datagram, destination) {
+            warn!("Failed to send a UDP reply to {}: {}", destination, e);
+        }
+    }
+}
+
+// Encodes `response`, replaced by an error with the same request id if it is over `max_datagram`
+fn fit(response: ServerMessage, max_datagram: usize) -> Vec<u8> {
+    let datagram = response.encode_to_vec();
+    if datagram.len() <= max_datagram {
+        return datagram;
+    }
+    let mut error = handler::error_message(
+        ErrorCode::InvalidArgument,
+        format!("reply of {} bytes does not fit in a {} byte datagram", datagram.len(), max_datagram),
+    );
+    error.request_id = response.request_id;
+    error.encode_to_vec()
+}
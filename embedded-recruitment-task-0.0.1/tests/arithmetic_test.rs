@@ -0,0 +1,229 @@
+use embedded_recruitment_task::{
+    arithmetic::{evaluate, ArithmeticError, Number},
+    message::{
+        client_message, operand, server_message, AddRequest, ArithmeticOperation,
+        ArithmeticRequest, ErrorCode, Operand,
+    },
+    server::Server,
+};
+use std::{
+    sync::Arc,
+    thread::{self, JoinHandle},
+};
+
+mod client;
+
+fn setup_server_thread(server: Arc<Server>) -> JoinHandle<()> {
+    thread::spawn(move || {
+        server.run().expect("Server encountered an error");
+    })
+}
+
+fn create_server() -> Arc<Server> {
+    let server = Server::new("localhost:0");
+    Arc::new(server.expect("Failed to start server"))
+}
+
+fn connect(server: &Server) -> client::Client {
+    let port = server.local_addr().expect("Server has no local address").port() as u32;
+    let mut client = client::Client::new("localhost", port, 1000);
+    assert!(client.connect().is_ok(), "Failed to connect to the server");
+    client
+}
+
+fn int(value: i64) -> Option<Operand> {
+    Some(Operand {
+        value: Some(operand::Value::IntValue(value)),
+    })
+}
+
+fn double(value: f64) -> Option<Operand> {
+    Some(Operand {
+        value: Some(operand::Value::DoubleValue(value)),
+    })
+}
+
+fn request(client: &mut client::Client, message: client_message::Message) -> server_message::Message {
+    assert!(client.send(message).is_ok(), "Failed to send request");
+    client
+        .receive()
+        .expect("Failed to receive response")
+        .message
+        .expect("Response carries no message")
+}
+
+fn arithmetic(
+    client: &mut client::Client,
+    operation: ArithmeticOperation,
+    lhs: Option<Operand>,
+    rhs: Option<Operand>,
+) -> server_message::Message {
+    let message = client_message::Message::ArithmeticRequest(ArithmeticRequest {
+        operation: operation as i32,
+        lhs,
+        rhs,
+    });
+    request(client, message)
+}
+
+fn expect_result(response: server_message::Message) -> operand::Value {
+    match response {
+        server_message::Message::ArithmeticResponse(response) => {
+            response.result.and_then(|result| result.value).expect("Result is missing")
+        }
+        other => panic!("Expected ArithmeticResponse, but received {:?}", other),
+    }
+}
+
+fn expect_error(response: server_message::Message, code: ErrorCode) {
+    match response {
+        server_message::Message::Error(error) => {
+            assert_eq!(error.code, code as i32, "Unexpected error: {}", error.message);
+        }
+        other => panic!("Expected Error, but received {:?}", other),
+    }
+}
+
+#[test]
+fn test_integer_operations() {
+    use ArithmeticOperation::*;
+
+    let cases = [
+        (Add, 7, 5, 12),
+        (Subtract, 7, 5, 2),
+        (Multiply, 7, -5, -35),
+        (Divide, -7, 2, -3), // Truncates toward zero
+        (Modulo, -7, 2, -1),
+        (Modulo, i64::MIN, -1, 0), // Representable, unlike the quotient
+        (Power, 3, 4, 81),
+        (Power, -1, i64::MAX, -1),
+    ];
+    for (operation, lhs, rhs, expected) in cases {
+        assert_eq!(
+            evaluate(operation, Number::Int(lhs), Number::Int(rhs)),
+            Ok(Number::Int(expected)),
+            "{:?}({}, {})",
+            operation,
+            lhs,
+            rhs
+        );
+    }
+}
+
+#[test]
+fn test_integer_errors() {
+    use ArithmeticOperation::*;
+
+    let cases = [
+        (Divide, 1, 0, ArithmeticError::DivisionByZero),
+        (Modulo, 1, 0, ArithmeticError::DivisionByZero),
+        (Add, i64::MAX, 1, ArithmeticError::Overflow),
+        (Subtract, i64::MIN, 1, ArithmeticError::Overflow),
+        (Multiply, i64::MAX, 2, ArithmeticError::Overflow),
+        (Divide, i64::MIN, -1, ArithmeticError::Overflow),
+        (Power, 2, 63, ArithmeticError::Overflow),
+        (Power, 2, -1, ArithmeticError::NegativeExponent(-1)),
+    ];
+    for (operation, lhs, rhs, expected) in cases {
+        assert_eq!(
+            evaluate(operation, Number::Int(lhs), Number::Int(rhs)),
+            Err(expected),
+            "{:?}({}, {})",
+            operation,
+            lhs,
+            rhs
+        );
+    }
+}
+
+#[test]
+fn test_double_operations() {
+    use ArithmeticOperation::*;
+
+    assert_eq!(evaluate(Divide, Number::Int(7), Number::Double(2.0)), Ok(Number::Double(3.5)));
+    assert_eq!(evaluate(Modulo, Number::Double(7.5), Number::Int(2)), Ok(Number::Double(1.5)));
+    assert_eq!(evaluate(Power, Number::Double(4.0), Number::Double(0.5)), Ok(Number::Double(2.0)));
+
+    assert_eq!(
+        evaluate(Divide, Number::Double(1.0), Number::Double(0.0)),
+        Err(ArithmeticError::DivisionByZero)
+    );
+    assert_eq!(
+        evaluate(Multiply, Number::Double(f64::MAX), Number::Double(2.0)),
+        Err(ArithmeticError::Overflow)
+    );
+    assert_eq!(
+        evaluate(Power, Number::Double(-8.0), Number::Double(0.5)),
+        Err(ArithmeticError::NotANumber)
+    );
+    // Infinite inputs are not an overflow of the operation itself
+    assert_eq!(
+        evaluate(Add, Number::Double(f64::INFINITY), Number::Double(1.0)),
+        Ok(Number::Double(f64::INFINITY))
+    );
+}
+
+#[test]
+fn test_arithmetic_request_over_tcp() {
+    let server = create_server();
+    let handle = setup_server_thread(server.clone());
+    let mut client = connect(&server);
+
+    let result = expect_result(arithmetic(&mut client, ArithmeticOperation::Multiply, int(6), int(7)));
+    assert_eq!(result, operand::Value::IntValue(42));
+
+    let result = expect_result(arithmetic(&mut client, ArithmeticOperation::Divide, int(1), double(4.0)));
+    assert_eq!(result, operand::Value::DoubleValue(0.25));
+
+    expect_error(
+        arithmetic(&mut client, ArithmeticOperation::Modulo, int(5), int(0)),
+        ErrorCode::DivisionByZero,
+    );
+    expect_error(
+        arithmetic(&mut client, ArithmeticOperation::Add, int(i64::MAX), int(1)),
+        ErrorCode::Overflow,
+    );
+    expect_error(
+        arithmetic(&mut client, ArithmeticOperation::Add, int(1), None),
+        ErrorCode::InvalidArgument,
+    );
+
+    // Unknown enum values from newer clients are rejected, not misinterpreted
+    let unknown = client_message::Message::ArithmeticRequest(ArithmeticRequest {
+        operation: 99,
+        lhs: int(1),
+        rhs: int(1),
+    });
+    expect_error(request(&mut client, unknown), ErrorCode::InvalidArgument);
+
+    client.disconnect().unwrap();
+    server.stop();
+    handle.join().unwrap();
+}
+
+#[test]
+fn test_add_request_alias() {
+    let server = create_server();
+    let handle = setup_server_thread(server.clone());
+    let mut client = connect(&server);
+
+    let add = client_message::Message::AddRequest(AddRequest { a: -4, b: 10 });
+    match request(&mut client, add) {
+        server_message::Message::AddResponse(response) => assert_eq!(response.result, 6),
+        other => panic!("Expected AddResponse, but received {:?}", other),
+    }
+
+    // The legacy int32 response cannot carry the sum, the connection must survive it
+    let overflow = client_message::Message::AddRequest(AddRequest { a: i32::MAX, b: 1 });
+    expect_error(request(&mut client, overflow), ErrorCode::Overflow);
+
+    let add = client_message::Message::AddRequest(AddRequest { a: 1, b: 2 });
+    match request(&mut client, add) {
+        server_message::Message::AddResponse(response) => assert_eq!(response.result, 3),
+        other => panic!("Expected AddResponse, but received {:?}", other),
+    }
+
+    client.disconnect().unwrap();
+    server.stop();
+    handle.join().unwrap();
+}
@@ -0,0 +1,175 @@
+use embedded_recruitment_task::{
+    audit::{verify_chain, AuditConfig, AuditLog, AuditRecord, AuditSink, Outcome},
+    auth::Credentials,
+    client::Client as AuditedClient,
+    message::{client_message, server_message, EchoMessage, ErrorCode, Put},
+    policy::Policy,
+    server::{Server, ServerConfig},
+};
+use std::{
+    fs,
+    sync::{Arc, Mutex},
+    thread::{self, JoinHandle},
+    time::Duration,
+};
+
+fn setup_server_thread(server: Arc<Server>) -> JoinHandle<()> {
+    thread::spawn(move || {
+        server.run().expect("Server encountered an error");
+    })
+}
+
+fn record(request_id: u64, identity: Option<&str>) -> AuditRecord {
+    AuditRecord {
+        time_ms: 1_700_000_000_000,
+        connection: 7,
+        peer: Some("127.0.0.1:4000".parse().unwrap()),
+        identity: identity.map(str::to_string),
+        request_id,
+        operation: "put",
+        outcome: Outcome::Ok,
+        duration: Duration::from_micros(42),
+    }
+}
+
+// Keeps records in memory so a test can look at them
+#[derive(Debug, Default)]
+struct MemorySink {
+    records: Mutex<Vec<AuditRecord>>,
+}
+
+impl AuditSink for MemorySink {
+    fn record(&self, record: &AuditRecord) {
+        self.records.lock().unwrap().push(record.clone());
+    }
+}
+
+#[test]
+fn test_record_json() {
+    assert_eq!(
+        record(3, Some("sensor \"1\"\n")).to_json(),
+        "{\"time_ms\":1700000000000,\"connection\":7,\"peer\":\"127.0.0.1:4000\",\"identity\":\"sensor \\\"1\\\"\\n\",\
+         \"request_id\":3,\"operation\":\"put\",\"outcome\":\"ok\",\"duration_us\":42}"
+    );
+
+    let denied = AuditRecord {
+        peer: None,
+        outcome: Outcome::Error(ErrorCode::PermissionDenied),
+        ..record(4, None)
+    };
+    let json = denied.to_json();
+    assert!(json.contains("\"peer\":null,\"identity\":null"), "{}", json);
+    assert!(json.contains("\"outcome\":\"error\",\"error\":\"ERROR_CODE_PERMISSION_DENIED\""), "{}", json);
+}
+
+#[test]
+fn test_hash_chain_detects_tampering() {
+    let dir = tempfile::tempdir().unwrap();
+    let config = AuditConfig::new(dir.path().join("audit/audit.log"));
+
+    {
+        let log = AuditLog::open(config.clone()).unwrap();
+        for request_id in 1..=5 {
+            log.append(&record(request_id, Some("sensor-01"))).unwrap();
+        }
+    }
+    // Reopening continues the chain
+    AuditLog::open(config.clone()).unwrap().append(&record(6, Some("sensor-01"))).unwrap();
+    assert_eq!(verify_chain(&config).unwrap(), 6);
+
+    let original = fs::read_to_string(&config.path).unwrap();
+    let lines: Vec<&str> = original.lines().collect();
+    assert!(lines[0].contains("\"prev\":\"0000"), "{}", lines[0]);
+
+    // Editing a record, dropping one, or swapping two all break the chain
+    let edited = original.replacen("sensor-01", "sensor-02", 1);
+    let dropped = [&lines[..2], &lines[3..]].concat().join("\n");
+    let swapped = [lines[0], lines[2], lines[1], lines[3], lines[4], lines[5]].join("\n");
+    for (tampered, broken_line) in [(edited, 1), (dropped, 3), (swapped, 2)] {
+        fs::write(&config.path, tampered).unwrap();
+        let error = verify_chain(&config).unwrap_err();
+        assert!(error.to_string().ends_with(&format!("audit.log:{}: hash chain broken", broken_line)), "{}", error);
+    }
+}
+
+#[test]
+fn test_rotation() {
+    let dir = tempfile::tempdir().unwrap();
+    let config = AuditConfig {
+        max_bytes: 1000,
+        max_files: 2,
+        ..AuditConfig::new(dir.path().join("audit.log"))
+    };
+    let log = AuditLog::open(config.clone()).unwrap();
+    for request_id in 0..40 {
+        log.append(&record(request_id, None)).unwrap();
+    }
+
+    assert!(config.rotated_path(1).exists() && config.rotated_path(2).exists());
+    assert!(!config.rotated_path(3).exists(), "Only max_files rotated files are kept");
+    for index in 0..=2 {
+        // A file is rotated once it reaches the limit, so it is at most one record over
+        let size = fs::metadata(config.rotated_path(index)).unwrap().len();
+        assert!(size < 1000 + 400, "{} bytes", size);
+    }
+
+    // The chain runs on across files, from the oldest kept record to the newest
+    let kept = verify_chain(&config).unwrap();
+    assert!(kept > 3 && kept < 40, "{} records kept", kept);
+    let newest = fs::read_to_string(&config.path).unwrap();
+    assert!(newest.lines().last().unwrap().contains("\"request_id\":39"));
+
+    // Without the chain, lines are plain records
+    let plain = AuditConfig {
+        hash_chain: false,
+        ..AuditConfig::new(dir.path().join("plain.log"))
+    };
+    AuditLog::open(plain.clone()).unwrap().append(&record(1, None)).unwrap();
+    assert_eq!(fs::read_to_string(&plain.path).unwrap(), format!("{}\n", record(1, None).to_json()));
+}
+
+#[test]
+fn test_server_records_every_request() {
+    let sink = Arc::new(MemorySink::default());
+    let config = ServerConfig {
+        authenticator: Some(Arc::new(Credentials::parse("sensor-01 token s1").unwrap())),
+        policy: Some(Policy::parse("allow sensor echo_message\ngrant sensor-01 sensor").unwrap()),
+        audit: Some(sink.clone()),
+        ..ServerConfig::default()
+    };
+    let server = Arc::new(Server::with_config("localhost:0", config).expect("Failed to start server"));
+    let handle = setup_server_thread(server.clone());
+
+    let addr = server.local_addr().unwrap();
+    let mut client = AuditedClient::connect(addr, Duration::from_secs(1)).unwrap();
+    client.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    let echo = || client_message::Message::EchoMessage(EchoMessage { content: "ping".to_string() });
+
+    assert!(client.request(echo()).is_ok()); // Refused, not authenticated yet
+    client.authenticate_token("sensor-01", "s1").unwrap();
+    assert!(client.request(echo()).is_ok());
+    let put = client_message::Message::Put(Put { key: "config/x".to_string(), value: Vec::new() });
+    assert!(matches!(client.request(put).unwrap(), server_message::Message::Error(_)));
+    client.disconnect().unwrap();
+
+    let records = sink.records.lock().unwrap().clone();
+    let summary: Vec<_> = records
+        .iter()
+        .map(|record| (record.request_id, record.operation, record.identity.as_deref(), record.outcome))
+        .collect();
+    assert_eq!(
+        summary,
+        [
+            (1, "echo_message", None, Outcome::Error(ErrorCode::Unauthenticated)),
+            (2, "authenticate", Some("sensor-01"), Outcome::Ok),
+            (3, "echo_message", Some("sensor-01"), Outcome::Ok),
+            (4, "put", Some("sensor-01"), Outcome::Error(ErrorCode::PermissionDenied)),
+        ]
+    );
+    let local = records[0].peer.expect("Peer address is recorded");
+    assert!(local.ip().is_loopback());
+    assert!(records.iter().all(|record| record.connection == records[0].connection && record.time_ms > 0));
+
+    server.stop();
+    handle.join().unwrap();
+}
@@ -0,0 +1,263 @@
+use embedded_recruitment_task::{
+    auth::{hmac_sha256, AuthError, Authenticator, Credential, Credentials, MAX_ATTEMPTS},
+    client::Client as AuthClient,
+    message::{
+        authenticate, client_message, server_message, AuthChallenge, Authenticate, BatchRequest, ClientMessage,
+        EchoMessage, ErrorCode,
+    },
+    server::{Server, ServerConfig},
+};
+use std::{
+    sync::Arc,
+    thread::{self, JoinHandle},
+    time::Duration,
+};
+
+mod client;
+
+const KEY: [u8; 16] = [0x5a; 16];
+
+fn setup_server_thread(server: Arc<Server>) -> JoinHandle<()> {
+    thread::spawn(move || {
+        server.run().expect("Server encountered an error");
+    })
+}
+
+fn create_server(authenticator: Arc<dyn Authenticator>) -> Arc<Server> {
+    let config = ServerConfig {
+        authenticator: Some(authenticator),
+        ..ServerConfig::default()
+    };
+    Arc::new(Server::with_config("localhost:0", config).expect("Failed to start server"))
+}
+
+fn credentials() -> Arc<Credentials> {
+    let file = "\
+        # identity  kind   secret\n\
+        sensor-01   token  s3cret#token\n\
+        \n\
+        gateway     hmac   5a5a5a5a5a5a5a5a5a5a5a5a5a5a5a5a\n";
+    Arc::new(Credentials::parse(file).unwrap())
+}
+
+fn connect(server: &Server) -> AuthClient {
+    let addr = server.local_addr().expect("Server has no local address");
+    let client = AuthClient::connect(addr, Duration::from_secs(1)).expect("Failed to connect to the server");
+    client.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    client
+}
+
+fn echo() -> client_message::Message {
+    client_message::Message::EchoMessage(EchoMessage {
+        content: "hello".to_string(),
+    })
+}
+
+fn expect_error(response: server_message::Message, code: ErrorCode) {
+    match response {
+        server_message::Message::Error(error) => {
+            assert_eq!(error.code, code as i32, "Unexpected error: {}", error.message);
+        }
+        other => panic!("Expected Error, but received {:?}", other),
+    }
+}
+
+fn hmac_request(identity: &str, mac: Vec<u8>) -> client_message::Message {
+    client_message::Message::Authenticate(Authenticate {
+        identity: identity.to_string(),
+        credential: Some(authenticate::Credential::HmacSha256(mac)),
+    })
+}
+
+fn challenge(client: &mut AuthClient) -> Vec<u8> {
+    match client.request(client_message::Message::AuthChallenge(AuthChallenge {})).unwrap() {
+        server_message::Message::AuthChallengeResponse(response) => response.nonce,
+        other => panic!("Expected AuthChallengeResponse, but received {:?}", other),
+    }
+}
+
+#[test]
+fn test_parse_credentials() {
+    let credentials = credentials();
+    assert_eq!(credentials.len(), 2);
+    let debug = format!("{:?}", credentials);
+    assert!(!debug.contains("s3cret"), "Secrets must not be printed: {}", debug);
+
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("credentials");
+    std::fs::write(&path, "device token abc\n").unwrap();
+    assert_eq!(Credentials::load(&path).unwrap().len(), 1);
+
+    for (file, reason) in [
+        ("device token", "expected identity, kind and secret"),
+        ("device password abc", "unknown kind"),
+        ("device hmac xyz", "not hex"),
+        ("device hmac abc", "not hex"),
+        ("device token a\ndevice token b", "appears twice"),
+    ] {
+        let error = Credentials::parse(file).unwrap_err();
+        assert!(error.to_string().contains(reason), "{:?} gave {}", file, error);
+    }
+}
+
+#[test]
+fn test_credentials_authenticator() {
+    let mut credentials = Credentials::new();
+    credentials.insert("device", Credential::Key(KEY.to_vec()));
+    let request = |mac: Vec<u8>| Authenticate {
+        identity: "device".to_string(),
+        credential: Some(authenticate::Credential::HmacSha256(mac)),
+    };
+
+    let nonce = [7; 32];
+    assert_eq!(credentials.authenticate(&request(hmac_sha256(&KEY, &nonce)), Some(&nonce)), Ok("device".to_string()));
+    assert_eq!(
+        credentials.authenticate(&request(hmac_sha256(&KEY, &[8; 32])), Some(&nonce)),
+        Err(AuthError::InvalidCredential)
+    );
+    assert_eq!(credentials.authenticate(&request(hmac_sha256(&KEY, &nonce)), None), Err(AuthError::NoChallenge));
+
+    let token = Authenticate {
+        identity: "device".to_string(),
+        credential: Some(authenticate::Credential::Token("secret".to_string())),
+    };
+    assert_eq!(credentials.authenticate(&token, None), Err(AuthError::WrongMethod));
+    let stranger = Authenticate {
+        identity: "stranger".to_string(),
+        ..token
+    };
+    assert_eq!(credentials.authenticate(&stranger, None), Err(AuthError::UnknownIdentity("stranger".to_string())));
+}
+
+#[test]
+fn test_token_authentication() {
+    let server = create_server(credentials());
+    let handle = setup_server_thread(server.clone());
+    let mut client = connect(&server);
+
+    expect_error(client.request(echo()).unwrap(), ErrorCode::Unauthenticated);
+    // A batch cannot be used to get around the check
+    let batch = client_message::Message::BatchRequest(BatchRequest {
+        requests: vec![ClientMessage {
+            request_id: 0,
+            message: Some(echo()),
+            ..Default::default()
+        }],
+        atomic: false,
+    });
+    expect_error(client.request(batch).unwrap(), ErrorCode::Unauthenticated);
+
+    assert!(client.authenticate_token("sensor-01", "wrong").is_err());
+    assert!(client.authenticate_token("nobody", "s3cret#token").is_err());
+    assert_eq!(client.authenticate_token("sensor-01", "s3cret#token").unwrap(), "sensor-01");
+    assert!(matches!(client.request(echo()).unwrap(), server_message::Message::EchoMessage(_)));
+
+    // The connection keeps its identity
+    let again = client_message::Message::Authenticate(Authenticate {
+        identity: "gateway".to_string(),
+        credential: Some(authenticate::Credential::Token("s3cret#token".to_string())),
+    });
+    expect_error(client.request(again).unwrap(), ErrorCode::InvalidArgument);
+
+    client.disconnect().unwrap();
+    server.stop();
+    handle.join().unwrap();
+}
+
+#[test]
+fn test_hmac_challenge_response() {
+    let server = create_server(credentials());
+    let handle = setup_server_thread(server.clone());
+
+    let mut client = connect(&server);
+    assert_eq!(client.authenticate_hmac("gateway", &KEY).unwrap(), "gateway");
+    assert!(matches!(client.request(echo()).unwrap(), server_message::Message::EchoMessage(_)));
+    client.disconnect().unwrap();
+
+    // Answering without a challenge, or with a stale one, fails
+    let mut client = connect(&server);
+    expect_error(client.request(hmac_request("gateway", hmac_sha256(&KEY, &[0; 32]))).unwrap(), ErrorCode::Unauthenticated);
+    let nonce = challenge(&mut client);
+    assert_eq!(nonce.len(), 32);
+    assert_ne!(nonce, challenge(&mut client), "Every challenge gets a fresh nonce");
+    expect_error(client.request(hmac_request("gateway", hmac_sha256(&KEY, &nonce))).unwrap(), ErrorCode::Unauthenticated);
+
+    // A nonce is used up by the attempt answering it, so a captured answer cannot be replayed
+    let nonce = challenge(&mut client);
+    let answer = hmac_sha256(&KEY, &nonce);
+    expect_error(client.request(hmac_request("gateway", hmac_sha256(b"wrong key", &nonce))).unwrap(), ErrorCode::Unauthenticated);
+    expect_error(client.request(hmac_request("gateway", answer)).unwrap(), ErrorCode::Unauthenticated);
+    client.disconnect().unwrap();
+
+    server.stop();
+    handle.join().unwrap();
+}
+
+#[test]
+fn test_attempts_are_limited() {
+    let server = create_server(credentials());
+    let handle = setup_server_thread(server.clone());
+    let mut client = connect(&server);
+
+    for _ in 0..MAX_ATTEMPTS {
+        assert!(client.authenticate_token("sensor-01", "guess").is_err());
+    }
+    let error = client.authenticate_token("sensor-01", "s3cret#token").unwrap_err();
+    assert!(error.to_string().contains("too many failed attempts"), "{}", error);
+    client.disconnect().unwrap();
+
+    // Other connections are not affected
+    let mut client = connect(&server);
+    assert!(client.authenticate_token("sensor-01", "s3cret#token").is_ok());
+    client.disconnect().unwrap();
+
+    server.stop();
+    handle.join().unwrap();
+}
+
+// Accepts any identity whose token is the identity spelled backwards
+#[derive(Debug)]
+struct Reversed;
+
+impl Authenticator for Reversed {
+    fn authenticate(&self, request: &Authenticate, _challenge: Option<&[u8]>) -> Result<String, AuthError> {
+        match &request.credential {
+            Some(authenticate::Credential::Token(token)) if *token == request.identity.chars().rev().collect::<String>() => {
+                Ok(request.identity.clone())
+            }
+            _ => Err(AuthError::InvalidCredential),
+        }
+    }
+}
+
+#[test]
+fn test_custom_authenticator() {
+    let server = create_server(Arc::new(Reversed));
+    let handle = setup_server_thread(server.clone());
+    let mut client = connect(&server);
+
+    assert!(client.authenticate_token("drone", "drone").is_err());
+    assert_eq!(client.authenticate_token("drone", "enord").unwrap(), "drone");
+    client.disconnect().unwrap();
+
+    server.stop();
+    handle.join().unwrap();
+}
+
+#[test]
+fn test_authentication_disabled() {
+    let server = Arc::new(Server::new("localhost:0").expect("Failed to start server"));
+    let handle = setup_server_thread(server.clone());
+
+    let port = server.local_addr().unwrap().port() as u32;
+    let mut client = client::Client::new("localhost", port, 1000);
+    assert!(client.connect().is_ok(), "Failed to connect to the server");
+    assert!(client.send(client_message::Message::AuthChallenge(AuthChallenge {})).is_ok());
+    expect_error(client.receive().unwrap().message.unwrap(), ErrorCode::Unavailable);
+    assert!(client.send(echo()).is_ok());
+    assert!(matches!(client.receive().unwrap().message, Some(server_message::Message::EchoMessage(_))));
+
+    client.disconnect().unwrap();
+    server.stop();
+    handle.join().unwrap();
+}
@@ -0,0 +1,223 @@
+use embedded_recruitment_task::{
+    client::Client as FramedClient,
+    frame,
+    message::{client_message, server_message, ClientMessage, CountTo, EchoMessage, ServerMessage, StreamStatus, Subscribe},
+    outbound::{self, BackpressureConfig, QueueError},
+    server::{Server, ServerConfig},
+};
+use std::{
+    io::{ErrorKind, Read},
+    net::TcpStream,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+
+fn setup_server_thread(server: Arc<Server>) -> JoinHandle<()> {
+    thread::spawn(move || {
+        server.run().expect("Server encountered an error");
+    })
+}
+
+fn create_server(backpressure: BackpressureConfig) -> Arc<Server> {
+    let config = ServerConfig { backpressure, ..ServerConfig::default() };
+    Arc::new(Server::with_config("localhost:0", config).expect("Failed to start server"))
+}
+
+// Limits small enough for a test to reach
+fn small_limits(max_congestion: Duration) -> BackpressureConfig {
+    BackpressureConfig { high_watermark: 4 * 1024, low_watermark: 1024, max_queued: 64 * 1024, max_congestion }
+}
+
+fn echo_reply(len: usize) -> ServerMessage {
+    let echo = EchoMessage { content: "x".repeat(len) };
+    ServerMessage { request_id: 0, message: Some(server_message::Message::EchoMessage(echo)) }
+}
+
+// Reads whatever the server still sends until it closes the connection, fails if it stays open
+fn assert_closed(mut stream: TcpStream) {
+    stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    let mut buffer = vec![0u8; 64 * 1024];
+    loop {
+        match stream.read(&mut buffer) {
+            Ok(0) => return,
+            Ok(_) => continue,
+            Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => panic!("Connection is still open"),
+            Err(_) => return, // Reset, the server dropped what the client had not read
+        }
+    }
+}
+
+#[test]
+fn test_watermarks() {
+    let config = BackpressureConfig { high_watermark: 300, low_watermark: 100, max_queued: 10_000, max_congestion: Duration::from_secs(5) };
+    let (outbound, queue) = outbound::channel(config);
+
+    // Up to the high watermark nothing waits
+    for _ in 0..3 {
+        outbound.send(echo_reply(90)).unwrap();
+    }
+    assert!(!outbound.is_congested());
+    assert!(outbound.wait_for_room().is_ok());
+    outbound.send(echo_reply(90)).unwrap();
+    assert!(outbound.is_congested());
+
+    // A waiting producer resumes once the queue drains below the low watermark, not before
+    let started = Instant::now();
+    thread::scope(|scope| {
+        let waiter = scope.spawn(|| outbound.wait_for_room());
+        thread::sleep(Duration::from_millis(100));
+        queue.recv().unwrap();
+        queue.recv().unwrap();
+        assert!(outbound.is_congested(), "Still above the low watermark");
+        assert!(!waiter.is_finished());
+        queue.recv().unwrap();
+        assert_eq!(waiter.join().unwrap(), Ok(()));
+    });
+    assert!(started.elapsed() >= Duration::from_millis(100));
+    assert!(!outbound.is_congested());
+    assert!(outbound.queued_bytes() < 100);
+
+    // The queue ends once every sender is gone and it is drained
+    let pushed = outbound.clone();
+    drop(outbound);
+    pushed.send(echo_reply(10)).unwrap();
+    drop(pushed);
+    assert_eq!(queue.iter().count(), 2);
+    assert!(queue.recv().is_none());
+}
+
+#[test]
+fn test_overloaded_queue_disconnects() {
+    let config = BackpressureConfig { high_watermark: 200, low_watermark: 100, max_queued: 1000, max_congestion: Duration::from_millis(200) };
+
+    // Going past `max_queued` disconnects at once
+    let (outbound, queue) = outbound::channel(config.clone());
+    let hook_ran = Arc::new(AtomicBool::new(false));
+    let flag = hook_ran.clone();
+    outbound.on_disconnect(move || flag.store(true, Ordering::SeqCst));
+    let mut sent = 0;
+    let error = loop {
+        match outbound.send(echo_reply(90)) {
+            Ok(()) => sent += 1,
+            Err(e) => break e,
+        }
+    };
+    assert_eq!(error, QueueError::Overloaded);
+    assert!((9..=11).contains(&sent), "{} sent", sent);
+    assert!(hook_ran.load(Ordering::SeqCst));
+    assert_eq!(outbound.send(echo_reply(1)), Err(QueueError::Overloaded));
+    assert!(queue.recv().is_none(), "Queued messages are dropped");
+
+    // So does staying congested, whether a producer is waiting or sending
+    let (outbound, _queue) = outbound::channel(config.clone());
+    outbound.send(echo_reply(300)).unwrap();
+    let started = Instant::now();
+    assert_eq!(outbound.wait_for_room(), Err(QueueError::Overloaded));
+    assert!(started.elapsed() >= Duration::from_millis(200));
+    let (outbound, _queue) = outbound::channel(config);
+    outbound.send(echo_reply(300)).unwrap();
+    thread::sleep(Duration::from_millis(250));
+    assert_eq!(outbound.send(echo_reply(1)), Err(QueueError::Overloaded));
+
+    // Once the writer is gone sends fail without disconnecting anyone
+    let (outbound, queue) = outbound::channel(BackpressureConfig::default());
+    drop(queue);
+    assert_eq!(outbound.send(echo_reply(1)), Err(QueueError::Closed));
+    assert_eq!(outbound.wait_for_room(), Err(QueueError::Closed));
+
+    let inconsistent = ServerConfig {
+        backpressure: BackpressureConfig { low_watermark: 2048, high_watermark: 1024, ..BackpressureConfig::default() },
+        ..ServerConfig::default()
+    };
+    assert_eq!(Server::with_config("localhost:0", inconsistent).err().unwrap().kind(), ErrorKind::InvalidInput);
+}
+
+#[test]
+fn test_slow_subscriber_is_disconnected() {
+    let server = create_server(small_limits(Duration::from_secs(30)));
+    let handle = setup_server_thread(server.clone());
+
+    let mut subscriber = TcpStream::connect(server.local_addr().unwrap()).unwrap();
+    let subscribe = ClientMessage {
+        request_id: 1,
+        message: Some(client_message::Message::Subscribe(Subscribe { pattern: "firehose".to_string() })),
+        ..Default::default()
+    };
+    frame::write_message(&mut subscriber, &subscribe).unwrap();
+    let reply: ServerMessage = frame::read_message(&mut subscriber).unwrap().unwrap();
+    assert!(matches!(reply.message, Some(server_message::Message::SubscribeResponse(_))));
+
+    // Publishing never blocks on the subscriber, which is dropped once the socket and its queue are full
+    let payload = vec![7u8; 32 * 1024];
+    let mut published = 0;
+    while server.topics().publish("firehose", &payload).unwrap() == 1 {
+        published += 1;
+        assert!(published < 10_000, "Subscriber was never disconnected");
+    }
+    assert_eq!(server.topics().subscription_count(1), 0);
+    assert_closed(subscriber);
+
+    // Everyone else is still served
+    let mut client = FramedClient::connect(server.local_addr().unwrap(), Duration::from_secs(1)).unwrap();
+    assert!(client.ping().is_ok());
+
+    server.stop();
+    handle.join().unwrap();
+}
+
+#[test]
+fn test_stream_waits_for_slow_reader() {
+    let server = create_server(small_limits(Duration::from_secs(5)));
+    let handle = setup_server_thread(server.clone());
+    let mut client = FramedClient::connect(server.local_addr().unwrap(), Duration::from_secs(1)).unwrap();
+    client.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+
+    // Far more than the queue may hold, held back while the client is busy elsewhere
+    let count = CountTo { start: 1, end: 100_000, step: 1, interval_ms: 0 };
+    let request_id = client.send(client_message::Message::CountTo(count)).unwrap();
+    thread::sleep(Duration::from_millis(300));
+
+    let mut received = 0;
+    let end = loop {
+        let frame = client.receive().unwrap();
+        assert_eq!(frame.request_id, request_id);
+        match frame.message {
+            Some(server_message::Message::StreamItem(_)) => received += 1,
+            Some(server_message::Message::StreamEnd(end)) => break end,
+            other => panic!("Expected a stream frame, but received {:?}", other),
+        }
+    };
+    assert_eq!(end.status(), StreamStatus::Completed);
+    assert_eq!((end.items, received), (100_000, 100_000), "Nothing is dropped");
+    assert!(client.ping().is_ok());
+
+    server.stop();
+    handle.join().unwrap();
+}
+
+#[test]
+fn test_client_that_never_reads_is_disconnected() {
+    let server = create_server(small_limits(Duration::from_millis(500)));
+    let handle = setup_server_thread(server.clone());
+    let mut stream = TcpStream::connect(server.local_addr().unwrap()).unwrap();
+
+    // Pipelined echoes whose replies are never read: the server stops reading, then gives up on the client
+    stream.set_write_timeout(Some(Duration::from_millis(200))).unwrap();
+    let echo = ClientMessage {
+        request_id: 1,
+        message: Some(client_message::Message::EchoMessage(EchoMessage { content: "x".repeat(16 * 1024) })),
+        ..Default::default()
+    };
+    let started = Instant::now();
+    while frame::write_message(&mut stream, &echo).is_ok() {
+        assert!(started.elapsed() < Duration::from_secs(10), "Server kept reading");
+    }
+    assert_closed(stream);
+
+    server.stop();
+    handle.join().unwrap();
+}
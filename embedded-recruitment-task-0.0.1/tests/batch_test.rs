@@ -0,0 +1,309 @@
+use embedded_recruitment_task::{
+    message::{
+        client_message, server_message, AddRequest, BatchRequest, BatchResponse, ClientMessage,
+        CompareAndSwap, EchoMessage, ErrorCode, Get, Publish, Put, ServerMessage, Subscribe,
+    },
+    persistence::{PersistenceConfig, WAL_FILE},
+    server::Server,
+    store::{KeyValueAccess, KeyValueStore, StoreError},
+};
+use std::{
+    fs::{self, OpenOptions},
+    sync::Arc,
+    thread::{self, JoinHandle},
+};
+
+mod client;
+
+fn setup_server_thread(server: Arc<Server>) -> JoinHandle<()> {
+    thread::spawn(move || {
+        server.run().expect("Server encountered an error");
+    })
+}
+
+fn create_server() -> Arc<Server> {
+    let server = Server::new("localhost:0");
+    Arc::new(server.expect("Failed to start server"))
+}
+
+fn connect(server: &Server) -> client::Client {
+    let port = server.local_addr().expect("Server has no local address").port() as u32;
+    let mut client = client::Client::new("localhost", port, 1000);
+    assert!(client.connect().is_ok(), "Failed to connect to the server");
+    client
+}
+
+fn item(message: client_message::Message) -> ClientMessage {
+    ClientMessage { request_id: 0, message: Some(message), ..Default::default() }
+}
+
+fn put(key: &str, value: &[u8]) -> ClientMessage {
+    item(client_message::Message::Put(Put {
+        key: key.to_string(),
+        value: value.to_vec(),
+    }))
+}
+
+fn get(key: &str) -> ClientMessage {
+    item(client_message::Message::Get(Get { key: key.to_string() }))
+}
+
+fn echo(content: &str) -> ClientMessage {
+    item(client_message::Message::EchoMessage(EchoMessage {
+        content: content.to_string(),
+    }))
+}
+
+fn send_batch(client: &mut client::Client, requests: Vec<ClientMessage>, atomic: bool) -> BatchResponse {
+    let message = client_message::Message::BatchRequest(BatchRequest { requests, atomic });
+    assert!(client.send(message).is_ok(), "Failed to send BatchRequest");
+    match client.receive().expect("Failed to receive BatchResponse").message {
+        Some(server_message::Message::BatchResponse(response)) => response,
+        other => panic!("Expected BatchResponse, but received {:?}", other),
+    }
+}
+
+fn error_code(response: &ServerMessage) -> Option<i32> {
+    match &response.message {
+        Some(server_message::Message::Error(error)) => Some(error.code),
+        _ => None,
+    }
+}
+
+#[test]
+fn test_independent_batch_preserves_order() {
+    let server = create_server();
+    let handle = setup_server_thread(server.clone());
+    let mut client = connect(&server);
+
+    // Only pure requests, so the server runs them in parallel
+    let requests: Vec<_> = (0..200)
+        .map(|i| {
+            if i % 2 == 0 {
+                echo(&format!("item {}", i))
+            } else {
+                item(client_message::Message::AddRequest(AddRequest { a: i, b: 1 }))
+            }
+        })
+        .collect();
+
+    let response = send_batch(&mut client, requests, false);
+    assert!(response.committed);
+    assert_eq!(response.responses.len(), 200);
+    for (i, response) in response.responses.into_iter().enumerate() {
+        match response.message {
+            Some(server_message::Message::EchoMessage(echo)) => assert_eq!(echo.content, format!("item {}", i)),
+            Some(server_message::Message::AddResponse(add)) => assert_eq!(add.result, i as i32 + 1),
+            other => panic!("Unexpected response {:?} at index {}", other, i),
+        }
+    }
+
+    client.disconnect().unwrap();
+    server.stop();
+    handle.join().unwrap();
+}
+
+#[test]
+fn test_independent_batch_isolates_failures() {
+    let server = create_server();
+    let handle = setup_server_thread(server.clone());
+    let mut client = connect(&server);
+
+    let requests = vec![
+        put("a", b"1"),
+        put("", b"rejected"),
+        item(client_message::Message::AddRequest(AddRequest { a: i32::MAX, b: 1 })),
+        ClientMessage { request_id: 0, message: None, ..Default::default() },
+        get("a"),
+    ];
+    let response = send_batch(&mut client, requests, false);
+    assert!(response.committed);
+
+    let responses = response.responses;
+    assert!(matches!(responses[0].message, Some(server_message::Message::PutResponse(_))));
+    assert_eq!(error_code(&responses[1]), Some(ErrorCode::InvalidArgument as i32));
+    assert_eq!(error_code(&responses[2]), Some(ErrorCode::Overflow as i32));
+    assert_eq!(error_code(&responses[3]), Some(ErrorCode::InvalidArgument as i32));
+    match &responses[4].message {
+        Some(server_message::Message::GetResponse(get)) => assert_eq!(get.value, b"1"),
+        other => panic!("Expected GetResponse, but received {:?}", other),
+    }
+
+    client.disconnect().unwrap();
+    server.stop();
+    handle.join().unwrap();
+}
+
+#[test]
+fn test_atomic_batch_commits_all() {
+    let server = create_server();
+    let handle = setup_server_thread(server.clone());
+    let mut client = connect(&server);
+
+    let requests = vec![
+        put("x", b"1"),
+        put("y", b"2"),
+        get("x"), // Sees the write staged earlier in the same batch
+    ];
+    let response = send_batch(&mut client, requests, true);
+    assert!(response.committed);
+    match &response.responses[2].message {
+        Some(server_message::Message::GetResponse(get)) => {
+            assert!(get.found);
+            assert_eq!(get.value, b"1");
+        }
+        other => panic!("Expected GetResponse, but received {:?}", other),
+    }
+    assert_eq!(server.store().list_keys("", 0), ["x", "y"]);
+
+    client.disconnect().unwrap();
+    server.stop();
+    handle.join().unwrap();
+}
+
+#[test]
+fn test_atomic_batch_rolls_back_on_failure() {
+    let server = create_server();
+    let handle = setup_server_thread(server.clone());
+    let mut client = connect(&server);
+    let mut subscriber = connect(&server);
+
+    let subscribe = client_message::Message::Subscribe(Subscribe {
+        pattern: "events".to_string(),
+    });
+    assert!(subscriber.send(subscribe).is_ok());
+    assert!(subscriber.receive().is_ok());
+
+    let version = server.store().put("guard", b"v1".to_vec()).unwrap();
+    let requests = vec![
+        put("x", b"1"),
+        item(client_message::Message::Publish(Publish {
+            topic: "events".to_string(),
+            payload: b"x written".to_vec(),
+        })),
+        item(client_message::Message::CompareAndSwap(CompareAndSwap {
+            key: "guard".to_string(),
+            expected_version: version + 100, // Stale, makes the batch fail
+            value: b"v2".to_vec(),
+        })),
+        put("y", b"2"),
+    ];
+    let response = send_batch(&mut client, requests, true);
+    assert!(!response.committed, "Batch should have been rolled back");
+
+    let responses = response.responses;
+    assert_eq!(error_code(&responses[0]), Some(ErrorCode::Aborted as i32));
+    assert_eq!(error_code(&responses[1]), Some(ErrorCode::Aborted as i32));
+    match &responses[2].message {
+        Some(server_message::Message::CompareAndSwapResponse(cas)) => assert!(!cas.swapped),
+        other => panic!("Expected CompareAndSwapResponse, but received {:?}", other),
+    }
+    assert_eq!(error_code(&responses[3]), Some(ErrorCode::Aborted as i32));
+
+    assert_eq!(server.store().list_keys("", 0), ["guard"], "No write may survive a rollback");
+
+    // The deferred publication was never delivered, the next frame is this echo
+    assert!(subscriber.send(client_message::Message::EchoMessage(Default::default())).is_ok());
+    match subscriber.receive().unwrap().message {
+        Some(server_message::Message::EchoMessage(_)) => {}
+        other => panic!("Expected EchoMessage, but received {:?}", other),
+    }
+
+    client.disconnect().unwrap();
+    subscriber.disconnect().unwrap();
+    server.stop();
+    handle.join().unwrap();
+}
+
+#[test]
+fn test_atomic_batch_publishes_after_commit() {
+    let server = create_server();
+    let handle = setup_server_thread(server.clone());
+    let mut client = connect(&server);
+
+    let subscribe = client_message::Message::Subscribe(Subscribe {
+        pattern: "events/#".to_string(),
+    });
+    assert!(client.send(subscribe).is_ok());
+    assert!(client.receive().is_ok());
+
+    let requests = vec![
+        put("state", b"ready"),
+        item(client_message::Message::Publish(Publish {
+            topic: "events/state".to_string(),
+            payload: b"ready".to_vec(),
+        })),
+    ];
+    let message = client_message::Message::BatchRequest(BatchRequest { requests, atomic: true });
+    assert!(client.send(message).is_ok());
+
+    // Publishing happens while the batch reply is being built, so the publication comes first
+    match client.receive().unwrap().message {
+        Some(server_message::Message::Publication(publication)) => assert_eq!(publication.payload, b"ready"),
+        other => panic!("Expected Publication, but received {:?}", other),
+    }
+    match client.receive().unwrap().message {
+        Some(server_message::Message::BatchResponse(response)) => {
+            assert!(response.committed);
+            match &response.responses[1].message {
+                Some(server_message::Message::PublishResponse(publish)) => assert_eq!(publish.delivered, 1),
+                other => panic!("Expected PublishResponse, but received {:?}", other),
+            }
+        }
+        other => panic!("Expected BatchResponse, but received {:?}", other),
+    }
+
+    client.disconnect().unwrap();
+    server.stop();
+    handle.join().unwrap();
+}
+
+#[test]
+fn test_nested_batch_rejected() {
+    let server = create_server();
+    let handle = setup_server_thread(server.clone());
+    let mut client = connect(&server);
+
+    let nested = item(client_message::Message::BatchRequest(BatchRequest {
+        requests: vec![echo("inner")],
+        atomic: false,
+    }));
+    let response = send_batch(&mut client, vec![echo("outer"), nested], false);
+    assert_eq!(error_code(&response.responses[1]), Some(ErrorCode::InvalidArgument as i32));
+
+    client.disconnect().unwrap();
+    server.stop();
+    handle.join().unwrap();
+}
+
+#[test]
+fn test_transaction_is_durable_as_a_unit() {
+    let dir = tempfile::tempdir().unwrap();
+    let config = PersistenceConfig {
+        snapshot_every: 0,
+        ..PersistenceConfig::new(dir.path())
+    };
+
+    {
+        let store = KeyValueStore::open(config.clone()).unwrap();
+        store
+            .transaction(|transaction| -> Result<(), StoreError> {
+                transaction.put("a", b"1".to_vec())?;
+                transaction.put("b", b"2".to_vec())?;
+                transaction.delete("missing")?;
+                Ok(())
+            })
+            .unwrap();
+    }
+    let store = KeyValueStore::open(config.clone()).unwrap();
+    assert_eq!(store.list_keys("", 0), ["a", "b"]);
+    drop(store);
+
+    // Cutting the single batch record short loses the whole transaction, never half of it
+    let path = dir.path().join(WAL_FILE);
+    let len = fs::metadata(&path).unwrap().len();
+    OpenOptions::new().write(true).open(&path).unwrap().set_len(len - 1).unwrap();
+
+    let store = KeyValueStore::open(config).unwrap();
+    assert!(store.is_empty(), "A torn transaction must not be partially applied");
+}
@@ -0,0 +1,350 @@
+use embedded_recruitment_task::{
+    blob::{upload_id, validate_name, BlobConfig, BlobError, BlobStore, MAX_CHUNK_LEN},
+    client::Client as TransferClient,
+    message::{
+        client_message, server_message, DownloadBegin, DownloadChunk, ErrorCode, UploadBegin, UploadChunk,
+        UploadCommit,
+    },
+    server::{Server, ServerConfig},
+};
+use sha2::{Digest, Sha256};
+use std::{
+    fs,
+    path::Path,
+    sync::Arc,
+    thread::{self, JoinHandle},
+    time::Duration,
+};
+
+mod client;
+
+fn setup_server_thread(server: Arc<Server>) -> JoinHandle<()> {
+    thread::spawn(move || {
+        server.run().expect("Server encountered an error");
+    })
+}
+
+fn create_server(directory: &Path, quota: u64) -> Arc<Server> {
+    let config = ServerConfig {
+        blobs: Some(BlobConfig {
+            quota,
+            ..BlobConfig::new(directory)
+        }),
+        ..ServerConfig::default()
+    };
+    Arc::new(Server::with_config("localhost:0", config).expect("Failed to start server"))
+}
+
+fn connect(server: &Server) -> TransferClient {
+    let addr = server.local_addr().expect("Server has no local address");
+    let client = TransferClient::connect(addr, Duration::from_secs(1)).expect("Failed to connect to the server");
+    client.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    client
+}
+
+// Deterministic test data that is not the same byte over and over
+fn firmware(len: usize) -> Vec<u8> {
+    (0..len).map(|i| (i * 7 + i / 251) as u8).collect()
+}
+
+fn expect_error(response: server_message::Message, code: ErrorCode) {
+    match response {
+        server_message::Message::Error(error) => {
+            assert_eq!(error.code, code as i32, "Unexpected error: {}", error.message);
+        }
+        other => panic!("Expected Error, but received {:?}", other),
+    }
+}
+
+fn begin(client: &mut TransferClient, name: &str, data: &[u8]) -> (String, u64) {
+    let begin = client_message::Message::UploadBegin(UploadBegin {
+        name: name.to_string(),
+        size: data.len() as u64,
+        sha256: Sha256::digest(data).to_vec(),
+    });
+    match client.request(begin).unwrap() {
+        server_message::Message::UploadBeginResponse(response) => (response.upload_id, response.offset),
+        other => panic!("Expected UploadBeginResponse, but received {:?}", other),
+    }
+}
+
+fn chunk(upload_id: &str, offset: u64, data: &[u8]) -> client_message::Message {
+    client_message::Message::UploadChunk(UploadChunk {
+        upload_id: upload_id.to_string(),
+        offset,
+        data: data.to_vec(),
+    })
+}
+
+#[test]
+fn test_validate_name() {
+    for name in ["firmware.bin", "image v2", ".hidden", "a".repeat(255).as_str()] {
+        assert_eq!(validate_name(name), Ok(()), "{:?}", name);
+    }
+    for name in ["", ".", "..", "../etc/passwd", "dir/file", "dir\\file", "nul\0", "a".repeat(256).as_str()] {
+        assert!(matches!(validate_name(name), Err(BlobError::InvalidName(_))), "{:?}", name);
+    }
+}
+
+#[test]
+fn test_upload_and_download() {
+    let dir = tempfile::tempdir().unwrap();
+    let server = create_server(dir.path(), 10 * 1024 * 1024);
+    let handle = setup_server_thread(server.clone());
+    let mut client = connect(&server);
+
+    // Larger than one download chunk and not a multiple of the upload chunk
+    let data = firmware(MAX_CHUNK_LEN + 12_345);
+    let committed = client.upload("firmware.bin", &data, 64 * 1024).unwrap();
+    assert_eq!(committed.name, "firmware.bin");
+    assert_eq!(committed.size, data.len() as u64);
+    assert_eq!(std::fs::read(dir.path().join("files/firmware.bin")).unwrap(), data);
+
+    assert_eq!(client.download("firmware.bin").unwrap(), data);
+
+    // Chunks can be fetched from any offset, past the end is an error
+    let partial = client_message::Message::DownloadChunk(DownloadChunk {
+        name: "firmware.bin".to_string(),
+        offset: 100,
+        length: 10,
+    });
+    match client.request(partial).unwrap() {
+        server_message::Message::DownloadChunkResponse(response) => assert_eq!(response.data, data[100..110]),
+        other => panic!("Expected DownloadChunkResponse, but received {:?}", other),
+    }
+    let past_end = client_message::Message::DownloadChunk(DownloadChunk {
+        name: "firmware.bin".to_string(),
+        offset: data.len() as u64 + 1,
+        length: 10,
+    });
+    expect_error(client.request(past_end).unwrap(), ErrorCode::InvalidArgument);
+
+    let missing = client_message::Message::DownloadBegin(DownloadBegin {
+        name: "missing.bin".to_string(),
+    });
+    expect_error(client.request(missing).unwrap(), ErrorCode::NotFound);
+
+    client.disconnect().unwrap();
+    server.stop();
+    handle.join().unwrap();
+}
+
+#[test]
+fn test_resume_after_reconnect() {
+    let dir = tempfile::tempdir().unwrap();
+    let server = create_server(dir.path(), 10 * 1024 * 1024);
+    let handle = setup_server_thread(server.clone());
+    let data = firmware(100_000);
+
+    // First attempt sends 40 KB and loses its connection
+    let mut client = connect(&server);
+    let (upload_id, offset) = begin(&mut client, "image.bin", &data);
+    assert_eq!(offset, 0);
+    for start in (0..40_000).step_by(10_000) {
+        let response = client.request(chunk(&upload_id, start, &data[start as usize..start as usize + 10_000]));
+        assert!(matches!(response.unwrap(), server_message::Message::UploadChunkResponse(_)));
+    }
+    client.disconnect().unwrap();
+
+    // Beginning the same upload again picks up at the same id and offset
+    let mut client = connect(&server);
+    assert_eq!(begin(&mut client, "image.bin", &data), (upload_id.clone(), 40_000));
+
+    // Chunks must continue exactly where the upload stopped
+    expect_error(client.request(chunk(&upload_id, 30_000, &data[30_000..40_000])).unwrap(), ErrorCode::InvalidArgument);
+    let early_commit = client_message::Message::UploadCommit(UploadCommit {
+        upload_id: upload_id.clone(),
+    });
+    expect_error(client.request(early_commit).unwrap(), ErrorCode::InvalidArgument);
+
+    let committed = client.upload("image.bin", &data, 25_000).unwrap();
+    assert_eq!(committed.size, data.len() as u64);
+    assert_eq!(server.blobs().unwrap().pending_uploads(), 0);
+    assert_eq!(client.download("image.bin").unwrap(), data);
+
+    client.disconnect().unwrap();
+    server.stop();
+    handle.join().unwrap();
+}
+
+#[test]
+fn test_resume_after_restart() {
+    let dir = tempfile::tempdir().unwrap();
+    let config = BlobConfig::new(dir.path());
+    let data = firmware(5000);
+    let sha256 = Sha256::digest(&data).to_vec();
+
+    {
+        let blobs = BlobStore::open(config.clone()).unwrap();
+        let (id, _) = blobs.begin_upload("update.bin", data.len() as u64, &sha256).unwrap();
+        assert_eq!(id, upload_id("update.bin", data.len() as u64, &sha256));
+        blobs.write_chunk(&id, 0, &data[..3000]).unwrap();
+    }
+
+    let blobs = BlobStore::open(config).unwrap();
+    assert_eq!(blobs.pending_uploads(), 1);
+    let (id, offset) = blobs.begin_upload("update.bin", data.len() as u64, &sha256).unwrap();
+    assert_eq!(offset, 3000);
+    assert_eq!(blobs.write_chunk(&id, offset, &data[3000..]), Ok(5000));
+    assert_eq!(blobs.commit_upload(&id), Ok(("update.bin".to_string(), 5000)));
+    assert_eq!(blobs.describe("update.bin"), Ok((5000, sha256)));
+}
+
+#[test]
+fn test_checksum_mismatch_discards_upload() {
+    let dir = tempfile::tempdir().unwrap();
+    let server = create_server(dir.path(), 1024 * 1024);
+    let handle = setup_server_thread(server.clone());
+    let mut client = connect(&server);
+
+    let data = firmware(1000);
+    let (upload_id, _) = begin(&mut client, "corrupt.bin", &data);
+    let mut corrupted = data.clone();
+    corrupted[500] ^= 0xff;
+    assert!(matches!(
+        client.request(chunk(&upload_id, 0, &corrupted)).unwrap(),
+        server_message::Message::UploadChunkResponse(_)
+    ));
+
+    let commit = client_message::Message::UploadCommit(UploadCommit {
+        upload_id: upload_id.clone(),
+    });
+    expect_error(client.request(commit.clone()).unwrap(), ErrorCode::ChecksumMismatch);
+    expect_error(client.request(commit).unwrap(), ErrorCode::NotFound);
+    assert!(!dir.path().join("files/corrupt.bin").exists());
+    assert_eq!(server.blobs().unwrap().used(), 0, "A discarded upload must release its quota");
+
+    client.disconnect().unwrap();
+    server.stop();
+    handle.join().unwrap();
+}
+
+#[test]
+fn test_quota_and_invalid_uploads() {
+    let dir = tempfile::tempdir().unwrap();
+    let server = create_server(dir.path(), 10_000);
+    let handle = setup_server_thread(server.clone());
+    let mut client = connect(&server);
+
+    client.upload("a.bin", &firmware(6000), 1000).unwrap();
+
+    // Reserved up front, so a large upload fails before any data is sent
+    let too_large = client_message::Message::UploadBegin(UploadBegin {
+        name: "b.bin".to_string(),
+        size: 5000,
+        sha256: vec![0; 32],
+    });
+    expect_error(client.request(too_large).unwrap(), ErrorCode::QuotaExceeded);
+
+    // Replacing a file only needs room for the difference
+    client.upload("a.bin", &firmware(9000), 4000).unwrap();
+    assert_eq!(server.blobs().unwrap().used(), 9000);
+
+    let bad_name = client_message::Message::UploadBegin(UploadBegin {
+        name: "../escape".to_string(),
+        size: 1,
+        sha256: vec![0; 32],
+    });
+    expect_error(client.request(bad_name).unwrap(), ErrorCode::InvalidArgument);
+
+    let bad_digest = client_message::Message::UploadBegin(UploadBegin {
+        name: "c.bin".to_string(),
+        size: 1,
+        sha256: vec![0; 4],
+    });
+    expect_error(client.request(bad_digest).unwrap(), ErrorCode::InvalidArgument);
+
+    // Sending more bytes than declared is rejected
+    let data = firmware(100);
+    let (upload_id, _) = begin(&mut client, "small.bin", &data);
+    expect_error(client.request(chunk(&upload_id, 0, &firmware(101))).unwrap(), ErrorCode::InvalidArgument);
+
+    expect_error(client.request(chunk("no-such-upload", 0, b"x")).unwrap(), ErrorCode::NotFound);
+
+    client.disconnect().unwrap();
+    server.stop();
+    handle.join().unwrap();
+}
+
+#[test]
+fn test_transfer_disabled_by_default() {
+    let server = Arc::new(Server::new("localhost:0").expect("Failed to start server"));
+    let handle = setup_server_thread(server.clone());
+    assert!(server.blobs().is_none());
+
+    let port = server.local_addr().unwrap().port() as u32;
+    let mut client = client::Client::new("localhost", port, 1000);
+    assert!(client.connect().is_ok(), "Failed to connect to the server");
+    let download = client_message::Message::DownloadBegin(DownloadBegin {
+        name: "firmware.bin".to_string(),
+    });
+    assert!(client.send(download).is_ok());
+    expect_error(client.receive().unwrap().message.unwrap(), ErrorCode::Unavailable);
+
+    client.disconnect().unwrap();
+    server.stop();
+    handle.join().unwrap();
+}
+
+// Number of `.part` and `.meta` files left in the storage directory
+fn partial_files(directory: &Path) -> usize {
+    fs::read_dir(directory.join("partial")).unwrap().count()
+}
+
+#[test]
+fn test_idle_uploads_expire() {
+    let dir = tempfile::tempdir().unwrap();
+    let config = BlobConfig { upload_expiry: Some(Duration::from_millis(300)), ..BlobConfig::new(dir.path()) };
+    let data = firmware(5000);
+    let sha256 = Sha256::digest(&data).to_vec();
+
+    {
+        let blobs = BlobStore::open(config.clone()).unwrap();
+        let (id, _) = blobs.begin_upload("slow.bin", data.len() as u64, &sha256).unwrap();
+        assert_eq!(blobs.used(), 5000);
+
+        // Every chunk keeps the upload alive
+        for offset in [0, 1000, 2000] {
+            thread::sleep(Duration::from_millis(150));
+            assert_eq!(blobs.write_chunk(&id, offset, &data[offset as usize..offset as usize + 1000]), Ok(offset + 1000));
+        }
+
+        // Once idle for too long it is gone, with its reservation and files
+        thread::sleep(Duration::from_millis(400));
+        assert_eq!((blobs.pending_uploads(), blobs.used()), (0, 0));
+        assert_eq!(partial_files(dir.path()), 0);
+        assert_eq!(blobs.write_chunk(&id, 3000, &data[3000..]), Err(BlobError::UnknownUpload(id)));
+
+        // An upload left behind by a restart expires too
+        let (id, _) = blobs.begin_upload("abandoned.bin", data.len() as u64, &sha256).unwrap();
+        blobs.write_chunk(&id, 0, &data[..1000]).unwrap();
+    }
+    thread::sleep(Duration::from_millis(400));
+    let blobs = BlobStore::open(config).unwrap();
+    assert_eq!(blobs.pending_uploads(), 0);
+    assert_eq!(partial_files(dir.path()), 0);
+}
+
+#[test]
+fn test_upload_is_committed_once() {
+    let dir = tempfile::tempdir().unwrap();
+    let blobs = BlobStore::open(BlobConfig::new(dir.path())).unwrap();
+    let data = firmware(4 * 1024 * 1024);
+    let sha256 = Sha256::digest(&data).to_vec();
+    let (id, _) = blobs.begin_upload("large.bin", data.len() as u64, &sha256).unwrap();
+    for (index, piece) in data.chunks(MAX_CHUNK_LEN).enumerate() {
+        blobs.write_chunk(&id, (index * MAX_CHUNK_LEN) as u64, piece).unwrap();
+    }
+
+    // Verification runs outside the lock, yet only one of two racing commits stores the file
+    let results: Vec<_> = thread::scope(|scope| {
+        let commits: Vec<_> = (0..2).map(|_| scope.spawn(|| blobs.commit_upload(&id))).collect();
+        commits.into_iter().map(|commit| commit.join().unwrap()).collect()
+    });
+    assert_eq!(results.iter().filter(|result| result.is_ok()).count(), 1, "{:?}", results);
+    assert!(results
+        .iter()
+        .any(|result| matches!(result, Err(BlobError::Committing(_) | BlobError::UnknownUpload(_)))));
+    assert_eq!(blobs.describe("large.bin"), Ok((data.len() as u64, sha256)));
+    assert_eq!((blobs.pending_uploads(), blobs.used()), (0, data.len() as u64));
+}
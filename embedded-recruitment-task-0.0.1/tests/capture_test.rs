@@ -0,0 +1,197 @@
+use embedded_recruitment_task::{
+    auth::Credentials,
+    capture::{decode_replies, replay, Capture, CaptureConfig, Direction, ReplayOptions},
+    client::Client as RecordedClient,
+    codec::{CompressionConfig, CompressionStats, FrameCodec},
+    message::{client_message, server_message, ClientMessage, CountTo, EchoMessage, Get, Put},
+    server::{Server, ServerConfig},
+};
+use prost::Message;
+use std::{
+    fs::{self, OpenOptions},
+    io::Write,
+    path::{Path, PathBuf},
+    sync::Arc,
+    thread::{self, JoinHandle},
+    time::Duration,
+};
+
+fn setup_server_thread(server: Arc<Server>) -> JoinHandle<()> {
+    thread::spawn(move || {
+        server.run().expect("Server encountered an error");
+    })
+}
+
+fn create_server(capture: Option<&Path>) -> Arc<Server> {
+    let config = ServerConfig {
+        capture: capture.map(CaptureConfig::new),
+        ..ServerConfig::default()
+    };
+    Arc::new(Server::with_config("localhost:0", config).expect("Failed to start server"))
+}
+
+// Runs a short session with compression, checksums, store requests and a stream
+fn record_session(server: &Server) {
+    let addr = server.local_addr().unwrap();
+    let mut client = RecordedClient::connect(addr, Duration::from_secs(1)).unwrap();
+    client.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    let compression = CompressionConfig { threshold: 64, ..CompressionConfig::default() };
+    client.negotiate(&compression, true).unwrap();
+
+    let echo = client_message::Message::EchoMessage(EchoMessage { content: "field report ".repeat(50) });
+    assert!(matches!(client.request(echo).unwrap(), server_message::Message::EchoMessage(_)));
+    let put = client_message::Message::Put(Put { key: "device/mode".to_string(), value: b"eco".to_vec() });
+    assert!(matches!(client.request(put).unwrap(), server_message::Message::PutResponse(_)));
+    let get = client_message::Message::Get(Get { key: "device/mode".to_string() });
+    assert!(matches!(client.request(get).unwrap(), server_message::Message::GetResponse(_)));
+
+    let count = client_message::Message::CountTo(CountTo { start: 1, end: 5, step: 1, interval_ms: 0 });
+    let mut stream = client.stream(count).unwrap();
+    assert_eq!(stream.by_ref().count(), 5);
+    assert!(stream.end().is_some());
+    drop(stream);
+    client.disconnect().unwrap();
+}
+
+// The only capture file in `directory`
+fn capture_file(directory: &Path) -> PathBuf {
+    let files: Vec<_> = fs::read_dir(directory).unwrap().map(|entry| entry.unwrap().path()).collect();
+    assert_eq!(files.len(), 1, "{:?}", files);
+    files.into_iter().next().unwrap()
+}
+
+#[test]
+fn test_capture_records_frames() {
+    let dir = tempfile::tempdir().unwrap();
+    let server = create_server(Some(dir.path()));
+    let handle = setup_server_thread(server.clone());
+    record_session(&server);
+
+    let path = capture_file(dir.path());
+    let capture = Capture::load(&path).unwrap();
+    assert_eq!(capture.connection, 1);
+    assert!(capture.peer.starts_with("127.0.0.1:"), "{}", capture.peer);
+    assert!(capture.started_ms > 0);
+    assert!(capture.frames.windows(2).all(|pair| pair[0].offset <= pair[1].offset));
+
+    // The first frame is the plain Hello, later ones are in the negotiated format
+    let inbound: Vec<_> = capture.inbound().collect();
+    assert_eq!(inbound.len(), 5);
+    let hello = ClientMessage::decode(inbound[0].payload.as_slice()).unwrap();
+    assert!(matches!(hello.message, Some(client_message::Message::Hello(_))));
+    assert!(ClientMessage::decode(inbound[1].payload.as_slice()).is_err(), "Negotiated frames are not plain protobuf");
+
+    let replies = capture.replies().unwrap();
+    let kinds: Vec<_> = replies
+        .iter()
+        .map(|reply| match reply.message.as_ref().unwrap() {
+            server_message::Message::HelloResponse(_) => "hello",
+            server_message::Message::EchoMessage(_) => "echo",
+            server_message::Message::PutResponse(_) => "put",
+            server_message::Message::GetResponse(_) => "get",
+            server_message::Message::StreamItem(_) => "item",
+            server_message::Message::StreamEnd(_) => "end",
+            other => panic!("Unexpected reply {:?}", other),
+        })
+        .collect();
+    assert_eq!(kinds, ["hello", "echo", "put", "get", "item", "item", "item", "item", "item", "end"]);
+
+    // A record cut short by a crash is skipped
+    OpenOptions::new().append(true).open(&path).unwrap().write_all(&[0x80, 0x01, 0x0a]).unwrap();
+    assert_eq!(Capture::load(&path).unwrap(), capture);
+
+    server.stop();
+    handle.join().unwrap();
+}
+
+#[test]
+fn test_replay_matches_fresh_server() {
+    let dir = tempfile::tempdir().unwrap();
+    let recording = create_server(Some(dir.path()));
+    let handle = setup_server_thread(recording.clone());
+    record_session(&recording);
+    recording.stop();
+    handle.join().unwrap();
+    let capture = Capture::load(capture_file(dir.path())).unwrap();
+
+    let target = create_server(None);
+    let handle = setup_server_thread(target.clone());
+    let options = ReplayOptions { idle_timeout: Duration::from_millis(200), ..ReplayOptions::default() };
+    let report = replay(&capture, target.local_addr().unwrap(), &options).unwrap();
+    assert_eq!(report.sent, 5);
+    assert!(report.matches(), "{:?}", report.mismatches);
+    assert_eq!(report.received, report.expected);
+
+    // Replaying again meets a store that already has the key, so versions differ
+    let report = replay(&capture, target.local_addr().unwrap(), &options).unwrap();
+    let ids: Vec<u64> = report.mismatches.iter().map(|mismatch| mismatch.request_id).collect();
+    assert_eq!(ids, [3, 4], "The Put and the Get see version 2");
+    match &report.mismatches[1].received[0].message {
+        Some(server_message::Message::GetResponse(get)) => assert_eq!(get.version, 2),
+        other => panic!("Expected GetResponse, but received {:?}", other),
+    }
+
+    target.stop();
+    handle.join().unwrap();
+}
+
+#[test]
+fn test_decode_replies_follows_negotiation() {
+    let dir = tempfile::tempdir().unwrap();
+    let server = create_server(Some(dir.path()));
+    let handle = setup_server_thread(server.clone());
+    record_session(&server);
+    server.stop();
+    handle.join().unwrap();
+
+    let capture = Capture::load(capture_file(dir.path())).unwrap();
+    let outbound: Vec<&[u8]> = capture
+        .frames
+        .iter()
+        .filter(|frame| frame.direction == Direction::Outbound)
+        .map(|frame| frame.payload.as_slice())
+        .collect();
+    assert_eq!(decode_replies(outbound.iter().copied()).unwrap(), capture.replies().unwrap());
+
+    // Without the HelloResponse the rest cannot be read
+    assert!(decode_replies(outbound[1..].iter().copied()).is_err());
+}
+
+#[test]
+fn test_capture_leaves_out_credentials() {
+    let dir = tempfile::tempdir().unwrap();
+    let config = ServerConfig {
+        capture: Some(CaptureConfig::new(dir.path())),
+        authenticator: Some(Arc::new(Credentials::parse("sensor-01 token capture-secret-7f3a").unwrap())),
+        ..ServerConfig::default()
+    };
+    let server = Arc::new(Server::with_config("localhost:0", config).expect("Failed to start server"));
+    let handle = setup_server_thread(server.clone());
+
+    let mut client = RecordedClient::connect(server.local_addr().unwrap(), Duration::from_secs(1)).unwrap();
+    client.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    let hello = client.negotiate(&CompressionConfig::default(), true).unwrap();
+    assert_eq!(client.authenticate_token("sensor-01", "capture-secret-7f3a").unwrap(), "sensor-01");
+    client.disconnect().unwrap();
+    server.stop();
+    handle.join().unwrap();
+
+    let path = capture_file(dir.path());
+    let contents = fs::read(&path).unwrap();
+    assert!(!contents.windows(b"capture-secret".len()).any(|window| window == b"capture-secret"));
+
+    // The Authenticate is still there, in the negotiated format, with only the identity
+    let capture = Capture::load(&path).unwrap();
+    let inbound: Vec<_> = capture.inbound().collect();
+    assert_eq!(inbound.len(), 2);
+    let codec = FrameCodec::new(hello.compression(), usize::MAX).with_checksums(hello.checksums);
+    let message = codec.decode(inbound[1].payload.clone(), &CompressionStats::default()).unwrap();
+    match ClientMessage::decode(message.as_slice()).unwrap().message {
+        Some(client_message::Message::Authenticate(authenticate)) => {
+            assert_eq!(authenticate.identity, "sensor-01");
+            assert_eq!(authenticate.credential, None);
+        }
+        other => panic!("Expected Authenticate, but received {:?}", other),
+    }
+    assert!(matches!(capture.replies().unwrap()[1].message, Some(server_message::Message::AuthenticateResponse(_))));
+}
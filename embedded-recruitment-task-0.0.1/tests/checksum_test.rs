@@ -0,0 +1,257 @@
+use embedded_recruitment_task::{
+    client::Client as NegotiatingClient,
+    codec::{is_checksum_error, supported, CompressionConfig, CompressionStats, FrameCodec, CHECKSUM_LEN},
+    frame::{self, MAX_FRAME_LEN},
+    message::{
+        client_message, server_message, ClientMessage, Compression, EchoMessage, ErrorCode, Get, GetResponse, Hello,
+        HelloResponse, Put, ServerMessage,
+    },
+    server::{Server, ServerConfig},
+};
+use prost::Message;
+use std::{
+    net::TcpStream,
+    sync::Arc,
+    thread::{self, JoinHandle},
+    time::Duration,
+};
+
+fn setup_server_thread(server: Arc<Server>) -> JoinHandle<()> {
+    thread::spawn(move || {
+        server.run().expect("Server encountered an error");
+    })
+}
+
+fn create_server(checksums: bool) -> Arc<Server> {
+    let config = ServerConfig {
+        checksums,
+        ..ServerConfig::default()
+    };
+    Arc::new(Server::with_config("localhost:0", config).expect("Failed to start server"))
+}
+
+fn echo_request(request_id: u64, content: &str) -> ClientMessage {
+    ClientMessage {
+        request_id,
+        message: Some(client_message::Message::EchoMessage(EchoMessage {
+            content: content.to_string(),
+        })),
+        ..Default::default()
+    }
+}
+
+// Raw connection that has negotiated checksums without compression
+fn connect_raw(server: &Server) -> (TcpStream, FrameCodec) {
+    let mut stream = TcpStream::connect(server.local_addr().unwrap()).expect("Failed to connect to the server");
+    stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+
+    let hello = ClientMessage {
+        request_id: 1,
+        message: Some(client_message::Message::Hello(Hello {
+            compression: Vec::new(),
+            checksums: true,
+        })),
+        ..Default::default()
+    };
+    frame::write_frame(&mut stream, &hello.encode_to_vec()).unwrap();
+    let payload = frame::read_frame(&mut stream).unwrap().unwrap();
+    let response = ServerMessage::decode(payload.as_slice()).unwrap();
+    assert_eq!(
+        response.message,
+        Some(server_message::Message::HelloResponse(HelloResponse {
+            compression: Compression::None as i32,
+            checksums: true,
+        }))
+    );
+    (stream, FrameCodec::new(Compression::None, usize::MAX).with_checksums(true))
+}
+
+fn receive(stream: &mut TcpStream, codec: &FrameCodec) -> ServerMessage {
+    let stats = CompressionStats::default();
+    codec.read_message(stream, &stats).unwrap().expect("Server closed the connection")
+}
+
+#[test]
+fn test_every_bit_flip_is_detected() {
+    let stats = CompressionStats::default();
+    let codecs = std::iter::once(Compression::None)
+        .chain(supported())
+        .map(|compression| FrameCodec::new(compression, 16).with_checksums(true));
+
+    for codec in codecs {
+        let message = echo_request(7, &"telemetry ".repeat(20)).encode_to_vec();
+        let captured = codec.encode(message.clone(), &stats).unwrap();
+        assert_eq!(codec.decode(captured.clone(), &stats).unwrap(), message);
+
+        // Every single-bit error in the payload or the trailer is caught
+        for bit in 0..captured.len() * 8 {
+            let mut corrupted = captured.clone();
+            corrupted[bit / 8] ^= 1 << (bit % 8);
+            let error = codec.decode(corrupted, &stats).unwrap_err();
+            assert!(is_checksum_error(&error), "{:?}: bit {} gave {}", codec.compression(), bit, error);
+        }
+
+        // Truncated frames are never accepted either
+        for len in 0..captured.len() {
+            assert!(codec.decode(captured[..len].to_vec(), &stats).is_err(), "length {}", len);
+        }
+    }
+}
+
+#[test]
+fn test_trailer_layout() {
+    let stats = CompressionStats::default();
+    let codec = FrameCodec::default().with_checksums(true);
+    let payload = codec.encode(b"abc".to_vec(), &stats).unwrap();
+    assert_eq!(payload.len(), 3 + CHECKSUM_LEN);
+    assert_eq!(&payload[..3], b"abc");
+    assert_eq!(payload[3..], crc32c::crc32c(b"abc").to_be_bytes());
+
+    // Other errors are not mistaken for checksum failures
+    let plain = FrameCodec::new(Compression::Lz4, 0);
+    assert!(!is_checksum_error(&plain.decode(vec![9], &stats).unwrap_err()));
+}
+
+#[test]
+fn test_server_drops_corrupt_frames() {
+    let server = create_server(true);
+    let handle = setup_server_thread(server.clone());
+    let (mut stream, codec) = connect_raw(&server);
+    let stats = CompressionStats::default();
+
+    let mut corrupted = codec.encode(echo_request(2, "lost").encode_to_vec(), &stats).unwrap();
+    corrupted[5] ^= 0x10;
+    frame::write_frame(&mut stream, &corrupted).unwrap();
+    let response = receive(&mut stream, &codec);
+    assert_eq!(response.request_id, 0, "A corrupt frame's request id is not trusted");
+    match response.message {
+        Some(server_message::Message::Error(error)) => assert_eq!(error.code, ErrorCode::ChecksumMismatch as i32),
+        other => panic!("Expected Error, but received {:?}", other),
+    }
+
+    // The connection stays usable
+    codec.write_message(&mut stream, &echo_request(3, "intact"), &stats).unwrap();
+    let response = receive(&mut stream, &codec);
+    assert_eq!(response.request_id, 3);
+    match response.message {
+        Some(server_message::Message::EchoMessage(echo)) => assert_eq!(echo.content, "intact"),
+        other => panic!("Expected EchoMessage, but received {:?}", other),
+    }
+
+    drop(stream);
+    server.stop();
+    handle.join().unwrap();
+}
+
+#[test]
+fn test_checksums_with_compression() {
+    let server = create_server(true);
+    let handle = setup_server_thread(server.clone());
+
+    for compression in std::iter::once(Compression::None).chain(supported()) {
+        let addr = server.local_addr().unwrap();
+        let mut client = NegotiatingClient::connect(addr, Duration::from_secs(1)).unwrap();
+        client.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        let config = CompressionConfig {
+            algorithms: vec![compression],
+            threshold: 64,
+        };
+        let response = client.negotiate(&config, true).unwrap();
+        assert!(response.checksums);
+
+        for content in ["small", &"checksummed and compressed ".repeat(500)] {
+            let message = client_message::Message::EchoMessage(EchoMessage {
+                content: content.to_string(),
+            });
+            match client.request(message).unwrap() {
+                server_message::Message::EchoMessage(echo) => assert_eq!(echo.content, content),
+                other => panic!("Expected EchoMessage, but received {:?}", other),
+            }
+        }
+        client.disconnect().unwrap();
+    }
+
+    server.stop();
+    handle.join().unwrap();
+}
+
+#[test]
+fn test_server_can_refuse_checksums() {
+    let server = create_server(false);
+    let handle = setup_server_thread(server.clone());
+
+    let addr = server.local_addr().unwrap();
+    let mut client = NegotiatingClient::connect(addr, Duration::from_secs(1)).unwrap();
+    client.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    let response = client.negotiate(&CompressionConfig::default(), true).unwrap();
+    assert!(!response.checksums);
+
+    // Frames keep flowing without a trailer
+    let message = client_message::Message::EchoMessage(EchoMessage {
+        content: "unchecked".to_string(),
+    });
+    match client.request(message).unwrap() {
+        server_message::Message::EchoMessage(echo) => assert_eq!(echo.content, "unchecked"),
+        other => panic!("Expected EchoMessage, but received {:?}", other),
+    }
+    client.disconnect().unwrap();
+
+    server.stop();
+    handle.join().unwrap();
+}
+
+#[test]
+fn test_trailer_counts_toward_frame_limit() {
+    let stats = CompressionStats::default();
+    let codec = FrameCodec::default().with_checksums(true);
+    assert!(codec.encode(vec![0; MAX_FRAME_LEN - CHECKSUM_LEN], &stats).is_ok());
+    assert!(codec.encode(vec![0; MAX_FRAME_LEN - CHECKSUM_LEN + 1], &stats).is_err());
+
+    let server = create_server(true);
+    let handle = setup_server_thread(server.clone());
+    let (mut stream, codec) = connect_raw(&server);
+
+    // A GetResponse that would fit in a plain frame, but not with the trailer
+    let key = "blob/full";
+    let reply = |len: usize| ServerMessage {
+        request_id: 3,
+        message: Some(server_message::Message::GetResponse(GetResponse {
+            key: key.to_string(),
+            found: true,
+            value: vec![0; len],
+            version: 1,
+        })),
+    };
+    let limit = MAX_FRAME_LEN - CHECKSUM_LEN + 1;
+    let len = (0..limit).rev().find(|&len| reply(len).encoded_len() <= limit).unwrap();
+    assert_eq!(reply(len).encoded_len(), limit);
+
+    let put = ClientMessage {
+        request_id: 2,
+        message: Some(client_message::Message::Put(Put { key: key.to_string(), value: vec![0; len] })),
+        ..Default::default()
+    };
+    codec.write_message(&mut stream, &put, &stats).unwrap();
+    assert!(matches!(receive(&mut stream, &codec).message, Some(server_message::Message::PutResponse(_))));
+
+    let get = ClientMessage {
+        request_id: 3,
+        message: Some(client_message::Message::Get(Get { key: key.to_string() })),
+        ..Default::default()
+    };
+    codec.write_message(&mut stream, &get, &stats).unwrap();
+    let response = receive(&mut stream, &codec);
+    assert_eq!(response.request_id, 3);
+    match response.message {
+        Some(server_message::Message::Error(error)) => assert_eq!(error.code, ErrorCode::InvalidArgument as i32),
+        other => panic!("Expected Error, but received {:?}", other),
+    }
+
+    // The connection stays usable
+    codec.write_message(&mut stream, &echo_request(4, "intact"), &stats).unwrap();
+    assert_eq!(receive(&mut stream, &codec).request_id, 4);
+
+    drop(stream);
+    server.stop();
+    handle.join().unwrap();
+}
@@ -1,9 +1,8 @@
-use embedded_recruitment_task::message::{client_message, ServerMessage};
+use embedded_recruitment_task::frame;
+use embedded_recruitment_task::message::{client_message, ClientMessage, ServerMessage};
 use log::error;
 use log::info;
 use prost::Message;
-use std::io::Read;
-use std::io::Write;
 use std::{
     io,
     net::{SocketAddr, TcpStream, ToSocketAddrs},
@@ -45,6 +44,8 @@ impl Client {
 
         // Connect to the server with a timeout
         let stream = TcpStream::connect_timeout(&socket_addrs[0], self.timeout)?;
+        // The same timeout bounds every receive, so a reply that never comes fails the test instead of hanging it
+        stream.set_read_timeout(Some(self.timeout))?;
         self.stream = Some(stream);
 
         println!("Connected to the server!");
@@ -64,13 +65,13 @@ impl Client {
     // generic message to send message to the server
     pub fn send(&mut self, message: client_message::Message) -> io::Result<()> {
         if let Some(ref mut stream) = self.stream {
-            // Encode the message to a buffer
-            let mut buffer = Vec::new();
-            message.encode(&mut buffer);
-
-            // Send the buffer to the server
-            stream.write_all(&buffer)?;
-            stream.flush()?;
+            // Wrap the message in the envelope and send it as one frame
+            let envelope = ClientMessage {
+                request_id: 0,
+                message: Some(message.clone()),
+                ..Default::default()
+            };
+            frame::write_message(stream, &envelope)?;
 
             println!("Sent message: {:?}", message);
             Ok(())
@@ -85,20 +86,21 @@ impl Client {
     pub fn receive(&mut self) -> io::Result<ServerMessage> {
         if let Some(ref mut stream) = self.stream {
             info!("Receiving message from the server");
-            let mut buffer = vec![0u8; 1024];
-            let bytes_read = stream.read(&mut buffer)?;
-            if bytes_read == 0 {
-                info!("Server disconnected.");
-                return Err(io::Error::new(
-                    io::ErrorKind::ConnectionAborted,
-                    "Server disconnected",
-                ));
-            }
+            let payload = match frame::read_frame(stream)? {
+                Some(payload) => payload,
+                None => {
+                    info!("Server disconnected.");
+                    return Err(io::Error::new(
+                        io::ErrorKind::ConnectionAborted,
+                        "Server disconnected",
+                    ));
+                }
+            };
 
-            info!("Received {} bytes from the server", bytes_read);
+            info!("Received {} bytes from the server", payload.len());
 
             // Decode the received message
-            let decode = ServerMessage::decode(&buffer[..bytes_read]);
+            let decode = ServerMessage::decode(payload.as_slice());
             decode.map_err(|e| {
                 io::Error::new(
                     io::ErrorKind::InvalidData,
@@ -1,3 +1,5 @@
+// The original tests build messages field by field, kept as written
+#![allow(clippy::field_reassign_with_default, clippy::clone_on_copy, clippy::useless_vec)]
 use embedded_recruitment_task::{
     message::{client_message, server_message, AddRequest, EchoMessage},
     server::Server,
@@ -17,11 +19,16 @@ fn setup_server_thread(server: Arc<Server>) -> JoinHandle<()> {
 }
 
 fn create_server() -> Arc<Server> {
-    let server = Server::new("localhost:8080");
+    // Port 0 lets the OS pick a free port so tests can run in parallel
+    let server = Server::new("localhost:0");
     let msg = "Failed to start server";
     Arc::new(server.expect(msg))
 }
 
+fn server_port(server: &Server) -> u32 {
+    server.local_addr().expect("Server has no local address").port() as u32
+}
+
 #[test]
 fn test_client_connection() {
     // Set up the server in a separate thread
@@ -29,7 +36,7 @@ fn test_client_connection() {
     let handle = setup_server_thread(server.clone());
 
     // Create and connect the client
-    let mut client = client::Client::new("localhost", 8080, 1000);
+    let mut client = client::Client::new("localhost", server_port(&server), 1000);
     assert!(client.connect().is_ok(), "Failed to connect to the server");
 
     // Disconnect the client
@@ -55,7 +62,7 @@ fn test_multiple_echo_messages() {
     let handle = setup_server_thread(server.clone());
 
     // Create and connect the client
-    let mut client = client::Client::new("localhost", 8080, 1000);
+    let mut client = client::Client::new("localhost", server_port(&server), 1000);
     assert!(client.connect().is_ok(), "Failed to connect to the server");
 
     // Prepare multiple messages
@@ -115,9 +122,9 @@ fn test_multiple_clients() {
 
     // Create and connect multiple clients
     let mut clients = vec![
-        client::Client::new("localhost", 8080, 1000),
-        client::Client::new("localhost", 8080, 1000),
-        client::Client::new("localhost", 8080, 1000),
+        client::Client::new("localhost", server_port(&server), 1000),
+        client::Client::new("localhost", server_port(&server), 1000),
+        client::Client::new("localhost", server_port(&server), 1000),
     ];
 
     for client in clients.iter_mut() {
@@ -188,7 +195,7 @@ fn test_client_echo_message() {
     let handle = setup_server_thread(server.clone());
 
     // Create and connect the client
-    let mut client = client::Client::new("localhost", 8080, 1000);
+    let mut client = client::Client::new("localhost", server_port(&server), 1000);
     assert!(client.connect().is_ok(), "Failed to connect to the server");
 
     // Prepare the message
@@ -238,7 +245,7 @@ fn test_client_add_request() {
     let handle = setup_server_thread(server.clone());
 
     // Create and connect the client
-    let mut client = client::Client::new("localhost", 8080, 1000);
+    let mut client = client::Client::new("localhost", server_port(&server), 1000);
     assert!(client.connect().is_ok(), "Failed to connect to the server");
 
     // Prepare the message
@@ -289,7 +296,7 @@ fn test_empty_message() {
     let server = create_server();
     let handle = setup_server_thread(server.clone());
 
-    let mut client = client::Client::new("localhost", 8080, 1000);
+    let mut client = client::Client::new("localhost", server_port(&server), 1000);
     assert!(client.connect().is_ok(), "Failed to connect to the server");
 
     // Send an empty message
@@ -318,7 +325,7 @@ fn test_unknown_message() {
     let server = create_server();
     let handle = setup_server_thread(server.clone());
 
-    let mut client = client::Client::new("localhost", 8080, 1000);
+    let mut client = client::Client::new("localhost", server_port(&server), 1000);
     assert!(client.connect().is_ok(), "Failed to connect to the server");
 
     // Send an unknown message
@@ -344,7 +351,7 @@ fn test_long_disconnection() {
     let server = create_server();
     let handle = setup_server_thread(server.clone());
 
-    let mut client = client::Client::new("localhost", 8080, 1000);
+    let mut client = client::Client::new("localhost", server_port(&server), 1000);
     assert!(client.connect().is_ok(), "Failed to connect to the server");
 
     // Disconnect the client
@@ -380,7 +387,7 @@ fn test_max_message_limit() {
     let server = create_server();
     let handle = setup_server_thread(server.clone());
 
-    let mut client = client::Client::new("localhost", 8080, 1000);
+    let mut client = client::Client::new("localhost", server_port(&server), 1000);
     assert!(client.connect().is_ok(), "Failed to connect to the server");
 
     // Send many messages quickly
@@ -414,7 +421,7 @@ fn test_echo_and_add_request() {
     let handle = setup_server_thread(server.clone());
 
     // Create and connect the client
-    let mut client = client::Client::new("localhost", 8080, 1000);
+    let mut client = client::Client::new("localhost", server_port(&server), 1000);
     assert!(client.connect().is_ok(), "Failed to connect to the server");
 
     // Prepare EchoMessage
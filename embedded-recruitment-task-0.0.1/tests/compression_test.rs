@@ -0,0 +1,243 @@
+use embedded_recruitment_task::{
+    client::Client as NegotiatingClient,
+    codec::{negotiate, supported, CompressionConfig, CompressionStats, FrameCodec},
+    frame::MAX_FRAME_LEN,
+    message::{client_message, server_message, Compression, EchoMessage, ErrorCode, Get, GetResponse, Hello, Put, ServerMessage},
+    server::{Server, ServerConfig},
+};
+use prost::Message;
+use std::{
+    sync::Arc,
+    thread::{self, JoinHandle},
+    time::Duration,
+};
+
+mod client;
+
+fn setup_server_thread(server: Arc<Server>) -> JoinHandle<()> {
+    thread::spawn(move || {
+        server.run().expect("Server encountered an error");
+    })
+}
+
+fn create_server(compression: CompressionConfig) -> Arc<Server> {
+    let config = ServerConfig {
+        compression,
+        ..ServerConfig::default()
+    };
+    Arc::new(Server::with_config("localhost:0", config).expect("Failed to start server"))
+}
+
+fn connect(server: &Server) -> NegotiatingClient {
+    let addr = server.local_addr().expect("Server has no local address");
+    let client = NegotiatingClient::connect(addr, Duration::from_secs(1)).expect("Failed to connect to the server");
+    client.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    client
+}
+
+fn echo(client: &mut NegotiatingClient, content: &str) -> String {
+    let message = client_message::Message::EchoMessage(EchoMessage {
+        content: content.to_string(),
+    });
+    match client.request(message).unwrap() {
+        server_message::Message::EchoMessage(echo) => echo.content,
+        other => panic!("Expected EchoMessage, but received {:?}", other),
+    }
+}
+
+// Bytes that do not compress, from a small linear congruential generator
+fn noise(len: usize) -> Vec<u8> {
+    let mut state: u32 = 0x2545_f491;
+    (0..len)
+        .map(|_| {
+            state = state.wrapping_mul(1_103_515_245).wrapping_add(12_345);
+            (state >> 16) as u8
+        })
+        .collect()
+}
+
+#[test]
+fn test_negotiate() {
+    let accepts = [Compression::Zstd, Compression::Lz4];
+    let offer = |offered: &[Compression]| offered.iter().map(|&c| c as i32).collect::<Vec<_>>();
+
+    // The client's preference wins among what the server accepts
+    assert_eq!(negotiate(&offer(&[Compression::Lz4, Compression::Zstd]), &accepts), Compression::Lz4);
+    assert_eq!(negotiate(&offer(&[Compression::Zstd]), &[Compression::Lz4]), Compression::None);
+    assert_eq!(negotiate(&[99, Compression::Zstd as i32], &accepts), Compression::Zstd, "Unknown values are skipped");
+    assert_eq!(negotiate(&[], &accepts), Compression::None);
+    assert_eq!(negotiate(&offer(&[Compression::Lz4]), &[]), Compression::None);
+}
+
+#[test]
+fn test_codec_round_trip() {
+    for compression in supported() {
+        let codec = FrameCodec::new(compression, 64);
+        let stats = CompressionStats::default();
+
+        let repetitive = b"firmware block ".repeat(1000);
+        let payload = codec.encode(repetitive.clone(), &stats).unwrap();
+        assert!(payload.len() < repetitive.len() / 10, "{:?} did not compress", compression);
+        assert_eq!(codec.decode(payload, &stats).unwrap(), repetitive);
+
+        // Below the threshold, and data that does not shrink, only gain the flag byte
+        for message in [b"short".to_vec(), noise(4096)] {
+            let payload = codec.encode(message.clone(), &stats).unwrap();
+            assert_eq!(payload.len(), message.len() + 1);
+            assert_eq!(codec.decode(payload, &stats).unwrap(), message);
+        }
+
+        let snapshot = stats.snapshot();
+        assert_eq!(snapshot.frames, 6);
+        assert_eq!(snapshot.compressed_frames, 2, "One compressed frame each way");
+        assert!(snapshot.ratio() > 1.5, "ratio {}", snapshot.ratio());
+    }
+
+    // The plain codec leaves payloads untouched
+    let stats = CompressionStats::default();
+    assert_eq!(FrameCodec::default().encode(b"abc".to_vec(), &stats).unwrap(), b"abc");
+    assert_eq!(stats.snapshot().ratio(), 1.0);
+}
+
+#[test]
+fn test_codec_rejects_bad_frames() {
+    let stats = CompressionStats::default();
+    for compression in supported() {
+        let codec = FrameCodec::new(compression, 0);
+        assert!(codec.decode(Vec::new(), &stats).is_err(), "Missing flag byte");
+        assert!(codec.decode(vec![7, 1, 2, 3], &stats).is_err(), "Unknown flag");
+        assert!(codec.decode(vec![1, 0xde, 0xad, 0xbe, 0xef], &stats).is_err(), "Garbage compressed data");
+        assert!(codec.encode(vec![0; MAX_FRAME_LEN + 1], &stats).is_err(), "Oversized message");
+    }
+
+    // A forged size must not make the receiver allocate beyond the frame limit
+    if supported().contains(&Compression::Lz4) {
+        let mut bomb = vec![1];
+        bomb.extend_from_slice(&u32::MAX.to_le_bytes());
+        bomb.extend_from_slice(&[0; 16]);
+        assert!(FrameCodec::new(Compression::Lz4, 0).decode(bomb, &stats).is_err());
+    }
+}
+
+#[test]
+fn test_compressed_connection() {
+    let server = create_server(CompressionConfig::default());
+    let handle = setup_server_thread(server.clone());
+
+    for compression in supported() {
+        let mut client = connect(&server);
+        let config = CompressionConfig {
+            algorithms: vec![compression],
+            threshold: 128,
+        };
+        assert_eq!(client.negotiate(&config, false).unwrap().compression(), compression);
+
+        let large = "sensor reading 42;".repeat(5000);
+        assert_eq!(echo(&mut client, &large), large);
+        assert_eq!(echo(&mut client, "small"), "small");
+
+        let sent = client.frame_stats().sent.snapshot();
+        let received = client.frame_stats().received.snapshot();
+        assert_eq!(sent.compressed_frames, 1, "Only the large echo crosses the threshold");
+        assert_eq!(received.compressed_frames, 1);
+        assert!(sent.ratio() > 5.0 && received.ratio() > 5.0, "{:?} {:?}", sent, received);
+        client.disconnect().unwrap();
+    }
+
+    let server_sent = server.frame_stats().sent.snapshot();
+    assert_eq!(server_sent.compressed_frames, supported().len() as u64);
+    assert!(supported().is_empty() || server_sent.ratio() > 1.0);
+
+    server.stop();
+    handle.join().unwrap();
+}
+
+#[test]
+fn test_negotiation_fallbacks() {
+    // A server without compression answers every Hello with none
+    let server = create_server(CompressionConfig {
+        algorithms: Vec::new(),
+        threshold: 0,
+    });
+    let handle = setup_server_thread(server.clone());
+
+    let mut client = connect(&server);
+    assert_eq!(client.negotiate(&CompressionConfig::default(), false).unwrap().compression(), Compression::None);
+    let large = "x".repeat(10_000);
+    assert_eq!(echo(&mut client, &large), large);
+    assert_eq!(client.frame_stats().received.snapshot().compressed_frames, 0);
+
+    // Hello is only understood as the first message
+    match client.request(client_message::Message::Hello(Hello::default())).unwrap() {
+        server_message::Message::Error(error) => assert_eq!(error.code, ErrorCode::InvalidArgument as i32),
+        other => panic!("Expected Error, but received {:?}", other),
+    }
+    client.disconnect().unwrap();
+
+    // Clients that never send a Hello keep the plain format
+    let port = server.local_addr().unwrap().port() as u32;
+    let mut legacy = client::Client::new("localhost", port, 1000);
+    assert!(legacy.connect().is_ok(), "Failed to connect to the server");
+    let message = client_message::Message::EchoMessage(EchoMessage { content: large.clone() });
+    assert!(legacy.send(message).is_ok());
+    match legacy.receive().unwrap().message {
+        Some(server_message::Message::EchoMessage(echo)) => assert_eq!(echo.content, large),
+        other => panic!("Expected EchoMessage, but received {:?}", other),
+    }
+    legacy.disconnect().unwrap();
+
+    server.stop();
+    handle.join().unwrap();
+}
+
+// Length of a stored value whose `GetResponse` encodes to exactly `MAX_FRAME_LEN` bytes
+fn value_len_at_limit(key: &str) -> usize {
+    let reply = |len: usize| ServerMessage {
+        request_id: 3,
+        message: Some(server_message::Message::GetResponse(GetResponse {
+            key: key.to_string(),
+            found: true,
+            value: vec![0; len],
+            version: 1,
+        })),
+    };
+    let len = (0..MAX_FRAME_LEN).rev().find(|&len| reply(len).encoded_len() <= MAX_FRAME_LEN).unwrap();
+    assert_eq!(reply(len).encoded_len(), MAX_FRAME_LEN);
+    len
+}
+
+#[test]
+fn test_reply_at_frame_limit() {
+    let server = create_server(CompressionConfig::default());
+    let handle = setup_server_thread(server.clone());
+
+    for compression in supported() {
+        let mut client = connect(&server);
+        let config = CompressionConfig {
+            algorithms: vec![compression],
+            threshold: 128,
+        };
+        assert_eq!(client.negotiate(&config, false).unwrap().compression(), compression);
+
+        // The Put fits in a frame with its flag byte, the GetResponse holding the same value does not
+        let key = "blob/full";
+        let value = noise(value_len_at_limit(key));
+        let put = client_message::Message::Put(Put { key: key.to_string(), value });
+        assert!(matches!(client.request(put).unwrap(), server_message::Message::PutResponse(_)));
+        let get = client_message::Message::Get(Get { key: key.to_string() });
+        match client.request(get).unwrap() {
+            server_message::Message::Error(error) => {
+                assert_eq!(error.code(), ErrorCode::InvalidArgument);
+                assert!(error.message.contains("exceeds"), "{}", error.message);
+            }
+            other => panic!("Expected Error, but received {:?}", other),
+        }
+
+        // The connection is still usable
+        assert_eq!(echo(&mut client, "still here"), "still here");
+        client.disconnect().unwrap();
+    }
+
+    server.stop();
+    handle.join().unwrap();
+}
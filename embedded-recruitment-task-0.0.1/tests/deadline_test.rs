@@ -0,0 +1,253 @@
+use embedded_recruitment_task::{
+    audit::{AuditRecord, AuditSink},
+    auth::{AuthError, Authenticator},
+    client::Client as FramedClient,
+    handler::{Handler, Session},
+    outbound::{self, BackpressureConfig},
+    message::{
+        client_message, server_message, Authenticate, BatchRequest, ClientMessage, CountTo, EchoMessage, ErrorCode,
+        Ping, Put, ServerMessage, StreamStatus,
+    },
+    pubsub::TopicRegistry,
+    server::{Server, ServerConfig},
+    store::KeyValueStore,
+    inflight::InFlight,
+    stream::CancelToken,
+};
+use std::{
+    sync::Arc,
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+
+fn setup_server_thread(server: Arc<Server>) -> JoinHandle<()> {
+    thread::spawn(move || {
+        server.run().expect("Server encountered an error");
+    })
+}
+
+// Lets everyone in, after taking its time
+#[derive(Debug)]
+struct SlowAuthenticator(Duration);
+
+impl Authenticator for SlowAuthenticator {
+    fn authenticate(&self, request: &Authenticate, _challenge: Option<&[u8]>) -> Result<String, AuthError> {
+        thread::sleep(self.0);
+        Ok(request.identity.clone())
+    }
+}
+
+// Holds up the connection's worker after every echo, so the requests behind it wait in the queue
+#[derive(Debug)]
+struct SlowAudit(Duration);
+
+impl AuditSink for SlowAudit {
+    fn record(&self, record: &AuditRecord) {
+        if record.operation == "echo_message" {
+            thread::sleep(self.0);
+        }
+    }
+}
+
+fn create_server(config: ServerConfig) -> Arc<Server> {
+    Arc::new(Server::with_config("localhost:0", config).expect("Failed to start server"))
+}
+
+// A server whose `Authenticate` requests take `delay`
+fn slow_authentication(delay: Duration) -> ServerConfig {
+    ServerConfig { authenticator: Some(Arc::new(SlowAuthenticator(delay))), ..ServerConfig::default() }
+}
+
+fn connect(server: &Server) -> FramedClient {
+    let client = FramedClient::connect(server.local_addr().unwrap(), Duration::from_secs(1)).unwrap();
+    client.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    client
+}
+
+fn authenticate() -> client_message::Message {
+    client_message::Message::Authenticate(Authenticate { identity: "slow".to_string(), credential: None })
+}
+
+fn error_code(reply: &ServerMessage) -> ErrorCode {
+    match &reply.message {
+        Some(server_message::Message::Error(error)) => error.code(),
+        other => panic!("Expected an Error, but received {:?}", other),
+    }
+}
+
+#[test]
+fn test_deadline_does_not_preempt_authentication() {
+    let server = create_server(slow_authentication(Duration::from_millis(300)));
+    let handle = setup_server_thread(server.clone());
+    let mut client = connect(&server);
+
+    // Authenticating changes the session, so once started it gets its own reply whatever the deadline
+    client.set_deadline(Some(Duration::from_millis(100)));
+    let started = Instant::now();
+    let request_id = client.send(authenticate()).unwrap();
+    let reply = client.receive().unwrap();
+    assert_eq!(reply.request_id, request_id);
+    assert!(matches!(reply.message, Some(server_message::Message::AuthenticateResponse(_))), "{:?}", reply);
+    assert!(started.elapsed() >= Duration::from_millis(300));
+
+    client.set_deadline(None);
+    assert!(client.ping().is_ok());
+
+    server.stop();
+    handle.join().unwrap();
+}
+
+#[test]
+fn test_deadline_passes_while_queued() {
+    let server = create_server(slow_authentication(Duration::from_millis(300)));
+    let handle = setup_server_thread(server.clone());
+    let mut client = connect(&server);
+
+    let slow = client.send(authenticate()).unwrap();
+    client.set_deadline(Some(Duration::from_millis(50)));
+    let queued = client.send(client_message::Message::Ping(Ping {})).unwrap();
+
+    // Answered while the request ahead of it still runs
+    let reply = client.receive().unwrap();
+    assert_eq!((reply.request_id, error_code(&reply)), (queued, ErrorCode::DeadlineExceeded));
+    let reply = client.receive().unwrap();
+    assert_eq!(reply.request_id, slow);
+    assert!(matches!(reply.message, Some(server_message::Message::AuthenticateResponse(_))));
+
+    server.stop();
+    handle.join().unwrap();
+}
+
+#[test]
+fn test_cancel_pipelined_request() {
+    let server = create_server(ServerConfig { audit: Some(Arc::new(SlowAudit(Duration::from_millis(300)))), ..ServerConfig::default() });
+    let handle = setup_server_thread(server.clone());
+    let mut client = connect(&server);
+    let echo = |content: &str| client_message::Message::EchoMessage(EchoMessage { content: content.to_string() });
+
+    let slow = client.send(echo("holds up the worker")).unwrap();
+    let queued = client.send(echo("never runs")).unwrap();
+    let started = Instant::now();
+    assert!(client.cancel(queued).unwrap());
+    assert!(started.elapsed() < Duration::from_millis(200), "Cancel is not queued behind other requests");
+    assert!(!client.cancel(queued).unwrap(), "Already answered");
+    assert!(!client.cancel(999).unwrap());
+
+    // The cancelled request was answered before the one ahead of it
+    let reply = client.receive().unwrap();
+    assert_eq!((reply.request_id, error_code(&reply)), (queued, ErrorCode::Cancelled));
+    let reply = client.receive().unwrap();
+    assert_eq!(reply.request_id, slow);
+    assert!(matches!(reply.message, Some(server_message::Message::EchoMessage(_))));
+
+    server.stop();
+    handle.join().unwrap();
+}
+
+#[test]
+fn test_stream_deadline() {
+    let server = create_server(slow_authentication(Duration::ZERO));
+    let handle = setup_server_thread(server.clone());
+    let mut client = connect(&server);
+    client.authenticate_token("anyone", "accepted").unwrap();
+
+    client.set_deadline(Some(Duration::from_millis(100)));
+    let count = CountTo { start: 1, end: 1000, step: 1, interval_ms: 10 };
+    let mut stream = client.stream(client_message::Message::CountTo(count)).unwrap();
+    let items = stream.by_ref().map(Result::unwrap).count();
+    let end = stream.end().expect("Stream ended with a StreamEnd");
+    assert_eq!(end.status(), StreamStatus::DeadlineExceeded);
+    assert_eq!(end.items, items as u64);
+    assert!(items > 0 && items < 100, "{} items", items);
+
+    server.stop();
+    handle.join().unwrap();
+}
+
+#[test]
+fn test_batches_observe_the_token() {
+    let handler = Handler::new(Arc::new(TopicRegistry::new()), Arc::new(KeyValueStore::new()));
+    let (outbound, _queue) = outbound::channel(BackpressureConfig::default());
+    let session = Session::new(1, outbound);
+    let put = |key: &str| ClientMessage {
+        message: Some(client_message::Message::Put(Put { key: key.to_string(), value: b"v".to_vec() })),
+        ..Default::default()
+    };
+    let batch = |atomic| ClientMessage {
+        request_id: 7,
+        message: Some(client_message::Message::BatchRequest(BatchRequest { requests: vec![put("a"), put("b")], atomic })),
+        ..Default::default()
+    };
+
+    // Out of time before it starts: no item runs, whether the batch is atomic or not
+    let expired = CancelToken::with_deadline(Some(Instant::now()));
+    let reply = handler.handle_interruptible(&session, batch(true), &expired).unwrap();
+    assert_eq!((reply.request_id, error_code(&reply)), (7, ErrorCode::DeadlineExceeded));
+    assert!(handler.store().get("a").unwrap().is_none());
+
+    // Items of an independent batch may have taken effect, so each one says what happened to it
+    let reply = handler.handle_interruptible(&session, batch(false), &expired).unwrap();
+    match reply.message {
+        Some(server_message::Message::BatchResponse(response)) => {
+            assert!(response.responses.iter().all(|item| error_code(item) == ErrorCode::DeadlineExceeded));
+        }
+        other => panic!("Expected BatchResponse, but received {:?}", other),
+    }
+    assert!(handler.store().get("a").unwrap().is_none());
+
+    let cancelled = CancelToken::new();
+    cancelled.cancel();
+    let reply = handler.handle_interruptible(&session, batch(true), &cancelled).unwrap();
+    assert_eq!(error_code(&reply), ErrorCode::Cancelled);
+
+    // A deadline in the envelope counts from `handle`
+    let mut request = batch(true);
+    request.deadline_ms = 60_000;
+    let reply = handler.handle(&session, request).unwrap();
+    assert!(matches!(reply.message, Some(server_message::Message::BatchResponse(ref response)) if response.committed));
+}
+
+#[test]
+fn test_write_past_its_deadline_keeps_its_reply() {
+    let handler = Handler::new(Arc::new(TopicRegistry::new()), Arc::new(KeyValueStore::new()));
+    let (outbound, queue) = outbound::channel(BackpressureConfig::default());
+    let session = Session::new(1, outbound.clone());
+
+    // The watchdog answers a running read as soon as its deadline passes, but leaves a running write alone
+    let requests = InFlight::new();
+    let read = requests.register(2, 1);
+    let pending = requests.register(3, 1);
+    assert!(pending.protect());
+    thread::scope(|scope| {
+        scope.spawn(|| requests.watch(&outbound));
+        thread::sleep(Duration::from_millis(50));
+        assert!(!requests.cancel(3, &outbound));
+        requests.close();
+    });
+    let early = queue.try_recv().unwrap();
+    assert_eq!((early.request_id, error_code(&early)), (2, ErrorCode::DeadlineExceeded));
+    assert!(!read.claim());
+    assert!(pending.token.interrupted().is_some(), "The write still sees its token");
+    assert!(queue.try_recv().is_none(), "Nothing answered the write early");
+
+    // A Put that finishes past its deadline reports the version it wrote, so it is not retried
+    let put = ClientMessage {
+        request_id: 3,
+        message: Some(client_message::Message::Put(Put { key: "once".to_string(), value: b"1".to_vec() })),
+        ..Default::default()
+    };
+    let reply = handler.handle_interruptible(&session, put, &pending.token).unwrap();
+    assert!(pending.claim());
+    match reply.message {
+        Some(server_message::Message::PutResponse(response)) => {
+            assert_eq!(response.version, handler.store().get("once").unwrap().unwrap().version);
+        }
+        other => panic!("Expected PutResponse, but received {:?}", other),
+    }
+
+    // A request already answered while queued is not started
+    let answered = requests.register(4, 0);
+    assert!(requests.cancel(4, &outbound));
+    assert!(!answered.protect());
+    assert_eq!(error_code(&queue.try_recv().unwrap()), ErrorCode::Cancelled);
+}
@@ -0,0 +1,145 @@
+#![cfg(feature = "grpc")]
+
+use embedded_recruitment_task::{
+    auth::Credentials,
+    message::{
+        client_message, gateway_client::GatewayClient, server_message, AddRequest, ClientMessage, CountTo, EchoMessage, Get,
+        Put, StreamStatus,
+    },
+    server::{Server, ServerConfig},
+};
+use std::{
+    sync::Arc,
+    thread::{self, JoinHandle},
+};
+use tokio::runtime::Runtime;
+use tonic::{transport::Channel, Code, Request};
+
+fn setup_server_thread(server: Arc<Server>) -> JoinHandle<()> {
+    thread::spawn(move || {
+        server.run().expect("Server encountered an error");
+    })
+}
+
+fn create_server(config: ServerConfig) -> Arc<Server> {
+    let config = ServerConfig {
+        grpc_addr: Some("127.0.0.1:0".to_string()),
+        ..config
+    };
+    Arc::new(Server::with_config("localhost:0", config).expect("Failed to start server"))
+}
+
+async fn connect(server: &Server) -> GatewayClient<Channel> {
+    let addr = server.grpc_addr().expect("gRPC server is enabled");
+    GatewayClient::connect(format!("http://{}", addr)).await.expect("Failed to connect to the gRPC server")
+}
+
+#[test]
+fn test_unary_calls() {
+    let server = create_server(ServerConfig::default());
+    let runtime = Runtime::new().unwrap();
+    runtime.block_on(async {
+        let mut client = connect(&server).await;
+
+        let echo = client.echo(EchoMessage { content: "grpc".to_string() }).await.unwrap();
+        assert_eq!(echo.into_inner().content, "grpc");
+        let add = client.add(AddRequest { a: 40, b: 2 }).await.unwrap();
+        assert_eq!(add.into_inner().result, 42);
+
+        // Error replies become statuses
+        let status = client.add(AddRequest { a: i32::MAX, b: 1 }).await.unwrap_err();
+        assert_eq!(status.code(), Code::OutOfRange);
+    });
+}
+
+#[test]
+fn test_streaming_variants() {
+    let server = create_server(ServerConfig::default());
+    let runtime = Runtime::new().unwrap();
+    runtime.block_on(async {
+        let mut client = connect(&server).await;
+
+        let requests = tokio_stream::iter(["a", "b", "c"].map(|content| EchoMessage { content: content.to_string() }));
+        let mut replies = client.echo_stream(requests).await.unwrap().into_inner();
+        let mut contents = Vec::new();
+        while let Some(echo) = replies.message().await.unwrap() {
+            contents.push(echo.content);
+        }
+        assert_eq!(contents, ["a", "b", "c"]);
+
+        // The first error ends the stream with its status
+        let requests = tokio_stream::iter([AddRequest { a: 1, b: 2 }, AddRequest { a: i32::MIN, b: -1 }, AddRequest { a: 3, b: 4 }]);
+        let mut replies = client.add_stream(requests).await.unwrap().into_inner();
+        assert_eq!(replies.message().await.unwrap().unwrap().result, 3);
+        assert_eq!(replies.message().await.unwrap_err().code(), Code::OutOfRange);
+    });
+}
+
+#[test]
+fn test_session_behaves_like_a_connection() {
+    let server = create_server(ServerConfig::default());
+    let handle = setup_server_thread(server.clone());
+    let runtime = Runtime::new().unwrap();
+    runtime.block_on(async {
+        let mut client = connect(&server).await;
+        let (requests, inbound) = tokio::sync::mpsc::channel(4);
+        let mut replies = client.session(tokio_stream::wrappers::ReceiverStream::new(inbound)).await.unwrap().into_inner();
+
+        let put = Put { key: "device/mode".to_string(), value: b"eco".to_vec() };
+        requests.send(ClientMessage { request_id: 1, message: Some(client_message::Message::Put(put)), ..Default::default() }).await.unwrap();
+        let reply = replies.message().await.unwrap().unwrap();
+        assert_eq!(reply.request_id, 1);
+        assert!(matches!(reply.message, Some(server_message::Message::PutResponse(_))));
+
+        let count = CountTo { start: 1, end: 3, step: 1, interval_ms: 0 };
+        requests.send(ClientMessage { request_id: 2, message: Some(client_message::Message::CountTo(count)), ..Default::default() }).await.unwrap();
+        let mut frames = Vec::new();
+        loop {
+            let frame = replies.message().await.unwrap().unwrap();
+            assert_eq!(frame.request_id, 2);
+            if let Some(server_message::Message::StreamEnd(end)) = &frame.message {
+                assert_eq!(end.status(), StreamStatus::Completed);
+                break;
+            }
+            frames.push(frame);
+        }
+        assert_eq!(frames.len(), 3);
+
+        // Errors stay inside the envelope
+        let get = Get { key: String::new() };
+        requests.send(ClientMessage { request_id: 3, message: Some(client_message::Message::Get(get)), ..Default::default() }).await.unwrap();
+        let reply = replies.message().await.unwrap().unwrap();
+        assert!(matches!(reply.message, Some(server_message::Message::Error(_))), "{:?}", reply);
+
+        // Closing the request side ends the session
+        drop(requests);
+        assert!(replies.message().await.unwrap().is_none());
+    });
+
+    // The session wrote to the store every transport shares
+    assert_eq!(server.store().get("device/mode").unwrap().map(|entry| entry.value), Some(b"eco".to_vec()));
+    server.stop();
+    handle.join().unwrap();
+}
+
+#[test]
+fn test_bearer_metadata() {
+    let config = ServerConfig {
+        authenticator: Some(Arc::new(Credentials::parse("reporting token r1").unwrap())),
+        ..ServerConfig::default()
+    };
+    let server = create_server(config);
+    let runtime = Runtime::new().unwrap();
+    runtime.block_on(async {
+        let mut client = connect(&server).await;
+        let echo = || Request::new(EchoMessage { content: "ping".to_string() });
+
+        assert_eq!(client.echo(echo()).await.unwrap_err().code(), Code::Unauthenticated);
+        let mut wrong = echo();
+        wrong.metadata_mut().insert("authorization", "Bearer reporting:r2".parse().unwrap());
+        assert_eq!(client.echo(wrong).await.unwrap_err().code(), Code::Unauthenticated);
+        let mut right = echo();
+        right.metadata_mut().insert("authorization", "Bearer reporting:r1".parse().unwrap());
+        assert_eq!(client.echo(right).await.unwrap().into_inner().content, "ping");
+    });
+}
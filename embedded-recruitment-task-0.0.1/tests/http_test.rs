@@ -0,0 +1,219 @@
+use embedded_recruitment_task::{
+    auth::Credentials,
+    client::Client as FramedClient,
+    json::decode_server_message,
+    message::{client_message, server_message, Get, StreamStatus},
+    server::{Server, ServerConfig},
+};
+use std::{
+    io::{Read, Write},
+    net::{SocketAddr, TcpStream},
+    sync::Arc,
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+
+fn setup_server_thread(server: Arc<Server>) -> JoinHandle<()> {
+    thread::spawn(move || {
+        server.run().expect("Server encountered an error");
+    })
+}
+
+fn create_server(config: ServerConfig) -> Arc<Server> {
+    let config = ServerConfig {
+        http_addr: Some("127.0.0.1:0".to_string()),
+        ..config
+    };
+    Arc::new(Server::with_config("localhost:0", config).expect("Failed to start server"))
+}
+
+// Joins the chunks of a chunked body
+fn dechunk(mut body: &str) -> String {
+    let mut joined = String::new();
+    loop {
+        let (size, rest) = body.split_once("\r\n").expect("Chunk has a size line");
+        let size = usize::from_str_radix(size, 16).unwrap();
+        if size == 0 {
+            return joined;
+        }
+        joined.push_str(&rest[..size]);
+        body = &rest[size + 2..];
+    }
+}
+
+// Sends one HTTP/1.1 request and returns the status code and body
+fn http(addr: SocketAddr, method: &str, path: &str, headers: &[&str], body: &str) -> (u16, String) {
+    let (status, head, body) = http_with_head(addr, method, path, headers, body);
+    if head.to_ascii_lowercase().contains("transfer-encoding: chunked") {
+        return (status, dechunk(&body));
+    }
+    (status, body)
+}
+
+// Like `http`, also returning the response head, with the body as sent
+fn http_with_head(addr: SocketAddr, method: &str, path: &str, headers: &[&str], body: &str) -> (u16, String, String) {
+    let mut stream = TcpStream::connect(addr).unwrap();
+    stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    let mut request = format!("{} {} HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\nContent-Length: {}\r\n", method, path, body.len());
+    for header in headers {
+        request.push_str(&format!("{}\r\n", header));
+    }
+    request.push_str("\r\n");
+    request.push_str(body);
+    stream.write_all(request.as_bytes()).unwrap();
+
+    let mut response = String::new();
+    stream.read_to_string(&mut response).unwrap();
+    let (head, body) = response.split_once("\r\n\r\n").expect("Response has a header");
+    let status = head.split(' ').nth(1).unwrap().parse().unwrap();
+    (status, head.to_string(), body.to_string())
+}
+
+// Polls `path` until it answers `status`
+fn wait_for(addr: SocketAddr, path: &str, status: u16) {
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while http(addr, "GET", path, &[], "").0 != status {
+        assert!(Instant::now() < deadline, "{} never answered {}", path, status);
+        thread::sleep(Duration::from_millis(20));
+    }
+}
+
+#[test]
+fn test_echo_and_add() {
+    let server = create_server(ServerConfig::default());
+    let handle = setup_server_thread(server.clone());
+    let addr = server.http_addr().expect("HTTP gateway is enabled");
+
+    assert_eq!(http(addr, "POST", "/echo", &[], r#"{"content":"hello"}"#), (200, r#"{"content":"hello"}"#.to_string()));
+    assert_eq!(http(addr, "POST", "/add", &[], r#"{"a":40,"b":2}"#), (200, r#"{"result":42}"#.to_string()));
+
+    // Errors come with a matching status and the JSON Error
+    let (status, body) = http(addr, "POST", "/add", &[], r#"{"a":2147483647,"b":1}"#);
+    assert_eq!(status, 400);
+    assert!(body.starts_with(r#"{"code":"ERROR_CODE_OVERFLOW""#), "{}", body);
+    let (status, body) = http(addr, "POST", "/echo", &[], "{\"content\":");
+    assert_eq!(status, 400);
+    assert!(body.contains("invalid JSON message"), "{}", body);
+
+    assert_eq!(http(addr, "GET", "/echo", &[], "").0, 405);
+    assert_eq!(http(addr, "POST", "/subtract", &[], "{}").0, 404);
+
+    server.stop();
+    handle.join().unwrap();
+}
+
+#[test]
+fn test_rpc_shares_dispatch() {
+    let server = create_server(ServerConfig::default());
+    let handle = setup_server_thread(server.clone());
+    let addr = server.http_addr().unwrap();
+
+    let (status, body) = http(addr, "POST", "/rpc", &[], r#"{"requestId":"9","put":{"key":"device/mode","value":"ZWNv"}}"#);
+    assert_eq!(status, 200);
+    let reply = decode_server_message(&body).unwrap();
+    assert_eq!(reply.request_id, 9);
+    assert!(matches!(reply.message, Some(server_message::Message::PutResponse(_))), "{:?}", reply);
+
+    // The framed protocol sees what the gateway wrote
+    let mut framed = FramedClient::connect(server.local_addr().unwrap(), Duration::from_secs(1)).unwrap();
+    framed.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    match framed.request(client_message::Message::Get(Get { key: "device/mode".to_string() })).unwrap() {
+        server_message::Message::GetResponse(response) => assert_eq!(response.value, b"eco"),
+        other => panic!("Expected GetResponse, but received {:?}", other),
+    }
+    framed.disconnect().unwrap();
+
+    // Errors are part of the envelope, so /rpc still answers 200
+    let (status, body) = http(addr, "POST", "/rpc", &[], r#"{"requestId":"10","delete":{"key":""}}"#);
+    assert_eq!(status, 200);
+    assert!(matches!(decode_server_message(&body).unwrap().message, Some(server_message::Message::Error(_))));
+
+    // A stream answers with one message per line, sent as they come
+    let (status, head, body) = http_with_head(addr, "POST", "/rpc", &[], r#"{"requestId":"11","countTo":{"start":1,"end":3,"step":1}}"#);
+    assert_eq!(status, 200);
+    assert!(head.to_ascii_lowercase().contains("transfer-encoding: chunked"), "{}", head);
+    let body = dechunk(&body);
+    let frames: Vec<_> = body.lines().map(|line| decode_server_message(line).unwrap()).collect();
+    assert_eq!(frames.len(), 4, "{}", body);
+    assert!(frames.iter().all(|frame| frame.request_id == 11));
+    match &frames[3].message {
+        Some(server_message::Message::StreamEnd(end)) => assert_eq!(end.status(), StreamStatus::Completed),
+        other => panic!("Expected StreamEnd, but received {:?}", other),
+    }
+
+    server.stop();
+    handle.join().unwrap();
+}
+
+#[test]
+fn test_health_and_readiness() {
+    let server = create_server(ServerConfig::default());
+    let addr = server.http_addr().unwrap();
+
+    // Alive as soon as it is created, ready once it runs, not ready once stopped
+    assert_eq!(http(addr, "GET", "/healthz", &[], ""), (200, "ok\n".to_string()));
+    assert_eq!(http(addr, "GET", "/readyz", &[], "").0, 503);
+    let handle = setup_server_thread(server.clone());
+    wait_for(addr, "/readyz", 200);
+    assert_eq!(http(addr, "POST", "/readyz", &[], "").0, 405);
+
+    server.stop();
+    handle.join().unwrap();
+    assert_eq!(http(addr, "GET", "/readyz", &[], "").0, 503);
+    assert_eq!(http(addr, "GET", "/healthz", &[], "").0, 200);
+}
+
+#[test]
+fn test_bearer_authentication() {
+    let config = ServerConfig {
+        authenticator: Some(Arc::new(Credentials::parse("sensor-01 token s1").unwrap())),
+        ..ServerConfig::default()
+    };
+    let server = create_server(config);
+    let handle = setup_server_thread(server.clone());
+    let addr = server.http_addr().unwrap();
+    let echo = r#"{"content":"ping"}"#;
+
+    let (status, body) = http(addr, "POST", "/echo", &[], echo);
+    assert_eq!(status, 401);
+    assert!(body.contains("ERROR_CODE_UNAUTHENTICATED"), "{}", body);
+    assert_eq!(http(addr, "POST", "/echo", &["Authorization: Bearer sensor-01:wrong"], echo).0, 401);
+    assert_eq!(http(addr, "POST", "/echo", &["Authorization: Bearer sensor-01:s1"], echo), (200, echo.to_string()));
+
+    // Health checks need no credentials
+    assert_eq!(http(addr, "GET", "/healthz", &[], "").0, 200);
+
+    server.stop();
+    handle.join().unwrap();
+}
+
+#[test]
+fn test_stream_is_sent_as_it_runs() {
+    let server = create_server(ServerConfig::default());
+    let handle = setup_server_thread(server.clone());
+    let addr = server.http_addr().unwrap();
+
+    let mut stream = TcpStream::connect(addr).unwrap();
+    stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    let body = r#"{"requestId":"1","countTo":{"start":1,"end":10,"step":1,"intervalMs":200}}"#;
+    let request = format!("POST /rpc HTTP/1.1\r\nHost: localhost\r\nContent-Length: {}\r\n\r\n{}", body.len(), body);
+    stream.write_all(request.as_bytes()).unwrap();
+
+    // The first item arrives long before the stream could have finished
+    let started = Instant::now();
+    let mut received = String::new();
+    let mut buffer = [0; 1024];
+    while !received.contains("streamItem") {
+        let len = stream.read(&mut buffer).unwrap();
+        assert!(len > 0, "Connection closed early");
+        received.push_str(std::str::from_utf8(&buffer[..len]).unwrap());
+    }
+    assert!(started.elapsed() < Duration::from_secs(1), "Body was held back until the end");
+
+    // Hanging up ends the stream, and the gateway keeps serving
+    drop(stream);
+    assert_eq!(http(addr, "POST", "/echo", &[], r#"{"content":"still here"}"#).0, 200);
+
+    server.stop();
+    handle.join().unwrap();
+}
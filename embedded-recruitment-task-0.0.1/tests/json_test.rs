@@ -0,0 +1,212 @@
+use embedded_recruitment_task::{
+    client::Client as FramedClient,
+    json::{decode_client_message, decode_server_message, encode_client_message, encode_server_message},
+    handler::error_message,
+    message::{client_message, server_message, ClientMessage, ErrorCode, Get, Publish, Put, ServerMessage, StreamStatus},
+    server::{Server, ServerConfig},
+};
+use std::{
+    io::{BufRead, BufReader, Read, Write},
+    net::TcpStream,
+    sync::Arc,
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+
+fn setup_server_thread(server: Arc<Server>) -> JoinHandle<()> {
+    thread::spawn(move || {
+        server.run().expect("Server encountered an error");
+    })
+}
+
+fn create_server() -> Arc<Server> {
+    let config = ServerConfig {
+        json_addr: Some("localhost:0".to_string()),
+        ..ServerConfig::default()
+    };
+    Arc::new(Server::with_config("localhost:0", config).expect("Failed to start server"))
+}
+
+// A JSON connection, one message per line each way
+struct JsonConnection {
+    writer: TcpStream,
+    reader: BufReader<TcpStream>,
+}
+
+impl JsonConnection {
+    fn connect(server: &Server) -> Self {
+        let stream = TcpStream::connect(server.json_addr().expect("JSON listener is enabled")).unwrap();
+        stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        JsonConnection {
+            writer: stream.try_clone().unwrap(),
+            reader: BufReader::new(stream),
+        }
+    }
+
+    fn send(&mut self, line: &str) {
+        self.writer.write_all(format!("{}\n", line).as_bytes()).unwrap();
+    }
+
+    fn receive(&mut self) -> ServerMessage {
+        let mut line = String::new();
+        self.reader.read_line(&mut line).expect("Failed to read a reply line");
+        assert!(line.ends_with('\n'), "Reply is not a whole line: {:?}", line);
+        decode_server_message(&line).unwrap()
+    }
+}
+
+fn error_code(reply: &ServerMessage) -> ErrorCode {
+    match &reply.message {
+        Some(server_message::Message::Error(error)) => error.code(),
+        other => panic!("Expected an Error, but received {:?}", other),
+    }
+}
+
+#[test]
+fn test_proto3_json_mapping() {
+    let put = ClientMessage {
+        request_id: 7,
+        message: Some(client_message::Message::Put(Put { key: "device/mode".to_string(), value: b"eco".to_vec() })),
+        ..Default::default()
+    };
+    let json = encode_client_message(&put);
+    assert_eq!(json, r#"{"requestId":"7","put":{"key":"device/mode","value":"ZWNv"}}"#);
+    assert_eq!(decode_client_message(&json).unwrap(), put);
+
+    // Integers may be numbers as well, default fields may be left out
+    let add = decode_client_message(r#"{"requestId": 3, "addRequest": {"a": 2}}"#).unwrap();
+    assert_eq!(add.request_id, 3);
+    match add.message {
+        Some(client_message::Message::AddRequest(request)) => assert_eq!((request.a, request.b), (2, 0)),
+        other => panic!("Expected AddRequest, but received {:?}", other),
+    }
+
+    let error = error_message(ErrorCode::NotFound, "no such key");
+    assert_eq!(
+        encode_server_message(&error),
+        r#"{"error":{"code":"ERROR_CODE_NOT_FOUND","message":"no such key"}}"#
+    );
+
+    for invalid in ["", "not json", r#"{"echoMessage":{"content":1}}"#, r#"{"put":{},"get":{}}"#] {
+        let error = decode_client_message(invalid).unwrap_err();
+        assert!(error.to_string().starts_with("invalid JSON message: "), "{}", error);
+    }
+}
+
+#[test]
+fn test_json_requests() {
+    let server = create_server();
+    let handle = setup_server_thread(server.clone());
+    let mut connection = JsonConnection::connect(&server);
+
+    connection.send(r#"{"requestId":"1","echoMessage":{"content":"hello"}}"#);
+    let reply = connection.receive();
+    assert_eq!(reply.request_id, 1);
+    match reply.message {
+        Some(server_message::Message::EchoMessage(echo)) => assert_eq!(echo.content, "hello"),
+        other => panic!("Expected EchoMessage, but received {:?}", other),
+    }
+
+    connection.send(r#"{"requestId":2,"addRequest":{"a":40,"b":2}}"#);
+    match connection.receive().message {
+        Some(server_message::Message::AddResponse(response)) => assert_eq!(response.result, 42),
+        other => panic!("Expected AddResponse, but received {:?}", other),
+    }
+
+    // A broken line gets an error and the connection carries on
+    connection.send("{\"requestId\":3,");
+    let reply = connection.receive();
+    assert_eq!((reply.request_id, error_code(&reply)), (0, ErrorCode::InvalidArgument));
+
+    // There is no frame format to negotiate
+    connection.send(r#"{"requestId":4,"hello":{}}"#);
+    assert_eq!(error_code(&connection.receive()), ErrorCode::InvalidArgument);
+
+    // Streams push their frames as lines of their own
+    connection.send("");
+    connection.send(r#"{"requestId":5,"countTo":{"start":1,"end":3,"step":1}}"#);
+    let mut numbers = Vec::new();
+    loop {
+        let reply = connection.receive();
+        assert_eq!(reply.request_id, 5);
+        match reply.message {
+            Some(server_message::Message::StreamItem(item)) => numbers.push(item.value),
+            Some(server_message::Message::StreamEnd(end)) => {
+                assert_eq!(end.status(), StreamStatus::Completed);
+                break;
+            }
+            other => panic!("Expected a stream frame, but received {:?}", other),
+        }
+    }
+    assert_eq!(numbers.len(), 3);
+
+    server.stop();
+    handle.join().unwrap();
+}
+
+#[test]
+fn test_json_and_framed_clients_share_state() {
+    let server = create_server();
+    let handle = setup_server_thread(server.clone());
+    let mut connection = JsonConnection::connect(&server);
+
+    connection.send(r#"{"requestId":1,"subscribe":{"pattern":"alerts/#"}}"#);
+    assert!(matches!(connection.receive().message, Some(server_message::Message::SubscribeResponse(_))));
+    connection.send(r#"{"requestId":2,"put":{"key":"device/mode","value":"ZWNv"}}"#);
+    assert!(matches!(connection.receive().message, Some(server_message::Message::PutResponse(_))));
+
+    let mut framed = FramedClient::connect(server.local_addr().unwrap(), Duration::from_secs(1)).unwrap();
+    framed.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    let get = client_message::Message::Get(Get { key: "device/mode".to_string() });
+    match framed.request(get).unwrap() {
+        server_message::Message::GetResponse(response) => assert_eq!(response.value, b"eco"),
+        other => panic!("Expected GetResponse, but received {:?}", other),
+    }
+    let publish = client_message::Message::Publish(Publish {
+        topic: "alerts/door".to_string(),
+        payload: b"open".to_vec(),
+    });
+    match framed.request(publish).unwrap() {
+        server_message::Message::PublishResponse(response) => assert_eq!(response.delivered, 1),
+        other => panic!("Expected PublishResponse, but received {:?}", other),
+    }
+
+    match connection.receive().message {
+        Some(server_message::Message::Publication(publication)) => {
+            assert_eq!((publication.topic.as_str(), publication.payload.as_slice()), ("alerts/door", &b"open"[..]));
+        }
+        other => panic!("Expected Publication, but received {:?}", other),
+    }
+    framed.disconnect().unwrap();
+
+    server.stop();
+    handle.join().unwrap();
+}
+
+#[test]
+fn test_silent_client_is_dropped_before_its_first_line() {
+    let config = ServerConfig {
+        json_addr: Some("localhost:0".to_string()),
+        handshake_timeout: Duration::from_millis(200),
+        ..ServerConfig::default()
+    };
+    let server = Arc::new(Server::with_config("localhost:0", config).unwrap());
+    let handle = setup_server_thread(server.clone());
+
+    let mut silent = TcpStream::connect(server.json_addr().unwrap()).unwrap();
+    silent.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    let started = Instant::now();
+    assert_eq!(silent.read(&mut [0; 16]).unwrap_or(0), 0, "Connection is still open");
+    assert!(started.elapsed() < Duration::from_secs(2));
+
+    // Once a line arrived the client may stay idle
+    let mut connection = JsonConnection::connect(&server);
+    connection.send(r#"{"requestId":"1","ping":{}}"#);
+    assert_eq!(connection.receive().request_id, 1);
+    thread::sleep(Duration::from_millis(400));
+    connection.send(r#"{"requestId":"2","ping":{}}"#);
+    assert_eq!(connection.receive().request_id, 2);
+
+    server.stop();
+    handle.join().unwrap();
+}
@@ -0,0 +1,379 @@
+use embedded_recruitment_task::{
+    message::{client_message, server_message, Put},
+    persistence::{FsyncPolicy, PersistenceConfig, WalFile, SNAPSHOT_FILE, WAL_FILE},
+    server::{Server, ServerConfig},
+    store::{KeyValueStore, StoreError, SwapOutcome},
+};
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{self, Write},
+    path::Path,
+    sync::{Arc, Mutex},
+    thread::{self, JoinHandle},
+    time::Duration,
+};
+
+mod client;
+
+fn setup_server_thread(server: Arc<Server>) -> JoinHandle<()> {
+    thread::spawn(move || {
+        server.run().expect("Server encountered an error");
+    })
+}
+
+fn config(directory: &Path) -> PersistenceConfig {
+    PersistenceConfig {
+        snapshot_every: 0, // Tests decide when to snapshot
+        ..PersistenceConfig::new(directory)
+    }
+}
+
+fn wal_len(directory: &Path) -> u64 {
+    fs::metadata(directory.join(WAL_FILE)).unwrap().len()
+}
+
+fn truncate_wal(directory: &Path, len: u64) {
+    let file = OpenOptions::new().write(true).open(directory.join(WAL_FILE)).unwrap();
+    file.set_len(len).unwrap();
+}
+
+// Ways the next append to a `FlakyWal` fails
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Fault {
+    TornWrite,     // Writes half the record, then fails
+    Sync,          // Writes the record, then fails to sync it
+    SyncAndResize, // Fails to sync, and to truncate afterwards
+}
+
+// WAL file that fails the next append as armed, once
+struct FlakyWal {
+    file: File,
+    fault: Arc<Mutex<Option<Fault>>>,
+}
+
+impl FlakyWal {
+    fn armed(&self, fault: Fault) -> bool {
+        *self.fault.lock().unwrap() == Some(fault)
+    }
+}
+
+impl Write for FlakyWal {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.armed(Fault::TornWrite) {
+            *self.fault.lock().unwrap() = None;
+            self.file.write_all(&buf[..buf.len() / 2])?;
+            return Err(io::Error::other("no space left on device"));
+        }
+        self.file.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+impl WalFile for FlakyWal {
+    fn set_len(&mut self, len: u64) -> io::Result<()> {
+        if self.armed(Fault::SyncAndResize) {
+            return Err(io::Error::other("read-only file system"));
+        }
+        self.file.set_len(len)
+    }
+
+    fn sync_data(&mut self) -> io::Result<()> {
+        let mut fault = self.fault.lock().unwrap();
+        match *fault {
+            Some(Fault::Sync) => {
+                *fault = None;
+                Err(io::Error::other("input/output error"))
+            }
+            Some(Fault::SyncAndResize) => Err(io::Error::other("input/output error")),
+            _ => self.file.sync_data(),
+        }
+    }
+}
+
+// Opens a store whose WAL fails as told by the returned handle
+fn open_flaky(directory: &Path) -> (KeyValueStore, Arc<Mutex<Option<Fault>>>) {
+    let fault = Arc::new(Mutex::new(None));
+    let wal_fault = fault.clone();
+    let store = KeyValueStore::open_with(config(directory), move |file| Box::new(FlakyWal { file, fault: wal_fault })).unwrap();
+    (store, fault)
+}
+
+#[test]
+fn test_store_survives_reopen() {
+    let dir = tempfile::tempdir().unwrap();
+
+    let versions = {
+        let store = KeyValueStore::open(config(dir.path())).unwrap();
+        let a = store.put("a", b"1".to_vec()).unwrap();
+        let b = store.put("b", b"2".to_vec()).unwrap();
+        store.put("c", b"3".to_vec()).unwrap();
+        assert!(store.delete("c").unwrap());
+        (a, b)
+    };
+
+    let store = KeyValueStore::open(config(dir.path())).unwrap();
+    assert_eq!(store.len(), 2);
+    let a = store.get("a").unwrap().unwrap();
+    assert_eq!((a.value.as_slice(), a.version), (b"1".as_slice(), versions.0));
+    assert_eq!(store.get("b").unwrap().unwrap().version, versions.1);
+    assert!(store.get("c").unwrap().is_none(), "Deleted key came back");
+
+    // The version counter continues where it left off
+    assert!(store.put("d", Vec::new()).unwrap() > versions.1);
+}
+
+#[test]
+fn test_recovery_truncates_partial_record() {
+    let dir = tempfile::tempdir().unwrap();
+
+    let complete_len = {
+        let store = KeyValueStore::open(config(dir.path())).unwrap();
+        store.put("first", b"kept".to_vec()).unwrap();
+        store.put("second", b"kept".to_vec()).unwrap();
+        let complete_len = wal_len(dir.path());
+        store.put("third", b"torn by the crash".to_vec()).unwrap();
+        complete_len
+    };
+
+    // Simulate a crash in the middle of writing the third record
+    let full_len = wal_len(dir.path());
+    truncate_wal(dir.path(), full_len - 5);
+
+    let store = KeyValueStore::open(config(dir.path())).unwrap();
+    assert!(store.get("first").unwrap().is_some());
+    assert!(store.get("second").unwrap().is_some());
+    assert!(store.get("third").unwrap().is_none(), "Partial record must not be applied");
+    assert_eq!(wal_len(dir.path()), complete_len, "Torn tail should be cut off");
+
+    // New writes append after the last good record and survive another restart
+    store.put("fourth", b"after recovery".to_vec()).unwrap();
+    drop(store);
+    let store = KeyValueStore::open(config(dir.path())).unwrap();
+    assert_eq!(store.list_keys("", 0), ["first", "fourth", "second"]);
+}
+
+#[test]
+fn test_recovery_stops_at_truncated_header() {
+    let dir = tempfile::tempdir().unwrap();
+
+    let first_len = {
+        let store = KeyValueStore::open(config(dir.path())).unwrap();
+        store.put("only", b"value".to_vec()).unwrap();
+        let first_len = wal_len(dir.path());
+        store.put("lost", b"value".to_vec()).unwrap();
+        first_len
+    };
+
+    // Leave only a few bytes of the second record's header
+    truncate_wal(dir.path(), first_len + 3);
+
+    let store = KeyValueStore::open(config(dir.path())).unwrap();
+    assert_eq!(store.list_keys("", 0), ["only"]);
+    assert_eq!(wal_len(dir.path()), first_len);
+}
+
+#[test]
+fn test_recovery_stops_at_corrupted_record() {
+    let dir = tempfile::tempdir().unwrap();
+
+    {
+        let store = KeyValueStore::open(config(dir.path())).unwrap();
+        store.put("good", b"value".to_vec()).unwrap();
+        store.put("bad", b"value".to_vec()).unwrap();
+    }
+
+    // Flip a bit in the last byte of the second record's payload
+    let path = dir.path().join(WAL_FILE);
+    let mut data = fs::read(&path).unwrap();
+    let last = data.len() - 1;
+    data[last] ^= 0x01;
+    fs::write(&path, &data).unwrap();
+
+    let store = KeyValueStore::open(config(dir.path())).unwrap();
+    assert_eq!(store.list_keys("", 0), ["good"]);
+}
+
+#[test]
+fn test_snapshot_compacts_wal() {
+    let dir = tempfile::tempdir().unwrap();
+
+    let versions = {
+        let store = KeyValueStore::open(config(dir.path())).unwrap();
+        let before = store.put("before", b"snapshot".to_vec()).unwrap();
+        store.snapshot().unwrap();
+        assert_eq!(wal_len(dir.path()), 0, "Snapshot should reset the WAL");
+        let after = store.put("after", b"snapshot".to_vec()).unwrap();
+        (before, after)
+    };
+    assert!(dir.path().join(SNAPSHOT_FILE).exists());
+
+    let store = KeyValueStore::open(config(dir.path())).unwrap();
+    assert_eq!(store.get("before").unwrap().unwrap().version, versions.0);
+    assert_eq!(store.get("after").unwrap().unwrap().version, versions.1);
+}
+
+#[test]
+fn test_periodic_snapshots() {
+    let dir = tempfile::tempdir().unwrap();
+    let config = PersistenceConfig {
+        directory: dir.path().to_path_buf(),
+        fsync: FsyncPolicy::Never,
+        snapshot_every: 10,
+    };
+
+    {
+        let store = KeyValueStore::open(config.clone()).unwrap();
+        for i in 0..25 {
+            store.put(&format!("key{:02}", i), vec![i]).unwrap();
+        }
+    }
+    assert!(dir.path().join(SNAPSHOT_FILE).exists(), "A snapshot should have been written");
+
+    let store = KeyValueStore::open(config).unwrap();
+    assert_eq!(store.len(), 25);
+    assert_eq!(store.get("key24").unwrap().unwrap().value, [24]);
+}
+
+#[test]
+fn test_wal_replayed_over_stale_snapshot_is_idempotent() {
+    let dir = tempfile::tempdir().unwrap();
+
+    let version = {
+        let store = KeyValueStore::open(config(dir.path())).unwrap();
+        let version = store.put("counter", b"1".to_vec()).unwrap();
+        // Keep a copy of the WAL as it was before compaction
+        let wal = fs::read(dir.path().join(WAL_FILE)).unwrap();
+        store.snapshot().unwrap();
+        // Simulate a crash after the snapshot rename but before the WAL was reset
+        fs::write(dir.path().join(WAL_FILE), wal).unwrap();
+        version
+    };
+
+    let store = KeyValueStore::open(config(dir.path())).unwrap();
+    let entry = store.get("counter").unwrap().unwrap();
+    assert_eq!(entry.version, version);
+    assert_eq!(
+        store.compare_and_swap("counter", version, b"2".to_vec()).unwrap(),
+        SwapOutcome::Swapped(version + 1)
+    );
+}
+
+#[test]
+fn test_interval_fsync_policy() {
+    let dir = tempfile::tempdir().unwrap();
+    let config = PersistenceConfig {
+        directory: dir.path().to_path_buf(),
+        fsync: FsyncPolicy::Interval(Duration::from_secs(60)),
+        snapshot_every: 0,
+    };
+
+    let store = KeyValueStore::open(config.clone()).unwrap();
+    store.put("lazy", b"value".to_vec()).unwrap();
+    store.sync().unwrap();
+    drop(store);
+
+    let store = KeyValueStore::open(config).unwrap();
+    assert!(store.get("lazy").unwrap().is_some());
+}
+
+#[test]
+fn test_server_recovers_store_on_new() {
+    let dir = tempfile::tempdir().unwrap();
+    let server_config = ServerConfig {
+        persistence: Some(config(dir.path())),
+        ..ServerConfig::default()
+    };
+
+    let server = Arc::new(Server::with_config("localhost:0", server_config.clone()).unwrap());
+    let handle = setup_server_thread(server.clone());
+
+    let port = server.local_addr().unwrap().port() as u32;
+    let mut client = client::Client::new("localhost", port, 1000);
+    assert!(client.connect().is_ok(), "Failed to connect to the server");
+    let put = client_message::Message::Put(Put {
+        key: "firmware/version".to_string(),
+        value: b"1.2.3".to_vec(),
+    });
+    assert!(client.send(put).is_ok(), "Failed to send Put");
+    match client.receive().unwrap().message {
+        Some(server_message::Message::PutResponse(_)) => {}
+        other => panic!("Expected PutResponse, but received {:?}", other),
+    }
+    client.disconnect().unwrap();
+
+    server.stop();
+    handle.join().unwrap();
+    drop(server);
+
+    let restarted = Server::with_config("localhost:0", server_config).unwrap();
+    let entry = restarted.store().get("firmware/version").unwrap();
+    assert_eq!(entry.map(|entry| entry.value), Some(b"1.2.3".to_vec()));
+}
+
+#[test]
+fn test_torn_append_does_not_hide_later_writes() {
+    let dir = tempfile::tempdir().unwrap();
+
+    let (before, after) = {
+        let (store, fault) = open_flaky(dir.path());
+        let before = store.put("before", b"1".to_vec()).unwrap();
+        let len = wal_len(dir.path());
+        *fault.lock().unwrap() = Some(Fault::TornWrite);
+        assert!(matches!(store.put("torn", b"2".to_vec()), Err(StoreError::Persistence(_))));
+        assert_eq!(wal_len(dir.path()), len, "Partial record left in the WAL");
+        assert!(store.get("torn").unwrap().is_none());
+        (before, store.put("after", b"3".to_vec()).unwrap())
+    };
+
+    // Recovery no longer stops at the failed record, so the write acknowledged after it survives
+    let store = KeyValueStore::open(config(dir.path())).unwrap();
+    assert_eq!(store.get("before").unwrap().unwrap().version, before);
+    assert!(store.get("torn").unwrap().is_none());
+    assert_eq!(store.get("after").unwrap().unwrap().version, after);
+}
+
+#[test]
+fn test_failed_sync_is_not_recovered() {
+    let dir = tempfile::tempdir().unwrap();
+
+    let after = {
+        let (store, fault) = open_flaky(dir.path());
+        store.put("before", b"1".to_vec()).unwrap();
+        *fault.lock().unwrap() = Some(Fault::Sync);
+        assert!(matches!(store.put("unsynced", b"2".to_vec()), Err(StoreError::Persistence(_))));
+        assert!(store.get("unsynced").unwrap().is_none());
+        store.put("after", b"3".to_vec()).unwrap()
+    };
+
+    // A write reported as failed does not come back as a phantom after a restart
+    let store = KeyValueStore::open(config(dir.path())).unwrap();
+    assert!(store.get("unsynced").unwrap().is_none());
+    assert_eq!(store.get("after").unwrap().unwrap().version, after);
+    assert_eq!(store.len(), 2);
+}
+
+#[test]
+fn test_wal_that_cannot_be_rolled_back_refuses_writes() {
+    let dir = tempfile::tempdir().unwrap();
+
+    {
+        let (store, fault) = open_flaky(dir.path());
+        store.put("before", b"1".to_vec()).unwrap();
+        *fault.lock().unwrap() = Some(Fault::SyncAndResize);
+        assert!(store.put("unknown", b"2".to_vec()).is_err());
+
+        // The end of the log is unknown, so nothing may be appended after it, even once the disk recovers
+        *fault.lock().unwrap() = None;
+        assert!(matches!(store.put("later", b"3".to_vec()), Err(StoreError::Persistence(_))));
+        assert!(store.get("later").unwrap().is_none());
+        assert_eq!(store.get("before").unwrap().unwrap().value, b"1");
+    }
+
+    let store = KeyValueStore::open(config(dir.path())).unwrap();
+    assert!(store.get("before").unwrap().is_some());
+    assert!(store.get("later").unwrap().is_none());
+    store.put("later", b"3".to_vec()).unwrap();
+}
@@ -0,0 +1,250 @@
+use embedded_recruitment_task::{
+    auth::Credentials,
+    blob::{upload_id, BlobConfig},
+    client::Client as PolicyClient,
+    message::{
+        client_message, server_message, BatchRequest, ClientMessage, EchoMessage, ErrorCode, Get, ListKeys, Publish,
+        Put, Subscribe, UploadBegin, UploadChunk, UploadCommit,
+    },
+    policy::{Denial, Policy},
+    server::{Server, ServerConfig},
+};
+use std::{
+    sync::Arc,
+    thread::{self, JoinHandle},
+    time::Duration,
+};
+
+const POLICY: &str = "\
+    # Roles\n\
+    allow reader     get,list_keys          config/\n\
+    allow sensor     put,get                sensors/\n\
+    allow sensor     publish,subscribe      telemetry/\n\
+    allow sensor     batch_request,echo_message\n\
+    allow admin      *\n\
+    \n\
+    # Identities\n\
+    grant sensor-01  sensor reader\n\
+    grant root       admin\n\
+    grant *          reader\n";
+
+fn setup_server_thread(server: Arc<Server>) -> JoinHandle<()> {
+    thread::spawn(move || {
+        server.run().expect("Server encountered an error");
+    })
+}
+
+fn create_server(authenticate: bool) -> Arc<Server> {
+    let credentials = Credentials::parse("sensor-01 token s1\nroot token r00t\nguest token g\n").unwrap();
+    let config = ServerConfig {
+        authenticator: authenticate.then(|| Arc::new(credentials) as _),
+        policy: Some(Policy::parse(POLICY).unwrap()),
+        ..ServerConfig::default()
+    };
+    Arc::new(Server::with_config("localhost:0", config).expect("Failed to start server"))
+}
+
+fn connect(server: &Server) -> PolicyClient {
+    let addr = server.local_addr().expect("Server has no local address");
+    let client = PolicyClient::connect(addr, Duration::from_secs(1)).expect("Failed to connect to the server");
+    client.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    client
+}
+
+fn put(key: &str) -> client_message::Message {
+    client_message::Message::Put(Put {
+        key: key.to_string(),
+        value: b"1".to_vec(),
+    })
+}
+
+fn get(key: &str) -> client_message::Message {
+    client_message::Message::Get(Get { key: key.to_string() })
+}
+
+fn expect_denied(response: server_message::Message, expected: &str) {
+    match response {
+        server_message::Message::Error(error) => {
+            assert_eq!(error.code, ErrorCode::PermissionDenied as i32, "Unexpected error: {}", error.message);
+            assert_eq!(error.message, expected);
+        }
+        other => panic!("Expected Error, but received {:?}", other),
+    }
+}
+
+#[test]
+fn test_parse_policy() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("policy");
+    std::fs::write(&path, POLICY).unwrap();
+    assert_eq!(Policy::load(&path).unwrap(), Policy::parse(POLICY).unwrap());
+
+    for (file, reason) in [
+        ("allow reader fetch", "unknown operation 'fetch'"),
+        ("allow reader get a/ b/", "expected"),
+        ("grant device", "expected"),
+        ("deny reader get", "expected"),
+        ("allow reader get\ngrant device raeder", "role 'raeder' is granted but never allowed anything"),
+    ] {
+        let error = Policy::parse(file).unwrap_err();
+        assert!(error.to_string().contains(reason), "{:?} gave {}", file, error);
+    }
+}
+
+#[test]
+fn test_check() {
+    let policy = Policy::parse(POLICY).unwrap();
+    let sensor = Some("sensor-01");
+
+    assert_eq!(policy.check(sensor, &put("sensors/temperature")), Ok(()));
+    assert_eq!(policy.check(sensor, &get("config/interval")), Ok(()), "Roles add up");
+    assert_eq!(
+        policy.check(sensor, &put("config/interval")),
+        Err(Denial {
+            identity: Some("sensor-01".to_string()),
+            operation: "put",
+            resource: Some("config/interval".to_string()),
+        })
+    );
+    assert!(policy.check(None, &get("config/interval")).is_ok(), "Granted to everyone");
+    assert!(policy.check(None, &get("sensors/temperature")).is_err());
+    assert!(policy.check(Some("root"), &put("anything")).is_ok());
+
+    // Topic patterns and listings must stay under the prefix
+    let subscribe = |pattern: &str| client_message::Message::Subscribe(Subscribe { pattern: pattern.to_string() });
+    assert!(policy.check(sensor, &subscribe("telemetry/+/temperature")).is_ok());
+    assert!(policy.check(sensor, &subscribe("#")).is_err());
+    let list = |prefix: &str| client_message::Message::ListKeys(ListKeys { prefix: prefix.to_string(), limit: 0 });
+    assert!(policy.check(None, &list("config/")).is_ok());
+    assert!(policy.check(None, &list("")).is_err());
+
+    // Operations without a resource only need the operation
+    let echo = client_message::Message::EchoMessage(EchoMessage { content: String::new() });
+    assert!(policy.check(sensor, &echo).is_ok());
+    assert!(policy.check(None, &echo).is_err());
+
+    let upload = client_message::Message::UploadBegin(UploadBegin::default());
+    assert_eq!(policy.check(sensor, &upload).unwrap_err().to_string(), "'sensor-01' may not upload_begin ''");
+}
+
+#[test]
+fn test_denials_over_the_wire() {
+    let server = create_server(true);
+    let handle = setup_server_thread(server.clone());
+
+    let mut sensor = connect(&server);
+    sensor.authenticate_token("sensor-01", "s1").unwrap();
+    assert!(matches!(sensor.request(put("sensors/temperature")).unwrap(), server_message::Message::PutResponse(_)));
+    expect_denied(sensor.request(put("config/interval")).unwrap(), "'sensor-01' may not put 'config/interval'");
+    let publish = client_message::Message::Publish(Publish {
+        topic: "alerts/fire".to_string(),
+        payload: Vec::new(),
+    });
+    expect_denied(sensor.request(publish).unwrap(), "'sensor-01' may not publish 'alerts/fire'");
+
+    // One denied item denies the whole batch, nothing is written
+    let batch = client_message::Message::BatchRequest(BatchRequest {
+        requests: [put("sensors/humidity"), put("config/interval")]
+            .into_iter()
+            .map(|message| ClientMessage { request_id: 0, message: Some(message), ..Default::default() })
+            .collect(),
+        atomic: false,
+    });
+    expect_denied(sensor.request(batch).unwrap(), "'sensor-01' may not put 'config/interval'");
+    assert_eq!(server.store().get("sensors/humidity").unwrap(), None);
+
+    // A role granted to everyone covers identities without grants of their own
+    let mut guest = connect(&server);
+    guest.authenticate_token("guest", "g").unwrap();
+    assert!(matches!(guest.request(get("config/interval")).unwrap(), server_message::Message::GetResponse(_)));
+    expect_denied(guest.request(get("sensors/temperature")).unwrap(), "'guest' may not get 'sensors/temperature'");
+
+    let mut root = connect(&server);
+    root.authenticate_token("root", "r00t").unwrap();
+    assert!(matches!(root.request(put("config/interval")).unwrap(), server_message::Message::PutResponse(_)));
+
+    for client in [sensor, guest, root] {
+        client.disconnect().unwrap();
+    }
+    server.stop();
+    handle.join().unwrap();
+}
+
+#[test]
+fn test_policy_without_authentication() {
+    let server = create_server(false);
+    let handle = setup_server_thread(server.clone());
+
+    // Connections without an identity only get what is granted to everyone
+    let mut client = connect(&server);
+    assert!(matches!(client.request(get("config/interval")).unwrap(), server_message::Message::GetResponse(_)));
+    expect_denied(client.request(put("config/interval")).unwrap(), "unauthenticated connections may not put 'config/interval'");
+
+    client.disconnect().unwrap();
+    server.stop();
+    handle.join().unwrap();
+}
+
+#[test]
+fn test_upload_continuations_use_the_upload_name() {
+    let policy = Policy::parse("allow firmware upload_begin,upload_chunk,upload_commit fw-\nallow admin *\ngrant device firmware\ngrant root admin\n").unwrap();
+    let chunk = |id: &str| client_message::Message::UploadChunk(UploadChunk { upload_id: id.to_string(), ..Default::default() });
+    let commit = |id: &str| client_message::Message::UploadCommit(UploadCommit { upload_id: id.to_string() });
+    let names = |id: &str| match id {
+        "mine" => Some("fw-2.bin".to_string()),
+        "theirs" => Some("secrets.bin".to_string()),
+        _ => None,
+    };
+
+    let device = Some("device");
+    assert!(policy.check_with(device, &chunk("mine"), &names).is_ok());
+    assert!(policy.check_with(device, &commit("mine"), &names).is_ok());
+    assert_eq!(
+        policy.check_with(device, &commit("theirs"), &names).unwrap_err().to_string(),
+        "'device' may not upload_commit 'secrets.bin'"
+    );
+    assert!(policy.check_with(device, &chunk("theirs"), &names).is_err());
+
+    // Without a name a prefix never matches, a rule without one still applies
+    assert!(policy.check_with(device, &chunk("unknown"), &names).is_err());
+    assert!(policy.check(device, &chunk("mine")).is_err());
+    assert!(policy.check(Some("root"), &chunk("mine")).is_ok());
+}
+
+#[test]
+fn test_upload_of_another_name_cannot_be_continued() {
+    let dir = tempfile::tempdir().unwrap();
+    let credentials = Credentials::parse("device token d\nroot token r00t\n").unwrap();
+    let config = ServerConfig {
+        authenticator: Some(Arc::new(credentials)),
+        policy: Some(Policy::parse("allow firmware upload_begin,upload_chunk,upload_commit fw-\nallow admin *\ngrant device firmware\ngrant root admin\n").unwrap()),
+        blobs: Some(BlobConfig::new(dir.path())),
+        ..ServerConfig::default()
+    };
+    let server = Arc::new(Server::with_config("localhost:0", config).expect("Failed to start server"));
+    let handle = setup_server_thread(server.clone());
+
+    // The upload id follows from the name, size and digest, so the device can work it out
+    let sha256 = [7u8; 32].to_vec();
+    let mut root = connect(&server);
+    root.authenticate_token("root", "r00t").unwrap();
+    let begin = UploadBegin { name: "secrets.bin".to_string(), size: 4, sha256: sha256.clone() };
+    assert!(matches!(root.request(client_message::Message::UploadBegin(begin)).unwrap(), server_message::Message::UploadBeginResponse(_)));
+    let id = upload_id("secrets.bin", 4, &sha256);
+
+    let mut device = connect(&server);
+    device.authenticate_token("device", "d").unwrap();
+    let chunk = UploadChunk { upload_id: id.clone(), offset: 0, data: b"evil".to_vec() };
+    expect_denied(
+        device.request(client_message::Message::UploadChunk(chunk)).unwrap(),
+        "'device' may not upload_chunk 'secrets.bin'",
+    );
+    expect_denied(
+        device.request(client_message::Message::UploadCommit(UploadCommit { upload_id: id })).unwrap(),
+        "'device' may not upload_commit 'secrets.bin'",
+    );
+    assert_eq!(server.blobs().unwrap().begin_upload("secrets.bin", 4, &sha256), Ok((upload_id("secrets.bin", 4, &sha256), 0)), "Nothing was written");
+
+    server.stop();
+    handle.join().unwrap();
+}
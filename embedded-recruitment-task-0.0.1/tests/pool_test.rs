@@ -0,0 +1,266 @@
+use embedded_recruitment_task::{
+    auth::Credentials,
+    codec::CompressionConfig,
+    frame,
+    message::{
+        client_message, server_message, AddRequest, ClientMessage, CountTo, ErrorCode, Pong, ServerMessage, StreamStatus,
+    },
+    pool::{ConnectionPool, Initializer, PoolConfig, PoolStatus},
+    server::{Server, ServerConfig},
+};
+use std::{
+    io::ErrorKind,
+    net::{Shutdown, SocketAddr, TcpListener, TcpStream},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+
+fn setup_server_thread(server: Arc<Server>) -> JoinHandle<()> {
+    thread::spawn(move || {
+        server.run().expect("Server encountered an error");
+    })
+}
+
+fn create_server() -> Arc<Server> {
+    Arc::new(Server::new("localhost:0").expect("Failed to start server"))
+}
+
+fn add(a: i32, b: i32) -> client_message::Message {
+    client_message::Message::AddRequest(AddRequest { a, b })
+}
+
+// Polls `condition` for up to five seconds
+fn wait_until(condition: impl Fn() -> bool) {
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while !condition() {
+        assert!(Instant::now() < deadline, "Condition not reached in time");
+        thread::sleep(Duration::from_millis(10));
+    }
+}
+
+// A server that only answers pings and can drop every connection it accepted
+struct PingServer {
+    addr: SocketAddr,
+    accepted: Arc<Mutex<Vec<TcpStream>>>,
+}
+
+impl PingServer {
+    fn start() -> Self {
+        let listener = TcpListener::bind("localhost:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let accepted = Arc::new(Mutex::new(Vec::new()));
+        let registry = accepted.clone();
+        thread::spawn(move || {
+            for mut stream in listener.incoming().flatten() {
+                registry.lock().unwrap().push(stream.try_clone().unwrap());
+                thread::spawn(move || {
+                    while let Ok(Some(request)) = frame::read_message::<_, ClientMessage>(&mut stream) {
+                        let reply = ServerMessage {
+                            request_id: request.request_id,
+                            message: Some(server_message::Message::Pong(Pong {})),
+                        };
+                        if frame::write_message(&mut stream, &reply).is_err() {
+                            return;
+                        }
+                    }
+                });
+            }
+        });
+        PingServer { addr, accepted }
+    }
+
+    fn connections(&self) -> usize {
+        self.accepted.lock().unwrap().len()
+    }
+
+    fn drop_connections(&self) {
+        for stream in self.accepted.lock().unwrap().iter() {
+            let _ = stream.shutdown(Shutdown::Both);
+        }
+    }
+}
+
+#[test]
+fn test_connections_are_reused() {
+    let server = create_server();
+    let handle = setup_server_thread(server.clone());
+    let config = PoolConfig { min_size: 1, max_size: 2, checkout_timeout: Duration::from_millis(200), ..PoolConfig::default() };
+    let pool = ConnectionPool::new(server.local_addr().unwrap(), config).unwrap();
+    assert_eq!(pool.status(), PoolStatus { open: 1, idle: 1 });
+
+    // Sequential calls share the connection opened up front
+    for i in 0..20 {
+        match pool.request(add(i, 1)).unwrap() {
+            server_message::Message::AddResponse(response) => assert_eq!(response.result, i + 1),
+            other => panic!("Expected AddResponse, but received {:?}", other),
+        }
+    }
+    assert_eq!(pool.status(), PoolStatus { open: 1, idle: 1 });
+
+    // Up to `max_size` connections are checked out at once, then callers wait
+    let first = pool.get().unwrap();
+    let mut second = pool.get().unwrap();
+    assert_eq!(pool.status(), PoolStatus { open: 2, idle: 0 });
+    let started = Instant::now();
+    let error = pool.get().err().expect("Pool should be exhausted");
+    assert_eq!(error.kind(), ErrorKind::TimedOut);
+    assert!(started.elapsed() >= Duration::from_millis(200));
+
+    // A waiting caller gets the connection another thread returns
+    thread::scope(|scope| {
+        let waiter = scope.spawn(|| pool.request(add(2, 2)).map(|_| ()));
+        thread::sleep(Duration::from_millis(50));
+        drop(first);
+        assert!(waiter.join().unwrap().is_ok());
+    });
+    assert!(second.ping().is_ok());
+    drop(second);
+    assert_eq!(pool.status(), PoolStatus { open: 2, idle: 2 });
+
+    // A discarded connection frees its slot
+    pool.get().unwrap().discard();
+    assert_eq!(pool.status(), PoolStatus { open: 1, idle: 1 });
+
+    drop(pool);
+    server.stop();
+    handle.join().unwrap();
+}
+
+#[test]
+fn test_idle_connections_are_evicted() {
+    let server = PingServer::start();
+    let config = PoolConfig {
+        min_size: 1,
+        max_size: 4,
+        idle_timeout: Duration::from_millis(100),
+        health_interval: Duration::from_millis(20),
+        ..PoolConfig::default()
+    };
+    let pool = ConnectionPool::new(server.addr, config).unwrap();
+
+    let clients: Vec<_> = (0..3).map(|_| pool.get().unwrap()).collect();
+    drop(clients);
+    assert_eq!(pool.status().open, 3);
+    wait_until(|| pool.status() == PoolStatus { open: 1, idle: 1 });
+    thread::sleep(Duration::from_millis(200));
+    assert_eq!(pool.status().open, 1, "Never below min_size"); // The one left may be out for a health check
+    assert_eq!(server.connections(), 3);
+}
+
+#[test]
+fn test_dead_connections_are_replaced() {
+    let server = PingServer::start();
+    let config = PoolConfig { min_size: 2, health_interval: Duration::from_millis(20), ..PoolConfig::default() };
+    let pool = ConnectionPool::new(server.addr, config).unwrap();
+    wait_until(|| server.connections() == 2);
+
+    // The health check finds both idle connections dead and opens new ones
+    server.drop_connections();
+    wait_until(|| server.connections() == 4 && pool.status() == PoolStatus { open: 2, idle: 2 });
+    assert!(pool.get().unwrap().ping().is_ok());
+}
+
+#[test]
+fn test_failed_connection_is_not_returned() {
+    let server = PingServer::start();
+    let config = PoolConfig { health_interval: Duration::from_secs(60), ..PoolConfig::default() };
+    let pool = ConnectionPool::new(server.addr, config).unwrap();
+
+    let mut client = pool.get().unwrap();
+    wait_until(|| server.connections() == 1);
+    server.drop_connections();
+    assert!(client.ping().is_err());
+    drop(client);
+    assert_eq!(pool.status(), PoolStatus { open: 0, idle: 0 });
+
+    // The next caller gets a fresh connection
+    assert!(pool.get().unwrap().ping().is_ok());
+    assert_eq!(pool.status(), PoolStatus { open: 1, idle: 1 });
+
+    let inconsistent = PoolConfig { min_size: 3, max_size: 2, ..PoolConfig::default() };
+    assert_eq!(ConnectionPool::new(server.addr, inconsistent).err().unwrap().kind(), ErrorKind::InvalidInput);
+}
+
+#[test]
+fn test_deadline_is_not_inherited() {
+    let server = create_server();
+    let handle = setup_server_thread(server.clone());
+    let config = PoolConfig { min_size: 1, max_size: 1, ..PoolConfig::default() };
+    let pool = ConnectionPool::new(server.local_addr().unwrap(), config).unwrap();
+    let count = |end| client_message::Message::CountTo(CountTo { start: 1, end, step: 1, interval_ms: 10 });
+
+    {
+        let mut first = pool.get().unwrap();
+        first.set_deadline(Some(Duration::from_millis(50)));
+        let mut stream = first.stream(count(1000)).unwrap();
+        assert!(stream.by_ref().all(|item| item.is_ok()));
+        assert_eq!(stream.end().unwrap().status(), StreamStatus::DeadlineExceeded);
+    }
+
+    // Same connection, without the first borrower's deadline
+    let mut second = pool.get().unwrap();
+    let mut stream = second.stream(count(20)).unwrap();
+    assert_eq!(stream.by_ref().map(Result::unwrap).count(), 20);
+    assert_eq!(stream.end().unwrap().status(), StreamStatus::Completed);
+    drop(stream);
+    drop(second);
+    assert_eq!(pool.status(), PoolStatus { open: 1, idle: 1 });
+
+    drop(pool);
+    server.stop();
+    handle.join().unwrap();
+}
+
+#[test]
+fn test_initializer_sets_up_every_connection() {
+    let credentials = Credentials::parse("sensor-01 token s1\nsensor-02 token s2").unwrap();
+    let config = ServerConfig { authenticator: Some(Arc::new(credentials)), ..ServerConfig::default() };
+    let server = Arc::new(Server::with_config("localhost:0", config).expect("Failed to start server"));
+    let handle = setup_server_thread(server.clone());
+    let addr = server.local_addr().unwrap();
+
+    let runs = Arc::new(AtomicUsize::new(0));
+    let initializer = {
+        let runs = runs.clone();
+        Initializer::new(move |client| {
+            runs.fetch_add(1, Ordering::SeqCst);
+            client.negotiate(&CompressionConfig::default(), true)?;
+            client.authenticate_token("sensor-01", "s1").map(drop)
+        })
+    };
+    let config = PoolConfig { min_size: 1, max_size: 1, initializer: Some(initializer), ..PoolConfig::default() };
+    let pool = ConnectionPool::new(addr, config).unwrap();
+    assert_eq!(runs.load(Ordering::SeqCst), 1);
+
+    // Authenticated before it is handed out, and reused as it is
+    assert!(matches!(pool.request(add(1, 2)).unwrap(), server_message::Message::AddResponse(_)));
+    assert_eq!(runs.load(Ordering::SeqCst), 1);
+
+    // A connection opened to replace a closed one is set up again
+    pool.get().unwrap().discard();
+    assert!(matches!(pool.request(add(2, 2)).unwrap(), server_message::Message::AddResponse(_)));
+    assert_eq!(runs.load(Ordering::SeqCst), 2);
+    drop(pool);
+
+    // Without an initializer, a borrower's own login is not passed on to the next one
+    let config = PoolConfig { min_size: 1, max_size: 1, ..PoolConfig::default() };
+    let pool = ConnectionPool::new(addr, config).unwrap();
+    {
+        let mut first = pool.get().unwrap();
+        assert_eq!(first.authenticate_token("sensor-02", "s2").unwrap(), "sensor-02");
+        assert!(matches!(first.request(add(3, 3)).unwrap(), server_message::Message::AddResponse(_)));
+    }
+    assert_eq!(pool.status(), PoolStatus { open: 0, idle: 0 });
+    match pool.request(add(4, 4)).unwrap() {
+        server_message::Message::Error(error) => assert_eq!(error.code(), ErrorCode::Unauthenticated),
+        other => panic!("Expected Error, but received {:?}", other),
+    }
+
+    drop(pool);
+    server.stop();
+    handle.join().unwrap();
+}
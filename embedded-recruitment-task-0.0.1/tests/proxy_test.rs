@@ -0,0 +1,233 @@
+use embedded_recruitment_task::{
+    auth::Credentials,
+    client::Client as FramedClient,
+    codec::CompressionConfig,
+    frame,
+    message::{
+        client_message, server_message, AddRequest, ClientMessage, Compression, EchoMessage, ErrorCode, Ping, Pong,
+        Put, ServerMessage,
+    },
+    proxy::{Balance, Proxy, ProxyConfig},
+    server::{Server, ServerConfig},
+};
+use std::{
+    net::TcpListener,
+    sync::Arc,
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+
+fn setup_server_thread(server: Arc<Server>) -> JoinHandle<()> {
+    thread::spawn(move || {
+        server.run().expect("Server encountered an error");
+    })
+}
+
+fn setup_proxy_thread(proxy: Arc<Proxy>) -> JoinHandle<()> {
+    thread::spawn(move || {
+        proxy.run().expect("Proxy encountered an error");
+    })
+}
+
+fn create_server() -> Arc<Server> {
+    Arc::new(Server::new("localhost:0").expect("Failed to start server"))
+}
+
+fn create_proxy(backends: Vec<String>, balance: Balance, health_interval: Duration) -> Arc<Proxy> {
+    let config = ProxyConfig { balance, health_interval, ..ProxyConfig::new(backends) };
+    Arc::new(Proxy::new("localhost:0", config).expect("Failed to start proxy"))
+}
+
+fn address(server: &Server) -> String {
+    server.local_addr().expect("Server has no local address").to_string()
+}
+
+fn connect(proxy: &Proxy) -> FramedClient {
+    let client = FramedClient::connect(proxy.local_addr().unwrap(), Duration::from_secs(1)).unwrap();
+    client.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    client
+}
+
+fn put(client: &mut FramedClient, key: &str) {
+    let reply = client.request(client_message::Message::Put(Put { key: key.to_string(), value: b"v".to_vec() })).unwrap();
+    assert!(matches!(reply, server_message::Message::PutResponse(_)), "{:?}", reply);
+}
+
+fn echo(content: &str) -> client_message::Message {
+    client_message::Message::EchoMessage(EchoMessage { content: content.to_string() })
+}
+
+// Polls `condition` for up to five seconds
+fn wait_until(condition: impl Fn() -> bool) {
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while !condition() {
+        assert!(Instant::now() < deadline, "Condition not reached in time");
+        thread::sleep(Duration::from_millis(10));
+    }
+}
+
+// A backend that answers pings and echoes, and dies on anything else
+fn spawn_fragile_backend() -> String {
+    let listener = TcpListener::bind("localhost:0").unwrap();
+    let addr = listener.local_addr().unwrap().to_string();
+    thread::spawn(move || {
+        for mut stream in listener.incoming().flatten() {
+            thread::spawn(move || {
+                while let Ok(Some(request)) = frame::read_message::<_, ClientMessage>(&mut stream) {
+                    let message = match request.message {
+                        Some(client_message::Message::Ping(_)) => server_message::Message::Pong(Pong {}),
+                        Some(client_message::Message::EchoMessage(echo)) => server_message::Message::EchoMessage(echo),
+                        _ => return, // Crashes with the request in flight
+                    };
+                    let reply = ServerMessage { request_id: request.request_id, message: Some(message) };
+                    frame::write_message(&mut stream, &reply).unwrap();
+                }
+            });
+        }
+    });
+    addr
+}
+
+#[test]
+fn test_round_robin() {
+    let servers = [create_server(), create_server()];
+    let handles: Vec<_> = servers.iter().map(|server| setup_server_thread(server.clone())).collect();
+    let proxy = create_proxy(servers.iter().map(|server| address(server)).collect(), Balance::RoundRobin, Duration::from_secs(60));
+    let proxy_handle = setup_proxy_thread(proxy.clone());
+
+    // Each connection goes to the next backend and stays there
+    let mut first = connect(&proxy);
+    put(&mut first, "first");
+    let mut second = connect(&proxy);
+    put(&mut second, "second");
+    put(&mut first, "first-again");
+    assert!(servers[0].store().get("first").unwrap().is_some());
+    assert!(servers[0].store().get("first-again").unwrap().is_some());
+    assert!(servers[1].store().get("second").unwrap().is_some());
+    assert!(servers[1].store().get("first").unwrap().is_none());
+    assert!(proxy.backends().iter().all(|backend| backend.healthy && backend.connections == 1));
+
+    // The proxy answers the handshake itself, with plain frames
+    let mut third = connect(&proxy);
+    let response = third.negotiate(&CompressionConfig::default(), true).unwrap();
+    assert_eq!((response.compression(), response.checksums), (Compression::None, false));
+    put(&mut third, "third");
+    assert!(servers[0].store().get("third").unwrap().is_some());
+
+    proxy.stop();
+    proxy_handle.join().unwrap();
+    for (server, handle) in servers.iter().zip(handles) {
+        server.stop();
+        handle.join().unwrap();
+    }
+}
+
+#[test]
+fn test_least_connections() {
+    let servers = [create_server(), create_server()];
+    let handles: Vec<_> = servers.iter().map(|server| setup_server_thread(server.clone())).collect();
+    let proxy = create_proxy(servers.iter().map(|server| address(server)).collect(), Balance::LeastConnections, Duration::from_secs(60));
+    let proxy_handle = setup_proxy_thread(proxy.clone());
+
+    let mut long_lived = connect(&proxy);
+    put(&mut long_lived, "long-lived");
+    let mut short_lived = connect(&proxy);
+    put(&mut short_lived, "short-lived");
+    assert!(servers[1].store().get("short-lived").unwrap().is_some());
+    short_lived.disconnect().unwrap();
+    wait_until(|| proxy.backends()[1].connections == 0);
+
+    // Round robin would pick the first backend again, it still has a connection
+    let mut next = connect(&proxy);
+    put(&mut next, "next");
+    assert!(servers[1].store().get("next").unwrap().is_some());
+    assert_eq!(proxy.backends().iter().map(|backend| backend.connections).collect::<Vec<_>>(), [1, 1]);
+
+    proxy.stop();
+    proxy_handle.join().unwrap();
+    for (server, handle) in servers.iter().zip(handles) {
+        server.stop();
+        handle.join().unwrap();
+    }
+}
+
+#[test]
+fn test_unhealthy_backend_is_skipped() {
+    // Nothing listens on the first address any more
+    let dead = TcpListener::bind("localhost:0").unwrap().local_addr().unwrap().to_string();
+    let server = create_server();
+    let handle = setup_server_thread(server.clone());
+    let proxy = create_proxy(vec![dead, address(&server)], Balance::RoundRobin, Duration::from_millis(50));
+    let proxy_handle = setup_proxy_thread(proxy.clone());
+
+    wait_until(|| !proxy.backends()[0].healthy);
+    for i in 0..4 {
+        let mut client = connect(&proxy);
+        put(&mut client, &format!("key-{}", i));
+    }
+    assert_eq!(server.store().list_keys("key-", 10).len(), 4);
+    assert!(proxy.backends()[1].healthy);
+
+    proxy.stop();
+    proxy_handle.join().unwrap();
+    server.stop();
+    handle.join().unwrap();
+}
+
+#[test]
+fn test_failover_when_backend_dies() {
+    let server = create_server();
+    let handle = setup_server_thread(server.clone());
+    let proxy = create_proxy(vec![spawn_fragile_backend(), address(&server)], Balance::RoundRobin, Duration::from_secs(60));
+    let proxy_handle = setup_proxy_thread(proxy.clone());
+
+    let mut client = connect(&proxy);
+    assert!(matches!(client.request(echo("fragile")).unwrap(), server_message::Message::EchoMessage(_)));
+    assert_eq!(proxy.backends()[0].connections, 1);
+
+    // The request the backend died with is answered by the proxy
+    let request_id = client.send(client_message::Message::AddRequest(AddRequest { a: 1, b: 2 })).unwrap();
+    let reply = client.receive().unwrap();
+    assert_eq!(reply.request_id, request_id);
+    match reply.message {
+        Some(server_message::Message::Error(error)) => assert_eq!(error.code(), ErrorCode::Unavailable),
+        other => panic!("Expected an Error, but received {:?}", other),
+    }
+    assert!(!proxy.backends()[0].healthy);
+
+    // The next request goes to the backend that is left, on the same client connection
+    match client.request(client_message::Message::AddRequest(AddRequest { a: 1, b: 2 })).unwrap() {
+        server_message::Message::AddResponse(response) => assert_eq!(response.result, 3),
+        other => panic!("Expected AddResponse, but received {:?}", other),
+    }
+    assert_eq!(proxy.backends().iter().map(|backend| backend.connections).collect::<Vec<_>>(), [0, 1]);
+
+    proxy.stop();
+    proxy_handle.join().unwrap();
+    server.stop();
+    handle.join().unwrap();
+}
+
+#[test]
+fn test_ping_needs_no_authentication() {
+    let config = ServerConfig {
+        authenticator: Some(Arc::new(Credentials::parse("alice token secret").unwrap())),
+        ..ServerConfig::default()
+    };
+    let server = Arc::new(Server::with_config("localhost:0", config).expect("Failed to start server"));
+    let handle = setup_server_thread(server.clone());
+
+    let mut client = FramedClient::connect(server.local_addr().unwrap(), Duration::from_secs(1)).unwrap();
+    assert!(client.ping().is_ok());
+    let reply = client.request(client_message::Message::Ping(Ping {})).unwrap();
+    assert_eq!(reply, server_message::Message::Pong(Pong {}));
+    match client.request(echo("denied")).unwrap() {
+        server_message::Message::Error(error) => assert_eq!(error.code(), ErrorCode::Unauthenticated),
+        other => panic!("Expected an Error, but received {:?}", other),
+    }
+
+    assert!(Proxy::new("localhost:0", ProxyConfig::new(Vec::<String>::new())).is_err());
+
+    server.stop();
+    handle.join().unwrap();
+}
@@ -0,0 +1,269 @@
+use embedded_recruitment_task::{
+    message::{client_message, server_message, ErrorCode, Publish, Subscribe, Unsubscribe},
+    pubsub::{topic_matches, validate_pattern, validate_topic},
+    server::Server,
+};
+use std::{
+    sync::Arc,
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+
+mod client;
+
+fn setup_server_thread(server: Arc<Server>) -> JoinHandle<()> {
+    thread::spawn(move || {
+        server.run().expect("Server encountered an error");
+    })
+}
+
+fn create_server() -> Arc<Server> {
+    let server = Server::new("localhost:0");
+    Arc::new(server.expect("Failed to start server"))
+}
+
+fn connect(server: &Server) -> client::Client {
+    let port = server.local_addr().expect("Server has no local address").port() as u32;
+    let mut client = client::Client::new("localhost", port, 1000);
+    assert!(client.connect().is_ok(), "Failed to connect to the server");
+    client
+}
+
+fn subscribe(client: &mut client::Client, pattern: &str) {
+    let message = client_message::Message::Subscribe(Subscribe {
+        pattern: pattern.to_string(),
+    });
+    assert!(client.send(message).is_ok(), "Failed to send Subscribe");
+
+    match client.receive().expect("Failed to receive SubscribeResponse").message {
+        Some(server_message::Message::SubscribeResponse(response)) => {
+            assert_eq!(response.pattern, pattern, "Subscribed pattern does not match");
+        }
+        other => panic!("Expected SubscribeResponse, but received {:?}", other),
+    }
+}
+
+fn publish(client: &mut client::Client, topic: &str, payload: &[u8]) -> u32 {
+    let message = client_message::Message::Publish(Publish {
+        topic: topic.to_string(),
+        payload: payload.to_vec(),
+    });
+    assert!(client.send(message).is_ok(), "Failed to send Publish");
+
+    match client.receive().expect("Failed to receive PublishResponse").message {
+        Some(server_message::Message::PublishResponse(response)) => response.delivered,
+        other => panic!("Expected PublishResponse, but received {:?}", other),
+    }
+}
+
+fn expect_publication(client: &mut client::Client, topic: &str, payload: &[u8]) {
+    match client.receive().expect("Failed to receive Publication").message {
+        Some(server_message::Message::Publication(publication)) => {
+            assert_eq!(publication.topic, topic, "Publication topic does not match");
+            assert_eq!(publication.payload, payload, "Publication payload does not match");
+        }
+        other => panic!("Expected Publication, but received {:?}", other),
+    }
+}
+
+#[test]
+fn test_topic_matching() {
+    assert!(topic_matches("sensors/kitchen/temp", "sensors/kitchen/temp"));
+    assert!(!topic_matches("sensors/kitchen/temp", "sensors/kitchen"));
+    assert!(!topic_matches("sensors/kitchen", "sensors/kitchen/temp"));
+
+    assert!(topic_matches("sensors/+/temp", "sensors/kitchen/temp"));
+    assert!(!topic_matches("sensors/+/temp", "sensors/kitchen/humidity"));
+    assert!(!topic_matches("sensors/+", "sensors/kitchen/temp"));
+
+    assert!(topic_matches("sensors/#", "sensors/kitchen/temp"));
+    assert!(topic_matches("sensors/#", "sensors"));
+    assert!(topic_matches("#", "anything/at/all"));
+    assert!(!topic_matches("sensors/#", "actuators/valve"));
+}
+
+#[test]
+fn test_topic_validation() {
+    assert!(validate_topic("sensors/kitchen").is_ok());
+    assert!(validate_topic("").is_err());
+    assert!(validate_topic("sensors/+").is_err());
+
+    assert!(validate_pattern("sensors/+/temp").is_ok());
+    assert!(validate_pattern("sensors/#").is_ok());
+    assert!(validate_pattern("").is_err());
+    assert!(validate_pattern("sensors/#/temp").is_err());
+    assert!(validate_pattern("sensors/kit+").is_err());
+}
+
+#[test]
+fn test_publish_fans_out_to_subscribers() {
+    let server = create_server();
+    let handle = setup_server_thread(server.clone());
+
+    let mut publisher = connect(&server);
+    let mut exact = connect(&server);
+    let mut wildcard = connect(&server);
+    let mut unrelated = connect(&server);
+
+    subscribe(&mut exact, "sensors/kitchen/temp");
+    subscribe(&mut wildcard, "sensors/#");
+    subscribe(&mut unrelated, "actuators/+");
+
+    let delivered = publish(&mut publisher, "sensors/kitchen/temp", b"21.5");
+    assert_eq!(delivered, 2, "Publication should reach both matching subscribers");
+
+    expect_publication(&mut exact, "sensors/kitchen/temp", b"21.5");
+    expect_publication(&mut wildcard, "sensors/kitchen/temp", b"21.5");
+
+    // The unrelated subscriber must get its own publication next, not the sensor one
+    assert_eq!(publish(&mut publisher, "actuators/valve", b"open"), 1);
+    expect_publication(&mut unrelated, "actuators/valve", b"open");
+
+    for client in [&mut publisher, &mut exact, &mut wildcard, &mut unrelated] {
+        client.disconnect().unwrap();
+    }
+    server.stop();
+    handle.join().unwrap();
+}
+
+#[test]
+fn test_overlapping_patterns_deliver_once() {
+    let server = create_server();
+    let handle = setup_server_thread(server.clone());
+
+    let mut publisher = connect(&server);
+    let mut subscriber = connect(&server);
+
+    subscribe(&mut subscriber, "sensors/#");
+    subscribe(&mut subscriber, "sensors/+/temp");
+
+    assert_eq!(publish(&mut publisher, "sensors/hall/temp", b"19"), 1);
+    expect_publication(&mut subscriber, "sensors/hall/temp", b"19");
+
+    // A single echo round trip proves no duplicate publication is queued ahead of it
+    let echo = client_message::Message::EchoMessage(Default::default());
+    assert!(subscriber.send(echo).is_ok());
+    match subscriber.receive().unwrap().message {
+        Some(server_message::Message::EchoMessage(_)) => {}
+        other => panic!("Expected EchoMessage, but received {:?}", other),
+    }
+
+    publisher.disconnect().unwrap();
+    subscriber.disconnect().unwrap();
+    server.stop();
+    handle.join().unwrap();
+}
+
+#[test]
+fn test_unsubscribe_stops_delivery() {
+    let server = create_server();
+    let handle = setup_server_thread(server.clone());
+
+    let mut publisher = connect(&server);
+    let mut subscriber = connect(&server);
+
+    subscribe(&mut subscriber, "alerts/+");
+    assert_eq!(publish(&mut publisher, "alerts/fire", b"!"), 1);
+    expect_publication(&mut subscriber, "alerts/fire", b"!");
+
+    let message = client_message::Message::Unsubscribe(Unsubscribe {
+        pattern: "alerts/+".to_string(),
+    });
+    assert!(subscriber.send(message).is_ok(), "Failed to send Unsubscribe");
+    match subscriber.receive().unwrap().message {
+        Some(server_message::Message::UnsubscribeResponse(response)) => {
+            assert!(response.removed, "Subscription should have been removed");
+        }
+        other => panic!("Expected UnsubscribeResponse, but received {:?}", other),
+    }
+
+    assert_eq!(publish(&mut publisher, "alerts/fire", b"!"), 0);
+
+    publisher.disconnect().unwrap();
+    subscriber.disconnect().unwrap();
+    server.stop();
+    handle.join().unwrap();
+}
+
+#[test]
+fn test_invalid_pattern_returns_error() {
+    let server = create_server();
+    let handle = setup_server_thread(server.clone());
+
+    let mut client = connect(&server);
+
+    let message = client_message::Message::Subscribe(Subscribe {
+        pattern: "sensors/#/temp".to_string(),
+    });
+    assert!(client.send(message).is_ok(), "Failed to send Subscribe");
+    match client.receive().unwrap().message {
+        Some(server_message::Message::Error(error)) => {
+            assert_eq!(error.code, ErrorCode::InvalidArgument as i32);
+        }
+        other => panic!("Expected Error, but received {:?}", other),
+    }
+
+    let message = client_message::Message::Publish(Publish {
+        topic: "sensors/+".to_string(),
+        payload: Vec::new(),
+    });
+    assert!(client.send(message).is_ok(), "Failed to send Publish");
+    match client.receive().unwrap().message {
+        Some(server_message::Message::Error(error)) => {
+            assert_eq!(error.code, ErrorCode::InvalidArgument as i32);
+        }
+        other => panic!("Expected Error, but received {:?}", other),
+    }
+
+    client.disconnect().unwrap();
+    server.stop();
+    handle.join().unwrap();
+}
+
+#[test]
+fn test_subscriptions_removed_on_disconnect() {
+    let server = create_server();
+    let handle = setup_server_thread(server.clone());
+
+    let mut publisher = connect(&server);
+    let mut subscriber = connect(&server);
+    subscribe(&mut subscriber, "telemetry/#");
+    assert_eq!(server.topics().connection_count(), 1);
+
+    subscriber.disconnect().unwrap();
+
+    // Cleanup happens on the connection's thread once it notices the disconnect
+    let deadline = Instant::now() + Duration::from_secs(2);
+    while server.topics().connection_count() > 0 && Instant::now() < deadline {
+        thread::sleep(Duration::from_millis(10));
+    }
+    assert_eq!(server.topics().connection_count(), 0, "Subscriptions were not cleaned up");
+    assert_eq!(publish(&mut publisher, "telemetry/cpu", b"42"), 0);
+
+    publisher.disconnect().unwrap();
+    server.stop();
+    handle.join().unwrap();
+}
+
+#[test]
+fn test_stop_before_run_is_not_lost() {
+    let server = create_server();
+    server.stop();
+    let started = Instant::now();
+    assert!(server.run().is_ok());
+    assert!(started.elapsed() < Duration::from_secs(1), "run should return at once");
+}
+
+#[test]
+fn test_server_runs_again_after_stop() {
+    let server = create_server();
+    for _ in 0..2 {
+        let handle = setup_server_thread(server.clone());
+        let mut client = connect(&server);
+        subscribe(&mut client, "restart/#");
+        assert!(client.disconnect().is_ok());
+
+        server.stop();
+        assert!(handle.join().is_ok(), "Server thread panicked or failed to join");
+    }
+}
@@ -0,0 +1,207 @@
+use embedded_recruitment_task::{
+    auth::Credentials,
+    client::{is_idempotent, Client as FramedClient, ConnectionState, ReconnectPolicy},
+    codec::CompressionConfig,
+    frame,
+    message::{client_message, server_message, EchoMessage, Get, Put},
+    server::{Server, ServerConfig},
+};
+use std::{
+    io,
+    net::{Shutdown, SocketAddr, TcpListener, TcpStream},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+
+fn setup_server_thread(server: Arc<Server>) -> JoinHandle<()> {
+    thread::spawn(move || {
+        server.run().expect("Server encountered an error");
+    })
+}
+
+fn echo(content: &str) -> client_message::Message {
+    client_message::Message::EchoMessage(EchoMessage { content: content.to_string() })
+}
+
+fn fast_policy(max_attempts: u32) -> ReconnectPolicy {
+    ReconnectPolicy {
+        initial_delay: Duration::from_millis(20),
+        max_delay: Duration::from_millis(100),
+        jitter: 0.0,
+        max_attempts,
+        ..ReconnectPolicy::default()
+    }
+}
+
+// Records every state a client reports
+fn record_states(client: &mut FramedClient) -> Arc<Mutex<Vec<ConnectionState>>> {
+    let states = Arc::new(Mutex::new(Vec::new()));
+    let recorded = states.clone();
+    client.on_state_change(move |state| recorded.lock().unwrap().push(state));
+    states
+}
+
+// Sits between the client and the server, and can cut the link the way a flaky network does
+struct Relay {
+    addr: SocketAddr,
+    cut_next: Arc<AtomicBool>,  // Drops the next request and closes its connection
+    accepting: Arc<AtomicBool>, // Cleared to close the listener, new connections are then refused
+}
+
+impl Relay {
+    fn start(upstream: SocketAddr) -> Self {
+        let listener = TcpListener::bind("localhost:0").unwrap();
+        listener.set_nonblocking(true).unwrap();
+        let addr = listener.local_addr().unwrap();
+        let cut_next = Arc::new(AtomicBool::new(false));
+        let accepting = Arc::new(AtomicBool::new(true));
+        let (cut, open) = (cut_next.clone(), accepting.clone());
+        thread::spawn(move || {
+            while open.load(Ordering::SeqCst) {
+                match listener.accept() {
+                    Ok((client, _)) => {
+                        client.set_nonblocking(false).unwrap();
+                        relay(client, TcpStream::connect(upstream).unwrap(), cut.clone());
+                    }
+                    Err(_) => thread::sleep(Duration::from_millis(5)),
+                }
+            }
+        });
+        Relay { addr, cut_next, accepting }
+    }
+}
+
+fn relay(mut client: TcpStream, mut server: TcpStream, cut_next: Arc<AtomicBool>) {
+    let (mut from_server, mut to_client) = (server.try_clone().unwrap(), client.try_clone().unwrap());
+    thread::spawn(move || io::copy(&mut from_server, &mut to_client));
+    thread::spawn(move || {
+        while let Ok(Some(payload)) = frame::read_frame(&mut client) {
+            if cut_next.swap(false, Ordering::SeqCst) {
+                break;
+            }
+            if frame::write_frame(&mut server, &payload).is_err() {
+                break;
+            }
+        }
+        let _ = client.shutdown(Shutdown::Both);
+        let _ = server.shutdown(Shutdown::Both);
+    });
+}
+
+fn start(config: ServerConfig) -> (Arc<Server>, JoinHandle<()>, Relay) {
+    let server = Arc::new(Server::with_config("localhost:0", config).expect("Failed to start server"));
+    let handle = setup_server_thread(server.clone());
+    let relay = Relay::start(server.local_addr().unwrap());
+    (server, handle, relay)
+}
+
+#[test]
+fn test_idempotent_request_is_retried() {
+    let (server, handle, relay) = start(ServerConfig::default());
+    let mut client = FramedClient::connect(relay.addr, Duration::from_secs(1)).unwrap().with_reconnect(fast_policy(3));
+    let states = record_states(&mut client);
+    assert!(matches!(client.request(echo("before")).unwrap(), server_message::Message::EchoMessage(_)));
+    assert!(states.lock().unwrap().is_empty());
+
+    // The link drops with the echo in flight, the caller only sees the reply
+    relay.cut_next.store(true, Ordering::SeqCst);
+    match client.request(echo("through the cut")).unwrap() {
+        server_message::Message::EchoMessage(echo) => assert_eq!(echo.content, "through the cut"),
+        other => panic!("Expected EchoMessage, but received {:?}", other),
+    }
+    assert_eq!(
+        *states.lock().unwrap(),
+        [ConnectionState::Disconnected, ConnectionState::Reconnecting { attempt: 1 }, ConnectionState::Connected]
+    );
+
+    // A write is not repeated: it fails, and the next call reconnects first
+    relay.cut_next.store(true, Ordering::SeqCst);
+    let put = client_message::Message::Put(Put { key: "once".to_string(), value: b"1".to_vec() });
+    assert!(!is_idempotent(&put));
+    assert!(client.request(put).is_err());
+    match client.request(client_message::Message::Get(Get { key: "once".to_string() })).unwrap() {
+        server_message::Message::GetResponse(response) => assert!(!response.found),
+        other => panic!("Expected GetResponse, but received {:?}", other),
+    }
+    assert_eq!(states.lock().unwrap().len(), 6);
+
+    server.stop();
+    handle.join().unwrap();
+}
+
+#[test]
+fn test_session_is_restored() {
+    let config = ServerConfig {
+        authenticator: Some(Arc::new(Credentials::parse("sensor token s3cret").unwrap())),
+        ..ServerConfig::default()
+    };
+    let (server, handle, relay) = start(config);
+    let mut client = FramedClient::connect(relay.addr, Duration::from_secs(1)).unwrap().with_reconnect(fast_policy(3));
+    let compression = CompressionConfig { threshold: 0, ..CompressionConfig::default() };
+    client.negotiate(&compression, true).unwrap();
+    client.authenticate_token("sensor", "s3cret").unwrap();
+    let compressed = client.frame_stats().received.snapshot().compressed_frames;
+
+    // Plain frames or a missing login would fail the retried request
+    relay.cut_next.store(true, Ordering::SeqCst);
+    let content = "compressed and authenticated ".repeat(20);
+    match client.request(echo(&content)).unwrap() {
+        server_message::Message::EchoMessage(echo) => assert_eq!(echo.content, content),
+        other => panic!("Expected EchoMessage, but received {:?}", other),
+    }
+    assert!(client.frame_stats().received.snapshot().compressed_frames > compressed);
+
+    server.stop();
+    handle.join().unwrap();
+}
+
+#[test]
+fn test_gives_up_after_max_attempts() {
+    let (server, handle, relay) = start(ServerConfig::default());
+    let mut client = FramedClient::connect(relay.addr, Duration::from_secs(1)).unwrap().with_reconnect(fast_policy(3));
+    let states = record_states(&mut client);
+
+    relay.accepting.store(false, Ordering::SeqCst);
+    thread::sleep(Duration::from_millis(50)); // Lets the relay close its listener
+    relay.cut_next.store(true, Ordering::SeqCst);
+    let started = Instant::now();
+    assert!(client.request(echo("lost")).is_err());
+    assert!(started.elapsed() >= Duration::from_millis(60), "Waits 20 ms, then 40 ms");
+    assert_eq!(
+        *states.lock().unwrap(),
+        [
+            ConnectionState::Disconnected,
+            ConnectionState::Reconnecting { attempt: 1 },
+            ConnectionState::Reconnecting { attempt: 2 },
+            ConnectionState::Reconnecting { attempt: 3 },
+            ConnectionState::GaveUp,
+        ]
+    );
+
+    server.stop();
+    handle.join().unwrap();
+}
+
+#[test]
+fn test_backoff_delays() {
+    let policy = ReconnectPolicy {
+        initial_delay: Duration::from_millis(100),
+        max_delay: Duration::from_secs(1),
+        multiplier: 2.0,
+        jitter: 0.0,
+        max_attempts: 10,
+    };
+    let delays: Vec<u128> = (1..=6).map(|attempt| policy.delay(attempt).as_millis()).collect();
+    assert_eq!(delays, [100, 200, 400, 800, 1000, 1000]);
+
+    // Jitter only ever shortens a delay, by at most its share
+    let jittered = ReconnectPolicy { jitter: 0.5, ..policy };
+    for _ in 0..100 {
+        let delay = jittered.delay(3);
+        assert!(delay >= Duration::from_millis(200) && delay <= Duration::from_millis(400), "{:?}", delay);
+    }
+}
@@ -0,0 +1,126 @@
+use embedded_recruitment_task::{
+    auth::Credentials,
+    message::{
+        authenticate, client_message, server_message, AddRequest, Authenticate, ClientMessage, CountTo, EchoMessage,
+        ErrorCode, ServerMessage,
+    },
+    serial::{self, FrameDecoder, Framing, SerialConfig},
+    server::{Server, ServerConfig},
+};
+use prost::Message;
+use serialport::{SerialPort, TTYPort};
+use std::{
+    io::{BufReader, Write},
+    sync::Arc,
+    thread::{self, JoinHandle},
+    time::Duration,
+};
+
+fn setup_server_thread(server: Arc<Server>) -> JoinHandle<()> {
+    thread::spawn(move || {
+        server.run().expect("Server encountered an error");
+    })
+}
+
+// A pseudo-terminal standing in for the UART: the server opens the device end, the test plays the microcontroller
+struct Device {
+    port: BufReader<TTYPort>,
+    framing: Framing,
+    decoder: FrameDecoder,
+    _line: TTYPort, // Keeps the device end open until the test ends
+}
+
+impl Device {
+    fn send(&mut self, request_id: u64, message: client_message::Message) {
+        let request = ClientMessage { request_id, message: Some(message), ..Default::default() };
+        serial::write_message(self.port.get_mut(), self.framing, &request).unwrap();
+    }
+
+    fn receive(&mut self) -> ServerMessage {
+        serial::read_message(&mut self.port, &mut self.decoder).unwrap().expect("Line closed")
+    }
+}
+
+fn create_server(framing: Framing, config: ServerConfig) -> (Arc<Server>, Device) {
+    let (mut master, line) = TTYPort::pair().expect("Failed to open a pseudo-terminal");
+    master.set_timeout(Duration::from_secs(5)).unwrap();
+    let serial = SerialConfig {
+        framing,
+        ..SerialConfig::new(line.name().expect("Pseudo-terminal has a path"))
+    };
+    let config = ServerConfig { serial: Some(serial), ..config };
+    let server = Arc::new(Server::with_config("localhost:0", config).expect("Failed to start server"));
+    let decoder = FrameDecoder::new(framing, 4096);
+    (server, Device { port: BufReader::new(master), framing, decoder, _line: line })
+}
+
+fn error_code(reply: &ServerMessage) -> ErrorCode {
+    match &reply.message {
+        Some(server_message::Message::Error(error)) => error.code(),
+        other => panic!("Expected an Error, but received {:?}", other),
+    }
+}
+
+#[test]
+fn test_serial_requests() {
+    let (server, mut device) = create_server(Framing::Cobs, ServerConfig::default());
+    let handle = setup_server_thread(server.clone());
+
+    device.send(1, client_message::Message::EchoMessage(EchoMessage { content: "uart".to_string() }));
+    let reply = device.receive();
+    assert_eq!(reply.request_id, 1);
+    assert!(matches!(reply.message, Some(server_message::Message::EchoMessage(ref echo)) if echo.content == "uart"));
+
+    device.send(2, client_message::Message::AddRequest(AddRequest { a: 40, b: 2 }));
+    match device.receive().message {
+        Some(server_message::Message::AddResponse(response)) => assert_eq!(response.result, 42),
+        other => panic!("Expected AddResponse, but received {:?}", other),
+    }
+
+    // A corrupted frame is refused and the line keeps working
+    let request = ClientMessage {
+        request_id: 3,
+        message: Some(client_message::Message::EchoMessage(EchoMessage { content: "noise".to_string() })),
+        ..Default::default()
+    };
+    let mut frame = serial::encode_frame(Framing::Cobs, &request.encode_to_vec());
+    frame[4] ^= 0x20;
+    device.port.get_mut().write_all(&frame).unwrap();
+    let reply = device.receive();
+    assert_eq!((reply.request_id, error_code(&reply)), (0, ErrorCode::ChecksumMismatch));
+
+    // Stream frames follow one another on the line
+    device.send(4, client_message::Message::CountTo(CountTo { start: 1, end: 3, step: 1, interval_ms: 0 }));
+    let frames: Vec<ServerMessage> = (0..4).map(|_| device.receive()).collect();
+    assert!(frames.iter().all(|frame| frame.request_id == 4));
+    assert!(matches!(frames[3].message, Some(server_message::Message::StreamEnd(_))));
+
+    server.stop();
+    handle.join().unwrap();
+}
+
+#[test]
+fn test_link_keeps_one_session() {
+    let config = ServerConfig {
+        authenticator: Some(Arc::new(Credentials::parse("meter-7 token m7").unwrap())),
+        ..ServerConfig::default()
+    };
+    let (server, mut device) = create_server(Framing::Slip, config);
+    let handle = setup_server_thread(server.clone());
+    let echo = || client_message::Message::EchoMessage(EchoMessage { content: "reading".to_string() });
+
+    device.send(1, echo());
+    assert_eq!(error_code(&device.receive()), ErrorCode::Unauthenticated);
+    device.send(2, client_message::Message::Authenticate(Authenticate {
+        identity: "meter-7".to_string(),
+        credential: Some(authenticate::Credential::Token("m7".to_string())),
+    }));
+    assert!(matches!(device.receive().message, Some(server_message::Message::AuthenticateResponse(_))));
+
+    // Later frames on the line belong to the authenticated session
+    device.send(3, echo());
+    assert!(matches!(device.receive().message, Some(server_message::Message::EchoMessage(_))));
+
+    server.stop();
+    handle.join().unwrap();
+}
@@ -0,0 +1,249 @@
+use embedded_recruitment_task::{
+    message::{
+        client_message, server_message, CompareAndSwap, CompareAndSwapResponse, Delete, ErrorCode,
+        Get, GetResponse, ListKeys, Put,
+    },
+    server::Server,
+    store::{KeyValueStore, SwapOutcome, ABSENT_VERSION},
+};
+use std::{
+    sync::Arc,
+    thread::{self, JoinHandle},
+};
+
+mod client;
+
+fn setup_server_thread(server: Arc<Server>) -> JoinHandle<()> {
+    thread::spawn(move || {
+        server.run().expect("Server encountered an error");
+    })
+}
+
+fn create_server() -> Arc<Server> {
+    let server = Server::new("localhost:0");
+    Arc::new(server.expect("Failed to start server"))
+}
+
+fn connect(server: &Server) -> client::Client {
+    let port = server.local_addr().expect("Server has no local address").port() as u32;
+    let mut client = client::Client::new("localhost", port, 1000);
+    assert!(client.connect().is_ok(), "Failed to connect to the server");
+    client
+}
+
+fn request(client: &mut client::Client, message: client_message::Message) -> server_message::Message {
+    assert!(client.send(message).is_ok(), "Failed to send request");
+    client
+        .receive()
+        .expect("Failed to receive response")
+        .message
+        .expect("Response carries no message")
+}
+
+fn get(client: &mut client::Client, key: &str) -> GetResponse {
+    match request(client, client_message::Message::Get(Get { key: key.to_string() })) {
+        server_message::Message::GetResponse(response) => response,
+        other => panic!("Expected GetResponse, but received {:?}", other),
+    }
+}
+
+fn compare_and_swap(client: &mut client::Client, key: &str, expected_version: u64, value: &[u8]) -> CompareAndSwapResponse {
+    let message = client_message::Message::CompareAndSwap(CompareAndSwap {
+        key: key.to_string(),
+        expected_version,
+        value: value.to_vec(),
+    });
+    match request(client, message) {
+        server_message::Message::CompareAndSwapResponse(response) => response,
+        other => panic!("Expected CompareAndSwapResponse, but received {:?}", other),
+    }
+}
+
+#[test]
+fn test_put_get_delete() {
+    let server = create_server();
+    let handle = setup_server_thread(server.clone());
+    let mut client = connect(&server);
+
+    assert!(!get(&mut client, "device/1").found, "Key should not exist yet");
+
+    let put = client_message::Message::Put(Put {
+        key: "device/1".to_string(),
+        value: b"online".to_vec(),
+    });
+    let version = match request(&mut client, put) {
+        server_message::Message::PutResponse(response) => response.version,
+        other => panic!("Expected PutResponse, but received {:?}", other),
+    };
+
+    let response = get(&mut client, "device/1");
+    assert!(response.found);
+    assert_eq!(response.value, b"online");
+    assert_eq!(response.version, version);
+
+    let delete = client_message::Message::Delete(Delete { key: "device/1".to_string() });
+    match request(&mut client, delete.clone()) {
+        server_message::Message::DeleteResponse(response) => assert!(response.deleted),
+        other => panic!("Expected DeleteResponse, but received {:?}", other),
+    }
+    match request(&mut client, delete) {
+        server_message::Message::DeleteResponse(response) => assert!(!response.deleted),
+        other => panic!("Expected DeleteResponse, but received {:?}", other),
+    }
+    assert!(!get(&mut client, "device/1").found, "Key should be gone");
+
+    client.disconnect().unwrap();
+    server.stop();
+    handle.join().unwrap();
+}
+
+#[test]
+fn test_compare_and_swap_semantics() {
+    let server = create_server();
+    let handle = setup_server_thread(server.clone());
+    let mut client = connect(&server);
+
+    // Create-only succeeds once
+    let created = compare_and_swap(&mut client, "config", ABSENT_VERSION, b"v1");
+    assert!(created.swapped);
+    let conflict = compare_and_swap(&mut client, "config", ABSENT_VERSION, b"other");
+    assert!(!conflict.swapped);
+    assert_eq!(conflict.version, created.version, "Conflict should report the current version");
+
+    // Stale versions are rejected, the current one is accepted
+    let updated = compare_and_swap(&mut client, "config", created.version, b"v2");
+    assert!(updated.swapped);
+    assert!(updated.version > created.version);
+    assert!(!compare_and_swap(&mut client, "config", created.version, b"v3").swapped);
+    assert_eq!(get(&mut client, "config").value, b"v2");
+
+    client.disconnect().unwrap();
+    server.stop();
+    handle.join().unwrap();
+}
+
+#[test]
+fn test_list_keys_and_errors() {
+    let server = create_server();
+    let handle = setup_server_thread(server.clone());
+    let mut client = connect(&server);
+
+    for key in ["sensor/b", "sensor/a", "actuator/a", "sensor/c"] {
+        server.store().put(key, Vec::new()).unwrap();
+    }
+
+    let list = client_message::Message::ListKeys(ListKeys {
+        prefix: "sensor/".to_string(),
+        limit: 0,
+    });
+    match request(&mut client, list) {
+        server_message::Message::ListKeysResponse(response) => {
+            assert_eq!(response.keys, ["sensor/a", "sensor/b", "sensor/c"]);
+        }
+        other => panic!("Expected ListKeysResponse, but received {:?}", other),
+    }
+
+    let limited = client_message::Message::ListKeys(ListKeys {
+        prefix: String::new(),
+        limit: 2,
+    });
+    match request(&mut client, limited) {
+        server_message::Message::ListKeysResponse(response) => {
+            assert_eq!(response.keys, ["actuator/a", "sensor/a"]);
+        }
+        other => panic!("Expected ListKeysResponse, but received {:?}", other),
+    }
+
+    let empty_key = client_message::Message::Get(Get { key: String::new() });
+    match request(&mut client, empty_key) {
+        server_message::Message::Error(error) => {
+            assert_eq!(error.code, ErrorCode::InvalidArgument as i32);
+        }
+        other => panic!("Expected Error, but received {:?}", other),
+    }
+
+    client.disconnect().unwrap();
+    server.stop();
+    handle.join().unwrap();
+}
+
+#[test]
+fn test_versions_never_reused_after_delete() {
+    let store = KeyValueStore::new();
+
+    let first = store.put("k", b"a".to_vec()).unwrap();
+    assert!(store.delete("k").unwrap());
+    let second = store.put("k", b"b".to_vec()).unwrap();
+    assert!(second > first, "A recreated key must get a fresh version");
+
+    // A client holding the old version must not be able to overwrite the new key
+    assert_eq!(
+        store.compare_and_swap("k", first, b"c".to_vec()).unwrap(),
+        SwapOutcome::Conflict(second)
+    );
+}
+
+#[test]
+fn test_concurrent_compare_and_swap_increments() {
+    let server = create_server();
+    let handle = setup_server_thread(server.clone());
+
+    const CLIENTS: usize = 8;
+    const INCREMENTS: u64 = 25;
+
+    let workers: Vec<_> = (0..CLIENTS)
+        .map(|_| {
+            let mut client = connect(&server);
+            thread::spawn(move || {
+                let mut conflicts = 0;
+                for _ in 0..INCREMENTS {
+                    // Read-modify-write loop, retried until our CAS wins
+                    loop {
+                        let current = get(&mut client, "counter");
+                        let value = if current.found {
+                            u64::from_be_bytes(current.value.as_slice().try_into().unwrap())
+                        } else {
+                            0
+                        };
+                        let next = (value + 1).to_be_bytes();
+                        if compare_and_swap(&mut client, "counter", current.version, &next).swapped {
+                            break;
+                        }
+                        conflicts += 1;
+                    }
+                }
+                client.disconnect().unwrap();
+                conflicts
+            })
+        })
+        .collect();
+
+    let conflicts: u64 = workers.into_iter().map(|worker| worker.join().unwrap()).sum();
+    println!("{} CAS conflicts were retried", conflicts);
+
+    let entry = server.store().get("counter").unwrap().expect("Counter is missing");
+    let total = u64::from_be_bytes(entry.value.as_slice().try_into().unwrap());
+    assert_eq!(total, CLIENTS as u64 * INCREMENTS, "Lost updates under contention");
+
+    server.stop();
+    handle.join().unwrap();
+}
+
+#[test]
+fn test_concurrent_create_only_has_single_winner() {
+    let store = Arc::new(KeyValueStore::new());
+
+    let workers: Vec<_> = (0..16)
+        .map(|i| {
+            let store = store.clone();
+            thread::spawn(move || store.compare_and_swap("leader", ABSENT_VERSION, vec![i]).unwrap())
+        })
+        .collect();
+
+    let winners = workers
+        .into_iter()
+        .map(|worker| worker.join().unwrap())
+        .filter(|outcome| matches!(outcome, SwapOutcome::Swapped(_)))
+        .count();
+    assert_eq!(winners, 1, "Exactly one create-only CAS may succeed");
+}
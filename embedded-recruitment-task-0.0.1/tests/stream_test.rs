@@ -0,0 +1,295 @@
+use embedded_recruitment_task::{
+    client::Client as StreamingClient,
+    frame,
+    handler::{Handler, Session},
+    outbound::{self, BackpressureConfig},
+    message::{
+        client_message, server_message, stream_item, BatchRequest, ClientMessage, CountTo, EchoMessage, ErrorCode,
+        ServerMessage, StreamItem, StreamStatus,
+    },
+    pubsub::TopicRegistry,
+    server::Server,
+    store::KeyValueStore,
+    stream::{CountRange, CountToError},
+};
+use std::{
+    net::TcpListener,
+    sync::Arc,
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+
+mod client;
+
+fn setup_server_thread(server: Arc<Server>) -> JoinHandle<()> {
+    thread::spawn(move || {
+        server.run().expect("Server encountered an error");
+    })
+}
+
+fn create_server() -> Arc<Server> {
+    let server = Server::new("localhost:0");
+    Arc::new(server.expect("Failed to start server"))
+}
+
+fn connect(server: &Server) -> StreamingClient {
+    let addr = server.local_addr().expect("Server has no local address");
+    let client = StreamingClient::connect(addr, Duration::from_secs(1)).expect("Failed to connect to the server");
+    client.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    client
+}
+
+fn count_to(start: i64, end: i64, step: i64, interval_ms: u32) -> client_message::Message {
+    client_message::Message::CountTo(CountTo {
+        start,
+        end,
+        step,
+        interval_ms,
+    })
+}
+
+fn number(item: stream_item::Value) -> i64 {
+    match item {
+        stream_item::Value::Number(number) => number,
+    }
+}
+
+fn error_code(response: &ServerMessage) -> Option<i32> {
+    match &response.message {
+        Some(server_message::Message::Error(error)) => Some(error.code),
+        _ => None,
+    }
+}
+
+#[test]
+fn test_count_range() {
+    let range = |start, end, step| {
+        CountRange::new(&CountTo {
+            start,
+            end,
+            step,
+            interval_ms: 0,
+        })
+    };
+
+    assert_eq!(range(1, 5, 0).unwrap().collect::<Vec<_>>(), [1, 2, 3, 4, 5]);
+    assert_eq!(range(3, 0, 0).unwrap().collect::<Vec<_>>(), [3, 2, 1, 0]);
+    assert_eq!(range(0, 10, 4).unwrap().collect::<Vec<_>>(), [0, 4, 8]);
+    assert_eq!(range(7, 7, -3).unwrap().collect::<Vec<_>>(), [7]);
+    assert_eq!(
+        range(i64::MAX - 1, i64::MAX, 5).unwrap().collect::<Vec<_>>(),
+        [i64::MAX - 1],
+        "Stepping past i64::MAX must end the range, not wrap"
+    );
+    assert_eq!(
+        range(0, 10, -1).unwrap_err(),
+        CountToError::WrongDirection {
+            start: 0,
+            end: 10,
+            step: -1
+        }
+    );
+}
+
+#[test]
+fn test_stream_runs_to_completion() {
+    let server = create_server();
+    let handle = setup_server_thread(server.clone());
+    let mut client = connect(&server);
+
+    let mut stream = client.stream(count_to(1, 100, 0, 0)).unwrap();
+    let items: Vec<_> = stream.by_ref().collect::<Result<_, _>>().unwrap();
+    assert_eq!(items.len(), 100);
+    for (i, item) in items.into_iter().enumerate() {
+        assert_eq!(item.sequence, i as u64);
+        assert_eq!(number(item.value.unwrap()), i as i64 + 1);
+    }
+
+    let end = stream.end().expect("Stream ended without a StreamEnd");
+    assert_eq!(end.items, 100);
+    assert_eq!(end.status, StreamStatus::Completed as i32);
+    drop(stream);
+
+    // The connection is still usable for ordinary requests
+    let echo = client_message::Message::EchoMessage(EchoMessage {
+        content: "after".to_string(),
+    });
+    assert!(matches!(client.request(echo).unwrap(), server_message::Message::EchoMessage(_)));
+
+    client.disconnect().unwrap();
+    server.stop();
+    handle.join().unwrap();
+}
+
+#[test]
+fn test_cancel_mid_stream() {
+    let server = create_server();
+    let handle = setup_server_thread(server.clone());
+    let mut client = connect(&server);
+
+    // Would take over a minute to complete
+    let mut stream = client.stream(count_to(0, 1000, 1, 100)).unwrap();
+    let first: Vec<_> = stream.by_ref().take(2).collect::<Result<_, _>>().unwrap();
+    assert_eq!(first.len(), 2);
+
+    let started = Instant::now();
+    let end = stream.cancel().unwrap();
+    assert!(started.elapsed() < Duration::from_secs(1), "Cancel must interrupt the interval");
+    assert_eq!(end.status, StreamStatus::Cancelled as i32);
+    assert!(end.items >= 2 && end.items < 1000);
+    drop(stream);
+
+    // Cancelling again reports that nothing is running under that id anymore
+    assert!(!client.cancel(1).unwrap());
+
+    client.disconnect().unwrap();
+    server.stop();
+    handle.join().unwrap();
+}
+
+#[test]
+fn test_requests_interleave_with_stream() {
+    let server = create_server();
+    let handle = setup_server_thread(server.clone());
+    let mut client = connect(&server);
+
+    let stream_id = client.send(count_to(0, 1000, 1, 20)).unwrap();
+
+    // Replies are matched by id, stream frames arriving meanwhile are kept for later
+    let echo = client_message::Message::EchoMessage(EchoMessage {
+        content: "while streaming".to_string(),
+    });
+    match client.request(echo).unwrap() {
+        server_message::Message::EchoMessage(echo) => assert_eq!(echo.content, "while streaming"),
+        other => panic!("Expected EchoMessage, but received {:?}", other),
+    }
+    thread::sleep(Duration::from_millis(100));
+    assert!(client.cancel(stream_id).unwrap(), "Stream should still be running");
+
+    let mut numbers = Vec::new();
+    loop {
+        let message = client.receive().unwrap();
+        assert_eq!(message.request_id, stream_id);
+        match message.message {
+            Some(server_message::Message::StreamItem(item)) => numbers.push(number(item.value.unwrap())),
+            Some(server_message::Message::StreamEnd(end)) => {
+                assert_eq!(end.status, StreamStatus::Cancelled as i32);
+                assert_eq!(end.items, numbers.len() as u64);
+                break;
+            }
+            other => panic!("Expected a stream frame, but received {:?}", other),
+        }
+    }
+    assert_eq!(numbers, (0..numbers.len() as i64).collect::<Vec<_>>(), "Items must arrive in order");
+
+    client.disconnect().unwrap();
+    server.stop();
+    handle.join().unwrap();
+}
+
+#[test]
+fn test_invalid_stream_requests() {
+    let server = create_server();
+    let handle = setup_server_thread(server.clone());
+    let mut streaming = connect(&server);
+
+    // A step away from the end is rejected with a single error reply
+    let mut stream = streaming.stream(count_to(0, 10, -1, 0)).unwrap();
+    let error = stream.next().unwrap().unwrap_err();
+    assert!(error.to_string().contains("never reaches"), "{}", error);
+    assert!(stream.next().is_none());
+    drop(stream);
+    streaming.disconnect().unwrap();
+
+    // The plain test client tags every request with id 0, which cannot be told apart from pushed messages
+    let port = server.local_addr().unwrap().port() as u32;
+    let mut client = client::Client::new("localhost", port, 1000);
+    assert!(client.connect().is_ok(), "Failed to connect to the server");
+    assert!(client.send(count_to(0, 10, 0, 0)).is_ok());
+    let response = client.receive().unwrap();
+    assert_eq!(error_code(&response), Some(ErrorCode::InvalidArgument as i32));
+
+    // Streams cannot be part of a batch
+    let batch = client_message::Message::BatchRequest(BatchRequest {
+        requests: vec![ClientMessage {
+            request_id: 7,
+            message: Some(count_to(0, 10, 0, 0)),
+            ..Default::default()
+        }],
+        atomic: false,
+    });
+    assert!(client.send(batch).is_ok());
+    match client.receive().unwrap().message {
+        Some(server_message::Message::BatchResponse(batch)) => {
+            assert_eq!(error_code(&batch.responses[0]), Some(ErrorCode::InvalidArgument as i32));
+        }
+        other => panic!("Expected BatchResponse, but received {:?}", other),
+    }
+
+    client.disconnect().unwrap();
+    server.stop();
+    handle.join().unwrap();
+}
+
+#[test]
+fn test_disconnect_cancels_streams() {
+    let handler = Handler::new(Arc::new(TopicRegistry::new()), Arc::new(KeyValueStore::new()));
+    let (outbound, frames) = outbound::channel(BackpressureConfig::default());
+    let session = Session::new(1, outbound);
+
+    let request = |request_id| ClientMessage {
+        request_id,
+        message: Some(count_to(0, 1000, 1, 1000)),
+        ..Default::default()
+    };
+    assert!(handler.handle(&session, request(5)).is_none(), "A started stream has no direct reply");
+
+    // Only one stream per request id at a time
+    let duplicate = handler.handle(&session, request(5)).expect("Duplicate id must be rejected");
+    assert_eq!(duplicate.request_id, 5);
+    assert_eq!(error_code(&duplicate), Some(ErrorCode::InvalidArgument as i32));
+
+    let first = frames.recv_timeout(Duration::from_secs(1)).unwrap();
+    assert!(matches!(first.message, Some(server_message::Message::StreamItem(_))));
+    assert_eq!(session.streams.len(), 1);
+
+    handler.disconnect(&session);
+    let end = frames.recv_timeout(Duration::from_secs(1)).expect("Stream did not stop");
+    match end.message {
+        Some(server_message::Message::StreamEnd(end)) => {
+            assert_eq!(end.status, StreamStatus::Cancelled as i32);
+            assert_eq!(end.items, 1);
+        }
+        other => panic!("Expected StreamEnd, but received {:?}", other),
+    }
+    assert!(session.streams.is_empty());
+}
+
+#[test]
+fn test_stream_ends_when_server_dies() {
+    // A server that sends two items and then goes away without a StreamEnd
+    let listener = TcpListener::bind("localhost:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let server = thread::spawn(move || {
+        let (mut socket, _) = listener.accept().unwrap();
+        let request: ClientMessage = frame::read_message(&mut socket).unwrap().unwrap();
+        for number in 1..=2 {
+            let item = StreamItem { sequence: number as u64, value: Some(stream_item::Value::Number(number)) };
+            let frame = ServerMessage { request_id: request.request_id, message: Some(server_message::Message::StreamItem(item)) };
+            frame::write_message(&mut socket, &frame).unwrap();
+        }
+    });
+
+    let mut client = StreamingClient::connect(addr, Duration::from_secs(1)).unwrap();
+    client.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    let mut stream = client.stream(count_to(1, 10, 1, 0)).unwrap();
+    server.join().unwrap();
+
+    // The items sent get through, then one error, then the iterator ends
+    let results: Vec<_> = stream.by_ref().take(10).collect();
+    assert_eq!(results.len(), 3, "{:?}", results);
+    assert_eq!(results.iter().filter(|result| result.is_ok()).count(), 2);
+    assert!(results[2].is_err());
+    assert!(stream.next().is_none());
+    assert!(stream.end().is_none());
+}
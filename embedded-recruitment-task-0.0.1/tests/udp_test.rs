@@ -0,0 +1,208 @@
+use embedded_recruitment_task::{
+    message::{
+        client_message, server_message, AddRequest, ClientMessage, CountTo, EchoMessage, ErrorCode, Get, Put, ServerMessage,
+        StreamEnd, StreamStatus,
+    },
+    server::{Server, ServerConfig},
+    udp::{UdpConfig, UdpStreamLimits},
+};
+use prost::Message;
+use std::{
+    net::UdpSocket,
+    sync::Arc,
+    thread::{self, JoinHandle},
+    time::Duration,
+};
+
+fn setup_server_thread(server: Arc<Server>) -> JoinHandle<()> {
+    thread::spawn(move || {
+        server.run().expect("Server encountered an error");
+    })
+}
+
+fn create_server(udp: UdpConfig) -> Arc<Server> {
+    let config = ServerConfig {
+        udp: Some(udp),
+        ..ServerConfig::default()
+    };
+    Arc::new(Server::with_config("localhost:0", config).expect("Failed to start server"))
+}
+
+// A device socket aimed at the server's UDP listener
+fn device(server: &Server) -> UdpSocket {
+    let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+    socket.connect(server.udp_addr().expect("UDP listener is enabled")).unwrap();
+    socket.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    socket
+}
+
+fn send(socket: &UdpSocket, request_id: u64, message: client_message::Message) {
+    let request = ClientMessage { request_id, message: Some(message), ..Default::default() };
+    socket.send(&request.encode_to_vec()).unwrap();
+}
+
+fn receive(socket: &UdpSocket) -> ServerMessage {
+    let mut buffer = [0; 2048];
+    let len = socket.recv(&mut buffer).expect("No reply datagram");
+    ServerMessage::decode(&buffer[..len]).unwrap()
+}
+
+fn error_code(reply: &ServerMessage) -> ErrorCode {
+    match &reply.message {
+        Some(server_message::Message::Error(error)) => error.code(),
+        other => panic!("Expected an Error, but received {:?}", other),
+    }
+}
+
+fn put(key: &str, value: &[u8]) -> client_message::Message {
+    client_message::Message::Put(Put { key: key.to_string(), value: value.to_vec() })
+}
+
+fn put_version(reply: &ServerMessage) -> u64 {
+    match &reply.message {
+        Some(server_message::Message::PutResponse(response)) => response.version,
+        other => panic!("Expected PutResponse, but received {:?}", other),
+    }
+}
+
+fn count_to(end: i64, interval_ms: u32) -> client_message::Message {
+    client_message::Message::CountTo(CountTo { start: 1, end, step: 1, interval_ms })
+}
+
+// Receives a stream's frames up to its end, checking they all carry `request_id`
+fn receive_stream(socket: &UdpSocket, request_id: u64) -> (usize, StreamEnd) {
+    let mut items = 0;
+    loop {
+        let frame = receive(socket);
+        assert_eq!(frame.request_id, request_id);
+        match frame.message {
+            Some(server_message::Message::StreamItem(_)) => items += 1,
+            Some(server_message::Message::StreamEnd(end)) => return (items, end),
+            other => panic!("Expected a stream frame, but received {:?}", other),
+        }
+    }
+}
+
+#[test]
+fn test_datagram_requests() {
+    let server = create_server(UdpConfig { max_datagram: 512, ..UdpConfig::new("127.0.0.1:0") });
+    let handle = setup_server_thread(server.clone());
+    let socket = device(&server);
+
+    send(&socket, 1, client_message::Message::EchoMessage(EchoMessage { content: "telemetry".to_string() }));
+    let reply = receive(&socket);
+    assert_eq!(reply.request_id, 1);
+    assert!(matches!(reply.message, Some(server_message::Message::EchoMessage(ref echo)) if echo.content == "telemetry"));
+
+    send(&socket, 2, client_message::Message::AddRequest(AddRequest { a: 40, b: 2 }));
+    match receive(&socket).message {
+        Some(server_message::Message::AddResponse(response)) => assert_eq!(response.result, 42),
+        other => panic!("Expected AddResponse, but received {:?}", other),
+    }
+
+    // Garbage and oversized datagrams are refused
+    socket.send(&[0xff, 0xff, 0xff]).unwrap();
+    assert_eq!(error_code(&receive(&socket)), ErrorCode::InvalidArgument);
+    send(&socket, 3, client_message::Message::EchoMessage(EchoMessage { content: "x".repeat(600) }));
+    let reply = receive(&socket);
+    assert_eq!((reply.request_id, error_code(&reply)), (0, ErrorCode::InvalidArgument));
+
+    // So are replies that would not fit, keeping the request id
+    server.store().put("blob", vec![7; 1000]).unwrap();
+    send(&socket, 4, client_message::Message::Get(Get { key: "blob".to_string() }));
+    let reply = receive(&socket);
+    assert_eq!((reply.request_id, error_code(&reply)), (4, ErrorCode::InvalidArgument));
+
+    // Streams are refused unless enabled, a spoofed source would receive every frame
+    send(&socket, 5, count_to(3, 0));
+    let reply = receive(&socket);
+    assert_eq!((reply.request_id, error_code(&reply)), (5, ErrorCode::Unavailable));
+
+    server.stop();
+    handle.join().unwrap();
+}
+
+#[test]
+fn test_retransmissions_are_deduplicated() {
+    let config = UdpConfig {
+        dedup_window: Some(Duration::from_secs(30)),
+        ..UdpConfig::new("127.0.0.1:0")
+    };
+    let server = create_server(config);
+    let handle = setup_server_thread(server.clone());
+    let socket = device(&server);
+
+    // The retransmission gets the first reply and the Put runs once
+    send(&socket, 7, put("meter/1", b"15.2"));
+    assert_eq!(put_version(&receive(&socket)), 1);
+    send(&socket, 7, put("meter/1", b"15.2"));
+    assert_eq!(put_version(&receive(&socket)), 1);
+    assert_eq!(server.store().get("meter/1").unwrap().unwrap().version, 1);
+
+    // A new request id, or the same id from another device, runs again
+    send(&socket, 8, put("meter/1", b"15.3"));
+    assert_eq!(put_version(&receive(&socket)), 2);
+    let other = device(&server);
+    send(&other, 7, put("meter/1", b"15.4"));
+    assert_eq!(put_version(&receive(&other)), 3);
+
+    // Unnumbered requests are never deduplicated
+    send(&socket, 0, put("meter/1", b"15.5"));
+    send(&socket, 0, put("meter/1", b"15.5"));
+    assert_eq!((put_version(&receive(&socket)), put_version(&receive(&socket))), (4, 5));
+
+    server.stop();
+    handle.join().unwrap();
+}
+
+#[test]
+fn test_without_dedup_every_datagram_runs() {
+    let server = create_server(UdpConfig::new("127.0.0.1:0"));
+    let handle = setup_server_thread(server.clone());
+    let socket = device(&server);
+
+    send(&socket, 7, put("meter/1", b"15.2"));
+    assert_eq!(put_version(&receive(&socket)), 1);
+    send(&socket, 7, put("meter/1", b"15.2"));
+    assert_eq!(put_version(&receive(&socket)), 2);
+
+    server.stop();
+    handle.join().unwrap();
+}
+
+#[test]
+fn test_streams_are_limited() {
+    let limits = UdpStreamLimits { max_items: 10, max_duration: Duration::from_millis(300), max_concurrent: 1 };
+    let server = create_server(UdpConfig { streams: Some(limits), ..UdpConfig::new("127.0.0.1:0") });
+    let handle = setup_server_thread(server.clone());
+    let socket = device(&server);
+
+    // A stream's frames arrive as datagrams of their own
+    send(&socket, 1, count_to(3, 0));
+    let (items, end) = receive_stream(&socket, 1);
+    assert_eq!((items, end.status()), (3, StreamStatus::Completed));
+
+    send(&socket, 2, count_to(11, 0));
+    let reply = receive(&socket);
+    assert_eq!((reply.request_id, error_code(&reply)), (2, ErrorCode::InvalidArgument));
+
+    // A slow stream is cut short whatever deadline it asked for, and holds the only slot meanwhile
+    let request = ClientMessage { request_id: 3, message: Some(count_to(10, 100)), deadline_ms: 60_000 };
+    socket.send(&request.encode_to_vec()).unwrap();
+    let other = device(&server);
+    thread::sleep(Duration::from_millis(50));
+    send(&other, 4, count_to(1, 0));
+    let reply = receive(&other);
+    assert_eq!((reply.request_id, error_code(&reply)), (4, ErrorCode::Unavailable));
+    let (items, end) = receive_stream(&socket, 3);
+    assert_eq!(end.status(), StreamStatus::DeadlineExceeded);
+    assert!(items < 10, "{} items", items);
+
+    // The slot is free again once the stream has ended
+    thread::sleep(Duration::from_millis(50));
+    send(&other, 5, count_to(1, 0));
+    assert_eq!(receive_stream(&other, 5).0, 1);
+
+    server.stop();
+    handle.join().unwrap();
+}
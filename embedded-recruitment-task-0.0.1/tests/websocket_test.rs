@@ -0,0 +1,209 @@
+use embedded_recruitment_task::{
+    auth::Credentials,
+    client::Client as FramedClient,
+    frame::MAX_FRAME_LEN,
+    message::{
+        authenticate, client_message, server_message, AddRequest, Authenticate, ClientMessage, CountTo, EchoMessage,
+        ErrorCode, Publish, ServerMessage, Subscribe,
+    },
+    server::{Server, ServerConfig},
+};
+use prost::Message;
+use std::{
+    io::Read,
+    net::TcpStream,
+    sync::Arc,
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+use tungstenite::{Message as WsMessage, WebSocket};
+
+fn setup_server_thread(server: Arc<Server>) -> JoinHandle<()> {
+    thread::spawn(move || {
+        server.run().expect("Server encountered an error");
+    })
+}
+
+fn create_server(config: ServerConfig) -> Arc<Server> {
+    let config = ServerConfig {
+        ws_addr: Some("localhost:0".to_string()),
+        ..config
+    };
+    Arc::new(Server::with_config("localhost:0", config).expect("Failed to start server"))
+}
+
+// A browser-like client: one protobuf message per binary WebSocket message
+struct WsConnection {
+    socket: WebSocket<TcpStream>,
+    next_request_id: u64,
+}
+
+impl WsConnection {
+    fn connect(server: &Server) -> Self {
+        let addr = server.ws_addr().expect("WebSocket listener is enabled");
+        let stream = TcpStream::connect(addr).unwrap();
+        stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        let (socket, _) = tungstenite::client(format!("ws://{}/", addr), stream).expect("WebSocket handshake failed");
+        WsConnection { socket, next_request_id: 1 }
+    }
+
+    fn send(&mut self, message: client_message::Message) -> u64 {
+        let request_id = self.next_request_id;
+        self.next_request_id += 1;
+        let request = ClientMessage { request_id, message: Some(message), ..Default::default() };
+        self.socket.send(WsMessage::Binary(request.encode_to_vec())).unwrap();
+        request_id
+    }
+
+    fn receive(&mut self) -> ServerMessage {
+        loop {
+            match self.socket.read().expect("Failed to read a WebSocket message") {
+                WsMessage::Binary(payload) => return ServerMessage::decode(payload.as_slice()).unwrap(),
+                WsMessage::Ping(_) | WsMessage::Pong(_) => continue,
+                other => panic!("Expected a binary message, but received {:?}", other),
+            }
+        }
+    }
+
+    fn request(&mut self, message: client_message::Message) -> server_message::Message {
+        let request_id = self.send(message);
+        let reply = self.receive();
+        assert_eq!(reply.request_id, request_id);
+        reply.message.expect("Reply carries a message")
+    }
+}
+
+fn error_code(reply: &server_message::Message) -> ErrorCode {
+    match reply {
+        server_message::Message::Error(error) => error.code(),
+        other => panic!("Expected an Error, but received {:?}", other),
+    }
+}
+
+#[test]
+fn test_websocket_requests() {
+    let server = create_server(ServerConfig::default());
+    let handle = setup_server_thread(server.clone());
+    let mut connection = WsConnection::connect(&server);
+
+    match connection.request(client_message::Message::EchoMessage(EchoMessage { content: "dashboard".to_string() })) {
+        server_message::Message::EchoMessage(echo) => assert_eq!(echo.content, "dashboard"),
+        other => panic!("Expected EchoMessage, but received {:?}", other),
+    }
+    match connection.request(client_message::Message::AddRequest(AddRequest { a: 40, b: 2 })) {
+        server_message::Message::AddResponse(response) => assert_eq!(response.result, 42),
+        other => panic!("Expected AddResponse, but received {:?}", other),
+    }
+
+    // Stream frames arrive as messages of their own
+    let request_id = connection.send(client_message::Message::CountTo(CountTo { start: 1, end: 3, step: 1, interval_ms: 10 }));
+    let frames: Vec<ServerMessage> = (0..4).map(|_| connection.receive()).collect();
+    assert!(frames.iter().all(|frame| frame.request_id == request_id));
+    assert!(matches!(frames[3].message, Some(server_message::Message::StreamEnd(_))));
+
+    // Text messages and undecodable payloads are refused, the connection stays open
+    connection.socket.send(WsMessage::Text("{}".to_string())).unwrap();
+    assert_eq!(error_code(&connection.receive().message.unwrap()), ErrorCode::InvalidArgument);
+    connection.socket.send(WsMessage::Binary(vec![0xff, 0xff, 0xff])).unwrap();
+    assert_eq!(error_code(&connection.receive().message.unwrap()), ErrorCode::InvalidArgument);
+    assert!(matches!(
+        connection.request(client_message::Message::EchoMessage(EchoMessage::default())),
+        server_message::Message::EchoMessage(_)
+    ));
+
+    connection.socket.close(None).unwrap();
+    server.stop();
+    handle.join().unwrap();
+}
+
+#[test]
+fn test_publications_reach_websocket_clients() {
+    let server = create_server(ServerConfig::default());
+    let handle = setup_server_thread(server.clone());
+    let mut connection = WsConnection::connect(&server);
+    let subscribe = client_message::Message::Subscribe(Subscribe { pattern: "alerts/#".to_string() });
+    assert!(matches!(connection.request(subscribe), server_message::Message::SubscribeResponse(_)));
+
+    // Published from a framed connection while the WebSocket connection is idle
+    let mut framed = FramedClient::connect(server.local_addr().unwrap(), Duration::from_secs(1)).unwrap();
+    framed.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    let publish = client_message::Message::Publish(Publish { topic: "alerts/door".to_string(), payload: b"open".to_vec() });
+    match framed.request(publish).unwrap() {
+        server_message::Message::PublishResponse(response) => assert_eq!(response.delivered, 1),
+        other => panic!("Expected PublishResponse, but received {:?}", other),
+    }
+    framed.disconnect().unwrap();
+
+    match connection.receive().message {
+        Some(server_message::Message::Publication(publication)) => assert_eq!(publication.payload, b"open"),
+        other => panic!("Expected Publication, but received {:?}", other),
+    }
+
+    server.stop();
+    handle.join().unwrap();
+}
+
+#[test]
+fn test_websocket_shares_auth_and_limits() {
+    let config = ServerConfig {
+        authenticator: Some(Arc::new(Credentials::parse("dashboard token d1").unwrap())),
+        ..ServerConfig::default()
+    };
+    let server = create_server(config);
+    let handle = setup_server_thread(server.clone());
+    let mut connection = WsConnection::connect(&server);
+    let echo = || client_message::Message::EchoMessage(EchoMessage { content: "ping".to_string() });
+
+    assert_eq!(error_code(&connection.request(echo())), ErrorCode::Unauthenticated);
+    let authenticate = client_message::Message::Authenticate(Authenticate {
+        identity: "dashboard".to_string(),
+        credential: Some(authenticate::Credential::Token("d1".to_string())),
+    });
+    match connection.request(authenticate) {
+        server_message::Message::AuthenticateResponse(response) => assert_eq!(response.identity, "dashboard"),
+        other => panic!("Expected AuthenticateResponse, but received {:?}", other),
+    }
+    assert!(matches!(connection.request(echo()), server_message::Message::EchoMessage(_)));
+
+    // A message over the frame limit ends the connection
+    let oversized = ClientMessage {
+        request_id: 99,
+        message: Some(client_message::Message::EchoMessage(EchoMessage { content: "x".repeat(MAX_FRAME_LEN) })),
+        ..Default::default()
+    };
+    let _ = connection.socket.send(WsMessage::Binary(oversized.encode_to_vec()));
+    loop {
+        match connection.socket.read() {
+            Ok(WsMessage::Close(_)) | Err(_) => break,
+            Ok(WsMessage::Binary(payload)) => panic!("Oversized request was answered: {:?}", ServerMessage::decode(payload.as_slice())),
+            Ok(_) => continue,
+        }
+    }
+
+    server.stop();
+    handle.join().unwrap();
+}
+
+#[test]
+fn test_silent_client_is_dropped_before_the_handshake() {
+    let server = create_server(ServerConfig { handshake_timeout: Duration::from_millis(200), ..ServerConfig::default() });
+    let handle = setup_server_thread(server.clone());
+
+    // Connecting and sending nothing does not hold a thread for ever
+    let mut silent = TcpStream::connect(server.ws_addr().unwrap()).unwrap();
+    silent.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    let started = Instant::now();
+    assert_eq!(silent.read(&mut [0; 16]).unwrap_or(0), 0, "Connection is still open");
+    assert!(started.elapsed() < Duration::from_secs(2));
+
+    // After the handshake the timeout no longer applies
+    let mut connection = WsConnection::connect(&server);
+    thread::sleep(Duration::from_millis(400));
+    match connection.request(client_message::Message::EchoMessage(EchoMessage { content: "idle".to_string() })) {
+        server_message::Message::EchoMessage(echo) => assert_eq!(echo.content, "idle"),
+        other => panic!("Expected EchoMessage, but received {:?}", other),
+    }
+
+    server.stop();
+    handle.join().unwrap();
+}
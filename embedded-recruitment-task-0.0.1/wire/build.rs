@@ -0,0 +1,16 @@
+use std::{env, error::Error, path::PathBuf};
+
+fn main() -> Result<(), Box<dyn Error>> {
+    let descriptors = PathBuf::from(env::var("OUT_DIR")?).join("descriptors.bin");
+    let mut config = prost_build::Config::new();
+    config.file_descriptor_set_path(&descriptors);
+    config.compile_protos(&["proto/messages.proto"], &["proto/"])?;
+
+    // Proto3 JSON mapping, used by the server's JSON listener and HTTP gateway
+    #[cfg(feature = "serde")]
+    pbjson_build::Builder::new()
+        .register_descriptors(&std::fs::read(&descriptors)?)?
+        .build(&[".messages"])?;
+
+    Ok(())
+}
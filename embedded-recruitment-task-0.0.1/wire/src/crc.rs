@@ -0,0 +1,30 @@
+// CRC32C (Castagnoli), the checksum of serial frames.
+//
+// A table-driven software implementation, so it runs on any target. The
+// server's other checksums use the hardware-accelerated `crc32c` crate, which
+// computes the same values.
+
+const POLYNOMIAL: u32 = 0x82F6_3B78; // Reflected form of 0x1EDC6F41
+
+const TABLE: [u32; 256] = table();
+
+const fn table() -> [u32; 256] {
+    let mut table = [0u32; 256];
+    let mut index = 0;
+    while index < 256 {
+        let mut crc = index as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 == 1 { (crc >> 1) ^ POLYNOMIAL } else { crc >> 1 };
+            bit += 1;
+        }
+        table[index] = crc;
+        index += 1;
+    }
+    table
+}
+
+/// CRC32C of `data`
+pub fn crc32c(data: &[u8]) -> u32 {
+    !data.iter().fold(!0u32, |crc, &byte| TABLE[((crc ^ byte as u32) & 0xFF) as usize] ^ (crc >> 8))
+}
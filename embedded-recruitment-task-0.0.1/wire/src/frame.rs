@@ -0,0 +1,100 @@
+// Length-prefixed framing for protobuf messages sent over a byte stream.
+//
+// Every frame is a 4-byte big-endian payload length followed by the encoded
+// message. Without a frame boundary, two messages written back to back (for
+// example a response and a pushed publication) can arrive in a single read and
+// no longer decode.
+use prost::Message;
+#[cfg(feature = "std")]
+use std::io::{self, ErrorKind, Read, Write};
+
+/// Size of the length prefix in front of every frame
+pub const HEADER_LEN: usize = 4;
+
+/// Largest payload accepted by `read_frame`, protects against absurd length prefixes
+pub const MAX_FRAME_LEN: usize = 1024 * 1024;
+
+/// Length prefix of a `len`-byte payload
+pub fn header(len: usize) -> [u8; HEADER_LEN] {
+    (len as u32).to_be_bytes()
+}
+
+/// Payload length announced by a length prefix, `None` if it is over `MAX_FRAME_LEN`
+pub fn payload_len(header: [u8; HEADER_LEN]) -> Option<usize> {
+    let len = u32::from_be_bytes(header) as usize;
+    (len <= MAX_FRAME_LEN).then_some(len)
+}
+
+/// Encodes `message` as a frame at the start of `buffer`, without allocating.
+///
+/// Returns the frame length, or `None` if it does not fit.
+pub fn encode_message_into<M: Message>(message: &M, buffer: &mut [u8]) -> Option<usize> {
+    let len = message.encoded_len();
+    let frame = buffer.get_mut(..HEADER_LEN + len).filter(|_| len <= MAX_FRAME_LEN)?;
+    frame[..HEADER_LEN].copy_from_slice(&header(len));
+    message.encode(&mut &mut frame[HEADER_LEN..]).ok()?;
+    Some(HEADER_LEN + len)
+}
+
+/// Writes `payload` as a single frame and flushes the writer
+#[cfg(feature = "std")]
+pub fn write_frame<W: Write>(writer: &mut W, payload: &[u8]) -> io::Result<()> {
+    if payload.len() > MAX_FRAME_LEN {
+        return Err(io::Error::new(
+            ErrorKind::InvalidInput,
+            std::format!("frame of {} bytes exceeds the {} byte limit", payload.len(), MAX_FRAME_LEN),
+        ));
+    }
+
+    let mut buffer = std::vec::Vec::with_capacity(HEADER_LEN + payload.len());
+    buffer.extend_from_slice(&header(payload.len())); // Length prefix
+    buffer.extend_from_slice(payload);
+    writer.write_all(&buffer)?; // One write so the header and payload are not split by Nagle
+    writer.flush()
+}
+
+/// Reads the next frame payload.
+///
+/// Returns `Ok(None)` when the peer closed the stream cleanly between frames.
+#[cfg(feature = "std")]
+pub fn read_frame<R: Read>(reader: &mut R) -> io::Result<Option<std::vec::Vec<u8>>> {
+    let mut header = [0u8; HEADER_LEN];
+    let mut filled = 0;
+    while filled < HEADER_LEN {
+        match reader.read(&mut header[filled..]) {
+            Ok(0) if filled == 0 => return Ok(None), // Clean disconnect
+            Ok(0) => return Err(ErrorKind::UnexpectedEof.into()), // Disconnected mid-header
+            Ok(n) => filled += n,
+            Err(ref e) if e.kind() == ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        }
+    }
+
+    let Some(len) = payload_len(header) else {
+        return Err(io::Error::new(
+            ErrorKind::InvalidData,
+            std::format!("frame of {} bytes exceeds the {} byte limit", u32::from_be_bytes(header), MAX_FRAME_LEN),
+        ));
+    };
+
+    let mut payload = std::vec![0u8; len];
+    reader.read_exact(&mut payload)?;
+    Ok(Some(payload))
+}
+
+/// Encodes `message` and writes it as a single frame
+#[cfg(feature = "std")]
+pub fn write_message<W: Write, M: Message>(writer: &mut W, message: &M) -> io::Result<()> {
+    write_frame(writer, &message.encode_to_vec())
+}
+
+/// Reads and decodes the next framed message, `Ok(None)` on clean disconnect
+#[cfg(feature = "std")]
+pub fn read_message<R: Read, M: Message + Default>(reader: &mut R) -> io::Result<Option<M>> {
+    match read_frame(reader)? {
+        Some(payload) => M::decode(payload.as_slice())
+            .map(Some)
+            .map_err(|e| io::Error::new(ErrorKind::InvalidData, e)),
+        None => Ok(None),
+    }
+}
@@ -0,0 +1,26 @@
+// Wire format shared by the server and device firmware.
+//
+// The messages of `messages.proto`, the length-prefixed framing used over
+// TCP and the COBS and SLIP framings used over serial lines. The crate is
+// `no_std` and only needs `alloc` to decode messages; frames can be encoded
+// and collected in fixed buffers. The `std` feature (on by default) adds the
+// helpers that read and write frames on `io` streams, and `serde` the proto3
+// JSON mapping of the messages.
+#![no_std]
+
+extern crate alloc;
+#[cfg(feature = "std")]
+extern crate std;
+
+pub mod crc;
+pub mod frame;
+pub mod serial;
+
+#[allow(clippy::needless_borrows_for_generic_args)] // In the generated serde code
+pub mod message {
+    include!(concat!(env!("OUT_DIR"), "/messages.rs"));
+    #[cfg(feature = "serde")]
+    use std::{format, prelude::rust_2021::*}; // The generated serde code expects the `std` prelude
+    #[cfg(feature = "serde")]
+    include!(concat!(env!("OUT_DIR"), "/messages.serde.rs"));
+}
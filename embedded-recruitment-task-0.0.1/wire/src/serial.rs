@@ -0,0 +1,304 @@
+// COBS and SLIP framing for serial lines.
+//
+// A UART has no message boundaries and may drop or corrupt bytes, so every
+// encoded message is followed by its big-endian CRC32C and framed with COBS
+// (no zero byte inside a frame, a zero byte ends it) or SLIP (0xC0 ends a
+// frame, 0xC0 and 0xDB inside it are escaped). A receiver that starts in the
+// middle of a frame, or loses bytes, drops that frame and picks up again at
+// the next delimiter.
+//
+// Encoding and decoding work in place on fixed buffers, so firmware without
+// a heap can use them: `encode_frame_into` and `FrameDecoder::with_buffer`.
+use crate::crc::crc32c;
+use alloc::{vec, vec::Vec};
+use core::fmt;
+#[cfg(feature = "std")]
+use prost::Message;
+#[cfg(feature = "std")]
+use std::io::{self, ErrorKind, Read, Write};
+
+/// Size of the CRC32C after every payload
+pub const CHECKSUM_LEN: usize = 4;
+
+const COBS_DELIMITER: u8 = 0x00;
+const SLIP_END: u8 = 0xC0;
+const SLIP_ESC: u8 = 0xDB;
+const SLIP_ESC_END: u8 = 0xDC;
+const SLIP_ESC_ESC: u8 = 0xDD;
+
+/// How frames are delimited on the line
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Framing {
+    /// Consistent Overhead Byte Stuffing, at most one extra byte per 254 and a trailing 0x00
+    #[default]
+    Cobs,
+    /// RFC 1055 SLIP, a 0xC0 on both ends and escapes that can double the size
+    Slip,
+}
+
+impl Framing {
+    fn delimiter(self) -> u8 {
+        match self {
+            Framing::Cobs => COBS_DELIMITER,
+            Framing::Slip => SLIP_END,
+        }
+    }
+
+    /// Largest frame, delimiters included, that a `len`-byte payload can take on the line
+    pub fn max_encoded_len(self, len: usize) -> usize {
+        let data = len + CHECKSUM_LEN;
+        match self {
+            Framing::Cobs => data + data / 254 + 2,
+            Framing::Slip => 2 * data + 2,
+        }
+    }
+}
+
+/// Reasons a frame is dropped or cannot be encoded
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FrameError {
+    /// The frame is over the limit, or does not fit the buffer
+    TooLong { limit: usize },
+    /// Invalid COBS or SLIP encoding, or too short to hold a checksum
+    Malformed,
+    /// The CRC32C does not match the payload
+    ChecksumMismatch,
+}
+
+impl fmt::Display for FrameError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FrameError::TooLong { limit } => write!(f, "frame exceeds the {} byte limit", limit),
+            FrameError::Malformed => write!(f, "malformed frame"),
+            FrameError::ChecksumMismatch => write!(f, "frame checksum mismatch"),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for FrameError {}
+
+#[cfg(feature = "std")]
+impl From<FrameError> for io::Error {
+    fn from(error: FrameError) -> Self {
+        io::Error::new(ErrorKind::InvalidData, error)
+    }
+}
+
+// Appends bytes to a fixed buffer, failing once it is full
+struct Cursor<'a> {
+    out: &'a mut [u8],
+    len: usize,
+}
+
+impl Cursor<'_> {
+    fn push(&mut self, byte: u8) -> Result<(), FrameError> {
+        let limit = self.out.len();
+        let slot = self.out.get_mut(self.len).ok_or(FrameError::TooLong { limit })?;
+        *slot = byte;
+        self.len += 1;
+        Ok(())
+    }
+}
+
+/// Appends the checksum to `payload` and frames the result into `out`, returning the frame length
+pub fn encode_frame_into(framing: Framing, payload: &[u8], out: &mut [u8]) -> Result<usize, FrameError> {
+    let checksum = crc32c(payload).to_be_bytes();
+    let data = payload.iter().chain(&checksum).copied();
+    let mut cursor = Cursor { out, len: 0 };
+    match framing {
+        Framing::Cobs => {
+            // Blocks of up to 254 non-zero bytes, each led by its length plus one
+            let mut code_index = 0;
+            cursor.push(0)?; // Patched once the block ends
+            let mut code = 1u8;
+            for byte in data {
+                if byte != 0 {
+                    cursor.push(byte)?;
+                    code += 1;
+                }
+                if byte == 0 || code == 0xFF {
+                    cursor.out[code_index] = code;
+                    code_index = cursor.len;
+                    cursor.push(0)?;
+                    code = 1;
+                }
+            }
+            cursor.out[code_index] = code;
+            cursor.push(COBS_DELIMITER)?;
+        }
+        Framing::Slip => {
+            cursor.push(SLIP_END)?; // Flushes any line noise the receiver collected before this frame
+            for byte in data {
+                match byte {
+                    SLIP_END => [SLIP_ESC, SLIP_ESC_END].into_iter().try_for_each(|byte| cursor.push(byte))?,
+                    SLIP_ESC => [SLIP_ESC, SLIP_ESC_ESC].into_iter().try_for_each(|byte| cursor.push(byte))?,
+                    byte => cursor.push(byte)?,
+                }
+            }
+            cursor.push(SLIP_END)?;
+        }
+    }
+    Ok(cursor.len)
+}
+
+/// Appends the checksum to `payload` and frames the result, delimiters included
+pub fn encode_frame(framing: Framing, payload: &[u8]) -> Vec<u8> {
+    let mut frame = vec![0; framing.max_encoded_len(payload.len())];
+    let len = encode_frame_into(framing, payload, &mut frame).expect("buffer holds the longest encoding");
+    frame.truncate(len);
+    frame
+}
+
+// Decodes COBS in place, the output never overtakes the input. Returns the decoded length.
+fn cobs_decode(data: &mut [u8]) -> Option<usize> {
+    let (mut read, mut written) = (0, 0);
+    while read < data.len() {
+        let code = data[read] as usize;
+        if code == 0 || read + code > data.len() {
+            return None;
+        }
+        data.copy_within(read + 1..read + code, written);
+        written += code - 1;
+        read += code;
+        if code < 0xFF && read < data.len() {
+            data[written] = 0; // A full block is not followed by a zero
+            written += 1;
+        }
+    }
+    Some(written)
+}
+
+/// Collects frames from the bytes of the line, one byte at a time.
+///
+/// Frames are gathered in `B`: a `Vec` sized by `new`, or any fixed buffer given to `with_buffer`.
+#[derive(Debug)]
+pub struct FrameDecoder<B = Vec<u8>> {
+    framing: Framing,
+    max_frame: usize,
+    buffer: B, // COBS bytes as received, SLIP bytes already unescaped
+    len: usize, // Bytes of the current frame in `buffer`
+    escaped: bool, // SLIP: the previous byte was an escape
+    error: Option<FrameError>, // Reported once the frame's delimiter arrives
+}
+
+impl FrameDecoder {
+    /// Decoder for messages of up to `max_frame` bytes
+    pub fn new(framing: Framing, max_frame: usize) -> Self {
+        let data = max_frame + CHECKSUM_LEN;
+        let buffered = match framing {
+            Framing::Cobs => data + data / 254 + 1, // Everything but the delimiter
+            Framing::Slip => data, // Escapes are undone as bytes arrive
+        };
+        let mut decoder = FrameDecoder::with_buffer(framing, vec![0; buffered]);
+        decoder.max_frame = max_frame;
+        decoder
+    }
+}
+
+impl<B: AsMut<[u8]>> FrameDecoder<B> {
+    /// Decoder that gathers frames in `buffer`, for firmware without a heap.
+    ///
+    /// A frame is refused once it no longer fits the buffer.
+    pub fn with_buffer(framing: Framing, mut buffer: B) -> Self {
+        let max_frame = buffer.as_mut().len().saturating_sub(CHECKSUM_LEN);
+        FrameDecoder { framing, max_frame, buffer, len: 0, escaped: false, error: None }
+    }
+
+    /// Feeds the next byte, returning the payload once a frame is complete.
+    ///
+    /// Empty frames, such as the leading SLIP delimiter, are skipped.
+    pub fn push(&mut self, byte: u8) -> Option<Result<&[u8], FrameError>> {
+        if byte == self.framing.delimiter() {
+            return self.finish();
+        }
+        if self.error.is_some() {
+            return None; // Skip to the delimiter
+        }
+        let byte = match self.framing {
+            Framing::Slip if self.escaped => {
+                self.escaped = false;
+                match byte {
+                    SLIP_ESC_END => SLIP_END,
+                    SLIP_ESC_ESC => SLIP_ESC,
+                    _ => {
+                        self.error = Some(FrameError::Malformed);
+                        return None;
+                    }
+                }
+            }
+            Framing::Slip if byte == SLIP_ESC => {
+                self.escaped = true;
+                return None;
+            }
+            _ => byte,
+        };
+        match self.buffer.as_mut().get_mut(self.len) {
+            Some(slot) => {
+                *slot = byte;
+                self.len += 1;
+            }
+            None => self.error = Some(FrameError::TooLong { limit: self.max_frame }),
+        }
+        None
+    }
+
+    fn finish(&mut self) -> Option<Result<&[u8], FrameError>> {
+        let len = core::mem::take(&mut self.len);
+        let escaped = core::mem::replace(&mut self.escaped, false);
+        if let Some(error) = self.error.take() {
+            return Some(Err(error));
+        }
+        if len == 0 {
+            return None;
+        }
+        if escaped {
+            return Some(Err(FrameError::Malformed));
+        }
+        let data = &mut self.buffer.as_mut()[..len];
+        let len = match self.framing {
+            Framing::Cobs => match cobs_decode(data) {
+                Some(len) => len,
+                None => return Some(Err(FrameError::Malformed)),
+            },
+            Framing::Slip => len,
+        };
+        if len < CHECKSUM_LEN {
+            return Some(Err(FrameError::Malformed));
+        }
+        let (payload, checksum) = data[..len].split_at(len - CHECKSUM_LEN);
+        if payload.len() > self.max_frame {
+            return Some(Err(FrameError::TooLong { limit: self.max_frame }));
+        }
+        if crc32c(payload).to_be_bytes() != checksum {
+            return Some(Err(FrameError::ChecksumMismatch));
+        }
+        Some(Ok(payload))
+    }
+}
+
+/// Encodes `message` and writes it as a single frame
+#[cfg(feature = "std")]
+pub fn write_message<W: Write, M: Message>(writer: &mut W, framing: Framing, message: &M) -> io::Result<()> {
+    writer.write_all(&encode_frame(framing, &message.encode_to_vec()))?;
+    writer.flush()
+}
+
+/// Reads and decodes the next message, `Ok(None)` when the line closes.
+///
+/// Reads one byte at a time so nothing past the frame is consumed; wrap an unbuffered port in a `BufReader`.
+#[cfg(feature = "std")]
+pub fn read_message<R: Read, M: Message + Default, B: AsMut<[u8]>>(reader: &mut R, decoder: &mut FrameDecoder<B>) -> io::Result<Option<M>> {
+    let mut byte = [0u8; 1];
+    loop {
+        match reader.read(&mut byte) {
+            Ok(0) => return Ok(None),
+            Ok(_) => {}
+            Err(ref e) if e.kind() == ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        }
+        if let Some(frame) = decoder.push(byte[0]) {
+            return M::decode(frame?).map(Some).map_err(|e| io::Error::new(ErrorKind::InvalidData, e));
+        }
+    }
+}
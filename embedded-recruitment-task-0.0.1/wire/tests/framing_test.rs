@@ -0,0 +1,105 @@
+// Uses the `io` helpers, the firmware path without `std` is in no_std_test.rs
+#![cfg(feature = "std")]
+
+use embedded_recruitment_wire::{
+    crc::crc32c,
+    frame::{self, HEADER_LEN},
+    message::{client_message, ClientMessage, EchoMessage},
+    serial::{self, FrameDecoder, FrameError, Framing},
+};
+use prost::Message;
+
+// Payload with zeros, SLIP's special bytes and a run longer than a COBS block
+fn awkward_payload() -> Vec<u8> {
+    let mut payload = vec![0x00, 0xC0, 0xDB, 0xDC, 0xDD, 0x00, 0x00];
+    payload.extend((0..600).map(|i| (i % 255 + 1) as u8));
+    payload.push(0x00);
+    payload
+}
+
+// Feeds `line` to `decoder`, collecting every frame it completes
+fn decode_all<B: AsMut<[u8]>>(decoder: &mut FrameDecoder<B>, line: &[u8]) -> Vec<Result<Vec<u8>, FrameError>> {
+    line.iter().filter_map(|&byte| decoder.push(byte).map(|frame| frame.map(<[u8]>::to_vec))).collect()
+}
+
+#[test]
+fn test_crc32c_check_values() {
+    assert_eq!(crc32c(b""), 0);
+    assert_eq!(crc32c(b"123456789"), 0xE306_9283);
+    assert_eq!(crc32c(&[0; 32]), 0x8A91_36AA);
+}
+
+#[test]
+fn test_serial_round_trip() {
+    let payload = awkward_payload();
+    for framing in [Framing::Cobs, Framing::Slip] {
+        let frame = serial::encode_frame(framing, &payload);
+        assert!(frame.len() <= framing.max_encoded_len(payload.len()));
+        let delimiter = if framing == Framing::Cobs { 0x00 } else { 0xC0 };
+        assert_eq!(frame.iter().filter(|&&byte| byte == delimiter).count(), if framing == Framing::Cobs { 1 } else { 2 });
+
+        // Line noise ahead of the frame is dropped as a frame of its own
+        let mut decoder = FrameDecoder::new(framing, 1024);
+        let line: Vec<u8> = [0x17, 0x42, delimiter].iter().chain(&frame).copied().collect();
+        let frames = decode_all(&mut decoder, &line);
+        assert_eq!(frames.len(), 2, "{:?}", framing);
+        assert!(frames[0].is_err());
+        assert_eq!(frames[1].as_ref().unwrap(), &payload);
+
+        // A flipped bit fails the checksum, a short limit refuses the frame
+        let mut corrupt = frame.clone();
+        corrupt[10] ^= 0x01;
+        let mut decoder = FrameDecoder::new(framing, 1024);
+        assert_eq!(decode_all(&mut decoder, &corrupt), [Err(FrameError::ChecksumMismatch)]);
+        let mut decoder = FrameDecoder::new(framing, 100);
+        assert_eq!(decode_all(&mut decoder, &frame), [Err(FrameError::TooLong { limit: 100 })]);
+    }
+}
+
+#[test]
+fn test_fixed_buffers() {
+    let request = ClientMessage {
+        request_id: 9,
+        message: Some(client_message::Message::EchoMessage(EchoMessage { content: "no heap".to_string() })),
+        ..Default::default()
+    };
+    let payload = request.encode_to_vec();
+
+    for framing in [Framing::Cobs, Framing::Slip] {
+        // What a device without a heap does: encode into and decode from arrays
+        let mut line = [0u8; 64];
+        let len = serial::encode_frame_into(framing, &payload, &mut line).unwrap();
+        assert_eq!(&line[..len], serial::encode_frame(framing, &payload).as_slice());
+
+        let mut decoder = FrameDecoder::with_buffer(framing, [0u8; 64]);
+        let decoded = line[..len].iter().find_map(|&byte| decoder.push(byte).map(|frame| ClientMessage::decode(frame.unwrap())));
+        assert_eq!(decoded.unwrap().unwrap(), request);
+
+        // Buffers that are too small refuse the frame instead of overflowing
+        let mut short = [0u8; 8];
+        assert_eq!(serial::encode_frame_into(framing, &payload, &mut short), Err(FrameError::TooLong { limit: 8 }));
+        let mut decoder = FrameDecoder::with_buffer(framing, [0u8; 12]);
+        assert!(matches!(decode_all(&mut decoder, &line[..len])[..], [Err(FrameError::TooLong { .. })]));
+    }
+}
+
+#[test]
+fn test_length_prefix_into_buffer() {
+    let request = ClientMessage {
+        request_id: 1,
+        message: Some(client_message::Message::EchoMessage(EchoMessage { content: "tcp".to_string() })),
+        ..Default::default()
+    };
+    let mut buffer = [0u8; 32];
+    let len = frame::encode_message_into(&request, &mut buffer).unwrap();
+
+    // Same bytes as the `io` helper writes
+    let mut written = Vec::new();
+    frame::write_message(&mut written, &request).unwrap();
+    assert_eq!(&buffer[..len], written.as_slice());
+    assert_eq!(frame::payload_len(buffer[..HEADER_LEN].try_into().unwrap()), Some(len - HEADER_LEN));
+    assert_eq!(frame::read_message::<_, ClientMessage>(&mut &buffer[..len]).unwrap(), Some(request.clone()));
+
+    assert_eq!(frame::encode_message_into(&request, &mut [0u8; 8]), None);
+    assert_eq!(frame::payload_len(u32::MAX.to_be_bytes()), None);
+}
@@ -0,0 +1,85 @@
+// Only the API that builds without `std`, as device firmware uses it. Run with
+// `--no-default-features` to check that it does.
+use embedded_recruitment_wire::{
+    frame::{self, HEADER_LEN, MAX_FRAME_LEN},
+    message::{client_message, server_message, AddRequest, AddResponse, ClientMessage, ServerMessage},
+    serial::{self, FrameDecoder, FrameError, Framing},
+};
+use prost::Message;
+
+fn add_request(request_id: u64) -> ClientMessage {
+    ClientMessage {
+        request_id,
+        message: Some(client_message::Message::AddRequest(AddRequest { a: 40, b: 2 })),
+        ..Default::default()
+    }
+}
+
+fn add_response(request_id: u64) -> ServerMessage {
+    ServerMessage {
+        request_id,
+        message: Some(server_message::Message::AddResponse(AddResponse { result: 42 })),
+    }
+}
+
+#[test]
+fn test_length_prefixed_request_in_array() {
+    let request = add_request(3);
+    let mut buffer = [0u8; 32];
+    let len = frame::encode_message_into(&request, &mut buffer).unwrap();
+    assert_eq!(len, HEADER_LEN + request.encoded_len());
+    assert_eq!(buffer[..HEADER_LEN], frame::header(request.encoded_len()));
+
+    // The payload is the message as it was encoded
+    let payload_len = frame::payload_len(buffer[..HEADER_LEN].try_into().unwrap()).unwrap();
+    assert_eq!(ClientMessage::decode(&buffer[HEADER_LEN..HEADER_LEN + payload_len]).unwrap(), request);
+
+    // Too small a buffer, or a prefix over the limit, is refused
+    assert_eq!(frame::encode_message_into(&request, &mut [0u8; HEADER_LEN]), None);
+    assert_eq!(frame::payload_len(((MAX_FRAME_LEN + 1) as u32).to_be_bytes()), None);
+    assert_eq!(frame::payload_len((MAX_FRAME_LEN as u32).to_be_bytes()), Some(MAX_FRAME_LEN));
+}
+
+#[test]
+fn test_length_prefixed_replies_from_byte_stream() {
+    // Two replies back to back, as a device reads them off the socket
+    let mut received = [0u8; 64];
+    let first = frame::encode_message_into(&add_response(1), &mut received).unwrap();
+    let second = frame::encode_message_into(&add_response(2), &mut received[first..]).unwrap();
+
+    let mut replies = [0u64; 2];
+    let mut offset = 0;
+    for reply in &mut replies {
+        let len = frame::payload_len(received[offset..offset + HEADER_LEN].try_into().unwrap()).unwrap();
+        let start = offset + HEADER_LEN;
+        *reply = ServerMessage::decode(&received[start..start + len]).unwrap().request_id;
+        offset = start + len;
+    }
+    assert_eq!(replies, [1, 2]);
+    assert_eq!(offset, first + second);
+}
+
+#[test]
+fn test_serial_replies_in_fixed_buffers() {
+    let mut payload = [0u8; 16];
+    let reply = add_response(7);
+    let payload_len = reply.encoded_len();
+    reply.encode(&mut &mut payload[..]).unwrap();
+
+    for framing in [Framing::Cobs, Framing::Slip] {
+        let mut line = [0u8; 32];
+        let len = serial::encode_frame_into(framing, &payload[..payload_len], &mut line).unwrap();
+        assert!(len <= framing.max_encoded_len(payload_len));
+
+        let mut decoder = FrameDecoder::with_buffer(framing, [0u8; 32]);
+        let decoded = line[..len].iter().find_map(|&byte| decoder.push(byte).map(|frame| ServerMessage::decode(frame.unwrap())));
+        assert_eq!(decoded.unwrap().unwrap(), reply, "{:?}", framing);
+
+        // The CRC32C trailer catches a corrupted line
+        let mut decoder = FrameDecoder::with_buffer(framing, [0u8; 32]);
+        let mut corrupt = line;
+        corrupt[2] ^= 0x04;
+        let result = corrupt[..len].iter().find_map(|&byte| decoder.push(byte).map(|frame| frame.map(|_| ())));
+        assert_eq!(result, Some(Err(FrameError::ChecksumMismatch)), "{:?}", framing);
+    }
+}